@@ -11,6 +11,7 @@ fuzz_target!(|func: Func| {
     let _ = env_logger::try_init();
     log::trace!("func:\n{:?}", func);
     let env = regalloc2::fuzzing::func::machine_env();
+    let options = regalloc2::RegallocOptions::default();
     let _out =
-        regalloc2::fuzzing::ion::run(&func, &env, false, false).expect("regalloc did not succeed");
+        regalloc2::fuzzing::ion::run(&func, &env, &options, None).expect("regalloc did not succeed");
 });
@@ -25,6 +25,8 @@ impl Arbitrary<'_> for TestCase {
                     fixed_nonallocatable: true,
                     clobbers: true,
                     reftypes: true,
+                    stack_constraints: false,
+                    conditional_defs: false,
                 },
             )?,
         })
@@ -36,8 +38,12 @@ fuzz_target!(|testcase: TestCase| {
     let _ = env_logger::try_init();
     log::trace!("func:\n{:?}", func);
     let env = regalloc2::fuzzing::func::machine_env();
+    let options = regalloc2::RegallocOptions {
+        verbose_log: true,
+        ..Default::default()
+    };
     let out =
-        regalloc2::fuzzing::ion::run(&func, &env, true, false).expect("regalloc did not succeed");
+        regalloc2::fuzzing::ion::run(&func, &env, &options, None).expect("regalloc did not succeed");
 
     let mut checker = Checker::new(&func, &env);
     checker.prepare(&out);
@@ -26,6 +26,8 @@ impl Arbitrary<'_> for TestCase {
                     fixed_nonallocatable: true,
                     clobbers: true,
                     reftypes: true,
+                    stack_constraints: false,
+                    conditional_defs: false,
                 },
             )?,
         })
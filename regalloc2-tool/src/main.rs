@@ -32,6 +32,7 @@ fn main() {
     let options = RegallocOptions {
         verbose_log: true,
         validate_ssa: true,
+        ..Default::default()
     };
     let output = match regalloc2::run(&function, function.machine_env(), &options) {
         Ok(output) => output,
@@ -88,6 +89,9 @@ fn print_output(func: &SerializableFunction, output: &Output) {
                 InstOrEdit::Edit(Edit::Move { from, to }) => {
                     print!("    edit: move {to} <- {from}\n");
                 }
+                InstOrEdit::Edit(Edit::Remat { inst, vreg, to }) => {
+                    print!("    edit: remat {to} <- inst{} ({vreg})\n", inst.index());
+                }
             }
         }
     }
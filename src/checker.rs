@@ -67,6 +67,13 @@
 //!
 //!       A' = A[alloc_d → A[alloc_s]]
 //!
+//!   - `Edit::Remat` inserted by RA:      [ alloc_d := remat V_i ]
+//!
+//!       A' = A[alloc_d → { V_i }]
+//!
+//!     Unlike a def, this leaves V_i in other allocs: re-executing
+//!     the defining instruction recomputes the same value.
+//!
 //!   - statement in pre-regalloc function [ V_i := op V_j, V_k, ... ]
 //!     with allocated form                [ A_i := op A_j, A_k, ... ]
 //!
@@ -98,7 +105,7 @@
 use crate::{
     Allocation, AllocationKind, Block, Edit, Function, FxHashMap, FxHashSet, Inst, InstOrEdit,
     InstPosition, MachineEnv, Operand, OperandConstraint, OperandKind, OperandPos, Output, PReg,
-    PRegSet, VReg,
+    PRegSet, ProgPoint, ReftypeSpillPolicy, RematInfo, VReg,
 };
 use alloc::vec::Vec;
 use alloc::{format, vec};
@@ -113,6 +120,13 @@ pub struct CheckerErrors {
     errors: Vec<CheckerError>,
 }
 
+impl CheckerErrors {
+    /// The errors detected, in the order found.
+    pub fn errors(&self) -> &[CheckerError] {
+        &self.errors
+    }
+}
+
 /// A single error detected by the regalloc checker.
 #[derive(Clone, Debug)]
 pub enum CheckerError {
@@ -120,6 +134,11 @@ pub enum CheckerError {
         inst: Inst,
         op: Operand,
     },
+    UnusedOperandAllocated {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
     UnknownValueInAllocation {
         inst: Inst,
         op: Operand,
@@ -162,6 +181,11 @@ pub enum CheckerError {
         op: Operand,
         alloc: Allocation,
     },
+    AllocationIsNotInSubclass {
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+    },
     ConflictedValueInStackmap {
         inst: Inst,
         alloc: Allocation,
@@ -171,10 +195,52 @@ pub enum CheckerError {
         alloc: Allocation,
         vregs: FxHashSet<VReg>,
     },
+    /// The stackmap lists a register, but the reftype spill policy
+    /// given with `Checker::with_reftype_spill_policy` is
+    /// `StackOnly`.
+    RegisterInStackmap {
+        inst: Inst,
+        alloc: Allocation,
+    },
+    /// The stackmap lists a register holding `vreg` but no spillslot
+    /// holding it, although the policy is `AlwaysBackedBySlot`.
+    RefNotBackedBySlot {
+        inst: Inst,
+        alloc: Allocation,
+        vreg: VReg,
+    },
     StackToStackMove {
         into: Allocation,
         from: Allocation,
     },
+    /// An `Edit::Remat` re-executed `inst` into `into` to recompute
+    /// `vreg`, but `Function::is_rematerializable` does not allow
+    /// that.
+    InvalidRemat {
+        inst: Inst,
+        vreg: VReg,
+        into: Allocation,
+    },
+    /// A use found the wrong value in `into`, and that value was put
+    /// there by the move from `from` at `point`, which did not hold
+    /// any vreg's value at the time. Only reported when edit-source
+    /// validation is enabled; see
+    /// `Checker::with_edit_source_validation`.
+    UnestablishedEditSource {
+        point: ProgPoint,
+        from: Allocation,
+        into: Allocation,
+    },
+    /// Two simultaneously-live vregs were given the same location:
+    /// writing `other` into `alloc` at `point` overwrote the last
+    /// copy of `live`, which is still used later. `other` is
+    /// `VReg::invalid()` if the written value carries no vreg.
+    InterferingVRegs {
+        point: ProgPoint,
+        alloc: Allocation,
+        live: VReg,
+        other: VReg,
+    },
 }
 
 /// Abstract state for an allocation.
@@ -441,6 +507,13 @@ impl CheckerState {
         allocs: &[Allocation],
         checker: &Checker<'a, F>,
     ) -> Result<(), CheckerError> {
+        if op.is_unused() {
+            if alloc != Allocation::none() {
+                return Err(CheckerError::UnusedOperandAllocated { inst, op, alloc });
+            }
+            return Ok(());
+        }
+
         if alloc == Allocation::none() {
             return Err(CheckerError::MissingAllocation { inst, op });
         }
@@ -487,9 +560,14 @@ impl CheckerState {
                 // Skip Use-checks at the After point if there are any
                 // reused inputs: the Def which reuses the input
                 // happens early.
-                let has_reused_input = operands
-                    .iter()
-                    .any(|op| matches!(op.constraint(), OperandConstraint::Reuse(_)));
+                let has_reused_input = operands.iter().any(|op| {
+                    matches!(
+                        op.constraint(),
+                        OperandConstraint::Reuse(_)
+                            | OperandConstraint::ReuseEither(..)
+                            | OperandConstraint::Tied(_)
+                    )
+                });
                 if has_reused_input && pos == InstPosition::After {
                     return Ok(());
                 }
@@ -516,6 +594,13 @@ impl CheckerState {
                         val
                     );
                     self.check_val(inst, *op, *alloc, val, allocs, checker)?;
+
+                    // A reused input takes the register of the def
+                    // reusing it, so only check subclasses without
+                    // reuses.
+                    if !has_reused_input {
+                        Self::check_subclass(inst, *op, *alloc, checker)?;
+                    }
                 }
             }
             &CheckerInst::Safepoint { inst, ref allocs } => {
@@ -540,6 +625,39 @@ impl CheckerState {
                             vregs: val.vregs().unwrap().clone(),
                         });
                     }
+
+                    if !checker.is_reg(alloc) {
+                        continue;
+                    }
+                    match checker.reftype_spill_policy {
+                        ReftypeSpillPolicy::StackOnly => {
+                            return Err(CheckerError::RegisterInStackmap { inst, alloc });
+                        }
+                        ReftypeSpillPolicy::RegisterAllowed => {}
+                        ReftypeSpillPolicy::AlwaysBackedBySlot => {
+                            // Each reference in a listed register
+                            // must also be in a listed slot.
+                            for &vreg in val.vregs().unwrap() {
+                                if !checker.reftyped_vregs.contains(&vreg) {
+                                    continue;
+                                }
+                                let backed = allocs.iter().any(|&slot| {
+                                    !checker.is_reg(slot)
+                                        && matches!(
+                                            self.get_value(&slot).and_then(|v| v.vregs()),
+                                            Some(vregs) if vregs.contains(&vreg)
+                                        )
+                                });
+                                if !backed {
+                                    return Err(CheckerError::RefNotBackedBySlot {
+                                        inst,
+                                        alloc,
+                                        vreg,
+                                    });
+                                }
+                            }
+                        }
+                    }
                 }
             }
             &CheckerInst::Move { into, from } => {
@@ -555,6 +673,11 @@ impl CheckerState {
                     return Err(CheckerError::StackToStackMove { into, from });
                 }
             }
+            &CheckerInst::Remat { inst, into, vreg } => {
+                if checker.f.is_rematerializable(inst) != Some(RematInfo { vreg }) {
+                    return Err(CheckerError::InvalidRemat { inst, vreg, into });
+                }
+            }
             &CheckerInst::ParallelMove { .. } => {
                 // This doesn't need verification; we just update
                 // according to the move semantics in the step
@@ -585,8 +708,15 @@ impl CheckerState {
                         val
                     );
                     self.set_value(into, val);
+                    self.remove_aliases(into, checker.machine_env);
                 }
             }
+            &CheckerInst::Remat { into, vreg, .. } => {
+                // Other allocations holding `vreg` remain valid: the
+                // recomputed value is the same.
+                self.set_value(into, CheckerValue::from_reg(vreg));
+                self.remove_aliases(into, checker.machine_env);
+            }
             &CheckerInst::ParallelMove { ref moves } => {
                 // First, build map of actions for each vreg in an
                 // alloc. If an alloc has a reg V_i before a parallel
@@ -634,20 +764,37 @@ impl CheckerState {
                 // vreg (and only that vreg), and (ii) update all
                 // other allocs in the checker state by removing this
                 // vreg, if defined (other defs are now stale).
+                //
+                // A conditional def is only possibly-defined: if the
+                // instruction did not write it, the alloc keeps its
+                // old contents, which then serve as the vreg's
+                // (unspecified) value. Either way the alloc may be
+                // read as the vreg afterward and nothing else can be
+                // relied on to survive in it, so it is modeled the
+                // same as a def.
                 for (op, alloc) in operands.iter().zip(allocs.iter()) {
-                    if op.kind() != OperandKind::Def {
+                    if op.kind() != OperandKind::Def || op.is_unused() {
                         continue;
                     }
                     self.remove_vreg(op.vreg());
                     self.set_value(*alloc, CheckerValue::from_reg(op.vreg()));
+                    self.remove_aliases(*alloc, checker.machine_env);
+                    if op.is_full_reg_def() {
+                        self.remove_full_register_parts(*alloc, checker.machine_env);
+                    }
                 }
                 for clobber in clobbers {
                     self.remove_value(&Allocation::reg(*clobber));
+                    self.remove_aliases(Allocation::reg(*clobber), checker.machine_env);
                 }
             }
             &CheckerInst::Safepoint { ref allocs, .. } => {
+                // Registers are only scanned by the collector when the
+                // policy lets references stay in them. Fixed stack-slot
+                // pregs are stack locations and always scanned.
+                let scans_regs = checker.reftype_spill_policy != ReftypeSpillPolicy::StackOnly;
                 for (alloc, value) in self.get_mappings_mut() {
-                    if alloc.is_reg() {
+                    if checker.is_reg(*alloc) && !scans_regs {
                         continue;
                     }
                     if !allocs.contains(&alloc) {
@@ -671,6 +818,155 @@ impl CheckerState {
         }
     }
 
+    /// Writing a register destroys the contents of any registers
+    /// that alias it.
+    fn remove_aliases(&mut self, alloc: Allocation, machine_env: &MachineEnv) {
+        if let Some(preg) = alloc.as_reg() {
+            for &alias in machine_env.aliases(preg) {
+                self.remove_value(&Allocation::reg(alias));
+            }
+        }
+    }
+
+    /// A full-register def (see `Operand::reg_full_def`) also
+    /// destroys the other parts of the registers aliasing it.
+    fn remove_full_register_parts(&mut self, alloc: Allocation, machine_env: &MachineEnv) {
+        if let Some(preg) = alloc.as_reg() {
+            for part in machine_env.full_register_parts(preg) {
+                self.remove_value(&Allocation::reg(part));
+            }
+        }
+    }
+
+    /// Does any allocation hold the value of `vreg`?
+    fn holds_vreg(&self, vreg: VReg) -> bool {
+        match self {
+            CheckerState::Top => true,
+            CheckerState::Allocations(allocs) => allocs.values().any(|value| match value.vregs() {
+                Some(vregs) => vregs.contains(&vreg),
+                None => true,
+            }),
+        }
+    }
+
+    /// Apply `checkinst` to the state, and check that doing so did not
+    /// destroy the only copy of any vreg in `live` (the vregs live
+    /// just after the instruction).
+    fn update_and_check_interference<'a, F: Function>(
+        &mut self,
+        checkinst: &CheckerInst,
+        point: ProgPoint,
+        live: &FxHashSet<VReg>,
+        checker: &Checker<'a, F>,
+        errors: &mut Vec<CheckerError>,
+    ) {
+        // Collect the allocations written, with the vreg written into
+        // each and the vregs they held beforehand.
+        let mut writes: SmallVec<[(Allocation, VReg, Vec<VReg>); 2]> = smallvec![];
+        let old_vregs = |state: &CheckerState, alloc: &Allocation| -> Vec<VReg> {
+            state
+                .get_value(alloc)
+                .and_then(|value| value.vregs())
+                .map(|vregs| vregs.iter().cloned().collect())
+                .unwrap_or_default()
+        };
+        match *checkinst {
+            CheckerInst::Move { into, from } => {
+                if let Some(value) = self.get_value(&from) {
+                    let other = value
+                        .vregs()
+                        .and_then(|vregs| vregs.iter().min().cloned())
+                        .unwrap_or(VReg::invalid());
+                    writes.push((into, other, old_vregs(self, &into)));
+                    if let Some(preg) = into.as_reg() {
+                        for &alias in checker.machine_env.aliases(preg) {
+                            let alias = Allocation::reg(alias);
+                            writes.push((alias, other, old_vregs(self, &alias)));
+                        }
+                    }
+                }
+            }
+            CheckerInst::Remat { into, vreg, .. } => {
+                writes.push((into, vreg, old_vregs(self, &into)));
+                if let Some(preg) = into.as_reg() {
+                    for &alias in checker.machine_env.aliases(preg) {
+                        let alias = Allocation::reg(alias);
+                        writes.push((alias, vreg, old_vregs(self, &alias)));
+                    }
+                }
+            }
+            CheckerInst::Op {
+                ref operands,
+                ref allocs,
+                ..
+            } => {
+                for (op, alloc) in operands.iter().zip(allocs.iter()) {
+                    if op.kind() == OperandKind::Def && !op.is_unused() {
+                        writes.push((*alloc, op.vreg(), old_vregs(self, alloc)));
+                        if let Some(preg) = alloc.as_reg() {
+                            for &alias in checker.machine_env.aliases(preg) {
+                                let alias = Allocation::reg(alias);
+                                writes.push((alias, op.vreg(), old_vregs(self, &alias)));
+                            }
+                            if op.is_full_reg_def() {
+                                for part in checker.machine_env.full_register_parts(preg) {
+                                    let part = Allocation::reg(part);
+                                    writes.push((part, op.vreg(), old_vregs(self, &part)));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        self.update(checkinst, checker);
+
+        for (alloc, other, old) in writes {
+            let mut old = old;
+            old.sort_unstable();
+            for vreg in old {
+                // A value that can be rematerialized may be nowhere
+                // until it is next needed.
+                if vreg != other
+                    && live.contains(&vreg)
+                    && !self.holds_vreg(vreg)
+                    && !checker.remat_vregs.contains(&vreg)
+                {
+                    errors.push(CheckerError::InterferingVRegs {
+                        point,
+                        alloc,
+                        live: vreg,
+                        other,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Check that a vreg restricted to a register subclass was given
+    /// a register in it, unless the operand names a fixed register.
+    fn check_subclass<'a, F: Function>(
+        inst: Inst,
+        op: Operand,
+        alloc: Allocation,
+        checker: &Checker<'a, F>,
+    ) -> Result<(), CheckerError> {
+        if let OperandConstraint::FixedReg(_) = op.constraint() {
+            return Ok(());
+        }
+        if op.is_unused() || op.as_fixed_nonallocatable().is_some() {
+            return Ok(());
+        }
+        if let (Some(preg), Some(subclass)) = (alloc.as_reg(), checker.f.reg_subclass(op.vreg())) {
+            if !checker.machine_env.reg_subclasses[subclass].contains(preg) {
+                return Err(CheckerError::AllocationIsNotInSubclass { inst, op, alloc });
+            }
+        }
+        Ok(())
+    }
+
     fn check_constraint<'a, F: Function>(
         &self,
         inst: Inst,
@@ -719,6 +1015,38 @@ impl CheckerState {
                     });
                 }
             }
+            OperandConstraint::Tied(group) => {
+                if alloc.kind() != AllocationKind::Reg {
+                    return Err(CheckerError::AllocationIsNotReg { inst, op, alloc });
+                }
+                let first = checker
+                    .f
+                    .inst_operands(inst)
+                    .iter()
+                    .position(|other| other.constraint() == OperandConstraint::Tied(group))
+                    .unwrap();
+                if alloc != allocs[first] {
+                    return Err(CheckerError::AllocationIsNotReuse {
+                        inst,
+                        op,
+                        alloc,
+                        expected_alloc: allocs[first],
+                    });
+                }
+            }
+            OperandConstraint::ReuseEither(a, b) => {
+                if alloc.kind() != AllocationKind::Reg {
+                    return Err(CheckerError::AllocationIsNotReg { inst, op, alloc });
+                }
+                if alloc != allocs[a] && alloc != allocs[b] {
+                    return Err(CheckerError::AllocationIsNotReuse {
+                        inst,
+                        op,
+                        alloc,
+                        expected_alloc: allocs[a],
+                    });
+                }
+            }
         }
         Ok(())
     }
@@ -731,6 +1059,14 @@ pub(crate) enum CheckerInst {
     /// spillslots).
     Move { into: Allocation, from: Allocation },
 
+    /// A rematerialization: `inst` is executed again to put the value
+    /// of `vreg` in `into`.
+    Remat {
+        inst: Inst,
+        into: Allocation,
+        vreg: VReg,
+    },
+
     /// A parallel move in the original program. Simultaneously moves
     /// from all source vregs to all corresponding dest vregs,
     /// permitting overlap in the src and dest sets and doing all
@@ -762,8 +1098,11 @@ pub struct Checker<'a, F: Function> {
     bb_insts: FxHashMap<Block, Vec<CheckerInst>>,
     edge_insts: FxHashMap<(Block, Block), Vec<CheckerInst>>,
     reftyped_vregs: FxHashSet<VReg>,
+    remat_vregs: FxHashSet<VReg>,
     machine_env: &'a MachineEnv,
     stack_pregs: PRegSet,
+    validate_edit_sources: bool,
+    reftype_spill_policy: ReftypeSpillPolicy,
 }
 
 impl<'a, F: Function> Checker<'a, F> {
@@ -790,7 +1129,23 @@ impl<'a, F: Function> Checker<'a, F> {
             reftyped_vregs.insert(vreg);
         }
 
-        bb_in.insert(f.entry_block(), CheckerState::default());
+        let mut remat_vregs = FxHashSet::default();
+        for inst in 0..f.num_insts() {
+            if let Some(RematInfo { vreg }) = f.is_rematerializable(Inst::new(inst)) {
+                remat_vregs.insert(vreg);
+            }
+        }
+
+        // Values live in registers on entry are the only known
+        // contents of any allocation at the start of the function.
+        let mut entry_state = CheckerState::default();
+        if !f.entry_live_regs().is_empty() {
+            entry_state = CheckerState::initial();
+            for &(vreg, preg) in f.entry_live_regs() {
+                entry_state.set_value(Allocation::reg(preg), CheckerValue::from_reg(vreg));
+            }
+        }
+        bb_in.insert(f.entry_block(), entry_state);
 
         let mut stack_pregs = PRegSet::empty();
         for &preg in &machine_env.fixed_stack_slots {
@@ -803,11 +1158,45 @@ impl<'a, F: Function> Checker<'a, F> {
             bb_insts,
             edge_insts,
             reftyped_vregs,
+            remat_vregs,
             machine_env,
             stack_pregs,
+            validate_edit_sources: false,
+            reftype_spill_policy: ReftypeSpillPolicy::StackOnly,
         }
     }
 
+    /// Check stackmaps against the given reftype spill policy, which
+    /// must be the one the allocation was made with. By default they
+    /// are checked as for `ReftypeSpillPolicy::StackOnly`. Under the
+    /// other policies, listed registers are checked like listed
+    /// slots, and references in registers that the stackmap omits do
+    /// not survive the safepoint; under `AlwaysBackedBySlot`, each
+    /// listed register must also have its reference in a listed
+    /// slot.
+    pub fn with_reftype_spill_policy(mut self, policy: ReftypeSpillPolicy) -> Self {
+        self.reftype_spill_policy = policy;
+        self
+    }
+
+    /// Is `alloc` a register, as opposed to a slot or a register
+    /// standing for a stack location?
+    fn is_reg(&self, alloc: Allocation) -> bool {
+        matches!(alloc.as_reg(), Some(preg) if !self.stack_pregs.contains(preg))
+    }
+
+    /// Also trace where wrong values came from: when a use finds the
+    /// wrong value in an allocation that was last written by a move
+    /// in the same block whose own source held no value, report that
+    /// move (following chains of such moves back to the first one) as
+    /// `CheckerError::UnestablishedEditSource` instead of the use.
+    /// Moves from empty sources that are never read, such as the
+    /// save and restore of an unused scratch register, are fine.
+    pub fn with_edit_source_validation(mut self) -> Self {
+        self.validate_edit_sources = true;
+        self
+    }
+
     /// Build the list of checker instructions based on the given func
     /// and allocation results.
     pub fn prepare(&mut self, out: &Output) {
@@ -847,7 +1236,22 @@ impl<'a, F: Function> Checker<'a, F> {
     ) {
         // If this is a safepoint, then check the spillslots at this point.
         if self.f.requires_refs_on_stack(inst) {
-            let allocs = safepoint_slots.remove(&inst).unwrap_or_else(|| vec![]);
+            let mut allocs = safepoint_slots.remove(&inst).unwrap_or_else(|| vec![]);
+
+            // Slots written by the instruction's own early defs are
+            // not yet filled when the safepoint is checked here,
+            // before the instruction; the def itself is checked by
+            // the `Op` below.
+            for (op, alloc) in self
+                .f
+                .inst_operands(inst)
+                .iter()
+                .zip(out.inst_allocs(inst).iter())
+            {
+                if op.kind() == OperandKind::Def && op.pos() == OperandPos::Early {
+                    allocs.retain(|a| a != alloc);
+                }
+            }
 
             let checkinst = CheckerInst::Safepoint { inst, allocs };
             self.bb_insts.get_mut(&block).unwrap().push(checkinst);
@@ -859,7 +1263,22 @@ impl<'a, F: Function> Checker<'a, F> {
         if !self.f.is_branch(inst) {
             let operands: Vec<_> = self.f.inst_operands(inst).iter().cloned().collect();
             let allocs: Vec<_> = out.inst_allocs(inst).iter().cloned().collect();
-            let clobbers: Vec<_> = self.f.inst_clobbers(inst).into_iter().collect();
+            let mut clobbers = self.f.inst_clobbers(inst);
+            // So does a register forbidden at the instruction.
+            clobbers.union_from(self.f.inst_forbidden_pregs(inst));
+            // And one reserved after it.
+            for &(preg, range) in self.f.reserved_preg_ranges() {
+                if range.contains_point(ProgPoint::after(inst)) {
+                    clobbers.add(preg);
+                }
+            }
+            // A spill-all instruction leaves no register value intact.
+            if self.f.spill_all_at(inst) {
+                for reg in self.machine_env.allocatable_regs() {
+                    clobbers.add(reg);
+                }
+            }
+            let clobbers: Vec<_> = clobbers.into_iter().collect();
             let checkinst = CheckerInst::Op {
                 inst,
                 operands,
@@ -897,13 +1316,23 @@ impl<'a, F: Function> Checker<'a, F> {
 
     fn handle_edit(&mut self, block: Block, edit: &Edit) {
         trace!("checker: adding edit {:?}", edit);
-        match edit {
-            &Edit::Move { from, to } => {
+        match *edit {
+            Edit::Move { from, to } => {
                 self.bb_insts
                     .get_mut(&block)
                     .unwrap()
                     .push(CheckerInst::Move { into: to, from });
             }
+            Edit::Remat { inst, vreg, to } => {
+                self.bb_insts
+                    .get_mut(&block)
+                    .unwrap()
+                    .push(CheckerInst::Remat {
+                        inst,
+                        into: to,
+                        vreg,
+                    });
+            }
         }
     }
 
@@ -975,22 +1404,161 @@ impl<'a, F: Function> Checker<'a, F> {
         }
     }
 
+    /// Add the effect of `inst` to a backward liveness scan: its defs
+    /// die and its uses become live.
+    fn inst_liveness(&self, inst: Inst, live: &mut FxHashSet<VReg>) {
+        let operands = self.f.inst_operands(inst);
+        for op in operands {
+            if op.kind() == OperandKind::Def
+                && op.as_fixed_nonallocatable().is_none()
+                && !op.is_unused()
+            {
+                live.remove(&op.vreg());
+            }
+        }
+        for op in operands {
+            if op.kind() == OperandKind::Use
+                && op.as_fixed_nonallocatable().is_none()
+                && !op.is_unused()
+            {
+                live.insert(op.vreg());
+            }
+        }
+    }
+
+    /// Compute the set of vregs live out of each block in the
+    /// original (pre-allocation) program.
+    fn compute_live_outs(&self) -> FxHashMap<Block, FxHashSet<VReg>> {
+        let mut live_ins: FxHashMap<Block, FxHashSet<VReg>> = FxHashMap::default();
+        let mut live_outs: FxHashMap<Block, FxHashSet<VReg>> = FxHashMap::default();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..self.f.num_blocks()).rev() {
+                let block = Block::new(block);
+                let insts = self.f.block_insns(block);
+                let mut live = FxHashSet::default();
+                for (i, &succ) in self.f.block_succs(block).iter().enumerate() {
+                    if let Some(succ_in) = live_ins.get(&succ) {
+                        let params = self.f.block_params(succ);
+                        live.extend(succ_in.iter().filter(|vreg| !params.contains(vreg)));
+                    }
+                    live.extend(
+                        self.f
+                            .branch_blockparams(block, insts.last(), i)
+                            .iter()
+                            .cloned(),
+                    );
+                }
+                live_outs.insert(block, live.clone());
+                for inst in insts.iter().rev() {
+                    self.inst_liveness(inst, &mut live);
+                }
+                if live_ins.get(&block) != Some(&live) {
+                    live_ins.insert(block, live);
+                    changed = true;
+                }
+            }
+        }
+        live_outs
+    }
+
     /// Using BB-start state computed by `analyze()`, step the checker state
     /// through each BB and check each instruction's register allocations
     /// for errors.
+    ///
+    /// This also verifies that no two simultaneously-live vregs share
+    /// an allocation: whenever an instruction or move writes to an
+    /// allocation, the vregs it previously held must either be dead
+    /// or still be available somewhere else.
     fn find_errors(&self) -> Result<(), CheckerErrors> {
         let mut errors = vec![];
+        let live_outs = self.compute_live_outs();
         for (block, input) in &self.bb_in {
+            // How each instruction in this block changes liveness:
+            // the vregs it uses for the last time, and those it
+            // defines that are used later. With these, the live set
+            // is stepped forward in place from the block's live-ins.
+            let insts = self.f.block_insns(*block);
+            let mut changes = Vec::with_capacity(insts.len());
+            let mut live = live_outs.get(block).cloned().unwrap_or_default();
+            for inst in insts.iter().rev() {
+                let mut dying: SmallVec<[VReg; 4]> = smallvec![];
+                let mut born: SmallVec<[VReg; 4]> = smallvec![];
+                for op in self.f.inst_operands(inst) {
+                    if op.as_fixed_nonallocatable().is_some() || op.is_unused() {
+                        continue;
+                    }
+                    let live_after = live.contains(&op.vreg());
+                    match op.kind() {
+                        OperandKind::Use if !live_after => dying.push(op.vreg()),
+                        OperandKind::Def if live_after => born.push(op.vreg()),
+                        _ => {}
+                    }
+                }
+                changes.push((dying, born));
+                self.inst_liveness(inst, &mut live);
+            }
+            changes.reverse();
+
+            let mut point = ProgPoint::before(insts.first());
             let mut state = input.clone();
+            // Allocations last written by a move from an allocation
+            // holding no value, with that (first) move.
+            let mut unestablished: FxHashMap<Allocation, (ProgPoint, Allocation)> =
+                FxHashMap::default();
             for inst in self.bb_insts.get(block).unwrap() {
                 if let Err(e) = state.check(InstPosition::Before, inst, self) {
                     trace!("Checker error: {:?}", e);
-                    errors.push(e);
+                    errors.push(self.trace_edit_source(e, &unestablished));
+                }
+                if let CheckerInst::Op { inst, .. } = *inst {
+                    point = ProgPoint::after(inst);
+                    let (dying, born) = &changes[inst.index() - insts.first().index()];
+                    for vreg in dying {
+                        live.remove(vreg);
+                    }
+                    live.extend(born.iter().cloned());
+                }
+                if let CheckerInst::Move { into, from } = *inst {
+                    if self.validate_edit_sources {
+                        let established = matches!(
+                            state.get_value(&from),
+                            Some(CheckerValue::VRegs(vregs)) if !vregs.is_empty()
+                        );
+                        if established {
+                            unestablished.remove(&into);
+                        } else {
+                            let origin = unestablished.get(&from).cloned();
+                            unestablished.insert(into, origin.unwrap_or((point, from)));
+                        }
+                    }
+                }
+                if let CheckerInst::Remat { into, .. } = *inst {
+                    unestablished.remove(&into);
+                }
+                state.update_and_check_interference(inst, point, &live, self, &mut errors);
+                if let CheckerInst::Op {
+                    ref operands,
+                    ref allocs,
+                    ref clobbers,
+                    ..
+                } = *inst
+                {
+                    // Defs and clobbers overwrite whatever a move left
+                    // behind.
+                    for (op, alloc) in operands.iter().zip(allocs.iter()) {
+                        if op.kind() == OperandKind::Def {
+                            unestablished.remove(alloc);
+                        }
+                    }
+                    for &preg in clobbers {
+                        unestablished.remove(&Allocation::reg(preg));
+                    }
                 }
-                state.update(inst, self);
                 if let Err(e) = state.check(InstPosition::After, inst, self) {
                     trace!("Checker error: {:?}", e);
-                    errors.push(e);
+                    errors.push(self.trace_edit_source(e, &unestablished));
                 }
             }
         }
@@ -1002,6 +1570,31 @@ impl<'a, F: Function> Checker<'a, F> {
         }
     }
 
+    /// If `error` is a use finding the wrong value in an allocation
+    /// that was filled by a move from an empty source, blame the move
+    /// instead.
+    fn trace_edit_source(
+        &self,
+        error: CheckerError,
+        unestablished: &FxHashMap<Allocation, (ProgPoint, Allocation)>,
+    ) -> CheckerError {
+        match error {
+            CheckerError::UnknownValueInAllocation { alloc, .. }
+            | CheckerError::ConflictedValueInAllocation { alloc, .. }
+            | CheckerError::IncorrectValuesInAllocation { alloc, .. } => {
+                match unestablished.get(&alloc) {
+                    Some(&(point, from)) => CheckerError::UnestablishedEditSource {
+                        point,
+                        from,
+                        into: alloc,
+                    },
+                    None => error,
+                }
+            }
+            _ => error,
+        }
+    }
+
     /// Find any errors, returning `Err(CheckerErrors)` with all errors found
     /// or `Ok(())` otherwise.
     pub fn run(mut self) -> Result<(), CheckerErrors> {
@@ -1049,6 +1642,9 @@ impl<'a, F: Function> Checker<'a, F> {
                     &CheckerInst::Move { from, into } => {
                         trace!("    {} -> {}", from, into);
                     }
+                    &CheckerInst::Remat { inst, into, vreg } => {
+                        trace!("    remat inst{} {} -> {}", inst.index(), vreg, into);
+                    }
                     &CheckerInst::Safepoint { ref allocs, .. } => {
                         let mut slotargs = vec![];
                         for &slot in allocs {
@@ -15,6 +15,10 @@ use super::arbitrary::Result as ArbitraryResult;
 use super::arbitrary::{Arbitrary, Unstructured};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "enable-serde",
+    derive(::serde::Serialize, ::serde::Deserialize)
+)]
 pub enum InstOpcode {
     Op,
     Ret,
@@ -22,6 +26,10 @@ pub enum InstOpcode {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "enable-serde",
+    derive(::serde::Serialize, ::serde::Deserialize)
+)]
 pub struct InstData {
     op: InstOpcode,
     operands: Vec<Operand>,
@@ -49,6 +57,10 @@ impl InstData {
 }
 
 #[derive(Clone)]
+#[cfg_attr(
+    feature = "enable-serde",
+    derive(::serde::Serialize, ::serde::Deserialize)
+)]
 pub struct Func {
     insts: Vec<InstData>,
     blocks: Vec<InstRange>,
@@ -236,6 +248,23 @@ impl Arbitrary<'_> for OperandConstraint {
     }
 }
 
+/// `Stack` is only chosen with `opts.stack_constraints`, so that
+/// existing fuzz inputs still decode to the same functions.
+fn arbitrary_constraint(
+    u: &mut Unstructured,
+    opts: &Options,
+) -> ArbitraryResult<OperandConstraint> {
+    if opts.stack_constraints {
+        Ok(*u.choose(&[
+            OperandConstraint::Any,
+            OperandConstraint::Reg,
+            OperandConstraint::Stack,
+        ])?)
+    } else {
+        OperandConstraint::arbitrary(u)
+    }
+}
+
 fn choose_dominating_block(
     idom: &[Block],
     mut block: Block,
@@ -268,6 +297,8 @@ pub struct Options {
     pub fixed_nonallocatable: bool,
     pub clobbers: bool,
     pub reftypes: bool,
+    pub stack_constraints: bool,
+    pub conditional_defs: bool,
 }
 
 impl core::default::Default for Options {
@@ -278,6 +309,8 @@ impl core::default::Default for Options {
             fixed_nonallocatable: false,
             clobbers: false,
             reftypes: false,
+            stack_constraints: false,
+            conditional_defs: false,
         }
     }
 }
@@ -399,18 +432,18 @@ impl Func {
             let mut avail = block_params[block].clone();
             let mut remaining_nonlocal_uses = u.int_in_range(0..=3)?;
             while let Some(vreg) = vregs_by_block_to_be_defined[block].pop() {
-                let def_constraint = OperandConstraint::arbitrary(u)?;
+                let def_constraint = arbitrary_constraint(u, opts)?;
                 let def_pos = if bool::arbitrary(u)? {
                     OperandPos::Early
                 } else {
                     OperandPos::Late
                 };
-                let mut operands = vec![Operand::new(
-                    vreg,
-                    def_constraint,
-                    OperandKind::Def,
-                    def_pos,
-                )];
+                let def = if opts.conditional_defs && bool::arbitrary(u)? {
+                    Operand::conditional_def(vreg, def_constraint, def_pos)
+                } else {
+                    Operand::new(vreg, def_constraint, OperandKind::Def, def_pos)
+                };
+                let mut operands = vec![def];
                 let mut allocations = vec![Allocation::none()];
                 for _ in 0..u.int_in_range(0..=3)? {
                     let vreg = if avail.len() > 0
@@ -432,7 +465,7 @@ impl Func {
                         remaining_nonlocal_uses -= 1;
                         *u.choose(&vregs_by_block[def_block.index()])?
                     };
-                    let use_constraint = OperandConstraint::arbitrary(u)?;
+                    let use_constraint = arbitrary_constraint(u, opts)?;
                     operands.push(Operand::new(
                         vreg,
                         use_constraint,
@@ -448,20 +481,32 @@ impl Func {
                     debug_assert_eq!(op.kind(), OperandKind::Def);
                     let reused = u.int_in_range(1..=(operands.len() - 1))?;
                     if op.class() == operands[reused].class() {
-                        operands[0] = Operand::new(
-                            op.vreg(),
-                            OperandConstraint::Reuse(reused),
-                            op.kind(),
-                            OperandPos::Late,
-                        );
-                        // Make sure reused input is a Reg.
-                        let op = operands[reused];
-                        operands[reused] = Operand::new(
-                            op.vreg(),
-                            OperandConstraint::Reg,
-                            op.kind(),
-                            OperandPos::Early,
-                        );
+                        // Possibly let the def reuse either of two
+                        // inputs, as for a commutative instruction.
+                        let other = u.int_in_range(1..=(operands.len() - 1))?;
+                        let either = other != reused
+                            && reused <= 3
+                            && other <= 3
+                            && op.class() == operands[other].class()
+                            && bool::arbitrary(u)?;
+                        let constraint = if either {
+                            OperandConstraint::ReuseEither(reused, other)
+                        } else {
+                            OperandConstraint::Reuse(reused)
+                        };
+                        operands[0] =
+                            Operand::new(op.vreg(), constraint, op.kind(), OperandPos::Late);
+                        // Make sure reused inputs are Regs.
+                        let inputs: &[usize] = if either { &[reused, other] } else { &[reused] };
+                        for &i in inputs {
+                            let op = operands[i];
+                            operands[i] = Operand::new(
+                                op.vreg(),
+                                OperandConstraint::Reg,
+                                op.kind(),
+                                OperandPos::Early,
+                            );
+                        }
                     }
                 } else if opts.fixed_regs && bool::arbitrary(u)? {
                     let mut fixed_early = vec![];
@@ -515,6 +560,22 @@ impl Func {
                     )));
                 }
 
+                // Liveness does not support a vreg used more than once
+                // by an instruction if one of the uses is on the stack.
+                for i in 0..operands.len() {
+                    let op = operands[i];
+                    if op.kind() == OperandKind::Use
+                        && op.constraint() == OperandConstraint::Stack
+                        && operands
+                            .iter()
+                            .enumerate()
+                            .any(|(j, other)| j != i && other.vreg() == op.vreg())
+                    {
+                        operands[i] =
+                            Operand::new(op.vreg(), OperandConstraint::Any, op.kind(), op.pos());
+                    }
+                }
+
                 let is_safepoint = opts.reftypes
                     && operands
                         .iter()
@@ -641,6 +702,56 @@ impl core::fmt::Debug for Func {
     }
 }
 
+/// How many byte streams `random_function` tries before giving up.
+const RANDOM_FUNCTION_ATTEMPTS: usize = 64;
+
+/// Generate a random, well-formed function from `seed`, using all
+/// of the optional features (fixed registers, reused inputs, stack
+/// constraints, conditional defs, clobbers, reftypes and
+/// safepoints). The same seed
+/// always produces the same function, so failures found by property
+/// tests can be reproduced. The result is meant to be allocated with
+/// [`machine_env()`] and checked with the checker.
+///
+/// Panics if none of `RANDOM_FUNCTION_ATTEMPTS` byte streams drawn
+/// from the seed builds a function; each does with a probability of
+/// roughly one half, so this does not happen in practice.
+pub fn random_function(seed: u64) -> Func {
+    let opts = Options {
+        reused_inputs: true,
+        fixed_regs: true,
+        fixed_nonallocatable: true,
+        clobbers: true,
+        reftypes: true,
+        stack_constraints: true,
+        conditional_defs: true,
+    };
+    // Expand the seed into byte streams with SplitMix64. Building a
+    // function from a given stream fails or succeeds deterministically
+    // (e.g. when a block parameter's class has no value available),
+    // so each attempt continues the sequence for fresh bytes.
+    let mut state = seed;
+    for _ in 0..RANDOM_FUNCTION_ATTEMPTS {
+        let mut bytes: Vec<u8> = Vec::with_capacity(1 << 14);
+        while bytes.len() < 1 << 14 {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            bytes.extend_from_slice(&z.to_le_bytes());
+        }
+        let mut u = Unstructured::new(&bytes[..]);
+        if let Ok(func) = Func::arbitrary_with_options(&mut u, &opts) {
+            return func;
+        }
+    }
+    panic!(
+        "no function generated from seed {} in {} attempts",
+        seed, RANDOM_FUNCTION_ATTEMPTS
+    );
+}
+
 pub fn machine_env() -> MachineEnv {
     fn regs(r: core::ops::Range<usize>, c: RegClass) -> Vec<PReg> {
         r.map(|i| PReg::new(i, c)).collect()
@@ -671,5 +782,8 @@ pub fn machine_env() -> MachineEnv {
         non_preferred_regs_by_class,
         scratch_by_class,
         fixed_stack_slots,
+        reg_aliases: vec![],
+        reg_subclasses: vec![],
+        already_saved: PRegSet::empty(),
     }
 }
@@ -13,12 +13,16 @@
 //! Data structures for backtracking allocator.
 
 use super::liveranges::SpillWeight;
+use super::process::AllocRegResult;
+use super::requirement::Requirement;
+use super::vreg_trace::VregTraceEvent;
 use crate::cfg::CFGInfo;
 use crate::index::ContainerComparator;
 use crate::indexset::IndexSet;
 use crate::{
-    define_index, Allocation, Block, Edit, Function, FxHashSet, Inst, MachineEnv, Operand, PReg,
-    ProgPoint, RegClass, VReg,
+    define_index, Allocation, Block, Edit, Function, FxHashMap, FxHashSet, Inst, MachineEnv,
+    Operand, PReg, PRegSet, ProgPoint, QueueTieBreak, RegClass, RegallocOptions, SpillReason,
+    SpillRegion, SpillSlot, SplitEvent, SplitProposal, VReg,
 };
 use alloc::collections::BTreeMap;
 use alloc::string::String;
@@ -30,6 +34,7 @@ use smallvec::{smallvec, SmallVec};
 
 /// A range from `from` (inclusive) to `to` (exclusive).
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CodeRange {
     pub from: ProgPoint,
     pub to: ProgPoint,
@@ -48,6 +53,13 @@ impl CodeRange {
     pub fn contains_point(&self, other: ProgPoint) -> bool {
         other >= self.from && other < self.to
     }
+    /// Do the two ranges share any program point? Both are
+    /// half-open, so a range ending at a point does not overlap one
+    /// starting there: e.g. `[Before(i), After(i))` and
+    /// `[After(i), Before(i+1))` are disjoint. This is exactly when
+    /// the two ranges compare `Equal` under `Ord`, which is the
+    /// comparison the allocator's per-register interval maps use to
+    /// find conflicts.
     #[inline(always)]
     pub fn overlaps(&self, other: &Self) -> bool {
         other.to > self.from && other.from < self.to
@@ -81,15 +93,18 @@ impl core::cmp::PartialOrd for CodeRange {
         Some(self.cmp(other))
     }
 }
+/// Orders disjoint ranges by position, and treats overlapping ranges
+/// as equal. This is only a total order on a set of pairwise
+/// disjoint ranges, such as the ranges allocated to one register.
 impl core::cmp::Ord for CodeRange {
     #[inline(always)]
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.to <= other.from {
+        if self.overlaps(other) {
+            Ordering::Equal
+        } else if self.to <= other.from {
             Ordering::Less
-        } else if self.from >= other.to {
-            Ordering::Greater
         } else {
-            Ordering::Equal
+            Ordering::Greater
         }
     }
 }
@@ -208,8 +223,9 @@ pub struct LiveBundle {
 
 pub const BUNDLE_MAX_SPILL_WEIGHT: u32 = (1 << 28) - 1;
 pub const MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT: u32 = BUNDLE_MAX_SPILL_WEIGHT;
-pub const MINIMAL_BUNDLE_SPILL_WEIGHT: u32 = BUNDLE_MAX_SPILL_WEIGHT - 1;
-pub const BUNDLE_MAX_NORMAL_SPILL_WEIGHT: u32 = BUNDLE_MAX_SPILL_WEIGHT - 2;
+pub const MINIMAL_SUBCLASS_BUNDLE_SPILL_WEIGHT: u32 = BUNDLE_MAX_SPILL_WEIGHT - 1;
+pub const MINIMAL_BUNDLE_SPILL_WEIGHT: u32 = BUNDLE_MAX_SPILL_WEIGHT - 2;
+pub const BUNDLE_MAX_NORMAL_SPILL_WEIGHT: u32 = BUNDLE_MAX_SPILL_WEIGHT - 3;
 
 impl LiveBundle {
     #[inline(always)]
@@ -301,12 +317,15 @@ pub struct SpillSet {
     pub spill_bundle: LiveBundleIndex,
     pub required: bool,
     pub splits: u8,
-
-    /// The aggregate [`CodeRange`] of all involved [`LiveRange`]s. The effect of this abstraction
-    /// is that we attempt to allocate one spill slot for the extent of a bundle. For fragmented
-    /// bundles with lots of open space this abstraction is pessimistic, but when bundles are small
-    /// or dense this yields similar results to tracking individual live ranges.
-    pub range: CodeRange,
+    /// Split budget requested through `Function::max_splits`, if any.
+    pub max_splits: Option<u8>,
+    /// Index into `MachineEnv::reg_subclasses`, if restricted.
+    pub subclass: Option<usize>,
+    /// Register given by `Function::vreg_fixed_preg`, if pinned.
+    pub pinned: Option<PReg>,
+    /// Group given by `Function::allocation_group`, if any.
+    pub group: Option<u32>,
+    pub region: SpillRegion,
 }
 
 pub(crate) const MAX_SPLITS_PER_SPILLSET: u8 = 2;
@@ -444,11 +463,32 @@ impl core::ops::IndexMut<VReg> for VRegs {
     }
 }
 
-#[derive(Clone, Debug)]
+/// A caller-supplied hook that may move a proposed split point; see
+/// `run_with_split_hook`.
+pub struct SplitHook<'a>(pub &'a mut dyn FnMut(SplitProposal) -> ProgPoint);
+
+impl<'a> core::fmt::Debug for SplitHook<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "SplitHook")
+    }
+}
+
+/// A caller-supplied order for bundles of equal priority; see
+/// `run_with_queue_tie_break`.
+pub struct QueueTieBreakHook<'a>(pub &'a QueueTieBreak<'a>);
+
+impl<'a> core::fmt::Debug for QueueTieBreakHook<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "QueueTieBreakHook")
+    }
+}
+
+#[derive(Debug)]
 pub struct Env<'a, F: Function> {
     pub func: &'a F,
     pub env: &'a MachineEnv,
     pub cfginfo: CFGInfo,
+    pub options: RegallocOptions,
     pub liveins: Vec<IndexSet>,
     pub liveouts: Vec<IndexSet>,
     pub blockparam_outs: Vec<BlockparamOut>,
@@ -460,14 +500,19 @@ pub struct Env<'a, F: Function> {
     pub vregs: VRegs,
     pub pregs: Vec<PRegData>,
     pub allocation_queue: PrioQueue,
-    pub safepoints: Vec<Inst>, // Sorted list of safepoint insts.
+    pub safepoints: Vec<Inst>,    // Sorted list of safepoint insts.
+    pub clobber_insts: Vec<Inst>, // Sorted list of insts with clobbers.
     pub safepoints_per_vreg: HashMap<usize, HashSet<Inst>>,
 
     pub spilled_bundles: Vec<LiveBundleIndex>,
     pub spillslots: Vec<SpillSlotData>,
-    pub slots_by_class: [SpillSlotList; 3],
+    pub slots_by_region_and_class: [[SpillSlotList; 3]; 3],
 
     pub extra_spillslots_by_class: [SmallVec<[Allocation; 2]>; 3],
+    // Spillslots holding the source of a move while another move
+    // overwrites a register aliasing it; kept so that resolving moves
+    // again reuses them.
+    pub alias_temp_slots_by_class: [SmallVec<[Allocation; 2]>; 3],
     pub preferred_victim_by_class: [PReg; 3],
 
     // When multiple fixed-register constraints are present on a
@@ -483,9 +528,19 @@ pub struct Env<'a, F: Function> {
     // Output:
     pub allocs: Vec<Allocation>,
     pub inst_alloc_offsets: Vec<u32>,
-    pub num_spillslots: u32,
+    pub num_spillslots_by_region: [u32; 3],
     pub safepoint_slots: Vec<(ProgPoint, Allocation)>,
     pub debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
+    pub split_events: Vec<SplitEvent>,
+    pub block_entry_locations: Vec<(Block, VReg, Allocation)>,
+    pub preg_maps: Vec<(PReg, CodeRange, Option<VReg>)>,
+    pub spilled_ranges: Vec<(VReg, SpillSlot, CodeRange)>,
+    pub live_ranges: Vec<(VReg, CodeRange, Allocation)>,
+    pub spillslot_classes: Vec<(SpillSlot, RegClass)>,
+    pub live_regs_by_inst: Vec<[u32; 3]>,
+    pub bundle_requirements: Vec<(Vec<VReg>, Result<Requirement, ProgPoint>)>,
+    pub loop_resident_fallbacks: Vec<(VReg, Block)>,
+    pub spill_reasons: Vec<(VRegIndex, LiveRangeIndex, SpillReason)>,
 
     pub allocated_bundle_count: usize,
 
@@ -497,11 +552,78 @@ pub struct Env<'a, F: Function> {
     pub annotations_enabled: bool,
 
     // Cached allocation for `try_to_allocate_bundle_to_reg` to avoid allocating
-    // a new HashSet on every call.
-    pub conflict_set: FxHashSet<LiveBundleIndex>,
+    // a new set on every call. Kept sorted, so that it is a plain
+    // function of the bundle indices inserted, with nothing depending
+    // on hashing.
+    pub conflict_set: Vec<LiveBundleIndex>,
+
+    // The result of probing each preg for the bundle being processed,
+    // indexed by preg: kept across the attempts of one
+    // `process_bundle` call, until an eviction changes the preg or a
+    // register aliasing it. Only complete conflict scans are kept.
+    pub probe_cache: Vec<Option<AllocRegResult>>,
+
+    // The live ranges of each spillset's vregs, sorted and
+    // coalesced; filled in when allocating spillslots.
+    pub spillset_ranges: Vec<SmallVec<[CodeRange; 4]>>,
+
+    // Registers kept free within each loop that requested it; see
+    // `Function::loop_reg_reserve`.
+    pub loop_reg_reserves: Vec<(CodeRange, PRegSet)>,
+
+    // Loops (by header and extent) that a vreg should stay in a
+    // register across; see `Function::loop_resident`.
+    pub loop_residents: Vec<(VRegIndex, Block, CodeRange)>,
+    // Whether to act on `Function::loop_resident` at all, rather
+    // than report every request as not honored.
+    pub honor_loop_residents: bool,
+
+    // Whether any operand is a full-register def (see
+    // `Operand::reg_full_def`), in which case probing a register
+    // must also look at the other parts of the registers aliasing it.
+    pub has_full_reg_defs: bool,
+
+    // Every register that has been given to some bundle, for
+    // `RegallocOptions::minimize_callee_saved`.
+    pub allocated_pregs: PRegSet,
+
+    // The input chosen for each `ReuseEither` def, keyed by
+    // instruction and operand index of the def.
+    pub reuse_either_choices: FxHashMap<(Inst, usize), usize>,
+
+    // Allocation groups (see `Function::allocation_group`) whose
+    // members are being spilled together.
+    pub spilled_groups: FxHashSet<u32>,
+
+    // The defining instruction of each vreg that may be
+    // rematerialized (see `Function::is_rematerializable`); once
+    // spillslots are allocated, only those that are.
+    pub remat_vregs: FxHashMap<VRegIndex, Inst>,
+
+    // Optional caller hook to adjust split points.
+    pub split_hook: Option<SplitHook<'a>>,
+
+    // Optional caller order for bundles of equal priority.
+    pub queue_tie_break: Option<QueueTieBreakHook<'a>>,
+
+    // Seed perturbing the register scan offset; see
+    // `RegallocOptions::scan_seed`.
+    pub scan_seed: Option<u64>,
+
+    // The vreg whose allocation decisions are recorded, and those
+    // decisions so far; see `RegallocOptions::trace_vreg`.
+    pub trace_vreg: Option<VReg>,
+    pub vreg_trace_events: Vec<VregTraceEvent>,
 }
 
 impl<'a, F: Function> Env<'a, F> {
+    /// Perturb the starting offset of each register scan by an
+    /// amount derived from `seed`; see `RegallocOptions::scan_seed`.
+    pub fn with_scan_seed(mut self, seed: u64) -> Self {
+        self.scan_seed = Some(seed);
+        self
+    }
+
     /// Get the VReg (with bundled RegClass) from a vreg index.
     #[inline]
     pub fn vreg(&self, index: VRegIndex) -> VReg {
@@ -543,10 +665,12 @@ impl SpillSetRanges {
 pub struct SpillSlotData {
     pub ranges: SpillSetRanges,
     pub slots: u32,
+    pub region: SpillRegion,
+    pub class: RegClass,
     pub alloc: Allocation,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct SpillSlotList {
     pub slots: SmallVec<[SpillSlotIndex; 32]>,
     pub probe_start: usize,
@@ -575,6 +699,8 @@ impl SpillSlotList {
 #[derive(Clone, Debug)]
 pub struct PrioQueue {
     pub heap: alloc::collections::BinaryHeap<PrioQueueEntry>,
+    /// The largest number of entries the queue has held at once.
+    pub peak_len: usize,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -613,10 +739,12 @@ impl LiveRangeKey {
     }
 }
 
+// Keys compare as their ranges do (see `CodeRange::overlaps`), so
+// a lookup finds any allocated range overlapping the query.
 impl core::cmp::PartialEq for LiveRangeKey {
     #[inline(always)]
     fn eq(&self, other: &Self) -> bool {
-        self.to > other.from && self.from < other.to
+        self.to_range().overlaps(&other.to_range())
     }
 }
 impl core::cmp::Eq for LiveRangeKey {}
@@ -629,13 +757,7 @@ impl core::cmp::PartialOrd for LiveRangeKey {
 impl core::cmp::Ord for LiveRangeKey {
     #[inline(always)]
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        if self.to <= other.from {
-            core::cmp::Ordering::Less
-        } else if self.from >= other.to {
-            core::cmp::Ordering::Greater
-        } else {
-            core::cmp::Ordering::Equal
-        }
+        self.to_range().cmp(&other.to_range())
     }
 }
 
@@ -653,6 +775,7 @@ impl PrioQueue {
     pub fn new() -> Self {
         PrioQueue {
             heap: alloc::collections::BinaryHeap::new(),
+            peak_len: 0,
         }
     }
 
@@ -663,6 +786,7 @@ impl PrioQueue {
             bundle,
             reg_hint,
         });
+        self.peak_len = core::cmp::max(self.peak_len, self.heap.len());
     }
 
     #[inline(always)]
@@ -696,6 +820,7 @@ pub struct InsertedMove {
 pub enum InsertMovePrio {
     InEdgeMoves,
     Regular,
+    SafepointStore,
     MultiFixedRegInitial,
     MultiFixedRegSecondary,
     ReusedInput,
@@ -728,6 +853,12 @@ impl InsertedMoves {
             trace!(" -> skipping move with same source and  dest");
             return;
         }
+        // A rematerialized value is nowhere while spilled, and never
+        // needs to be moved there.
+        if to_alloc.is_none() {
+            trace!(" -> skipping move into rematerialized range");
+            return;
+        }
         if let Some(from) = from_alloc.as_reg() {
             debug_assert_eq!(from.class(), to_vreg.class());
         }
@@ -782,6 +913,10 @@ impl Edits {
     }
 
     pub fn add(&mut self, pos_prio: PosWithPrio, from: Allocation, to: Allocation) {
+        // Move resolution never asks for a move from a location to
+        // itself; one here means a bug or a missed optimization
+        // upstream. Release builds just drop it.
+        debug_assert_ne!(from, to, "self-move at {:?}", pos_prio.pos);
         if from != to {
             if from.is_reg() && to.is_reg() {
                 debug_assert_eq!(from.as_reg().unwrap().class(), to.as_reg().unwrap().class());
@@ -789,6 +924,11 @@ impl Edits {
             self.edits.push((pos_prio, Edit::Move { from, to }));
         }
     }
+
+    pub fn add_remat(&mut self, pos_prio: PosWithPrio, inst: Inst, vreg: VReg, to: Allocation) {
+        debug_assert_eq!(to.as_reg().map(|reg| reg.class()), Some(vreg.class()));
+        self.edits.push((pos_prio, Edit::Remat { inst, vreg, to }));
+    }
 }
 
 /// The fields in this struct are reversed in sort order so that the entire
@@ -807,38 +947,95 @@ impl PosWithPrio {
     }
 }
 
+/// Counters describing the work the allocator did for one function,
+/// returned in `Output::stats`. They are meant for tuning and
+/// diagnostics; how each one moves with a given change to the
+/// allocator is not a stable property.
 #[derive(Clone, Copy, Debug, Default)]
 #[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Stats {
+    /// Blocks whose live-in sets were computed.
     pub livein_blocks: usize,
+    /// Block visits of the live-in dataflow analysis, including
+    /// revisits until it converged.
     pub livein_iterations: usize,
+    /// Live ranges built from the input, before any splitting.
     pub initial_liverange_count: usize,
+    /// Bundles queued for allocation after merging the vregs' initial
+    /// bundles.
     pub merged_bundle_count: usize,
+    /// Bundles taken from the allocation queue, counting requeues.
     pub process_bundle_count: usize,
+    /// Currently always zero.
     pub process_bundle_reg_probes_fixed: usize,
+    /// Currently always zero.
     pub process_bundle_reg_success_fixed: usize,
+    /// Currently always zero.
     pub process_bundle_bounding_range_probe_start_any: usize,
+    /// Currently always zero.
     pub process_bundle_bounding_range_probes_any: usize,
+    /// Currently always zero.
     pub process_bundle_bounding_range_success_any: usize,
+    /// Register scans started for a bundle without a fixed register.
     pub process_bundle_reg_probe_start_any: usize,
+    /// Registers tried for a bundle without a fixed register.
     pub process_bundle_reg_probes_any: usize,
+    /// Of those, the ones answered from the conflicts found on an
+    /// earlier attempt for the same bundle, without a rescan.
+    pub process_bundle_reg_probes_cached: usize,
+    /// Bundles without a fixed register allocated on a probe.
     pub process_bundle_reg_success_any: usize,
+    /// Times one or more bundles were evicted to make room.
     pub evict_bundle_event: usize,
+    /// Bundles evicted.
     pub evict_bundle_count: usize,
+    /// Bundles split.
     pub splits: usize,
+    /// Currently always zero.
     pub splits_clobbers: usize,
+    /// Currently always zero.
     pub splits_hot: usize,
+    /// Currently always zero.
     pub splits_conflicts: usize,
+    /// Currently always zero.
     pub splits_defs: usize,
+    /// Currently always zero.
     pub splits_all: usize,
+    /// Bundles reduced to minimal pieces once
+    /// `RegallocOptions::time_budget` ran out.
+    pub splits_timeout: usize,
+    /// Split bundles merged back together; see
+    /// `RegallocOptions::remerge`.
+    pub remerged_bundles: usize,
+    /// Bundles kept in their registers after the first phase of
+    /// `RegallocOptions::two_phase`.
+    pub frozen_bundles: usize,
+    /// The largest number of bundles waiting in the allocation queue
+    /// at once. Splits requeue bundles, so a peak well above
+    /// `merged_bundle_count` indicates heavy splitting.
+    pub peak_queue_len: usize,
+    /// Live ranges once all bundles are allocated, including those
+    /// created by splitting.
     pub final_liverange_count: usize,
+    /// Bundles once all are allocated, including those created by
+    /// splitting and the spill bundles.
     pub final_bundle_count: usize,
+    /// Spill bundles, which hold the parts of split bundles between
+    /// their uses, given a register if one is free or else a
+    /// spillslot.
     pub spill_bundle_count: usize,
+    /// Registers tried for spill bundles.
     pub spill_bundle_reg_probes: usize,
+    /// Spill bundles given a register.
     pub spill_bundle_reg_success: usize,
+    /// Incoming block-parameter transfers.
     pub blockparam_ins_count: usize,
+    /// Outgoing block-parameter transfers.
     pub blockparam_outs_count: usize,
+    /// Half-moves (the source or destination of a move across a
+    /// control-flow edge) generated.
     pub halfmoves_count: usize,
+    /// Edits in the output.
     pub edits_count: usize,
 }
 
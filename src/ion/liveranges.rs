@@ -21,8 +21,9 @@ use crate::ion::data_structures::{
     BlockparamIn, BlockparamOut, FixedRegFixupLevel, MultiFixedRegFixup,
 };
 use crate::{
-    Allocation, Block, Function, FxHashMap, FxHashSet, Inst, InstPosition, Operand,
-    OperandConstraint, OperandKind, OperandPos, PReg, ProgPoint, RegAllocError, VReg,
+    tied_uses, Allocation, Block, Function, FxHashMap, FxHashSet, Inst, InstPosition, Operand,
+    OperandConstraint, OperandKind, OperandPos, PReg, PRegSet, ProgPoint, ReftypeSpillPolicy,
+    RegAllocError, VReg,
 };
 use alloc::collections::VecDeque;
 use alloc::vec;
@@ -41,10 +42,27 @@ pub fn spill_weight_from_constraint(
     loop_depth: usize,
     is_def: bool,
 ) -> SpillWeight {
-    // A bonus of 1000 for one loop level, 4000 for two loop levels,
-    // 16000 for three loop levels, etc. Avoids exponentiation.
+    spill_weight_at_frequency(constraint, loop_depth_frequency(loop_depth), is_def)
+}
+
+/// The execution frequency, relative to the function's entry,
+/// assumed for a block at the given (approximate) loop depth when the
+/// function supplies none: four times as often per level, up to a
+/// depth of 10. Avoids exponentiation.
+#[inline(always)]
+pub fn loop_depth_frequency(loop_depth: usize) -> f32 {
     let loop_depth = core::cmp::min(10, loop_depth);
-    let hot_bonus: f32 = (0..loop_depth).fold(1000.0, |a, _| a * 4.0);
+    (0..loop_depth).fold(1.0, |a, _| a * 4.0)
+}
+
+#[inline(always)]
+pub fn spill_weight_at_frequency(
+    constraint: OperandConstraint,
+    frequency: f32,
+    is_def: bool,
+) -> SpillWeight {
+    // A bonus of 1000 for each execution of the use per entry.
+    let hot_bonus: f32 = 1000.0 * frequency;
     let def_bonus: f32 = if is_def { 2000.0 } else { 0.0 };
     let constraint_bonus: f32 = match constraint {
         OperandConstraint::Any => 1000.0,
@@ -92,6 +110,11 @@ impl SpillWeight {
     }
 }
 
+/// Scale applied to the spill weight of uses in cold blocks (see
+/// `Function::is_cold_block`), so that values used only there are
+/// cheap to spill.
+const COLD_BLOCK_SPILL_WEIGHT_SCALE: f32 = 0.125;
+
 impl core::ops::Add<SpillWeight> for SpillWeight {
     type Output = SpillWeight;
     fn add(self, other: SpillWeight) -> Self {
@@ -146,6 +169,108 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
+    /// The index of the input whose register the def at operand
+    /// `idx` of `inst` reuses, if it has a reuse constraint. A def
+    /// tied to a group with uses reuses the first of them.
+    pub fn reused_input_index(&self, inst: Inst, idx: usize) -> Option<usize> {
+        let operands = self.func.inst_operands(inst);
+        match operands[idx].constraint() {
+            OperandConstraint::Reuse(i) => Some(i),
+            OperandConstraint::ReuseEither(..) => Some(self.reuse_either_choices[&(inst, idx)]),
+            OperandConstraint::Tied(group) if operands[idx].kind() == OperandKind::Def => {
+                tied_uses(operands, group).next()
+            }
+            _ => None,
+        }
+    }
+
+    /// Is `block` a loop header, i.e. does it have a backedge
+    /// predecessor?
+    fn is_loop_header(&self, block: usize) -> bool {
+        self.func
+            .block_preds(Block::new(block))
+            .iter()
+            .any(|pred| pred.index() >= block)
+    }
+
+    /// The code covered by the loop headed by `header`.
+    fn loop_range(&self, header: usize) -> CodeRange {
+        let depth = &self.cfginfo.approx_loop_depth;
+        // The loop extends over the following blocks until its
+        // nesting level ends, or a sibling loop starts.
+        let mut last = header;
+        while last + 1 < self.func.num_blocks()
+            && depth[last + 1] >= depth[header]
+            && !(depth[last + 1] == depth[header] && self.is_loop_header(last + 1))
+        {
+            last += 1;
+        }
+        CodeRange {
+            from: self.cfginfo.block_entry[header],
+            to: self.cfginfo.block_exit[last].next(),
+        }
+    }
+
+    /// Collect the registers to keep free within each loop, as
+    /// requested by `Function::loop_reg_reserve`.
+    pub fn compute_loop_reg_reserves(&mut self) {
+        let mut reserves = vec![];
+        for header in 0..self.func.num_blocks() {
+            if !self.is_loop_header(header) {
+                continue;
+            }
+            let (class, count) = match self.func.loop_reg_reserve(Block::new(header)) {
+                Some((class, count)) if count > 0 => (class, count),
+                _ => continue,
+            };
+            let range = self.loop_range(header);
+
+            let mut regs = PRegSet::empty();
+            let class_idx = class as u8 as usize;
+            let order: Vec<PReg> = match self.func.reg_preference_order(class) {
+                Some(order) => order.to_vec(),
+                None => self.env.preferred_regs_by_class[class_idx]
+                    .iter()
+                    .chain(self.env.non_preferred_regs_by_class[class_idx].iter())
+                    .cloned()
+                    .collect(),
+            };
+            for &preg in order.iter().rev().take(count) {
+                regs.add(preg);
+            }
+            trace!(
+                "loop at {:?} ({:?}): reserving {:?}",
+                Block::new(header),
+                range,
+                regs
+            );
+            reserves.push((range, regs));
+        }
+        self.loop_reg_reserves = reserves;
+    }
+
+    /// Collect the loops that vregs should stay in a register
+    /// across, as requested by `Function::loop_resident`. Requests
+    /// naming a block that is not a loop header cannot be honored,
+    /// and are reported as such straight away, as are all requests
+    /// when not honoring them.
+    pub fn compute_loop_residents(&mut self) {
+        for &(vreg, header) in self.func.loop_resident() {
+            if !self.honor_loop_residents {
+                self.loop_resident_fallbacks.push((vreg, header));
+                continue;
+            }
+            if !self.is_loop_header(header.index()) {
+                trace!("loop-resident {} at {:?}: not a loop header", vreg, header);
+                self.loop_resident_fallbacks.push((vreg, header));
+                continue;
+            }
+            let range = self.loop_range(header.index());
+            self.loop_residents
+                .push((VRegIndex::new(vreg.vreg()), header, range));
+        }
+    }
+
     /// Mark `range` as live for the given `vreg`.
     ///
     /// Returns the liverange that contains the given range.
@@ -216,16 +341,55 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
+    /// How often `block` runs relative to the function's entry: the
+    /// frequency the function gives, or else one estimated from its
+    /// loop depth.
+    pub fn block_frequency(&self, block: Block) -> f32 {
+        self.func.block_frequency(block).unwrap_or_else(|| {
+            loop_depth_frequency(self.cfginfo.approx_loop_depth[block.index()] as usize)
+        })
+    }
+
+    /// The spill weight of a use with the given constraint in
+    /// `block`, accounting for its frequency (or loop depth) and
+    /// coldness.
+    pub fn spill_weight_in_block(
+        &self,
+        constraint: OperandConstraint,
+        block: Block,
+        is_def: bool,
+    ) -> SpillWeight {
+        let model = self.options.spill_weight_model;
+        let weight = match self.func.block_frequency(block) {
+            Some(frequency) => match model {
+                Some(model) => SpillWeight::from_f32(
+                    model.use_spill_weight_at_frequency(constraint, frequency, is_def),
+                ),
+                None => spill_weight_at_frequency(constraint, frequency, is_def),
+            },
+            None => {
+                let loop_depth = self.cfginfo.approx_loop_depth[block.index()] as usize;
+                match model {
+                    Some(model) => SpillWeight::from_f32(
+                        model.use_spill_weight(constraint, loop_depth, is_def),
+                    ),
+                    None => spill_weight_from_constraint(constraint, loop_depth, is_def),
+                }
+            }
+        };
+        if self.func.is_cold_block(block) {
+            SpillWeight::from_f32(weight.to_f32() * COLD_BLOCK_SPILL_WEIGHT_SCALE)
+        } else {
+            weight
+        }
+    }
+
     pub fn insert_use_into_liverange(&mut self, into: LiveRangeIndex, mut u: Use) {
         let operand = u.operand;
         let constraint = operand.constraint();
         let block = self.cfginfo.insn_block[u.pos.inst().index()];
-        let loop_depth = self.cfginfo.approx_loop_depth[block.index()] as usize;
-        let weight = spill_weight_from_constraint(
-            constraint,
-            loop_depth,
-            operand.kind() != OperandKind::Use,
-        );
+        let weight =
+            self.spill_weight_in_block(constraint, block, operand.kind() != OperandKind::Use);
         u.weight = weight.to_bits();
 
         trace!(
@@ -273,6 +437,54 @@ impl<'a, F: Function> Env<'a, F> {
         debug_assert!(res.is_none());
     }
 
+    /// Reserve `reg` over `range`, around whatever is already
+    /// reserved in it there: only the gaps between existing entries
+    /// in its allocation map are added.
+    pub fn reserve_preg_range(&mut self, range: CodeRange, reg: PReg) {
+        let start = LiveRangeKey::from_range(&CodeRange {
+            from: range.from,
+            to: range.from,
+        });
+        let mut gaps = vec![];
+        let mut pos = range.from;
+        for key in self.pregs[reg.index()]
+            .allocations
+            .btree
+            .range(start..)
+            .map(|(k, _)| k)
+        {
+            let taken = key.to_range();
+            if taken.from >= range.to {
+                break;
+            }
+            if taken.from > pos {
+                gaps.push(CodeRange {
+                    from: pos,
+                    to: taken.from,
+                });
+            }
+            pos = core::cmp::max(pos, taken.to);
+        }
+        if pos < range.to {
+            gaps.push(CodeRange {
+                from: pos,
+                to: range.to,
+            });
+        }
+        for gap in gaps {
+            self.add_liverange_to_preg(gap, reg);
+        }
+    }
+
+    /// Reserve the ranges given by `Function::reserved_preg_ranges`,
+    /// once every fixed range from the instructions is in place.
+    pub fn add_preg_reservations(&mut self) {
+        for &(preg, range) in self.func.reserved_preg_ranges() {
+            trace!("reserving {} over {:?}", preg, range);
+            self.reserve_preg_range(range, preg);
+        }
+    }
+
     pub fn is_live_in(&mut self, block: Block, vreg: VRegIndex) -> bool {
         self.liveins[block.index()].get(vreg.index())
     }
@@ -318,7 +530,7 @@ impl<'a, F: Function> Env<'a, F> {
             for inst in insns.iter().rev() {
                 for pos in &[OperandPos::Late, OperandPos::Early] {
                     for op in self.func.inst_operands(inst) {
-                        if op.as_fixed_nonallocatable().is_some() {
+                        if op.as_fixed_nonallocatable().is_some() || op.is_unused() {
                             continue;
                         }
                         if op.pos() == *pos {
@@ -355,8 +567,13 @@ impl<'a, F: Function> Env<'a, F> {
             self.liveins[block.index()] = live;
         }
 
-        // Check that there are no liveins to the entry block.
-        if !self.liveins[self.func.entry_block().index()].is_empty() {
+        // Check that there are no liveins to the entry block, other
+        // than those the function declares as live in registers.
+        let mut entry_liveins = self.liveins[self.func.entry_block().index()].clone();
+        for &(vreg, _) in self.func.entry_live_regs() {
+            entry_liveins.set(vreg.vreg(), false);
+        }
+        if !entry_liveins.is_empty() {
             trace!(
                 "non-empty liveins to entry block: {:?}",
                 self.liveins[self.func.entry_block().index()]
@@ -367,7 +584,7 @@ impl<'a, F: Function> Env<'a, F> {
         Ok(())
     }
 
-    pub fn build_liveranges(&mut self) {
+    pub fn build_liveranges(&mut self) -> Result<(), RegAllocError> {
         for &vreg in self.func.reftype_vregs() {
             self.safepoints_per_vreg.insert(vreg.vreg(), HashSet::new());
         }
@@ -387,6 +604,9 @@ impl<'a, F: Function> Env<'a, F> {
         let mut vreg_ranges: Vec<LiveRangeIndex> =
             vec![LiveRangeIndex::invalid(); self.func.num_vregs()];
 
+        // Registers reserved across any `spill_all_at` instruction.
+        let allocatable_regs = self.env.allocatable_regs();
+
         for i in (0..self.func.num_blocks()).rev() {
             let block = Block::new(i);
             let insns = self.func.block_insns(block);
@@ -443,8 +663,60 @@ impl<'a, F: Function> Env<'a, F> {
             // For each instruction, in reverse order, process
             // operands and clobbers.
             for inst in insns.iter().rev() {
+                // A spill-all instruction reserves every allocatable
+                // register over both of its points, so nothing can
+                // stay in a register across it; this subsumes any
+                // clobbers of those registers.
+                let spill_all = self.func.spill_all_at(inst);
+                if spill_all {
+                    // Nothing can be given a register here, so no
+                    // operand may require one.
+                    for op in self.func.inst_operands(inst) {
+                        let needs_reg = match op.constraint() {
+                            OperandConstraint::Any | OperandConstraint::Stack => false,
+                            OperandConstraint::FixedReg(preg) => allocatable_regs.contains(preg),
+                            OperandConstraint::Reg
+                            | OperandConstraint::Reuse(_)
+                            | OperandConstraint::ReuseEither(..)
+                            | OperandConstraint::Tied(_) => true,
+                        };
+                        if needs_reg && !op.is_unused() {
+                            return Err(RegAllocError::RegOperandAtSpillAll(inst));
+                        }
+                    }
+                    for preg in allocatable_regs {
+                        let range = CodeRange {
+                            from: ProgPoint::before(inst),
+                            to: ProgPoint::before(inst.next()),
+                        };
+                        self.add_liverange_to_preg(range, preg);
+                    }
+                }
+
+                // Forbidden registers are reserved over both points
+                // too, which likewise subsumes clobbering them.
+                let forbidden = self.func.inst_forbidden_pregs(inst);
+                for preg in forbidden {
+                    if spill_all && allocatable_regs.contains(preg) {
+                        continue;
+                    }
+                    let range = CodeRange {
+                        from: ProgPoint::before(inst),
+                        to: ProgPoint::before(inst.next()),
+                    };
+                    self.add_liverange_to_preg(range, preg);
+                }
+
                 // Mark clobbers with CodeRanges on PRegs.
+                if self.func.inst_clobbers(inst) != PRegSet::empty() {
+                    self.clobber_insts.push(inst);
+                }
                 for clobber in self.func.inst_clobbers(inst) {
+                    if (spill_all && allocatable_regs.contains(clobber))
+                        || forbidden.contains(clobber)
+                    {
+                        continue;
+                    }
                     // Clobber range is at After point only: an
                     // instruction can still take an input in a reg
                     // that it later clobbers. (In other words, the
@@ -462,14 +734,42 @@ impl<'a, F: Function> Env<'a, F> {
                 // proper interference wrt other inputs. We note the
                 // *vreg* that is reused, not the index.
                 let mut reused_input = None;
-                for op in self.func.inst_operands(inst) {
-                    if let OperandConstraint::Reuse(i) = op.constraint() {
-                        debug_assert!(self.func.inst_operands(inst)[i]
-                            .as_fixed_nonallocatable()
-                            .is_none());
-                        reused_input = Some(self.func.inst_operands(inst)[i].vreg());
-                        break;
-                    }
+                let operands = self.func.inst_operands(inst);
+                for (idx, op) in operands.iter().enumerate() {
+                    let i = match op.constraint() {
+                        OperandConstraint::Reuse(i) => i,
+                        OperandConstraint::Tied(group) => {
+                            // One register cannot hold two values at
+                            // once, so the group's uses must agree.
+                            let mut uses = tied_uses(operands, group);
+                            if let Some(first) = uses.next() {
+                                if !uses.all(|i| operands[i].vreg() == operands[first].vreg()) {
+                                    return Err(RegAllocError::TiedOperands(inst));
+                                }
+                            }
+                            match self.reused_input_index(inst, idx) {
+                                Some(i) => i,
+                                None => continue,
+                            }
+                        }
+                        OperandConstraint::ReuseEither(a, b) => {
+                            // Prefer an input that dies here, so that
+                            // the def can take over its register
+                            // without a copy.
+                            let live_after = |i: usize| live.get(operands[i].vreg().vreg());
+                            let i = if live_after(a) && !live_after(b) {
+                                b
+                            } else {
+                                a
+                            };
+                            trace!("inst{} operand {} reuses input {}", inst.index(), idx, i);
+                            self.reuse_either_choices.insert((inst, idx), i);
+                            i
+                        }
+                        _ => continue,
+                    };
+                    debug_assert!(operands[i].as_fixed_nonallocatable().is_none());
+                    reused_input.get_or_insert(operands[i].vreg());
                 }
 
                 // Preprocess defs and uses. Specifically, if there
@@ -498,7 +798,7 @@ impl<'a, F: Function> Env<'a, F> {
                     }
                 }
                 for (i, &operand) in self.func.inst_operands(inst).iter().enumerate() {
-                    if operand.as_fixed_nonallocatable().is_some() {
+                    if operand.as_fixed_nonallocatable().is_some() || operand.is_unused() {
                         continue;
                     }
                     if let OperandConstraint::FixedReg(preg) = operand.constraint() {
@@ -620,9 +920,18 @@ impl<'a, F: Function> Env<'a, F> {
                             continue;
                         }
 
+                        // An unused operand keeps its `none` alloc and
+                        // takes no part in allocation.
+                        if operand.is_unused() {
+                            continue;
+                        }
+
                         match operand.kind() {
                             OperandKind::Def => {
                                 trace!("Def of {} at {:?}", operand.vreg(), pos);
+                                if operand.is_full_reg_def() {
+                                    self.has_full_reg_defs = true;
+                                }
 
                                 // Get or create the LiveRange.
                                 let mut lr = vreg_ranges[operand.vreg().vreg()];
@@ -674,6 +983,30 @@ impl<'a, F: Function> Env<'a, F> {
                                 // doesn't already exist, and add the use
                                 // to the range.
                                 let mut lr = vreg_ranges[operand.vreg().vreg()];
+                                if operand.is_kill() && live.get(operand.vreg().vreg()) {
+                                    // The caller says this is the last
+                                    // use, but the vreg is live
+                                    // afterward. Trim the later range to
+                                    // start one point after this use,
+                                    // leaving a gap so that no move
+                                    // connects the two; later uses then
+                                    // see an unspecified value.
+                                    let from = pos.next().next();
+                                    let range = self.ranges[lr].range;
+                                    let first_use = self.ranges[lr].uses.last().map(|u| u.pos);
+                                    if from < range.to && !matches!(first_use, Some(p) if p < from)
+                                    {
+                                        trace!(
+                                            "Kill of {:?} at {:?}: trimming {:?} to start at {:?}",
+                                            operand,
+                                            pos,
+                                            lr,
+                                            from
+                                        );
+                                        self.ranges[lr].range.from = from;
+                                        live.set(operand.vreg().vreg(), false);
+                                    }
+                                }
                                 if !live.get(operand.vreg().vreg()) {
                                     let range = CodeRange {
                                         from: self.cfginfo.block_entry[block.index()],
@@ -740,6 +1073,7 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         self.safepoints.sort_unstable();
+        self.clobber_insts.sort_unstable();
 
         // Make ranges in each vreg and uses in each range appear in
         // sorted order. We built them in reverse order above, so this
@@ -784,8 +1118,59 @@ impl<'a, F: Function> Env<'a, F> {
                 while safepoint_idx < self.safepoints.len()
                     && range.contains_point(ProgPoint::before(self.safepoints[safepoint_idx]))
                 {
+                    let safepoint = self.safepoints[safepoint_idx];
+                    let pos = ProgPoint::before(safepoint);
+
+                    // The stackmap covers every ref whose range
+                    // covers the safepoint, including one defined
+                    // early by the safepoint itself (even if it is
+                    // otherwise dead), which liveness alone misses.
+                    self.safepoints_per_vreg
+                        .get_mut(&vreg.index())
+                        .unwrap()
+                        .insert(safepoint);
+
+                    // An early def at the safepoint can't also take
+                    // a virtual use at the same point; instead,
+                    // constrain the def itself to the stack.
+                    if range.from == pos {
+                        if let Some(u) = self.ranges[index]
+                            .uses
+                            .iter_mut()
+                            .find(|u| u.pos == pos && u.operand.kind() == OperandKind::Def)
+                        {
+                            match u.operand.constraint() {
+                                OperandConstraint::Stack => {}
+                                OperandConstraint::FixedReg(preg)
+                                    if self.pregs[preg.index()].is_stack => {}
+                                OperandConstraint::Any => {
+                                    trace!("Safepoint-induced stack def of {:?}", u.operand);
+                                    u.operand = Operand::new(
+                                        u.operand.vreg(),
+                                        OperandConstraint::Stack,
+                                        OperandKind::Def,
+                                        OperandPos::Early,
+                                    );
+                                }
+                                _ => {
+                                    return Err(RegAllocError::RefDefAtSafepoint(
+                                        u.operand.vreg(),
+                                        safepoint,
+                                    ));
+                                }
+                            }
+                            safepoint_idx += 1;
+                            continue;
+                        }
+                    }
+
+                    // Other policies let the ref stay in a register.
+                    if self.options.reftype_spill_policy != ReftypeSpillPolicy::StackOnly {
+                        safepoint_idx += 1;
+                        continue;
+                    }
+
                     // Create a virtual use.
-                    let pos = ProgPoint::before(self.safepoints[safepoint_idx]);
                     let operand = Operand::new(
                         self.vreg(vreg),
                         OperandConstraint::Stack,
@@ -822,6 +1207,7 @@ impl<'a, F: Function> Env<'a, F> {
         self.stats.initial_liverange_count = self.ranges.len();
         self.stats.blockparam_ins_count = self.blockparam_ins.len();
         self.stats.blockparam_outs_count = self.blockparam_outs.len();
+        Ok(())
     }
 
     pub fn fixup_multi_fixed_vregs(&mut self) {
@@ -860,7 +1246,10 @@ impl<'a, F: Function> Env<'a, F> {
                                 first_reg_slot.get_or_insert(u.slot);
                                 first_stack_slot.get_or_insert(u.slot);
                             }
-                            OperandConstraint::Reg | OperandConstraint::Reuse(_) => {
+                            OperandConstraint::Reg
+                            | OperandConstraint::Reuse(_)
+                            | OperandConstraint::ReuseEither(..)
+                            | OperandConstraint::Tied(_) => {
                                 first_reg_slot.get_or_insert(u.slot);
                                 requires_reg = true;
                             }
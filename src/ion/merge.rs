@@ -12,12 +12,17 @@
 
 //! Bundle merging.
 
-use super::{Env, LiveBundleIndex, SpillSet, SpillSlotIndex, VRegIndex};
+use super::{
+    Env, LiveBundleIndex, LiveRangeKey, Requirement, SpillSet, SpillSetIndex, SpillSlotIndex,
+    VRegIndex,
+};
 use crate::{
-    ion::data_structures::{BlockparamOut, CodeRange},
-    Function, Inst, OperandConstraint, OperandKind, PReg, ProgPoint,
+    ion::data_structures::{BlockparamOut, CodeRange, BUNDLE_MAX_SPILL_WEIGHT},
+    Allocation, Function, Inst, OperandConstraint, OperandKind, PReg, ProgPoint,
 };
 use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
 use smallvec::smallvec;
 
 impl<'a, F: Function> Env<'a, F> {
@@ -40,6 +45,42 @@ impl<'a, F: Function> Env<'a, F> {
             return false;
         }
 
+        // Values restricted to different register subclasses cannot
+        // share a register.
+        let from_subclass = self.spillsets[self.bundles[from].spillset].subclass;
+        let to_subclass = self.spillsets[self.bundles[to].spillset].subclass;
+        if from_subclass.is_some() && to_subclass.is_some() && from_subclass != to_subclass {
+            trace!(" -> mismatching reg subclasses");
+            return false;
+        }
+
+        // Nor can values pinned to different registers.
+        let from_pinned = self.spillsets[self.bundles[from].spillset].pinned;
+        let to_pinned = self.spillsets[self.bundles[to].spillset].pinned;
+        if from_pinned.is_some() && to_pinned.is_some() && from_pinned != to_pinned {
+            trace!(" -> mismatching pinned regs");
+            return false;
+        }
+
+        // Values spilled to different regions cannot share a
+        // spillslot.
+        if self.spillsets[self.bundles[from].spillset].region
+            != self.spillsets[self.bundles[to].spillset].region
+        {
+            trace!(" -> mismatching spill regions");
+            return false;
+        }
+
+        // Keep allocation groups apart from each other and from
+        // ungrouped values, so that spilling a group stays confined
+        // to its members.
+        if self.spillsets[self.bundles[from].spillset].group
+            != self.spillsets[self.bundles[to].spillset].group
+        {
+            trace!(" -> mismatching allocation groups");
+            return false;
+        }
+
         // If either bundle is already assigned (due to a pinned vreg), don't merge.
         if self.bundles[from].allocation.is_some() || self.bundles[to].allocation.is_some() {
             trace!("one of the bundles is already assigned (pinned)");
@@ -122,6 +163,14 @@ impl<'a, F: Function> Env<'a, F> {
         }
 
         trace!(" -> committing to merge");
+        self.spillsets[self.bundles[to].spillset].subclass = from_subclass.or(to_subclass);
+        self.spillsets[self.bundles[to].spillset].pinned = from_pinned.or(to_pinned);
+        let from_max_splits = self.spillsets[self.bundles[from].spillset].max_splits;
+        let to_max_splits = &mut self.spillsets[self.bundles[to].spillset].max_splits;
+        *to_max_splits = match (from_max_splits, *to_max_splits) {
+            (Some(a), Some(b)) => Some(core::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
 
         // If we reach here, then the bundles do not overlap -- merge
         // them!  We do this with a merge-sort-like scan over both
@@ -217,13 +266,6 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
-        if self.bundles[from].spillset != self.bundles[to].spillset {
-            // Widen the range for the target spillset to include the one being merged in.
-            let from_range = self.spillsets[self.bundles[from].spillset].range;
-            let to_range = &mut self.spillsets[self.bundles[to].spillset].range;
-            *to_range = to_range.join(from_range);
-        }
-
         if self.bundles[from].cached_stack() {
             self.bundles[to].set_cached_stack();
         }
@@ -247,8 +289,6 @@ impl<'a, F: Function> Env<'a, F> {
             }
 
             let bundle = self.bundles.add();
-            let mut range = self.vregs[vreg].ranges.first().unwrap().range;
-
             self.bundles[bundle].ranges = self.vregs[vreg].ranges.clone();
             trace!("vreg v{} gets bundle{}", vreg.index(), bundle.index());
             for entry in &self.bundles[bundle].ranges {
@@ -257,7 +297,6 @@ impl<'a, F: Function> Env<'a, F> {
                     entry.index.index(),
                     entry.range
                 );
-                range = range.join(entry.range);
                 self.ranges[entry.index].bundle = bundle;
             }
 
@@ -299,7 +338,14 @@ impl<'a, F: Function> Env<'a, F> {
                 reg_hint: PReg::invalid(),
                 spill_bundle: LiveBundleIndex::invalid(),
                 splits: 0,
-                range,
+                max_splits: self
+                    .func
+                    .max_splits(reg)
+                    .map(|n| core::cmp::min(n, u8::MAX as u32) as u8),
+                subclass: self.func.reg_subclass(reg),
+                pinned: self.func.vreg_fixed_preg(reg),
+                group: self.func.allocation_group(reg),
+                region: self.func.spill_region(reg),
             });
             self.bundles[bundle].spillset = ssidx;
         }
@@ -309,8 +355,8 @@ impl<'a, F: Function> Env<'a, F> {
 
             // Attempt to merge Reuse-constraint operand outputs with the
             // corresponding inputs.
-            for op in self.func.inst_operands(inst) {
-                if let OperandConstraint::Reuse(reuse_idx) = op.constraint() {
+            for (idx, op) in self.func.inst_operands(inst).iter().enumerate() {
+                if let Some(reuse_idx) = self.reused_input_index(inst, idx) {
                     let src_vreg = op.vreg();
                     let dst_vreg = self.func.inst_operands(inst)[reuse_idx].vreg();
 
@@ -350,6 +396,18 @@ impl<'a, F: Function> Env<'a, F> {
             self.merge_bundles(from_bundle, to_bundle);
         }
 
+        // Hint values that are live in registers on entry toward
+        // their entry register, so that the move we insert at the top
+        // of the function is usually elided.
+        for &(vreg, preg) in self.func.entry_live_regs() {
+            let vreg = VRegIndex::new(vreg.vreg());
+            if let Some(entry) = self.vregs[vreg].ranges.first() {
+                let bundle = self.ranges[entry.index].bundle;
+                let ssidx = self.bundles[bundle].spillset;
+                self.spillsets[ssidx].reg_hint = preg;
+            }
+        }
+
         trace!("done merging bundles");
     }
 
@@ -363,7 +421,39 @@ impl<'a, F: Function> Env<'a, F> {
         total
     }
 
+    /// The priority with which `bundle` is queued for allocation,
+    /// once its properties are computed. Normally this is its length,
+    /// so that the largest bundles go first and are broken up by
+    /// eviction if need be. In single-pass mode there is no eviction,
+    /// so bundles are queued by spill weight instead: those that would
+    /// have won an eviction are placed first, rather than long sparse
+    /// bundles taking registers that short dense ones then cannot have.
+    pub fn queue_prio(&self, bundle: LiveBundleIndex) -> usize {
+        if self.options.single_pass {
+            self.bundle_spill_weight(bundle) as usize
+        } else {
+            self.bundles[bundle].prio as usize
+        }
+    }
+
     pub fn queue_bundles(&mut self) {
+        // Values live in registers on entry are queued ahead of
+        // everything else, so that they usually keep their entry
+        // register rather than lose it to a value allocated first.
+        let entry_bundles: Vec<LiveBundleIndex> = self
+            .func
+            .entry_live_regs()
+            .iter()
+            .filter_map(|&(vreg, _)| {
+                let entry = self.vregs[VRegIndex::new(vreg.vreg())].ranges.first()?;
+                Some(self.ranges[entry.index].bundle)
+            })
+            .collect();
+        let entry_boost = if self.options.single_pass {
+            BUNDLE_MAX_SPILL_WEIGHT as usize + 1
+        } else {
+            2 * self.func.num_insts()
+        };
         for bundle in 0..self.bundles.len() {
             trace!("enqueueing bundle{}", bundle);
             let bundle = LiveBundleIndex::new(bundle);
@@ -375,9 +465,127 @@ impl<'a, F: Function> Env<'a, F> {
             trace!(" -> prio {}", prio);
             self.bundles[bundle].prio = prio;
             self.recompute_bundle_properties(bundle);
+            let queue_prio = if entry_bundles.contains(&bundle) {
+                self.queue_prio(bundle) + entry_boost
+            } else {
+                self.queue_prio(bundle)
+            };
             self.allocation_queue
-                .insert(bundle, prio as usize, PReg::invalid());
+                .insert(bundle, queue_prio, PReg::invalid());
         }
         self.stats.merged_bundle_count = self.allocation_queue.heap.len();
     }
+
+    /// Undo splits that turned out to be unnecessary: for each pair
+    /// of register-allocated bundles from the same spillset where one
+    /// ends exactly where the other begins (so that a move would be
+    /// inserted between them), move one into the other's register if
+    /// that register is free over it and its constraints allow it.
+    pub fn remerge_split_bundles(&mut self) {
+        trace!("remerging split bundles");
+        let mut candidates: Vec<(SpillSetIndex, ProgPoint, LiveBundleIndex)> = vec![];
+        for bundle in 0..self.bundles.len() {
+            let bundle = LiveBundleIndex::new(bundle);
+            let data = &self.bundles[bundle];
+            if data.allocation.is_reg() && !data.ranges.is_empty() {
+                candidates.push((data.spillset, data.ranges[0].range.from, bundle));
+            }
+        }
+        candidates.sort_unstable();
+
+        let mut prev: Option<(SpillSetIndex, LiveBundleIndex)> = None;
+        for (spillset, _, bundle) in candidates {
+            let survivor = match prev {
+                Some((prev_spillset, prev_bundle)) if prev_spillset == spillset => {
+                    self.try_remerge_bundles(prev_bundle, bundle)
+                }
+                _ => None,
+            };
+            prev = Some((spillset, survivor.unwrap_or(bundle)));
+        }
+    }
+
+    /// Try to recombine `first` with `second`, which immediately
+    /// follows it; returns the surviving bundle on success.
+    fn try_remerge_bundles(
+        &mut self,
+        first: LiveBundleIndex,
+        second: LiveBundleIndex,
+    ) -> Option<LiveBundleIndex> {
+        let last = *self.bundles[first].ranges.last().unwrap();
+        let next = self.bundles[second].ranges[0];
+        if last.range.to != next.range.from
+            || self.ranges[last.index].vreg != self.ranges[next.index].vreg
+        {
+            return None;
+        }
+        let first_reg = self.bundles[first].allocation.as_reg().unwrap();
+        let second_reg = self.bundles[second].allocation.as_reg().unwrap();
+        if first_reg == second_reg {
+            return None;
+        }
+        let req = self.merge_bundle_requirements(first, second).ok()?;
+        // Can `bundle` move into `preg`, as the merged requirement and
+        // any loop reserving registers it overlaps allow?
+        let fits = |preg: PReg, bundle: LiveBundleIndex| {
+            let allowed = match req {
+                Requirement::FixedReg(fixed) => fixed == preg,
+                Requirement::Register => !self.pregs[preg.index()].is_stack,
+                Requirement::Any => true,
+                Requirement::FixedStack(_) | Requirement::Stack => false,
+            };
+            allowed
+                && !self.bundle_reserved_regs(bundle).contains(preg)
+                && self.preg_is_free_over(preg, bundle)
+        };
+
+        let (from, into) = if fits(first_reg, second) {
+            (second, first)
+        } else if fits(second_reg, first) {
+            (first, second)
+        } else {
+            return None;
+        };
+        trace!(
+            "remerging bundle{} into bundle{}",
+            from.index(),
+            into.index()
+        );
+
+        let from_reg = self.bundles[from].allocation.as_reg().unwrap();
+        let into_reg = self.bundles[into].allocation.as_reg().unwrap();
+        let ranges = core::mem::take(&mut self.bundles[from].ranges);
+        for entry in &ranges {
+            let key = LiveRangeKey::from_range(&entry.range);
+            self.pregs[from_reg.index()].allocations.btree.remove(&key);
+            self.pregs[into_reg.index()]
+                .allocations
+                .btree
+                .insert(key, entry.index);
+            self.ranges[entry.index].bundle = into;
+        }
+        self.bundles[from].allocation = Allocation::none();
+        self.bundles[into].ranges.extend(ranges);
+        self.bundles[into]
+            .ranges
+            .sort_unstable_by_key(|entry| entry.range.from);
+        self.stats.remerged_bundles += 1;
+        Some(into)
+    }
+
+    /// Is `preg`, and every register aliasing it, unoccupied over all
+    /// of `bundle`'s ranges?
+    fn preg_is_free_over(&self, preg: PReg, bundle: LiveBundleIndex) -> bool {
+        core::iter::once(preg)
+            .chain(self.env.aliases(preg).iter().cloned())
+            .all(|reg| {
+                self.bundles[bundle].ranges.iter().all(|entry| {
+                    let key = LiveRangeKey::from_range(&entry.range);
+                    !self.pregs[reg.index()].allocations.btree.contains_key(&key)
+                })
+            })
+            && self
+                .full_reg_def_conflicts(preg, &self.bundles[bundle].ranges)
+                .is_empty()
+    }
 }
@@ -15,18 +15,21 @@
 
 use crate::cfg::CFGInfo;
 use crate::ssa::validate_ssa;
-use crate::{Function, MachineEnv, Output, PReg, ProgPoint, RegAllocError, RegClass};
+use crate::{
+    Allocation, Function, FxHashMap, FxHashSet, Inst, MachineEnv, Output, PReg, PRegSet, ProgPoint,
+    QueueTieBreak, RegAllocError, RegClass, RegallocOptions, SpillRegion, SplitProposal, VReg,
+};
 use alloc::vec;
 use alloc::vec::Vec;
 use hashbrown::HashMap;
 
 pub(crate) mod data_structures;
-pub use data_structures::Stats;
 use data_structures::*;
+pub use data_structures::{CodeRange, Stats};
 pub(crate) mod reg_traversal;
 use reg_traversal::*;
 pub(crate) mod requirement;
-use requirement::*;
+pub use requirement::Requirement;
 pub(crate) mod redundant_moves;
 use redundant_moves::*;
 pub(crate) mod liveranges;
@@ -37,21 +40,25 @@ use process::*;
 use smallvec::smallvec;
 pub(crate) mod dump;
 pub(crate) mod moves;
+pub(crate) mod reallocate;
 pub(crate) mod spill;
 pub(crate) mod stackmap;
+pub(crate) mod vreg_trace;
+pub use vreg_trace::{VregTrace, VregTraceEvent};
 
 impl<'a, F: Function> Env<'a, F> {
     pub(crate) fn new(
         func: &'a F,
         env: &'a MachineEnv,
         cfginfo: CFGInfo,
-        annotations_enabled: bool,
+        options: &RegallocOptions,
     ) -> Self {
         let n = func.num_insts();
         Self {
             func,
             env,
             cfginfo,
+            options: *options,
 
             liveins: Vec::with_capacity(func.num_blocks()),
             liveouts: Vec::with_capacity(func.num_blocks()),
@@ -64,40 +71,69 @@ impl<'a, F: Function> Env<'a, F> {
             pregs: vec![],
             allocation_queue: PrioQueue::new(),
             safepoints: vec![],
+            clobber_insts: vec![],
             safepoints_per_vreg: HashMap::new(),
             spilled_bundles: vec![],
             spillslots: vec![],
-            slots_by_class: [
-                SpillSlotList::new(),
-                SpillSlotList::new(),
-                SpillSlotList::new(),
-            ],
+            slots_by_region_and_class: Default::default(),
             allocated_bundle_count: 0,
 
             extra_spillslots_by_class: [smallvec![], smallvec![], smallvec![]],
+            alias_temp_slots_by_class: [smallvec![], smallvec![], smallvec![]],
             preferred_victim_by_class: [PReg::invalid(), PReg::invalid(), PReg::invalid()],
 
             multi_fixed_reg_fixups: vec![],
             allocs: Vec::with_capacity(4 * n),
             inst_alloc_offsets: vec![],
-            num_spillslots: 0,
+            num_spillslots_by_region: [0; 3],
             safepoint_slots: vec![],
             debug_locations: vec![],
+            split_events: vec![],
+            block_entry_locations: vec![],
+            preg_maps: vec![],
+            spilled_ranges: vec![],
+            live_ranges: vec![],
+            spillslot_classes: vec![],
+            live_regs_by_inst: vec![],
+            bundle_requirements: vec![],
+            loop_resident_fallbacks: vec![],
+            spill_reasons: vec![],
 
             stats: Stats::default(),
 
             debug_annotations: hashbrown::HashMap::new(),
-            annotations_enabled,
+            annotations_enabled: options.verbose_log,
 
             conflict_set: Default::default(),
+            probe_cache: vec![],
+            spillset_ranges: vec![],
+
+            loop_reg_reserves: vec![],
+            loop_residents: vec![],
+            honor_loop_residents: true,
+            has_full_reg_defs: false,
+            allocated_pregs: PRegSet::empty(),
+            reuse_either_choices: FxHashMap::default(),
+            spilled_groups: FxHashSet::default(),
+            remat_vregs: FxHashMap::default(),
+
+            split_hook: None,
+            queue_tie_break: None,
+            scan_seed: None,
+            trace_vreg: None,
+            vreg_trace_events: vec![],
         }
     }
 
     pub(crate) fn init(&mut self) -> Result<(), RegAllocError> {
         self.create_pregs_and_vregs();
+        self.compute_loop_reg_reserves();
+        self.compute_loop_residents();
+        self.compute_remat_candidates();
         self.compute_liveness()?;
-        self.build_liveranges();
+        self.build_liveranges()?;
         self.fixup_multi_fixed_vregs();
+        self.add_preg_reservations();
         self.merge_vreg_bundles();
         self.queue_bundles();
         if trace_enabled!() {
@@ -109,42 +145,393 @@ impl<'a, F: Function> Env<'a, F> {
     pub(crate) fn run(&mut self) -> Result<Edits, RegAllocError> {
         self.process_bundles()?;
         self.try_allocating_regs_for_spilled_bundles();
+        if self.options.remerge {
+            self.remerge_split_bundles();
+        }
         self.allocate_spillslots();
+        self.hoist_reloads();
+        let edits = self.resolve_moves()?;
+        self.check_loop_residents();
+        Ok(edits)
+    }
+
+    /// Generate the moves, operand allocations, stackmaps and the
+    /// other per-allocation results from the final allocation of
+    /// every bundle, without revisiting any allocation decision.
+    ///
+    /// This is the last phase of `run`, but may be called again
+    /// after changing bundle allocations to regenerate everything
+    /// consistently; without changes, it gives the same results
+    /// again. Such changes must keep each register's allocation map
+    /// in sync (scratch registers for move cycles are found there),
+    /// and of course respect operand constraints and avoid
+    /// overlapping ranges in one register.
+    pub(crate) fn resolve_moves(&mut self) -> Result<Edits, RegAllocError> {
+        // Everything below is recomputed from scratch; clear what
+        // a previous call left behind.
+        self.debug_locations.clear();
+        self.safepoint_slots.clear();
+        self.stats.halfmoves_count = 0;
+
         let moves = self.apply_allocations_and_insert_moves();
         let edits = self.resolve_inserted_moves(moves);
-        self.compute_stackmaps();
+        self.compute_stackmaps()?;
+        self.compute_spilled_ranges();
+        self.compute_live_ranges();
+        self.compute_live_regs_by_inst();
+        if self.options.collect_block_entry_state {
+            self.compute_block_entry_locations();
+        }
+        if self.options.collect_preg_maps {
+            self.compute_preg_maps();
+        }
         Ok(edits)
     }
+
+    /// Collect the results of the last `resolve_moves`, which
+    /// returned `edits`, into an `Output`.
+    pub(crate) fn output(&self, edits: Edits) -> Output {
+        Output {
+            edits: edits.into_edits().collect(),
+            allocs: self.allocs.clone(),
+            inst_alloc_offsets: self.inst_alloc_offsets.clone(),
+            num_spillslots: self.num_spillslots_by_region[SpillRegion::Frame as usize] as usize,
+            num_spillslots_by_region: self.num_spillslots_by_region.map(|n| n as usize),
+            debug_locations: self.debug_locations.clone(),
+            safepoint_slots: self.safepoint_slots.clone(),
+            split_events: self.split_events.clone(),
+            block_entry_locations: self.block_entry_locations.clone(),
+            preg_maps: self.preg_maps.clone(),
+            spilled_ranges: self.spilled_ranges.clone(),
+            live_ranges: self.live_ranges.clone(),
+            spillslot_classes: self.spillslot_classes.clone(),
+            live_regs_by_inst: self.live_regs_by_inst.clone(),
+            bundle_requirements: self.bundle_requirements.clone(),
+            loop_resident_fallbacks: self.loop_resident_fallbacks.clone(),
+            spill_reasons: self.final_spill_reasons(),
+            vreg_trace: self.vreg_trace(),
+            stats: self.stats,
+        }
+    }
+
+    /// The largest number of vregs of each class that are live at
+    /// the same program point. Valid at any point after liveranges
+    /// have been built.
+    pub fn max_live_vregs_by_class(&self) -> [usize; 3] {
+        // Sweep over range endpoints; ranges are half-open, so at
+        // equal points ends sort before starts.
+        let mut events: Vec<(ProgPoint, bool, RegClass)> = vec![];
+        for vreg in self.vregs.iter() {
+            let class = match vreg.class {
+                Some(class) => class,
+                None => continue,
+            };
+            for entry in &vreg.ranges {
+                let range = self.ranges[entry.index].range;
+                events.push((range.from, true, class));
+                events.push((range.to, false, class));
+            }
+        }
+        events.sort_unstable_by_key(|&(pos, is_start, _)| (pos, is_start));
+
+        let mut live = [0usize; 3];
+        let mut max_live = [0usize; 3];
+        for (_, is_start, class) in events {
+            let class = class as usize;
+            if is_start {
+                live[class] += 1;
+                max_live[class] = core::cmp::max(max_live[class], live[class]);
+            } else {
+                live[class] -= 1;
+            }
+        }
+        max_live
+    }
 }
 
-pub fn run<F: Function>(
-    func: &F,
-    mach_env: &MachineEnv,
-    enable_annotations: bool,
-    enable_ssa_checker: bool,
+/// Does any range in `a` overlap one in `b`? Both must be sorted by
+/// start, but the ranges in each may overlap one another.
+pub(crate) fn sorted_ranges_overlap(a: &[CodeRange], b: &[CodeRange]) -> bool {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].overlaps(&b[j]) {
+            return true;
+        }
+        if a[i].to <= b[j].to {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    false
+}
+
+/// Allocate `func` with the full set of `options`. This is also the
+/// entry point of the fuzz targets (through `fuzzing::ion`), so that
+/// they can exercise any option rather than only verbose logging and
+/// SSA validation.
+pub fn run<'a, F: Function>(
+    func: &'a F,
+    mach_env: &'a MachineEnv,
+    options: &RegallocOptions,
+    split_hook: Option<&'a mut dyn FnMut(SplitProposal) -> ProgPoint>,
+) -> Result<Output, RegAllocError> {
+    run_with_hooks(func, mach_env, options, split_hook, None)
+}
+
+pub fn run_with_queue_tie_break<'a, F: Function>(
+    func: &'a F,
+    mach_env: &'a MachineEnv,
+    options: &RegallocOptions,
+    tie_break: &'a QueueTieBreak<'a>,
 ) -> Result<Output, RegAllocError> {
+    run_with_hooks(func, mach_env, options, None, Some(tie_break))
+}
+
+fn run_with_hooks<'a, F: Function>(
+    func: &'a F,
+    mach_env: &'a MachineEnv,
+    options: &RegallocOptions,
+    mut split_hook: Option<&'a mut dyn FnMut(SplitProposal) -> ProgPoint>,
+    tie_break: Option<&'a QueueTieBreak<'a>>,
+) -> Result<Output, RegAllocError> {
+    run_checked(func, options, |cfginfo, honor_loop_residents| {
+        // Reborrowed for this attempt only, as there may be another.
+        let split_hook: Option<&mut dyn FnMut(SplitProposal) -> ProgPoint> = match split_hook {
+            Some(ref mut hook) => Some(&mut **hook),
+            None => None,
+        };
+        let (env, edits) = allocate(
+            func,
+            mach_env,
+            cfginfo,
+            options,
+            split_hook,
+            tie_break,
+            honor_loop_residents,
+        )?;
+        Ok(env.output(edits))
+    })
+}
+
+/// Allocate `func` as `run` does, and keep the allocator's state so
+/// that the allocation can be revisited; see `RetainedAllocation`.
+pub fn run_retained<'a, F: Function>(
+    func: &'a F,
+    mach_env: &'a MachineEnv,
+    options: &RegallocOptions,
+) -> Result<(Output, RetainedAllocation<'a, F>), RegAllocError> {
+    let (env, edits) = run_checked(func, options, |cfginfo, honor_loop_residents| {
+        allocate(
+            func,
+            mach_env,
+            cfginfo,
+            options,
+            None,
+            None,
+            honor_loop_residents,
+        )
+    })?;
+    let output = env.output(edits);
+    Ok((output, RetainedAllocation { env }))
+}
+
+/// The state of a finished allocation, kept by `run_retained`, from
+/// which the allocation can be changed and its results regenerated
+/// without allocating the function again.
+pub struct RetainedAllocation<'a, F: Function> {
+    pub(crate) env: Env<'a, F>,
+}
+
+impl<'a, F: Function> RetainedAllocation<'a, F> {
+    /// Move the bundle holding `vreg` at `pos` to `preg`: all of
+    /// `vreg`'s ranges allocated together with the one containing
+    /// `pos` (a spilled bundle among them) are moved, and
+    /// `resolve_moves` then gives the resulting moves.
+    ///
+    /// Returns whether anything was changed. Nothing is if `vreg` is
+    /// not live at `pos`, if `preg` is of another class or a stack
+    /// register, or if some other range holds `preg` or a register
+    /// aliasing it anywhere in the bundle. Operand constraints are
+    /// not checked: a change that breaks one gives an `Output` that
+    /// the checker rejects.
+    pub fn reassign(&mut self, vreg: VReg, pos: ProgPoint, preg: PReg) -> bool {
+        let env = &mut self.env;
+        if vreg.vreg() >= env.vregs.len()
+            || preg.class() != vreg.class()
+            || env.pregs[preg.index()].is_stack
+        {
+            return false;
+        }
+        let lr = match env.vregs[VRegIndex::new(vreg.vreg())]
+            .ranges
+            .iter()
+            .find(|entry| entry.range.contains_point(pos))
+        {
+            Some(entry) => entry.index,
+            None => return false,
+        };
+        let bundle = env.ranges[lr].bundle;
+        let old = env.bundles[bundle].allocation.as_reg();
+        if old == Some(preg) {
+            return true;
+        }
+        for &reg in core::iter::once(&preg).chain(env.env.aliases(preg)) {
+            let btree = &env.pregs[reg.index()].allocations.btree;
+            if env.bundles[bundle]
+                .ranges
+                .iter()
+                .any(|entry| btree.contains_key(&LiveRangeKey::from_range(&entry.range)))
+            {
+                return false;
+            }
+        }
+
+        trace!("reassigning bundle {:?} from {:?} to {}", bundle, old, preg);
+        for entry in &env.bundles[bundle].ranges {
+            let key = LiveRangeKey::from_range(&entry.range);
+            if let Some(old) = old {
+                env.pregs[old.index()].allocations.btree.remove(&key);
+            }
+            env.pregs[preg.index()]
+                .allocations
+                .btree
+                .insert(key, entry.index);
+        }
+        env.bundles[bundle].allocation = Allocation::reg(preg);
+        env.allocated_pregs.add(preg);
+        true
+    }
+
+    /// Redo the allocation after the operand constraints of `inst`
+    /// have changed in `func`, re-processing only the bundles of the
+    /// vregs it mentions (and whatever they evict) and then the second
+    /// chances of spilled bundles, and return the results as
+    /// `resolve_moves` does.
+    ///
+    /// `func` must be identical to the function allocated except in
+    /// the operands of `inst`, and there only in their constraints:
+    /// the CFG, the number of vregs, the clobbers, and the vreg, kind
+    /// and position of each operand are unchanged, so that liveness is
+    /// too. Reuse and tied constraints and the cases that need a
+    /// multi-fixed-reg fixup (one vreg constrained to several
+    /// registers, or an early fixed use of a register that is also
+    /// defined late or clobbered) are not supported, before or after
+    /// the change. These invariants are checked with debug assertions
+    /// only. On success, `func` becomes the function allocated, and
+    /// may be edited again in the same way.
+    ///
+    /// Reloads hoisted by the full allocation keep their position;
+    /// those of the re-allocated bundles are not hoisted.
+    pub fn reallocate_inst(&mut self, func: &'a F, inst: Inst) -> Result<Output, RegAllocError> {
+        let edits = self.env.reallocate_inst(func, inst)?;
+        Ok(self.env.output(edits))
+    }
+
+    /// Regenerate the moves, operand allocations, stackmaps and every
+    /// other result from the current allocation, as the last phase of
+    /// the allocation does, and return them. Without changes since
+    /// the last call (or since `run_retained`), the `Output` is the
+    /// same again.
+    pub fn resolve_moves(&mut self) -> Result<Output, RegAllocError> {
+        let edits = self.env.resolve_moves()?;
+        Ok(self.env.output(edits))
+    }
+}
+
+/// Validate `func` and allocate it with `attempt`, given its CFG and
+/// whether to honor `Function::loop_resident`.
+fn run_checked<F: Function, T>(
+    func: &F,
+    options: &RegallocOptions,
+    mut attempt: impl FnMut(CFGInfo, bool) -> Result<T, RegAllocError>,
+) -> Result<T, RegAllocError> {
+    if !options.objective.is_valid() {
+        return Err(RegAllocError::InvalidObjectiveWeights);
+    }
+    validate_entry_live_regs(func)?;
+
     let cfginfo = CFGInfo::new(func)?;
 
-    if enable_ssa_checker {
+    if options.validate_ssa {
         validate_ssa(func, &cfginfo)?;
     }
 
-    let mut env = Env::new(func, mach_env, cfginfo, enable_annotations);
+    match attempt(cfginfo, true) {
+        // Keeping vregs in registers across loops takes registers
+        // from everything else, and can leave too few for the rest.
+        // Rather than fail, try again without, reporting every such
+        // request as not honored.
+        Err(RegAllocError::TooManyLiveRegs { .. }) if !func.loop_resident().is_empty() => {
+            trace!("allocation failed with loop-resident vregs; retrying without");
+            let cfginfo = CFGInfo::new(func)?;
+            attempt(cfginfo, false)
+        }
+        result => result,
+    }
+}
+
+/// Check that `Function::entry_live_regs` names each vreg at most
+/// once and in range, each in a register of its class that no other
+/// entry takes.
+fn validate_entry_live_regs<F: Function>(func: &F) -> Result<(), RegAllocError> {
+    let mut vregs = FxHashSet::default();
+    let mut pregs = PRegSet::empty();
+    for &(vreg, preg) in func.entry_live_regs() {
+        if vreg.vreg() >= func.num_vregs()
+            || vreg.class() != preg.class()
+            || !vregs.insert(vreg.vreg())
+            || pregs.contains(preg)
+        {
+            return Err(RegAllocError::InvalidEntryLiveReg(vreg));
+        }
+        pregs.add(preg);
+    }
+    Ok(())
+}
+
+fn allocate<'a, F: Function>(
+    func: &'a F,
+    mach_env: &'a MachineEnv,
+    cfginfo: CFGInfo,
+    options: &RegallocOptions,
+    split_hook: Option<&'a mut dyn FnMut(SplitProposal) -> ProgPoint>,
+    tie_break: Option<&'a QueueTieBreak<'a>>,
+    honor_loop_residents: bool,
+) -> Result<(Env<'a, F>, Edits), RegAllocError> {
+    let mut env = Env::new(func, mach_env, cfginfo, options);
+    if let Some(seed) = options.scan_seed {
+        env = env.with_scan_seed(seed);
+    }
+    env.split_hook = split_hook.map(SplitHook);
+    env.queue_tie_break = tie_break.map(QueueTieBreakHook);
+    env.trace_vreg = options.trace_vreg;
+    env.honor_loop_residents = honor_loop_residents;
     env.init()?;
 
     let edits = env.run()?;
 
-    if enable_annotations {
+    if options.verbose_log {
         env.dump_results();
     }
 
-    Ok(Output {
-        edits: edits.into_edits().collect(),
-        allocs: env.allocs,
-        inst_alloc_offsets: env.inst_alloc_offsets,
-        num_spillslots: env.num_spillslots as usize,
-        debug_locations: env.debug_locations,
-        safepoint_slots: env.safepoint_slots,
-        stats: env.stats,
-    })
+    Ok((env, edits))
+}
+
+pub fn min_regs_required<F: Function>(func: &F) -> Result<[usize; 3], RegAllocError> {
+    let cfginfo = CFGInfo::new(func)?;
+    let mach_env = MachineEnv {
+        preferred_regs_by_class: [vec![], vec![], vec![]],
+        non_preferred_regs_by_class: [vec![], vec![], vec![]],
+        scratch_by_class: [None, None, None],
+        fixed_stack_slots: vec![],
+        reg_aliases: vec![],
+        reg_subclasses: vec![],
+        already_saved: PRegSet::empty(),
+    };
+    let mut env = Env::new(func, &mach_env, cfginfo, &RegallocOptions::default());
+    env.create_pregs_and_vregs();
+    env.compute_liveness()?;
+    env.build_liveranges()?;
+    Ok(env.max_live_vregs_by_class())
 }
@@ -23,8 +23,8 @@ use crate::ion::data_structures::{
 use crate::ion::reg_traversal::RegTraversalIter;
 use crate::moves::{MoveAndScratchResolver, ParallelMoves};
 use crate::{
-    Allocation, Block, Edit, Function, FxHashMap, Inst, InstPosition, OperandConstraint,
-    OperandKind, OperandPos, PReg, ProgPoint, RegClass, SpillSlot,
+    tied_uses, Allocation, Block, Edit, Function, FxHashMap, Inst, InstPosition, OperandConstraint,
+    OperandKind, OperandPos, PReg, PRegSet, ProgPoint, ReftypeSpillPolicy, RegClass, SpillSlot,
 };
 use alloc::vec::Vec;
 use alloc::{format, vec};
@@ -41,6 +41,17 @@ impl<'a, F: Function> Env<'a, F> {
         pos == self.cfginfo.block_exit[block.index()]
     }
 
+    /// The safepoints whose `Before` point lies within `range`.
+    pub fn safepoints_in(&self, range: CodeRange) -> &[Inst] {
+        let start = self
+            .safepoints
+            .partition_point(|&inst| ProgPoint::before(inst) < range.from);
+        let end = self
+            .safepoints
+            .partition_point(|&inst| ProgPoint::before(inst) < range.to);
+        &self.safepoints[start..end]
+    }
+
     pub fn get_alloc(&self, inst: Inst, slot: usize) -> Allocation {
         let inst_allocs = &self.allocs[self.inst_alloc_offsets[inst.index()] as usize..];
         inst_allocs[slot]
@@ -59,6 +70,11 @@ impl<'a, F: Function> Env<'a, F> {
         trace!(" -> allocation {:?}", bundledata.allocation);
         if bundledata.allocation != Allocation::none() {
             bundledata.allocation
+        } else if !self.spillsets[bundledata.spillset].slot.is_valid() {
+            // A rematerialized value has no slot, and is nowhere
+            // while spilled.
+            trace!(" -> rematerialized");
+            Allocation::none()
         } else {
             trace!(" -> spillset {:?}", bundledata.spillset);
             trace!(
@@ -69,6 +85,168 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
+    /// If `vreg` is defined into a register but lives in its
+    /// spillslot somewhere else, returns the point just after its def
+    /// together with the def's register and the spillslot, for an
+    /// eager store there.
+    fn eager_spill_store(&self, vreg: VRegIndex) -> Option<(ProgPoint, Allocation, Allocation)> {
+        // A reference's slot is only kept up to date at the
+        // safepoints whose stackmaps list it; at any other safepoint
+        // the collector may move the object, leaving an
+        // eagerly-stored copy stale.
+        if self.vregs[vreg].is_ref {
+            return None;
+        }
+        let first = *self.vregs[vreg].ranges.first()?;
+        if !self.ranges[first.index].has_flag(LiveRangeFlag::StartsAtDef) {
+            return None;
+        }
+        let def_alloc = self.get_alloc_for_range(first.index);
+        let preg = def_alloc.as_reg()?;
+        if self.pregs[preg.index()].is_stack {
+            return None;
+        }
+        let spillset = self.bundles[self.ranges[first.index].bundle].spillset;
+        let slot = self.spillsets[spillset].slot;
+        if !slot.is_valid() {
+            return None;
+        }
+        let slot_alloc = self.spillslots[slot.index()].alloc;
+        if !self.vregs[vreg]
+            .ranges
+            .iter()
+            .any(|entry| self.get_alloc_for_range(entry.index) == slot_alloc)
+        {
+            return None;
+        }
+        let def = self.ranges[first.index]
+            .uses
+            .iter()
+            .find(|u| u.operand.kind() == OperandKind::Def)?;
+        let pos = ProgPoint::before(def.pos.inst().next());
+        if !first.range.contains_point(pos) || self.is_start_of_block(pos) {
+            return None;
+        }
+        Some((pos, def_alloc, slot_alloc))
+    }
+
+    /// Hoist reloads ahead of instructions with a latency (see
+    /// `Function::inst_latency`): a live range in a register that
+    /// takes over from the vreg's spillslot partway through a block
+    /// is started up to `latency - 1` instructions earlier, and with
+    /// it the move that loads it, for as long as the register (and
+    /// every register aliasing it) is free, and the slot already
+    /// holds the value.
+    pub fn hoist_reloads(&mut self) {
+        for lr in 0..self.ranges.len() {
+            let lr = LiveRangeIndex::new(lr);
+            let range = self.ranges[lr].range;
+            let vreg = self.ranges[lr].vreg;
+            if vreg.is_invalid()
+                || !self.ranges[lr].bundle.is_valid()
+                || range.from.pos() != InstPosition::Before
+                || self.is_start_of_block(range.from)
+                || self.ranges[lr].has_flag(LiveRangeFlag::StartsAtDef)
+            {
+                continue;
+            }
+            let latency = self.func.inst_latency(range.from.inst());
+            if latency <= 1 {
+                continue;
+            }
+            let bundle = self.ranges[lr].bundle;
+            let preg = match self.bundles[bundle].allocation.as_reg() {
+                Some(preg) if !self.pregs[preg.index()].is_stack => preg,
+                _ => continue,
+            };
+            let block = self.cfginfo.insn_block[range.from.inst().index()];
+            let block_entry = self.cfginfo.block_entry[block.index()];
+            let mut from = range.from;
+            for _ in 1..latency {
+                let earlier = ProgPoint::before(from.inst().prev());
+                if earlier <= block_entry || !self.can_reload_at(vreg, preg, earlier, range.from) {
+                    break;
+                }
+                from = earlier;
+            }
+            if from == range.from {
+                continue;
+            }
+            trace!(
+                "hoisting reload of v{} into {} from {:?} to {:?}",
+                vreg.index(),
+                preg,
+                range.from,
+                from
+            );
+
+            let new_range = CodeRange { from, to: range.to };
+            let btree = &mut self.pregs[preg.index()].allocations.btree;
+            let removed = btree.remove(&LiveRangeKey::from_range(&range));
+            debug_assert_eq!(removed, Some(lr));
+            btree.insert(LiveRangeKey::from_range(&new_range), lr);
+            self.ranges[lr].range = new_range;
+            for entry in self.bundles[bundle].ranges.iter_mut() {
+                if entry.index == lr {
+                    entry.range = new_range;
+                }
+            }
+        }
+    }
+
+    /// Can a reload of `vreg` into `preg` for a range starting at
+    /// `start` be made at the earlier point `at` instead?
+    fn can_reload_at(&self, vreg: VRegIndex, preg: PReg, at: ProgPoint, start: ProgPoint) -> bool {
+        // Nothing else may be in the register (or an alias of it)
+        // from just before `at` on: not even a value leaving it
+        // with a move at `at` itself.
+        let window = CodeRange {
+            from: at.prev(),
+            to: start,
+        };
+        let key = LiveRangeKey::from_range(&window);
+        if core::iter::once(preg)
+            .chain(self.env.aliases(preg).iter().cloned())
+            .any(|reg| self.pregs[reg.index()].allocations.btree.contains_key(&key))
+        {
+            return false;
+        }
+        // Nor may a full-register def into another part of a wider
+        // register write it meanwhile.
+        let reload = LiveRangeListEntry {
+            range: window,
+            index: LiveRangeIndex::invalid(),
+        };
+        if !self.full_reg_def_conflicts(preg, &[reload]).is_empty() {
+            return false;
+        }
+        // A register copy of a reference goes stale at a safepoint.
+        if self.vregs[vreg].is_ref && !self.safepoints_in(window).is_empty() {
+            return false;
+        }
+        // The value must be in its slot throughout, and not only
+        // just being stored there by an eager spill at `at`.
+        let mut in_slot = false;
+        for entry in &self.vregs[vreg].ranges {
+            let lr = &self.ranges[entry.index];
+            if lr.has_flag(LiveRangeFlag::StartsAtDef)
+                && lr.uses.iter().any(|u| {
+                    u.operand.kind() == OperandKind::Def
+                        && ProgPoint::before(u.pos.inst().next()) == at
+                })
+            {
+                return false;
+            }
+            if lr.range.from <= window.from
+                && lr.range.to >= window.to
+                && self.get_alloc_for_range(entry.index).is_stack()
+            {
+                in_slot = true;
+            }
+        }
+        in_slot
+    }
+
     pub fn apply_allocations_and_insert_moves(&mut self) -> InsertedMoves {
         trace!("apply_allocations_and_insert_moves");
         trace!("blockparam_ins: {:?}", self.blockparam_ins);
@@ -281,6 +459,26 @@ impl<'a, F: Function> Env<'a, F> {
 
             inter_block_sources.clear();
 
+            // With eager def spilling, store a spilled value to its
+            // slot right after its def; the slot then holds it
+            // everywhere, so later moves into the slot are dropped.
+            let eager_spill = if self.options.eager_spill_defs {
+                self.eager_spill_store(vreg)
+            } else {
+                None
+            };
+            if let Some((pos, from, to)) = eager_spill {
+                trace!(
+                    "eager spill of v{} at {:?}: {} -> {}",
+                    vreg.index(),
+                    pos,
+                    from,
+                    to
+                );
+                inserted_moves.push(pos, InsertMovePrio::Regular, from, to, self.vreg(vreg));
+            }
+            let eager_slot = eager_spill.map(|(_, _, slot)| slot);
+
             // For each range in each vreg, insert moves or
             // half-moves.  We also scan over `blockparam_ins` and
             // `blockparam_outs`, which are sorted by (block, vreg),
@@ -297,7 +495,7 @@ impl<'a, F: Function> Env<'a, F> {
                     range,
                     alloc,
                 );
-                debug_assert!(alloc != Allocation::none());
+                debug_assert!(alloc != Allocation::none() || self.remat_vregs.contains_key(&vreg));
 
                 if self.annotations_enabled {
                     self.annotate(
@@ -346,11 +544,14 @@ impl<'a, F: Function> Env<'a, F> {
                 // instruction).
                 if let Some(prev) = prev.is_valid() {
                     let prev_alloc = self.get_alloc_for_range(prev.index);
-                    debug_assert!(prev_alloc != Allocation::none());
+                    debug_assert!(
+                        prev_alloc != Allocation::none() || self.remat_vregs.contains_key(&vreg)
+                    );
 
                     if prev.range.to >= range.from
                         && (prev.range.to > range.from || !self.is_start_of_block(range.from))
                         && !self.ranges[entry.index].has_flag(LiveRangeFlag::StartsAtDef)
+                        && eager_slot != Some(alloc)
                     {
                         trace!(
                             "prev LR {} abuts LR {} in same block; moving {} -> {} for v{}",
@@ -568,15 +769,22 @@ impl<'a, F: Function> Env<'a, F> {
                     if slot != SLOT_NONE {
                         self.set_alloc(inst, slot as usize, alloc);
                     }
-                    if let OperandConstraint::Reuse(_) = operand.constraint() {
+                    if let OperandConstraint::Reuse(_) | OperandConstraint::ReuseEither(..) =
+                        operand.constraint()
+                    {
                         reuse_input_insts.push(inst);
                     }
+                    if let OperandConstraint::Tied(_) = operand.constraint() {
+                        if operand.kind() == OperandKind::Def {
+                            reuse_input_insts.push(inst);
+                        }
+                    }
                 }
 
                 // Scan debug-labels on this vreg that overlap with
                 // this range, producing a debug-info output record
                 // giving the allocation location for each label.
-                if !debug_labels.is_empty() {
+                if !debug_labels.is_empty() && alloc.is_some() {
                     // Do a binary search to find the start of any
                     // labels for this vreg. Recall that we require
                     // debug-label requests to be sorted by vreg as a
@@ -607,7 +815,9 @@ impl<'a, F: Function> Env<'a, F> {
                         if label_vreg.vreg() != vreg.index() {
                             break;
                         }
-                        if !range.overlaps(&label_range) {
+                        // An empty label range overlaps nothing, even
+                        // where it falls inside `range`.
+                        if label_range.is_empty() || !range.overlaps(&label_range) {
                             continue;
                         }
 
@@ -627,6 +837,9 @@ impl<'a, F: Function> Env<'a, F> {
                 let vreg = self.vreg(vreg);
                 trace!("processing inter-block moves for {}", vreg);
                 for dest in inter_block_dests.drain(..) {
+                    if eager_slot == Some(dest.alloc) {
+                        continue;
+                    }
                     let src = inter_block_sources[&dest.from];
 
                     trace!(
@@ -658,8 +871,78 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
+        // Move values that are live in registers on entry from their
+        // entry register into wherever they were allocated at the top
+        // of the function. The entry block has no in-edges, so these
+        // moves form the first parallel-move group at that point.
+        let entry_pos = self.cfginfo.block_entry[self.func.entry_block().index()];
+        for &(vreg, preg) in self.func.entry_live_regs() {
+            let vreg_idx = VRegIndex::new(vreg.vreg());
+            let entry = self.vregs[vreg_idx]
+                .ranges
+                .iter()
+                .find(|entry| entry.range.contains_point(entry_pos))
+                .cloned();
+            if let Some(entry) = entry {
+                let alloc = self.get_alloc_for_range(entry.index);
+                trace!("entry-live {} in {} moved to {}", vreg, preg, alloc);
+                inserted_moves.push(
+                    entry_pos,
+                    InsertMovePrio::InEdgeMoves,
+                    Allocation::reg(preg),
+                    alloc,
+                    vreg,
+                );
+            }
+        }
+
+        // With slot-backed references, store each reference that is in
+        // a register at a safepoint to its spillslot just before it.
+        if self.options.reftype_spill_policy == ReftypeSpillPolicy::AlwaysBackedBySlot {
+            let mut stores = vec![];
+            for &vreg in self.func.reftype_vregs() {
+                let vreg = VRegIndex::new(vreg.vreg());
+                stores.clear();
+                for entry in &self.vregs[vreg].ranges {
+                    let safepoints = self.safepoints_in(entry.range);
+                    let alloc = self.get_alloc_for_range(entry.index);
+                    match alloc.as_reg() {
+                        Some(preg)
+                            if !safepoints.is_empty() && !self.pregs[preg.index()].is_stack => {}
+                        _ => continue,
+                    }
+                    let spillset = self.bundles[self.ranges[entry.index].bundle].spillset;
+                    let slot = self.spillslots[self.spillsets[spillset].slot.index()].alloc;
+                    for &safepoint in safepoints {
+                        stores.push((safepoint, alloc, slot));
+                    }
+                }
+                // A value held in two registers at once (around a
+                // multi-use instruction) needs only one store.
+                stores.sort_unstable_by_key(|&(safepoint, ..)| safepoint);
+                stores.dedup_by_key(|&mut (safepoint, ..)| safepoint);
+                for &(safepoint, from, to) in &stores {
+                    trace!(
+                        "safepoint store of v{} at {:?}: {} -> {}",
+                        vreg.index(),
+                        safepoint,
+                        from,
+                        to
+                    );
+                    inserted_moves.push(
+                        ProgPoint::before(safepoint),
+                        InsertMovePrio::SafepointStore,
+                        from,
+                        to,
+                        self.vreg(vreg),
+                    );
+                }
+            }
+        }
+
         // Handle multi-fixed-reg constraints by copying.
-        for fixup in core::mem::replace(&mut self.multi_fixed_reg_fixups, vec![]) {
+        for i in 0..self.multi_fixed_reg_fixups.len() {
+            let fixup = self.multi_fixed_reg_fixups[i].clone(); // don't borrow self
             let from_alloc = self.get_alloc(fixup.pos.inst(), fixup.from_slot as usize);
             let to_alloc = Allocation::reg(PReg::from_index(fixup.to_preg.index()));
             trace!(
@@ -729,7 +1012,7 @@ impl<'a, F: Function> Env<'a, F> {
             let mut input_reused: SmallVec<[usize; 4]> = smallvec![];
             for output_idx in 0..self.func.inst_operands(inst).len() {
                 let operand = self.func.inst_operands(inst)[output_idx];
-                if let OperandConstraint::Reuse(input_idx) = operand.constraint() {
+                if let Some(input_idx) = self.reused_input_index(inst, output_idx) {
                     debug_assert!(!input_reused.contains(&input_idx));
                     debug_assert_eq!(operand.pos(), OperandPos::Late);
                     input_reused.push(input_idx);
@@ -761,10 +1044,34 @@ impl<'a, F: Function> Env<'a, F> {
                         );
                         self.set_alloc(inst, input_idx, output_alloc);
                     }
+                    // The other uses tied to the group read the same
+                    // register.
+                    if let OperandConstraint::Tied(group) = operand.constraint() {
+                        let operands = self.func.inst_operands(inst);
+                        for i in tied_uses(operands, group) {
+                            self.set_alloc(inst, i, output_alloc);
+                        }
+                    }
                 }
             }
         }
 
+        // Splits that left a value in the same place produce abutting
+        // (or, for pieces that overlap in one slot, overlapping)
+        // records for a label; coalesce these so that each record
+        // marks an actual change of location. Records in other
+        // locations may start in between, so group by location first.
+        self.debug_locations
+            .sort_unstable_by_key(|&(label, from, to, alloc)| (label, alloc, from, to));
+        self.debug_locations.dedup_by(|next, prev| {
+            if prev.0 == next.0 && prev.3 == next.3 && prev.2 >= next.1 {
+                prev.2 = core::cmp::max(prev.2, next.2);
+                true
+            } else {
+                false
+            }
+        });
+
         // Sort the debug-locations vector; we provide this
         // invariant to the client.
         self.debug_locations.sort_unstable();
@@ -776,9 +1083,17 @@ impl<'a, F: Function> Env<'a, F> {
         // For each program point, gather all moves together. Then
         // resolve (see cases below).
         let mut i = 0;
-        inserted_moves
-            .moves
-            .sort_unstable_by_key(|m| m.pos_prio.key());
+        // Within a single position and priority, order moves by
+        // source and then destination allocation, so that the
+        // parallel-move resolution below (and hence the final order
+        // of edits at each point) depends only on the set of moves,
+        // not on the order in which they were inserted.
+        inserted_moves.moves.sort_unstable_by_key(|m| {
+            (
+                m.pos_prio.key(),
+                u64_key(m.from_alloc.bits(), m.to_alloc.bits()),
+            )
+        });
 
         // Redundant-move elimination state tracker.
         let mut redundant_moves = RedundantMoveEliminator::default();
@@ -821,12 +1136,37 @@ impl<'a, F: Function> Env<'a, F> {
                         OperandKind::Def => {
                             let alloc = this.get_alloc(inst, i);
                             redundant_moves.clear_alloc(alloc);
+                            if let Some(reg) = alloc.as_reg() {
+                                for &alias in this.env.aliases(reg) {
+                                    redundant_moves.clear_alloc(Allocation::reg(alias));
+                                }
+                            }
                         }
                         _ => {}
                     }
                 }
-                for reg in this.func.inst_clobbers(inst) {
+                if this.func.spill_all_at(inst) {
+                    for reg in this.env.allocatable_regs() {
+                        redundant_moves.clear_alloc(Allocation::reg(reg));
+                    }
+                }
+                for reg in this
+                    .func
+                    .inst_clobbers(inst)
+                    .into_iter()
+                    .chain(this.func.inst_forbidden_pregs(inst))
+                    .chain(
+                        this.func
+                            .reserved_preg_ranges()
+                            .iter()
+                            .filter(|(_, range)| range.contains_point(ProgPoint::after(inst)))
+                            .map(|&(preg, _)| preg),
+                    )
+                {
                     redundant_moves.clear_alloc(Allocation::reg(reg));
+                    for &alias in this.env.aliases(reg) {
+                        redundant_moves.clear_alloc(Allocation::reg(alias));
+                    }
                 }
                 // The dedicated scratch registers may be clobbered by any
                 // instruction.
@@ -852,6 +1192,50 @@ impl<'a, F: Function> Env<'a, F> {
             redundant_move_process_side_effects(self, &mut redundant_moves, last_pos, pos_prio.pos);
             last_pos = pos_prio.pos;
 
+            // The parallel-move resolver only sees dependencies between
+            // identical allocations. If a move writes a register that
+            // aliases another move's source, first copy that source to
+            // a temporary spillslot, and have its move read from there.
+            let mut alias_temps_used = [0; 3];
+            let mut unaliased_moves: SmallVec<[InsertedMove; 8]>;
+            let moves = if self.env.reg_aliases.is_empty() {
+                moves
+            } else {
+                unaliased_moves = moves.iter().cloned().collect();
+                for j in 0..unaliased_moves.len() {
+                    let src = match unaliased_moves[j].from_alloc.as_reg() {
+                        Some(src) => src,
+                        None => continue,
+                    };
+                    let aliases = self.env.aliases(src);
+                    if !moves.iter().any(|m| match m.to_alloc.as_reg() {
+                        Some(dst) => aliases.contains(&dst),
+                        None => false,
+                    }) {
+                        continue;
+                    }
+                    let class = src.class() as usize;
+                    let temp_idx = alias_temps_used[class];
+                    alias_temps_used[class] += 1;
+                    if temp_idx >= self.alias_temp_slots_by_class[class].len() {
+                        let slot = self.allocate_spillslot(src.class());
+                        self.alias_temp_slots_by_class[class].push(slot);
+                    }
+                    let temp = self.alias_temp_slots_by_class[class][temp_idx];
+                    trace!("  alias hazard: copying {} to {} first", src, temp);
+                    let from = Allocation::reg(src);
+                    let to_vreg = unaliased_moves[j].to_vreg;
+                    if !redundant_moves
+                        .process_move(from, temp, Some(to_vreg))
+                        .elide
+                    {
+                        edits.add(pos_prio, from, temp);
+                    }
+                    unaliased_moves[j].from_alloc = temp;
+                }
+                &unaliased_moves[..]
+            };
+
             // Gather all the moves in each RegClass separately.
             // These cannot interact, so it is safe to have separate
             // ParallelMove instances. They need to be separate because
@@ -890,12 +1274,30 @@ impl<'a, F: Function> Env<'a, F> {
                     pos_prio.pos,
                     pos_prio.prio
                 );
-                for m in moves {
+                // Moves out of a rematerialized range are not moves:
+                // they recompute the value once all the others are
+                // done, as they may overwrite a source.
+                let mut remats: SmallVec<[&InsertedMove; 2]> = smallvec![];
+                for m in moves.iter() {
                     trace!(" {} -> {}", m.from_alloc, m.to_alloc);
-                    parallel_moves.add(m.from_alloc, m.to_alloc, Some(m.to_vreg));
+                    if m.from_alloc.is_none() {
+                        remats.push(m);
+                    } else {
+                        parallel_moves.add(m.from_alloc, m.to_alloc, Some(m.to_vreg));
+                    }
                 }
+                // Two ranges of one vreg may both ask for it.
+                remats.dedup_by_key(|m| (m.to_alloc, m.to_vreg));
 
                 let resolved = parallel_moves.resolve();
+                // Don't borrow a register that a loop around this
+                // point keeps free.
+                let mut reserved = PRegSet::empty();
+                for (loop_range, loop_regs) in &self.loop_reg_reserves {
+                    if loop_range.contains_point(pos_prio.pos) {
+                        reserved.union_from(*loop_regs);
+                    }
+                }
                 let mut scratch_iter = RegTraversalIter::new(
                     self.env,
                     regclass,
@@ -903,38 +1305,52 @@ impl<'a, F: Function> Env<'a, F> {
                     PReg::invalid(),
                     0,
                     None,
-                );
+                )
+                .with_excluded(reserved);
                 let mut dedicated_scratch = self.env.scratch_by_class[regclass as usize];
+                let preferred = self.options.preferred_scratch_by_class[regclass as usize];
+                let mut preferred_scratch = preferred;
                 let key = LiveRangeKey::from_range(&CodeRange {
                     from: pos_prio.pos,
                     to: pos_prio.pos.next(),
                 });
+                let is_free = |preg: PReg| {
+                    let in_use = |preg: PReg| {
+                        self.pregs[preg.index()]
+                            .allocations
+                            .btree
+                            .contains_key(&key)
+                    };
+                    if in_use(preg) || self.env.aliases(preg).iter().any(|&a| in_use(a)) {
+                        return false;
+                    }
+                    // Skip pregs used by moves in this parallel move
+                    // set, even if not marked used at progpoint: edge
+                    // move liveranges meet but don't overlap so
+                    // otherwise we may incorrectly overwrite a source
+                    // reg.
+                    let alloc = Allocation::reg(preg);
+                    !moves
+                        .iter()
+                        .any(|m| m.from_alloc == alloc || m.to_alloc == alloc)
+                };
                 let find_free_reg = || {
                     // Use the dedicated scratch register first if it is
-                    // available.
+                    // available, then the preferred one if it is free.
                     if let Some(reg) = dedicated_scratch.take() {
                         return Some(Allocation::reg(reg));
                     }
+                    if let Some(reg) = preferred_scratch.take() {
+                        debug_assert_eq!(reg.class(), regclass);
+                        if !reserved.contains(reg) && is_free(reg) {
+                            return Some(Allocation::reg(reg));
+                        }
+                    }
+                    // The preferred register was tried above, so the
+                    // scan must not hand it out a second time.
                     while let Some(preg) = scratch_iter.next() {
-                        if !self.pregs[preg.index()]
-                            .allocations
-                            .btree
-                            .contains_key(&key)
-                        {
-                            let alloc = Allocation::reg(preg);
-                            if moves
-                                .iter()
-                                .any(|m| m.from_alloc == alloc || m.to_alloc == alloc)
-                            {
-                                // Skip pregs used by moves in this
-                                // parallel move set, even if not
-                                // marked used at progpoint: edge move
-                                // liveranges meet but don't overlap
-                                // so otherwise we may incorrectly
-                                // overwrite a source reg.
-                                continue;
-                            }
-                            return Some(alloc);
+                        if Some(preg) != preferred && is_free(preg) {
+                            return Some(Allocation::reg(preg));
                         }
                     }
                     None
@@ -970,8 +1386,7 @@ impl<'a, F: Function> Env<'a, F> {
                 let mut rewrites = FxHashMap::default();
                 for i in 0..stackslot_idx {
                     if i >= self.extra_spillslots_by_class[regclass as usize].len() {
-                        let slot =
-                            self.allocate_spillslot(self.func.spillslot_size(regclass) as u32);
+                        let slot = self.allocate_spillslot(regclass);
                         self.extra_spillslots_by_class[regclass as usize].push(slot);
                     }
                     rewrites.insert(
@@ -987,10 +1402,32 @@ impl<'a, F: Function> Env<'a, F> {
                     let action = redundant_moves.process_move(src, dst, to_vreg);
                     if !action.elide {
                         edits.add(pos_prio, src, dst);
+                        if let Some(reg) = dst.as_reg() {
+                            for &alias in self.env.aliases(reg) {
+                                redundant_moves.clear_alloc(Allocation::reg(alias));
+                            }
+                        }
                     } else {
                         trace!("    -> redundant move elided");
                     }
                 }
+
+                for m in remats {
+                    let inst = self.remat_vregs[&VRegIndex::new(m.to_vreg.vreg())];
+                    trace!(
+                        "  remat: inst{} {} -> {}",
+                        inst.index(),
+                        m.to_vreg,
+                        m.to_alloc
+                    );
+                    edits.add_remat(pos_prio, inst, m.to_vreg, m.to_alloc);
+                    redundant_moves.clear_alloc(m.to_alloc);
+                    if let Some(reg) = m.to_alloc.as_reg() {
+                        for &alias in self.env.aliases(reg) {
+                            redundant_moves.clear_alloc(Allocation::reg(alias));
+                        }
+                    }
+                }
             }
         }
 
@@ -1004,14 +1441,177 @@ impl<'a, F: Function> Env<'a, F> {
         // Add debug annotations.
         if self.annotations_enabled {
             for &(pos_prio, ref edit) in edits.iter() {
-                match edit {
-                    &Edit::Move { from, to } => {
+                match *edit {
+                    Edit::Move { from, to } => {
                         self.annotate(pos_prio.pos, format!("move {} -> {}", from, to));
                     }
+                    Edit::Remat { inst, vreg, to } => {
+                        self.annotate(
+                            pos_prio.pos,
+                            format!("remat inst{} {} -> {}", inst.index(), vreg, to),
+                        );
+                    }
                 }
             }
         }
 
         edits
     }
+
+    /// Record the location of every vreg whose range covers the
+    /// entry of a block, for `Output::block_reg_state`.
+    pub fn compute_block_entry_locations(&mut self) {
+        let mut locations = vec![];
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            for entry in &self.vregs[vreg].ranges {
+                let alloc = self.get_alloc_for_range(entry.index);
+                if alloc.is_none() {
+                    continue;
+                }
+                let mut block = self.cfginfo.insn_block[entry.range.from.inst().index()];
+                if self.cfginfo.block_entry[block.index()] < entry.range.from {
+                    block = block.next();
+                }
+                while block.is_valid()
+                    && block.index() < self.func.num_blocks()
+                    && self.cfginfo.block_entry[block.index()] < entry.range.to
+                {
+                    locations.push((block, self.vreg(vreg), alloc));
+                    block = block.next();
+                }
+            }
+        }
+        // Pieces of a vreg that overlap in one slot both cover the
+        // entries within the overlap; record these once.
+        locations.sort_unstable_by_key(|&(block, vreg, alloc)| (block, vreg.vreg(), alloc));
+        locations.dedup();
+        self.block_entry_locations = locations;
+    }
+
+    /// Record the contents of each register's allocation map, for
+    /// `Output::preg_map`.
+    pub fn compute_preg_maps(&mut self) {
+        let mut maps = vec![];
+        for (i, preg) in self.pregs.iter().enumerate() {
+            for (key, &lr) in preg.allocations.btree.iter() {
+                let vreg = if lr.is_valid() {
+                    Some(self.vreg(self.ranges[lr].vreg))
+                } else {
+                    None
+                };
+                maps.push((PReg::from_index(i), key.to_range(), vreg));
+            }
+        }
+        self.preg_maps = maps;
+    }
+
+    /// Record the stretches over which each vreg is in a spillslot,
+    /// for `Output::spilled_vregs`.
+    pub fn compute_spilled_ranges(&mut self) {
+        let mut spilled = vec![];
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            let start = spilled.len();
+            for entry in &self.vregs[vreg].ranges {
+                if let Some(slot) = self.get_alloc_for_range(entry.index).as_stack() {
+                    spilled.push((self.vreg(vreg), slot, entry.range));
+                }
+            }
+            // Coalesce abutting ranges in the same slot.
+            spilled[start..].sort_unstable_by_key(|&(_, _, range): &(_, _, CodeRange)| range.from);
+            let mut i = start;
+            for j in start..spilled.len() {
+                let (_, slot, range) = spilled[j];
+                if i > start && spilled[i - 1].1 == slot && spilled[i - 1].2.to == range.from {
+                    spilled[i - 1].2.to = range.to;
+                } else {
+                    spilled[i] = spilled[j];
+                    i += 1;
+                }
+            }
+            spilled.truncate(i);
+        }
+        self.spilled_ranges = spilled;
+    }
+
+    /// Record every live range with its final allocation, for
+    /// `Output::ranges`.
+    pub fn compute_live_ranges(&mut self) {
+        let mut ranges = vec![];
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            for entry in &self.vregs[vreg].ranges {
+                let alloc = self.get_alloc_for_range(entry.index);
+                ranges.push((self.vreg(vreg), entry.range, alloc));
+            }
+        }
+        self.live_ranges = ranges;
+    }
+
+    /// Record, for each instruction, the largest number of registers
+    /// of each class holding a value at either of its program
+    /// points, for `Output::pressure_profile`.
+    pub fn compute_live_regs_by_inst(&mut self) {
+        let num_points = 2 * self.func.num_insts();
+        let mut delta = vec![[0i32; 3]; num_points + 1];
+        for vreg in self.vregs.iter() {
+            for entry in &vreg.ranges {
+                let preg = match self.get_alloc_for_range(entry.index).as_reg() {
+                    Some(preg) if !self.pregs[preg.index()].is_stack => preg,
+                    _ => continue,
+                };
+                let class = preg.class() as usize;
+                delta[entry.range.from.to_index() as usize][class] += 1;
+                delta[entry.range.to.to_index() as usize][class] -= 1;
+            }
+        }
+        let mut live = [0i32; 3];
+        let mut live_regs = Vec::with_capacity(self.func.num_insts());
+        for point in delta[..num_points].chunks(2) {
+            let mut max_live = [0u32; 3];
+            for d in point {
+                for class in 0..3 {
+                    live[class] += d[class];
+                    max_live[class] = core::cmp::max(max_live[class], live[class] as u32);
+                }
+            }
+            live_regs.push(max_live);
+        }
+        self.live_regs_by_inst = live_regs;
+    }
+
+    /// Check that each vreg requested to stay in a register across a
+    /// loop did so, i.e. that all of its live ranges within the loop
+    /// ended up in one register, and record those that did not in
+    /// `loop_resident_fallbacks`.
+    pub fn check_loop_residents(&mut self) {
+        for &(vreg, header, loop_range) in &self.loop_residents {
+            let mut reg = None;
+            let mut honored = true;
+            for entry in &self.vregs[vreg].ranges {
+                if !entry.range.overlaps(&loop_range) {
+                    continue;
+                }
+                let alloc = self.get_alloc_for_range(entry.index);
+                match alloc.as_reg() {
+                    Some(preg) if !self.pregs[preg.index()].is_stack => {}
+                    _ => honored = false,
+                }
+                if *reg.get_or_insert(alloc) != alloc {
+                    honored = false;
+                }
+            }
+            if !honored {
+                let vreg = self.vreg(vreg);
+                trace!(
+                    "loop-resident {} at {:?}: not kept in one register",
+                    vreg,
+                    header
+                );
+                self.loop_resident_fallbacks.push((vreg, header));
+            }
+        }
+        self.loop_resident_fallbacks.sort_unstable();
+    }
 }
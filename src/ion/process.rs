@@ -13,18 +13,22 @@
 //! Main allocation loop that processes bundles.
 
 use super::{
-    spill_weight_from_constraint, Env, LiveBundleIndex, LiveBundleVec, LiveRangeFlag,
-    LiveRangeIndex, LiveRangeKey, LiveRangeList, LiveRangeListEntry, PRegIndex, RegTraversalIter,
-    Requirement, SpillWeight, UseList, VRegIndex,
+    Env, LiveBundleIndex, LiveBundleVec, LiveRangeFlag, LiveRangeIndex, LiveRangeKey,
+    LiveRangeList, LiveRangeListEntry, PRegIndex, QueueTieBreakHook, RegTraversalIter, Requirement,
+    SpillSetIndex, SpillWeight, SplitHook, UseList, VRegIndex, VregTraceEvent,
 };
 use crate::{
     ion::data_structures::{
-        CodeRange, BUNDLE_MAX_NORMAL_SPILL_WEIGHT, MAX_SPLITS_PER_SPILLSET,
-        MINIMAL_BUNDLE_SPILL_WEIGHT, MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT,
+        CodeRange, BUNDLE_MAX_NORMAL_SPILL_WEIGHT, BUNDLE_MAX_SPILL_WEIGHT,
+        MAX_SPLITS_PER_SPILLSET, MINIMAL_BUNDLE_SPILL_WEIGHT, MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT,
+        MINIMAL_SUBCLASS_BUNDLE_SPILL_WEIGHT,
     },
-    Allocation, Function, FxHashSet, Inst, InstPosition, OperandConstraint, OperandKind, PReg,
-    ProgPoint, RegAllocError,
+    Allocation, Block, Function, FxHashSet, Inst, InstPosition, MinimalBundleGranularity,
+    OperandConstraint, OperandKind, PReg, PRegSet, ProgPoint, QueuedBundle, RegAllocError,
+    RegClass, RegScanMode, SpillReason, SplitEvent, SplitPiece, SplitProposal, VReg,
 };
+use alloc::vec::Vec;
+use core::cmp::Ordering;
 use core::fmt::Debug;
 use smallvec::{smallvec, SmallVec};
 
@@ -37,11 +41,149 @@ pub enum AllocRegResult {
 }
 
 impl<'a, F: Function> Env<'a, F> {
+    /// Describe a queued bundle to the caller's tie-break.
+    fn queued_bundle(&self, bundle: LiveBundleIndex) -> QueuedBundle {
+        let ranges = &self.bundles[bundle].ranges;
+        let (range, vreg) = match (ranges.first(), ranges.last()) {
+            (Some(first), Some(last)) => (
+                CodeRange {
+                    from: first.range.from,
+                    to: last.range.to,
+                },
+                self.vreg(self.ranges[first.index].vreg),
+            ),
+            _ => (
+                CodeRange {
+                    from: ProgPoint::before(Inst::new(0)),
+                    to: ProgPoint::before(Inst::new(0)),
+                },
+                VReg::invalid(),
+            ),
+        };
+        let num_uses = ranges
+            .iter()
+            .map(|entry| self.ranges[entry.index].uses.len())
+            .sum();
+        QueuedBundle {
+            range,
+            num_uses,
+            vreg,
+        }
+    }
+
+    /// Take the next bundle from the allocation queue. With a
+    /// caller tie-break, every bundle at the top priority is
+    /// considered and the one it orders greatest is taken; the
+    /// others go back into the queue.
+    fn pop_bundle(&mut self) -> Option<(LiveBundleIndex, PReg)> {
+        let tie_break = match self.queue_tie_break {
+            Some(QueueTieBreakHook(tie_break)) => tie_break,
+            None => return self.allocation_queue.pop(),
+        };
+        let top = self.allocation_queue.heap.pop()?;
+        let mut best = top;
+        let mut best_desc = self.queued_bundle(top.bundle);
+        let mut rest = Vec::new();
+        while let Some(&entry) = self.allocation_queue.heap.peek() {
+            if entry.prio != top.prio {
+                break;
+            }
+            self.allocation_queue.heap.pop();
+            let desc = self.queued_bundle(entry.bundle);
+            if tie_break(&desc, &best_desc) == Ordering::Greater {
+                rest.push(best);
+                best = entry;
+                best_desc = desc;
+            } else {
+                rest.push(entry);
+            }
+        }
+        self.allocation_queue.heap.extend(rest);
+        Some((best.bundle, best.reg_hint))
+    }
+
     pub fn process_bundles(&mut self) -> Result<(), RegAllocError> {
-        while let Some((bundle, reg_hint)) = self.allocation_queue.pop() {
+        #[cfg(feature = "std")]
+        let past_deadline = {
+            let deadline = self
+                .options
+                .time_budget
+                .map(|budget| std::time::Instant::now() + budget);
+            move || match deadline {
+                Some(deadline) => std::time::Instant::now() >= deadline,
+                None => false,
+            }
+        };
+        #[cfg(not(feature = "std"))]
+        let past_deadline = || false;
+        let mut timed_out = false;
+
+        // In two-phase mode, the first phase only processes
+        // constrained bundles; everything else, including the
+        // unconstrained pieces of split constrained bundles, waits
+        // for the second phase.
+        let mut first_phase = self.options.two_phase;
+        let mut deferred: Vec<(LiveBundleIndex, PReg)> = Vec::new();
+
+        loop {
+            let (bundle, reg_hint) = match self.pop_bundle() {
+                Some(entry) => entry,
+                None if first_phase => {
+                    first_phase = false;
+                    self.freeze_allocated();
+                    for (bundle, reg_hint) in deferred.drain(..) {
+                        let prio = self.queue_prio(bundle);
+                        self.allocation_queue.insert(bundle, prio, reg_hint);
+                    }
+                    continue;
+                }
+                None => break,
+            };
+            if first_phase && !self.constrained_bundle(bundle) {
+                deferred.push((bundle, reg_hint));
+                continue;
+            }
+
             self.stats.process_bundle_count += 1;
+
+            if !timed_out {
+                timed_out = past_deadline();
+            }
+
+            // Once over the time budget, give up on finding a good
+            // allocation: reduce each bundle to minimal bundles plus
+            // its spill bundle, which needs no further backtracking.
+            if timed_out && !self.unsplittable_bundle(bundle) {
+                trace!("time budget exceeded; spilling bundle {:?}", bundle);
+                self.stats.splits_timeout += 1;
+                self.split_into_minimal_bundles(bundle, reg_hint, SpillReason::TimeBudget);
+                continue;
+            }
+
+            // Members of a spilled allocation group are reduced to
+            // minimal bundles too.
+            if self.in_spilled_group(bundle) && !self.unsplittable_bundle(bundle) {
+                trace!("bundle {:?} is in a spilled allocation group", bundle);
+                self.split_into_minimal_bundles(bundle, reg_hint, SpillReason::SpilledGroup);
+                continue;
+            }
+
+            // In single-pass mode, a bundle that needs a register
+            // either takes a free one or is spilled right away.
+            if self.options.single_pass
+                && !self.unsplittable_bundle(bundle)
+                && matches!(self.compute_requirement(bundle), Ok(Requirement::Register))
+            {
+                self.note_bundle_requirement(bundle, &Ok(Requirement::Register));
+                if !self.try_allocating_free_reg(bundle, reg_hint) {
+                    self.split_into_minimal_bundles(bundle, reg_hint, SpillReason::SinglePass);
+                }
+                continue;
+            }
+
             self.process_bundle(bundle, reg_hint)?;
         }
+        self.stats.peak_queue_len = self.allocation_queue.peak_len;
         self.stats.final_liverange_count = self.ranges.len();
         self.stats.final_bundle_count = self.bundles.len();
         self.stats.spill_bundle_count = self.spilled_bundles.len();
@@ -49,6 +191,108 @@ impl<'a, F: Function> Env<'a, F> {
         Ok(())
     }
 
+    /// `try_to_allocate_bundle_to_reg`, for the bundle being
+    /// processed, answered from `probe_cache` if the preg was scanned
+    /// on an earlier attempt. A cached conflict gives the same answer
+    /// a rescan would, `ConflictHighCost` included, since the cost
+    /// limit is only ever compared against the largest weight seen.
+    fn probe_with_cache(
+        &mut self,
+        bundle: LiveBundleIndex,
+        reg: PRegIndex,
+        max_allowable_cost: Option<u32>,
+    ) -> AllocRegResult {
+        let cached = match &self.probe_cache[reg.index()] {
+            Some(AllocRegResult::Conflict(bundles, _)) => {
+                Some(self.maximum_spill_weight_in_bundle_set(bundles))
+            }
+            Some(AllocRegResult::ConflictWithFixed(max_cost, _)) => Some(*max_cost),
+            _ => None,
+        };
+        if let Some(cost) = cached {
+            self.stats.process_bundle_reg_probes_cached += 1;
+            if matches!(max_allowable_cost, Some(max) if cost > max) {
+                return AllocRegResult::ConflictHighCost;
+            }
+            return self.probe_cache[reg.index()].clone().unwrap();
+        }
+        let result = self.try_to_allocate_bundle_to_reg(bundle, reg, max_allowable_cost);
+        if matches!(
+            result,
+            AllocRegResult::Conflict(..) | AllocRegResult::ConflictWithFixed(..)
+        ) {
+            self.probe_cache[reg.index()] = Some(result.clone());
+        }
+        result
+    }
+
+    /// Forget the cached probes that `bundle`'s eviction makes stale:
+    /// those of its register and of the registers aliasing it.
+    fn invalidate_probes(&mut self, bundle: LiveBundleIndex) {
+        let preg = match self.bundles[bundle].allocation.as_reg() {
+            Some(preg) => preg,
+            None => return,
+        };
+        self.probe_cache[preg.index()] = None;
+        for alias in self.env.aliases(preg) {
+            self.probe_cache[alias.index()] = None;
+        }
+        if self.has_full_reg_defs {
+            for part in self.env.full_register_parts(preg) {
+                self.probe_cache[part.index()] = None;
+            }
+        }
+    }
+
+    /// The allocated ranges that a full-register def (see
+    /// `Operand::reg_full_def`) would clobber if `ranges` were put in
+    /// `reg`, each with the point of the def: ranges in the other
+    /// parts of the registers aliasing `reg` that are live at a full
+    /// def in `ranges`, and ranges with a full def at which `ranges`
+    /// are live. An invalid index is a fixed reservation. Always empty
+    /// if the function has no full-register defs.
+    pub fn full_reg_def_conflicts(
+        &self,
+        reg: PReg,
+        ranges: &[LiveRangeListEntry],
+    ) -> SmallVec<[(LiveRangeIndex, ProgPoint); 4]> {
+        let mut conflicts = smallvec![];
+        if !self.has_full_reg_defs {
+            return conflicts;
+        }
+        for part in self.env.full_register_parts(reg) {
+            let btree = &self.pregs[part.index()].allocations.btree;
+            for entry in ranges {
+                if entry.index.is_valid() {
+                    for u in &self.ranges[entry.index].uses {
+                        if !u.operand.is_full_reg_def() {
+                            continue;
+                        }
+                        let key = LiveRangeKey::from_range(&CodeRange {
+                            from: u.pos,
+                            to: u.pos.next(),
+                        });
+                        if let Some(&lr) = btree.get(&key) {
+                            conflicts.push((lr, u.pos));
+                        }
+                    }
+                }
+                let key = LiveRangeKey::from_range(&entry.range);
+                for (_, &lr) in btree.range(key..).take_while(|(k, _)| **k == key) {
+                    if !lr.is_valid() {
+                        continue;
+                    }
+                    for u in &self.ranges[lr].uses {
+                        if u.operand.is_full_reg_def() && entry.range.contains_point(u.pos) {
+                            conflicts.push((lr, u.pos));
+                        }
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+
     pub fn try_to_allocate_bundle_to_reg(
         &mut self,
         bundle: LiveBundleIndex,
@@ -75,116 +319,161 @@ impl<'a, F: Function> Env<'a, F> {
         // *overlap*, so we are checking whether the BTree contains
         // any preg range that *overlaps* with range `range`, not
         // literally the range `range`.
-        let bundle_ranges = &self.bundles[bundle].ranges;
-        let from_key = LiveRangeKey::from_range(&CodeRange {
-            from: bundle_ranges.first().unwrap().range.from,
-            to: bundle_ranges.first().unwrap().range.from,
-        });
-        let mut preg_range_iter = self.pregs[reg.index()]
-            .allocations
-            .btree
-            .range(from_key..)
-            .peekable();
-        trace!(
-            "alloc map for {:?} in range {:?}..: {:?}",
-            reg,
-            from_key,
-            self.pregs[reg.index()].allocations.btree
-        );
         let mut first_conflict: Option<ProgPoint> = None;
 
-        'ranges: for entry in bundle_ranges {
-            trace!(" -> range LR {:?}: {:?}", entry.index, entry.range);
-            let key = LiveRangeKey::from_range(&entry.range);
+        // Scan the register itself and then any registers aliasing
+        // it: a range in any of them conflicts with this bundle.
+        let mut scan_regs: SmallVec<[PRegIndex; 4]> = smallvec![reg];
+        scan_regs.extend(
+            self.env
+                .aliases(PReg::from_index(reg.index()))
+                .iter()
+                .map(|alias| PRegIndex::new(alias.index())),
+        );
+        for scan_reg in scan_regs {
+            let bundle_ranges = &self.bundles[bundle].ranges;
+            let from_key = LiveRangeKey::from_range(&CodeRange {
+                from: bundle_ranges.first().unwrap().range.from,
+                to: bundle_ranges.first().unwrap().range.from,
+            });
+            let mut preg_range_iter = self.pregs[scan_reg.index()]
+                .allocations
+                .btree
+                .range(from_key..)
+                .peekable();
+            trace!(
+                "alloc map for {:?} in range {:?}..: {:?}",
+                scan_reg,
+                from_key,
+                self.pregs[scan_reg.index()].allocations.btree
+            );
+            'ranges: for entry in bundle_ranges {
+                trace!(" -> range LR {:?}: {:?}", entry.index, entry.range);
+                let key = LiveRangeKey::from_range(&entry.range);
 
-            let mut skips = 0;
-            'alloc: loop {
-                trace!("  -> PReg range {:?}", preg_range_iter.peek());
+                let mut skips = 0;
+                'alloc: loop {
+                    trace!("  -> PReg range {:?}", preg_range_iter.peek());
 
-                // Advance our BTree traversal until it is >= this bundle
-                // range (i.e., skip PReg allocations in the BTree that
-                // are completely before this bundle range).
+                    // Advance our BTree traversal until it is >= this bundle
+                    // range (i.e., skip PReg allocations in the BTree that
+                    // are completely before this bundle range).
 
-                if preg_range_iter.peek().is_some() && *preg_range_iter.peek().unwrap().0 < key {
-                    trace!(
-                        "Skipping PReg range {:?}",
-                        preg_range_iter.peek().unwrap().0
-                    );
-                    preg_range_iter.next();
-                    skips += 1;
-                    if skips >= 16 {
-                        let from_pos = entry.range.from;
-                        let from_key = LiveRangeKey::from_range(&CodeRange {
-                            from: from_pos,
-                            to: from_pos,
-                        });
-                        preg_range_iter = self.pregs[reg.index()]
-                            .allocations
-                            .btree
-                            .range(from_key..)
-                            .peekable();
-                        skips = 0;
+                    if preg_range_iter.peek().is_some() && *preg_range_iter.peek().unwrap().0 < key
+                    {
+                        trace!(
+                            "Skipping PReg range {:?}",
+                            preg_range_iter.peek().unwrap().0
+                        );
+                        preg_range_iter.next();
+                        skips += 1;
+                        if skips >= 16 {
+                            let from_pos = entry.range.from;
+                            let from_key = LiveRangeKey::from_range(&CodeRange {
+                                from: from_pos,
+                                to: from_pos,
+                            });
+                            preg_range_iter = self.pregs[scan_reg.index()]
+                                .allocations
+                                .btree
+                                .range(from_key..)
+                                .peekable();
+                            skips = 0;
+                        }
+                        continue 'alloc;
                     }
-                    continue 'alloc;
-                }
-                skips = 0;
+                    skips = 0;
 
-                // If there are no more PReg allocations, we're done!
-                if preg_range_iter.peek().is_none() {
-                    trace!(" -> no more PReg allocations; so no conflict possible!");
-                    break 'ranges;
-                }
+                    // If there are no more PReg allocations, we're done!
+                    if preg_range_iter.peek().is_none() {
+                        trace!(" -> no more PReg allocations; so no conflict possible!");
+                        break 'ranges;
+                    }
 
-                // If the current PReg range is beyond this range, there is no conflict; continue.
-                if *preg_range_iter.peek().unwrap().0 > key {
-                    trace!(
-                        " -> next PReg allocation is at {:?}; moving to next VReg range",
-                        preg_range_iter.peek().unwrap().0
-                    );
-                    break 'alloc;
-                }
+                    // If the current PReg range is beyond this range, there is no conflict; continue.
+                    if *preg_range_iter.peek().unwrap().0 > key {
+                        trace!(
+                            " -> next PReg allocation is at {:?}; moving to next VReg range",
+                            preg_range_iter.peek().unwrap().0
+                        );
+                        break 'alloc;
+                    }
+
+                    // Otherwise, there is a conflict.
+                    let preg_key = *preg_range_iter.peek().unwrap().0;
+                    debug_assert_eq!(preg_key, key); // Assert that this range overlaps.
+                    let preg_range = preg_range_iter.next().unwrap().1;
+
+                    trace!(" -> btree contains range {:?} that overlaps", preg_range);
+                    if preg_range.is_valid() {
+                        trace!("   -> from vreg {:?}", self.ranges[*preg_range].vreg);
+                        // range from an allocated bundle: find the bundle and add to
+                        // conflicts list.
+                        let conflict_bundle = self.ranges[*preg_range].bundle;
+                        trace!("   -> conflict bundle {:?}", conflict_bundle);
+                        if let Err(pos) = self.conflict_set.binary_search(&conflict_bundle) {
+                            self.conflict_set.insert(pos, conflict_bundle);
+                            conflicts.push(conflict_bundle);
+                            max_conflict_weight = core::cmp::max(
+                                max_conflict_weight,
+                                self.bundles[conflict_bundle].cached_spill_weight(),
+                            );
+                            if max_allowable_cost.is_some()
+                                && max_conflict_weight > max_allowable_cost.unwrap()
+                            {
+                                trace!("   -> reached high cost, retrying early");
+                                return AllocRegResult::ConflictHighCost;
+                            }
+                        }
 
-                // Otherwise, there is a conflict.
-                let preg_key = *preg_range_iter.peek().unwrap().0;
-                debug_assert_eq!(preg_key, key); // Assert that this range overlaps.
-                let preg_range = preg_range_iter.next().unwrap().1;
-
-                trace!(" -> btree contains range {:?} that overlaps", preg_range);
-                if preg_range.is_valid() {
-                    trace!("   -> from vreg {:?}", self.ranges[*preg_range].vreg);
-                    // range from an allocated bundle: find the bundle and add to
-                    // conflicts list.
-                    let conflict_bundle = self.ranges[*preg_range].bundle;
-                    trace!("   -> conflict bundle {:?}", conflict_bundle);
-                    if self.conflict_set.insert(conflict_bundle) {
-                        conflicts.push(conflict_bundle);
-                        max_conflict_weight = core::cmp::max(
+                        let conflict_point =
+                            ProgPoint::from_index(core::cmp::max(preg_key.from, key.from));
+                        if first_conflict.is_none() || conflict_point < first_conflict.unwrap() {
+                            first_conflict = Some(conflict_point);
+                        }
+                    } else {
+                        trace!("   -> conflict with fixed reservation");
+                        // range from a direct use of the PReg (due to clobber).
+                        return AllocRegResult::ConflictWithFixed(
                             max_conflict_weight,
-                            self.bundles[conflict_bundle].cached_spill_weight(),
+                            ProgPoint::from_index(preg_key.from),
                         );
-                        if max_allowable_cost.is_some()
-                            && max_conflict_weight > max_allowable_cost.unwrap()
-                        {
-                            trace!("   -> reached high cost, retrying early");
-                            return AllocRegResult::ConflictHighCost;
-                        }
                     }
+                }
+            }
+        }
 
-                    if first_conflict.is_none() {
-                        first_conflict = Some(ProgPoint::from_index(core::cmp::max(
-                            preg_key.from,
-                            key.from,
-                        )));
-                    }
-                } else {
-                    trace!("   -> conflict with fixed reservation");
-                    // range from a direct use of the PReg (due to clobber).
-                    return AllocRegResult::ConflictWithFixed(
-                        max_conflict_weight,
-                        ProgPoint::from_index(preg_key.from),
-                    );
+        // A full-register def also writes the other parts of the
+        // registers aliasing `reg`, or another part's def writes
+        // `reg`: whatever is live there at the def conflicts too.
+        let full_def_conflicts = self
+            .full_reg_def_conflicts(PReg::from_index(reg.index()), &self.bundles[bundle].ranges);
+        for (lr, conflict_point) in full_def_conflicts {
+            if !lr.is_valid() {
+                trace!("   -> full-register def conflicts with fixed reservation");
+                return AllocRegResult::ConflictWithFixed(max_conflict_weight, conflict_point);
+            }
+            let conflict_bundle = self.ranges[lr].bundle;
+            trace!(
+                "   -> full-register def conflicts with bundle {:?}",
+                conflict_bundle
+            );
+            if let Err(pos) = self.conflict_set.binary_search(&conflict_bundle) {
+                self.conflict_set.insert(pos, conflict_bundle);
+                conflicts.push(conflict_bundle);
+                max_conflict_weight = core::cmp::max(
+                    max_conflict_weight,
+                    self.bundles[conflict_bundle].cached_spill_weight(),
+                );
+                if max_allowable_cost.is_some() && max_conflict_weight > max_allowable_cost.unwrap()
+                {
+                    trace!("   -> reached high cost, retrying early");
+                    return AllocRegResult::ConflictHighCost;
                 }
             }
+            if first_conflict.is_none() || conflict_point < first_conflict.unwrap() {
+                first_conflict = Some(conflict_point);
+            }
         }
 
         if conflicts.len() > 0 {
@@ -194,7 +483,12 @@ impl<'a, F: Function> Env<'a, F> {
         // We can allocate! Add our ranges to the preg's BTree.
         let preg = PReg::from_index(reg.index());
         trace!("  -> bundle {:?} assigned to preg {:?}", bundle, preg);
+        self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Allocated {
+            bundle,
+            preg,
+        });
         self.bundles[bundle].allocation = Allocation::reg(preg);
+        self.allocated_pregs.add(preg);
         for entry in &self.bundles[bundle].ranges {
             let key = LiveRangeKey::from_range(&entry.range);
             let res = self.pregs[reg.index()]
@@ -225,6 +519,7 @@ impl<'a, F: Function> Env<'a, F> {
                 return;
             }
         };
+        self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Evicted { bundle });
         let preg_idx = PRegIndex::new(preg.index());
         self.bundles[bundle].allocation = Allocation::none();
         for entry in &self.bundles[bundle].ranges {
@@ -234,10 +529,9 @@ impl<'a, F: Function> Env<'a, F> {
                 .btree
                 .remove(&LiveRangeKey::from_range(&entry.range));
         }
-        let prio = self.bundles[bundle].prio;
+        let prio = self.queue_prio(bundle);
         trace!(" -> prio {}; back into queue", prio);
-        self.allocation_queue
-            .insert(bundle, prio as usize, PReg::invalid());
+        self.allocation_queue.insert(bundle, prio, PReg::invalid());
     }
 
     pub fn bundle_spill_weight(&self, bundle: LiveBundleIndex) -> u32 {
@@ -295,14 +589,18 @@ impl<'a, F: Function> Env<'a, F> {
                     break;
                 }
             }
-            // Minimal if the range covers only one instruction. Note
-            // that it could cover just one ProgPoint,
-            // i.e. X.Before..X.After, or two ProgPoints,
+            // Minimal if the range covers only one instruction, or
+            // with a finer granularity, only one ProgPoint. Note that
+            // a range within one instruction could cover just one
+            // ProgPoint, i.e. X.Before..X.After, or two ProgPoints,
             // i.e. X.Before..X+1.Before.
             trace!("  -> first range has range {:?}", first_range_data.range);
             let bundle_start = self.bundles[bundle].ranges.first().unwrap().range.from;
             let bundle_end = self.bundles[bundle].ranges.last().unwrap().range.to;
-            minimal = bundle_start.inst() == bundle_end.prev().inst();
+            minimal = match self.options.minimal_bundle_granularity {
+                MinimalBundleGranularity::Inst => bundle_start.inst() == bundle_end.prev().inst(),
+                MinimalBundleGranularity::ProgPoint => bundle_end == bundle_start.next(),
+            };
             trace!("  -> minimal: {}", minimal);
         }
 
@@ -310,6 +608,16 @@ impl<'a, F: Function> Env<'a, F> {
             if fixed {
                 trace!("  -> fixed and minimal");
                 MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT
+            } else if self.spillsets[self.bundles[bundle].spillset]
+                .subclass
+                .is_some()
+            {
+                // A minimal bundle restricted to a subclass must be
+                // able to evict unrestricted minimal bundles from the
+                // subclass, which can then go elsewhere, while still
+                // yielding to fixed-register minimal bundles.
+                trace!("  -> subclass and minimal");
+                MINIMAL_SUBCLASS_BUNDLE_SPILL_WEIGHT
             } else {
                 trace!("  -> non-fixed and minimal");
                 MINIMAL_BUNDLE_SPILL_WEIGHT
@@ -337,6 +645,27 @@ impl<'a, F: Function> Env<'a, F> {
                 0
             }
         };
+        // A bundle that should stay in a register across a loop
+        // outweighs every other non-minimal bundle, so it evicts
+        // rather than splits wherever it can.
+        let spill_weight = if !minimal && !self.bundle_resident_loops(bundle).is_empty() {
+            trace!("  -> loop-resident");
+            BUNDLE_MAX_NORMAL_SPILL_WEIGHT
+        } else {
+            spill_weight
+        };
+        // A pinned bundle outweighs any value that could go elsewhere,
+        // as a minimal bundle restricted to a subclass does, but still
+        // yields to a use fixed to its register.
+        let spill_weight = if self.spillsets[self.bundles[bundle].spillset]
+            .pinned
+            .is_some()
+        {
+            trace!("  -> pinned");
+            core::cmp::max(spill_weight, MINIMAL_SUBCLASS_BUNDLE_SPILL_WEIGHT)
+        } else {
+            spill_weight
+        };
 
         self.bundles[bundle].set_cached_spill_weight_and_props(
             spill_weight,
@@ -351,6 +680,45 @@ impl<'a, F: Function> Env<'a, F> {
         self.bundles[bundle].cached_minimal()
     }
 
+    /// Can `bundle` not be split any further? This is so if it is
+    /// minimal, or if it covers a single instruction, as any bundle
+    /// that is minimal with the default granularity does.
+    pub fn unsplittable_bundle(&self, bundle: LiveBundleIndex) -> bool {
+        let ranges = &self.bundles[bundle].ranges;
+        self.minimal_bundle(bundle)
+            || ranges.first().unwrap().range.from.inst()
+                == ranges.last().unwrap().range.to.prev().inst()
+    }
+
+    /// Make the unsplittable `bundle` minimal, whatever the
+    /// granularity, for a bundle that could not be placed on its
+    /// ordinary spill weight.
+    fn promote_to_minimal(&mut self, bundle: LiveBundleIndex) {
+        trace!("promoting bundle {:?} to minimal", bundle);
+        let fixed = self.bundles[bundle].cached_fixed();
+        let spill_weight = if fixed {
+            MINIMAL_FIXED_BUNDLE_SPILL_WEIGHT
+        } else if self.spillsets[self.bundles[bundle].spillset]
+            .subclass
+            .is_some()
+            || self.spillsets[self.bundles[bundle].spillset]
+                .pinned
+                .is_some()
+        {
+            MINIMAL_SUBCLASS_BUNDLE_SPILL_WEIGHT
+        } else {
+            MINIMAL_BUNDLE_SPILL_WEIGHT
+        };
+        let data = &mut self.bundles[bundle];
+        data.set_cached_spill_weight_and_props(
+            spill_weight,
+            /* minimal = */ true,
+            fixed,
+            data.cached_fixed_def(),
+            data.cached_stack(),
+        );
+    }
+
     pub fn recompute_range_properties(&mut self, range: LiveRangeIndex) {
         let rangedata = &mut self.ranges[range];
         let mut w = SpillWeight::zero();
@@ -365,7 +733,9 @@ impl<'a, F: Function> Env<'a, F> {
             // create an explicit use or def), and we want to preserve
             // that. We will never split or trim ranges in a way that
             // removes a def at the front and requires the flag to be
-            // cleared.
+            // cleared. Rematerialization relies on this too: a
+            // flagged range is filled by its def and not by a move,
+            // so it is never left without a slot.
             rangedata.set_flag(LiveRangeFlag::StartsAtDef);
         }
     }
@@ -390,6 +760,41 @@ impl<'a, F: Function> Env<'a, F> {
         }
     }
 
+    /// Find a block-entry point at which to split a bundle that spans
+    /// more than `max_len` instructions: the last block entry within
+    /// `max_len` instructions of the bundle start, or failing that
+    /// the first block entry after the start. Returns `None` if the
+    /// bundle is short enough or lies entirely within one block.
+    fn long_bundle_split_point(
+        &self,
+        bundle: LiveBundleIndex,
+        max_len: usize,
+    ) -> Option<ProgPoint> {
+        let bundle_start = self.bundles[bundle].ranges.first().unwrap().range.from;
+        let bundle_end = self.bundles[bundle].ranges.last().unwrap().range.to;
+        let start_inst = bundle_start.inst().index();
+        if bundle_end.inst().index() - start_inst <= max_len {
+            return None;
+        }
+
+        let mut chosen = None;
+        let first_block = self.cfginfo.insn_block[start_inst].index() + 1;
+        for block in first_block..self.func.num_blocks() {
+            let entry = self.cfginfo.block_entry[block];
+            if entry >= bundle_end {
+                break;
+            }
+            if entry.inst().index() - start_inst > max_len && chosen.is_some() {
+                break;
+            }
+            chosen = Some(entry);
+            if entry.inst().index() - start_inst > max_len {
+                break;
+            }
+        }
+        chosen
+    }
+
     pub fn split_and_requeue_bundle(
         &mut self,
         bundle: LiveBundleIndex,
@@ -398,6 +803,9 @@ impl<'a, F: Function> Env<'a, F> {
         // Do we trim the parts around the split and put them in the
         // spill bundle?
         mut trim_ends_into_spill_bundle: bool,
+        // Why, for `Output::spill_reasons`, if any ranges go to the
+        // spill bundle.
+        reason: SpillReason,
     ) {
         self.stats.splits += 1;
         trace!(
@@ -417,12 +825,16 @@ impl<'a, F: Function> Env<'a, F> {
         // to a "minimal bundles and spill bundle" setup for this
         // bundle. See the doc-comment on
         // `split_into_minimal_bundles()` above for more.
-        if self.spillsets[spillset].splits >= MAX_SPLITS_PER_SPILLSET {
-            self.split_into_minimal_bundles(bundle, reg_hint);
+        if self.spillsets[spillset].splits >= self.split_limit(spillset) {
+            self.split_into_minimal_bundles(bundle, reg_hint, SpillReason::SplitBudgetExhausted);
             return;
         }
         self.spillsets[spillset].splits += 1;
 
+        if !self.options.trim_empty_regions {
+            trim_ends_into_spill_bundle = false;
+        }
+
         debug_assert!(!self.bundles[bundle].ranges.is_empty());
         // Split point *at* start is OK; this means we peel off
         // exactly one use to create a minimal bundle.
@@ -481,6 +893,11 @@ impl<'a, F: Function> Env<'a, F> {
 
         debug_assert!(split_at > bundle_start && split_at < bundle_end);
 
+        self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Split {
+            bundle,
+            at: split_at,
+        });
+
         // We need to find which LRs fall on each side of the split,
         // which LR we need to split down the middle, then update the
         // current bundle, create a new one, and (re)-queue both.
@@ -602,6 +1019,9 @@ impl<'a, F: Function> Env<'a, F> {
         }
         self.bundles[new_bundle].ranges = new_lr_list;
 
+        // The ranges moved to the spill bundle below, for the split
+        // event.
+        let mut spilled: SmallVec<[(VRegIndex, LiveRangeListEntry); 4]> = smallvec![];
         if trim_ends_into_spill_bundle {
             // Finally, handle moving LRs to the spill bundle when
             // appropriate: If the first range in `new_bundle` or last
@@ -627,6 +1047,7 @@ impl<'a, F: Function> Env<'a, F> {
                     self.bundles[spill].ranges.push(entry);
                     self.bundles[bundle].ranges.pop();
                     self.ranges[entry.index].bundle = spill;
+                    spilled.push((vreg, entry));
                     continue;
                 }
                 let last_use = last_use.unwrap();
@@ -648,11 +1069,19 @@ impl<'a, F: Function> Env<'a, F> {
                         range,
                         index: empty_lr,
                     });
+                    self.ranges[empty_lr].vreg = vreg;
                     self.ranges[empty_lr].bundle = spill;
                     self.vregs[vreg].ranges.push(LiveRangeListEntry {
                         range,
                         index: empty_lr,
                     });
+                    spilled.push((
+                        vreg,
+                        LiveRangeListEntry {
+                            range,
+                            index: empty_lr,
+                        },
+                    ));
                     trace!(
                         " -> bundle {:?} range {:?}: last use implies split point {:?}",
                         bundle,
@@ -687,6 +1116,7 @@ impl<'a, F: Function> Env<'a, F> {
                     self.bundles[spill].ranges.push(entry);
                     self.bundles[new_bundle].ranges.drain(..1);
                     self.ranges[entry.index].bundle = spill;
+                    spilled.push((vreg, entry));
                     continue;
                 }
                 let first_use = first_use.unwrap();
@@ -713,11 +1143,19 @@ impl<'a, F: Function> Env<'a, F> {
                         range,
                         index: empty_lr,
                     });
+                    self.ranges[empty_lr].vreg = vreg;
                     self.ranges[empty_lr].bundle = spill;
                     self.vregs[vreg].ranges.push(LiveRangeListEntry {
                         range,
                         index: empty_lr,
                     });
+                    spilled.push((
+                        vreg,
+                        LiveRangeListEntry {
+                            range,
+                            index: empty_lr,
+                        },
+                    ));
                     trace!(
                         " -> bundle {:?} range {:?}: first use implies split point {:?}",
                         bundle,
@@ -734,17 +1172,44 @@ impl<'a, F: Function> Env<'a, F> {
             }
         }
 
+        if self.options.collect_spill_reasons {
+            self.spill_reasons.extend(
+                spilled
+                    .iter()
+                    .map(|&(vreg, entry)| (vreg, entry.index, reason)),
+            );
+        }
+
+        if self.options.collect_split_events {
+            let mut pieces: Vec<SplitPiece> = [bundle, new_bundle]
+                .iter()
+                .map(|&piece| self.split_piece(piece))
+                .collect();
+            pieces.push(SplitPiece {
+                bundle: self.spillsets[spillset].spill_bundle.raw_u32(),
+                spill: true,
+                ranges: spilled
+                    .iter()
+                    .map(|&(vreg, entry)| (self.vreg(vreg), entry.range))
+                    .collect(),
+            });
+            pieces.retain(|piece| !piece.ranges.is_empty());
+            self.split_events.push(SplitEvent {
+                bundle: bundle.raw_u32(),
+                at: Some(split_at),
+                pieces,
+            });
+        }
+
         if self.bundles[bundle].ranges.len() > 0 {
             self.recompute_bundle_properties(bundle);
-            let prio = self.bundles[bundle].prio;
-            self.allocation_queue
-                .insert(bundle, prio as usize, reg_hint);
+            let prio = self.queue_prio(bundle);
+            self.allocation_queue.insert(bundle, prio, reg_hint);
         }
         if self.bundles[new_bundle].ranges.len() > 0 {
             self.recompute_bundle_properties(new_bundle);
-            let prio = self.bundles[new_bundle].prio;
-            self.allocation_queue
-                .insert(new_bundle, prio as usize, reg_hint);
+            let prio = self.queue_prio(new_bundle);
+            self.allocation_queue.insert(new_bundle, prio, reg_hint);
         }
     }
 
@@ -778,16 +1243,23 @@ impl<'a, F: Function> Env<'a, F> {
     /// the spill bundle; and then does minimal reservations of
     /// registers just at uses/defs and moves the "spilled" value
     /// into/out of them immediately.
-    pub fn split_into_minimal_bundles(&mut self, bundle: LiveBundleIndex, reg_hint: PReg) {
+    pub fn split_into_minimal_bundles(
+        &mut self,
+        bundle: LiveBundleIndex,
+        reg_hint: PReg,
+        reason: SpillReason,
+    ) {
         let mut removed_lrs: FxHashSet<LiveRangeIndex> = FxHashSet::default();
         let mut removed_lrs_vregs: FxHashSet<VRegIndex> = FxHashSet::default();
         let mut new_lrs: SmallVec<[(VRegIndex, LiveRangeIndex); 16]> = smallvec![];
         let mut new_bundles: SmallVec<[LiveBundleIndex; 16]> = smallvec![];
 
+        self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::SplitMinimal { bundle });
         let spillset = self.bundles[bundle].spillset;
         let spill = self
             .get_or_create_spill_bundle(bundle, /* create_if_absent = */ true)
             .unwrap();
+        let spill_start = self.bundles[spill].ranges.len();
 
         trace!(
             "Splitting bundle {:?} into minimal bundles with reg hint {}",
@@ -975,18 +1447,381 @@ impl<'a, F: Function> Env<'a, F> {
             self.vregs[vreg].ranges.push(entry);
         }
 
+        if self.options.collect_spill_reasons {
+            for entry in &self.bundles[spill].ranges[spill_start..] {
+                let vreg = self.ranges[entry.index].vreg;
+                self.spill_reasons.push((vreg, entry.index, reason));
+            }
+        }
+
+        if self.options.collect_split_events {
+            let mut pieces: Vec<SplitPiece> = new_bundles
+                .iter()
+                .map(|&piece| self.split_piece(piece))
+                .collect();
+            pieces.push(SplitPiece {
+                bundle: spill.raw_u32(),
+                spill: true,
+                ranges: self.bundles[spill].ranges[spill_start..]
+                    .iter()
+                    .map(|entry| (self.vreg(self.ranges[entry.index].vreg), entry.range))
+                    .collect(),
+            });
+            pieces.retain(|piece| !piece.ranges.is_empty());
+            self.split_events.push(SplitEvent {
+                bundle: bundle.raw_u32(),
+                at: None,
+                pieces,
+            });
+        }
+
         // Recompute bundle properties for all new bundles and enqueue
         // them.
         for bundle in new_bundles {
             if self.bundles[bundle].ranges.len() > 0 {
                 self.recompute_bundle_properties(bundle);
-                let prio = self.bundles[bundle].prio;
-                self.allocation_queue
-                    .insert(bundle, prio as usize, reg_hint);
+                let prio = self.queue_prio(bundle);
+                self.allocation_queue.insert(bundle, prio, reg_hint);
+            }
+        }
+    }
+
+    /// `bundle`, which is not a spill bundle, and its ranges, as a
+    /// piece of a `SplitEvent`.
+    fn split_piece(&self, bundle: LiveBundleIndex) -> SplitPiece {
+        SplitPiece {
+            bundle: bundle.raw_u32(),
+            spill: false,
+            ranges: self.bundles[bundle]
+                .ranges
+                .iter()
+                .map(|entry| (self.vreg(self.ranges[entry.index].vreg), entry.range))
+                .collect(),
+        }
+    }
+
+    /// The offset at which to start a register scan, given the
+    /// demand-spreading offset that `RegScanMode::Spread` uses.
+    pub fn reg_scan_offset(&self, spread_offset: usize) -> usize {
+        let offset = match self.options.scan_mode {
+            RegScanMode::Spread => spread_offset,
+            RegScanMode::PackLow => 0,
+        };
+        match self.scan_seed {
+            // Mix the seed with the unperturbed offset (a
+            // splitmix64 finalizer), so that each scan is perturbed
+            // differently.
+            Some(seed) => {
+                let mut z = seed ^ (spread_offset as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                offset.wrapping_add((z ^ (z >> 31)) as usize)
+            }
+            None => offset,
+        }
+    }
+
+    /// The client's order in which to try registers of `class`, if
+    /// it supplies one. Not consulted when the register is fixed.
+    pub fn custom_reg_order(
+        &self,
+        class: RegClass,
+        hint_reg: PReg,
+        fixed: Option<PReg>,
+    ) -> Option<Vec<PReg>> {
+        if fixed.is_some() {
+            return None;
+        }
+        self.options
+            .reg_traversal_order
+            .map(|order| order.candidates(class, hint_reg))
+    }
+
+    /// The registers `bundle` may be allocated to, if it is
+    /// restricted to a subclass of its class.
+    pub fn bundle_subclass_regs(&self, bundle: LiveBundleIndex) -> Option<PRegSet> {
+        let subclass = self.spillsets[self.bundles[bundle].spillset].subclass?;
+        Some(self.env.reg_subclasses[subclass])
+    }
+
+    /// The registers `bundle` must not be allocated to because it
+    /// overlaps a loop that reserves them.
+    pub fn bundle_reserved_regs(&self, bundle: LiveBundleIndex) -> PRegSet {
+        let mut regs = PRegSet::empty();
+        for (loop_range, loop_regs) in &self.loop_reg_reserves {
+            if self.bundles[bundle]
+                .ranges
+                .iter()
+                .any(|entry| entry.range.overlaps(loop_range))
+            {
+                regs.union_from(*loop_regs);
+            }
+        }
+        regs
+    }
+
+    /// The registers clobbered by instructions that `bundle` is live
+    /// across, if `RegallocOptions::prefer_callee_saved_across_calls`
+    /// is set; these are tried last.
+    pub fn bundle_crossed_clobbers(&self, bundle: LiveBundleIndex) -> PRegSet {
+        let mut regs = PRegSet::empty();
+        if !self.options.prefer_callee_saved_across_calls {
+            return regs;
+        }
+        for entry in &self.bundles[bundle].ranges {
+            // Live both before the instruction and at its clobbers.
+            let start = self
+                .clobber_insts
+                .partition_point(|&inst| ProgPoint::after(inst) <= entry.range.from);
+            for &inst in &self.clobber_insts[start..] {
+                if ProgPoint::after(inst) >= entry.range.to {
+                    break;
+                }
+                regs.union_from(self.func.inst_clobbers(inst));
+            }
+        }
+        regs
+    }
+
+    /// The extents of the loops that `bundle` overlaps and some vreg
+    /// of it should stay in a register across.
+    pub fn bundle_resident_loops(&self, bundle: LiveBundleIndex) -> SmallVec<[CodeRange; 2]> {
+        let mut loops = smallvec![];
+        if self.loop_residents.is_empty() {
+            return loops;
+        }
+        for entry in &self.bundles[bundle].ranges {
+            let vreg = self.ranges[entry.index].vreg;
+            for &(resident, _, loop_range) in &self.loop_residents {
+                if resident == vreg && entry.range.overlaps(&loop_range) {
+                    loops.push(loop_range);
+                }
+            }
+        }
+        loops
+    }
+
+    /// Registers to try before the other non-preferred registers:
+    /// those the prologue saves anyway and, when minimizing the
+    /// callee-saved registers used, those already holding a value.
+    pub fn saved_regs(&self) -> PRegSet {
+        let mut saved = self.env.already_saved;
+        if self.options.minimize_callee_saved {
+            saved.union_from(self.allocated_pregs);
+        }
+        saved
+    }
+
+    /// How many times bundles of `spillset` may be split before they
+    /// are broken up into minimal bundles.
+    fn split_limit(&self, spillset: SpillSetIndex) -> u8 {
+        match self.spillsets[spillset].max_splits {
+            Some(max) => core::cmp::min(max, MAX_SPLITS_PER_SPILLSET),
+            None => MAX_SPLITS_PER_SPILLSET,
+        }
+    }
+
+    /// Has `bundle` used up the split budget its values asked for
+    /// through `Function::max_splits`?
+    fn split_budget_exhausted(&self, bundle: LiveBundleIndex) -> bool {
+        let spillset = &self.spillsets[self.bundles[bundle].spillset];
+        matches!(spillset.max_splits, Some(max) if spillset.splits >= max)
+    }
+
+    /// Is `bundle` a member of an allocation group that is being
+    /// spilled as a whole?
+    pub fn in_spilled_group(&self, bundle: LiveBundleIndex) -> bool {
+        let group = self.spillsets[self.bundles[bundle].spillset].group;
+        matches!(group, Some(group) if self.spilled_groups.contains(&group))
+    }
+
+    /// A member of allocation group `group` could not keep its
+    /// register: spill the whole group. Members already in registers
+    /// are evicted, to be split into minimal bundles when they are
+    /// dequeued again.
+    fn spill_allocation_group(&mut self, group: u32) {
+        trace!("spilling allocation group {}", group);
+        self.spilled_groups.insert(group);
+        for bundle in 0..self.bundles.len() {
+            let bundle = LiveBundleIndex::new(bundle);
+            if self.spillsets[self.bundles[bundle].spillset].group == Some(group)
+                && self.bundles[bundle].allocation.is_reg()
+                && !self.unsplittable_bundle(bundle)
+            {
+                self.evict_bundle(bundle);
+            }
+        }
+    }
+
+    /// Scale the cost of a move introduced by a split according to
+    /// the configured objective weights, relative to spill cost.
+    fn weighted_move_cost(&self, move_cost: u32) -> u32 {
+        let w = self.options.objective;
+        if w.spill == w.reg_move {
+            return move_cost;
+        }
+        let scaled = move_cost as f64 * w.reg_move as f64 / w.spill as f64;
+        core::cmp::min(scaled as u64, BUNDLE_MAX_SPILL_WEIGHT as u64) as u32
+    }
+
+    /// The cost by which evicting `evict_cost` worth of bundles from
+    /// the hinted register is ranked against evicting from others.
+    /// Taking the hinted register saves the move the hint was meant
+    /// to avoid, so the more moves weigh relative to spills, the more
+    /// eagerly the allocator evicts there. With equal weights this is
+    /// just `evict_cost`.
+    fn hinted_evict_rank(&self, evict_cost: u32) -> u32 {
+        let w = self.options.objective;
+        if w.spill == w.reg_move {
+            return evict_cost;
+        }
+        let scaled = evict_cost as f64 * w.spill as f64 / w.reg_move as f64;
+        core::cmp::min(scaled as u64, BUNDLE_MAX_SPILL_WEIGHT as u64) as u32
+    }
+
+    /// The cost of the move introduced by splitting at `point`. This
+    /// follows the block's frequency and coldness like a use there.
+    fn split_move_cost(&self, point: ProgPoint) -> u32 {
+        let block = self.cfginfo.insn_block[point.inst().index()];
+        self.weighted_move_cost(
+            self.spill_weight_in_block(OperandConstraint::Reg, block, /* is_def = */ true)
+                .to_int(),
+        )
+    }
+
+    /// May a bundle with spill weight `our_weight` evict a conflict
+    /// set of cost `evict_cost` at all? We never evict a conflict set
+    /// that is at least as heavy as ourselves, whatever the objective
+    /// weights: otherwise two bundles could evict each other forever.
+    /// The commit threshold widens the region in which the incumbents
+    /// are kept.
+    fn may_evict(&self, our_weight: u32, evict_cost: u32) -> bool {
+        our_weight > evict_cost.saturating_add(self.options.commit_threshold)
+    }
+
+    /// Should a bundle with spill weight `our_weight` be split rather
+    /// than evicting a conflict set of cost `evict_cost`, where both
+    /// are allowed? Splitting costs moves and evicting costs spills,
+    /// so each side is scaled by its objective weight. With equal
+    /// weights this is exactly `our_weight <= evict_cost`.
+    fn prefer_split_over_evict(&self, our_weight: u32, evict_cost: u32) -> bool {
+        let w = self.options.objective;
+        our_weight as f64 * w.reg_move as f64 <= evict_cost as f64 * w.spill as f64
+    }
+
+    /// Is `bundle` minimal or fixed to a register somewhere? Such
+    /// bundles are processed first in two-phase mode.
+    fn constrained_bundle(&self, bundle: LiveBundleIndex) -> bool {
+        self.bundles[bundle].cached_minimal() || self.bundles[bundle].cached_fixed()
+    }
+
+    /// Raise every non-minimal bundle that currently holds a register
+    /// to the maximum normal spill weight, so that no other
+    /// non-minimal bundle can evict it. Minimal bundles still can, so
+    /// allocation cannot get stuck. A frozen bundle that is evicted
+    /// anyway gets its weight recomputed when it is split.
+    pub fn freeze_allocated(&mut self) {
+        for bundle in 0..self.bundles.len() {
+            let bundle = LiveBundleIndex::new(bundle);
+            let data = &mut self.bundles[bundle];
+            if data.ranges.is_empty() || !data.allocation.is_reg() || data.cached_minimal() {
+                continue;
             }
+            trace!("freezing bundle {:?} in {}", bundle, data.allocation);
+            data.set_cached_spill_weight_and_props(
+                BUNDLE_MAX_NORMAL_SPILL_WEIGHT,
+                /* minimal = */ false,
+                data.cached_fixed(),
+                data.cached_fixed_def(),
+                data.cached_stack(),
+            );
+            self.stats.frozen_bundles += 1;
         }
     }
 
+    /// Try to allocate `bundle` to a register that is free over its
+    /// whole extent, without evicting anything. Returns whether a
+    /// register was found.
+    pub fn try_allocating_free_reg(&mut self, bundle: LiveBundleIndex, reg_hint: PReg) -> bool {
+        let spillset = self.bundles[bundle].spillset;
+        let class = self.spillsets[spillset].class;
+        let mut hint_reg = if reg_hint != PReg::invalid() {
+            reg_hint
+        } else {
+            self.spillsets[spillset].reg_hint
+        };
+        if self.pregs[hint_reg.index()].is_stack {
+            hint_reg = PReg::invalid();
+        }
+        let scan_offset = self.reg_scan_offset(
+            self.ranges[self.bundles[bundle].ranges[0].index]
+                .range
+                .from
+                .inst()
+                .index()
+                + bundle.index(),
+        );
+        let custom_order = self.custom_reg_order(class, hint_reg, None);
+        for preg in RegTraversalIter::new(
+            self.env,
+            class,
+            hint_reg,
+            PReg::invalid(),
+            scan_offset,
+            None,
+        )
+        .with_order(self.func.reg_preference_order(class))
+        .with_custom_order(custom_order.as_deref())
+        .with_subclass(self.bundle_subclass_regs(bundle))
+        .with_excluded(self.bundle_reserved_regs(bundle))
+        .with_deferred(self.bundle_crossed_clobbers(bundle))
+        .with_already_saved(self.saved_regs())
+        {
+            self.stats.process_bundle_reg_probes_any += 1;
+            let preg_idx = PRegIndex::new(preg.index());
+            if let AllocRegResult::Allocated(alloc) =
+                self.try_to_allocate_bundle_to_reg(bundle, preg_idx, Some(0))
+            {
+                self.stats.process_bundle_reg_success_any += 1;
+                trace!(" -> single pass: allocated {:?} to {:?}", bundle, preg_idx);
+                self.spillsets[spillset].reg_hint = alloc.as_reg().unwrap();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Record why `bundle`'s ranges are going to the spill path, if
+    /// `RegallocOptions::collect_spill_reasons` is set.
+    fn note_spill_reason(&mut self, bundle: LiveBundleIndex, reason: SpillReason) {
+        if !self.options.collect_spill_reasons {
+            return;
+        }
+        for entry in &self.bundles[bundle].ranges {
+            let vreg = self.ranges[entry.index].vreg;
+            self.spill_reasons.push((vreg, entry.index, reason));
+        }
+    }
+
+    /// The reasons recorded for ranges that went to the spill path
+    /// and did not get a register there after all, by vreg, once
+    /// allocation is complete. Ranges replaced by `reallocate_inst`
+    /// no longer belong to a bundle, and are skipped.
+    pub fn final_spill_reasons(&self) -> Vec<(VReg, SpillReason)> {
+        let mut reasons: Vec<(VReg, SpillReason)> = self
+            .spill_reasons
+            .iter()
+            .copied()
+            .filter(|&(_, range, _)| {
+                self.ranges[range].bundle.is_valid() && !self.get_alloc_for_range(range).is_reg()
+            })
+            .map(|(vreg, _, reason)| (self.vreg(vreg), reason))
+            .collect();
+        reasons.sort_unstable();
+        reasons.dedup();
+        reasons
+    }
+
     pub fn process_bundle(
         &mut self,
         bundle: LiveBundleIndex,
@@ -1004,7 +1839,9 @@ impl<'a, F: Function> Env<'a, F> {
         }
         trace!("process_bundle: bundle {:?} hint {:?}", bundle, hint_reg,);
 
-        let req = match self.compute_requirement(bundle) {
+        let req = self.compute_requirement(bundle);
+        self.note_bundle_requirement(bundle, &req);
+        let req = match req {
             Ok(req) => req,
             Err(conflict) => {
                 trace!("conflict!: {:?}", conflict);
@@ -1012,7 +1849,7 @@ impl<'a, F: Function> Env<'a, F> {
                 // split that would allow at least the first half of the
                 // split to be conflict-free.
                 debug_assert!(
-                    !self.minimal_bundle(bundle),
+                    !self.unsplittable_bundle(bundle),
                     "Minimal bundle with conflict!"
                 );
                 self.split_and_requeue_bundle(
@@ -1021,6 +1858,7 @@ impl<'a, F: Function> Env<'a, F> {
                     reg_hint,
                     /* trim_ends_into_spill_bundle = */
                     conflict.should_trim_edges_around_split(),
+                    SpillReason::ConflictingConstraints,
                 );
                 return Ok(());
             }
@@ -1034,6 +1872,8 @@ impl<'a, F: Function> Env<'a, F> {
                 if let Some(spill) =
                     self.get_or_create_spill_bundle(bundle, /* create_if_absent = */ false)
                 {
+                    self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Spilled { bundle });
+                    self.note_spill_reason(bundle, SpillReason::NoUses);
                     let mut list =
                         core::mem::replace(&mut self.bundles[bundle].ranges, smallvec![]);
                     for entry in &list {
@@ -1046,12 +1886,58 @@ impl<'a, F: Function> Env<'a, F> {
             _ => {}
         }
 
-        // Try to allocate!
+        // If the bundle is longer than the configured cap, split it
+        // at a block boundary up front rather than trying to hold a
+        // register across its whole extent.
+        if let Some(max_len) = self.options.max_unsplit_range_length {
+            if matches!(req, Requirement::Register | Requirement::FixedReg(_))
+                && !self.unsplittable_bundle(bundle)
+                && self.spillsets[self.bundles[bundle].spillset].splits
+                    < self.split_limit(self.bundles[bundle].spillset)
+            {
+                if let Some(split_at) = self.long_bundle_split_point(bundle, max_len) {
+                    trace!(
+                        "bundle {:?} exceeds max unsplit length {}; splitting at {:?}",
+                        bundle,
+                        max_len,
+                        split_at
+                    );
+                    self.split_and_requeue_bundle(
+                        bundle,
+                        split_at,
+                        reg_hint,
+                        /* trim_ends_into_spill_bundle = */ true,
+                        SpillReason::RangeTooLong,
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        // Try to allocate! Conflicts found on one attempt are kept
+        // for the next, for the pregs that no eviction touched.
+        self.probe_cache.clear();
+        self.probe_cache.resize(self.pregs.len(), None);
         let mut attempts = 0;
+        // Attempts since the bundle was last promoted to minimal,
+        // which starts it on a fresh round. `max_bundle_attempts`
+        // counts the attempts of every round.
+        let mut round_attempts = 0;
         loop {
             attempts += 1;
+            round_attempts += 1;
             trace!("attempt {}, req {:?}", attempts, req);
-            debug_assert!(attempts < 100 * self.func.num_insts());
+            debug_assert!(round_attempts < 100 * self.func.num_insts());
+            if let Some(max_attempts) = self.options.max_bundle_attempts {
+                if attempts > max_attempts {
+                    trace!("bundle {:?}: out of attempts", bundle);
+                    let vreg = self.ranges[self.bundles[bundle].ranges[0].index].vreg;
+                    return Err(RegAllocError::AllocationBudgetExceeded(
+                        bundle,
+                        self.vreg(vreg),
+                    ));
+                }
+            }
 
             let fixed_preg = match req {
                 Requirement::FixedReg(preg) | Requirement::FixedStack(preg) => Some(preg),
@@ -1060,10 +1946,14 @@ impl<'a, F: Function> Env<'a, F> {
                     // If we must be on the stack, mark our spillset
                     // as required immediately.
                     self.spillsets[self.bundles[bundle].spillset].required = true;
+                    self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Spilled { bundle });
+                    self.note_spill_reason(bundle, SpillReason::StackRequired);
                     return Ok(());
                 }
 
                 Requirement::Any => {
+                    self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Spilled { bundle });
+                    self.note_spill_reason(bundle, SpillReason::NoUses);
                     self.spilled_bundles.push(bundle);
                     return Ok(());
                 }
@@ -1072,6 +1962,7 @@ impl<'a, F: Function> Env<'a, F> {
 
             let mut lowest_cost_evict_conflict_set: Option<LiveBundleVec> = None;
             let mut lowest_cost_evict_conflict_cost: Option<u32> = None;
+            let mut lowest_evict_rank: Option<u32> = None;
 
             let mut lowest_cost_split_conflict_cost: Option<u32> = None;
             let mut lowest_cost_split_conflict_point = ProgPoint::before(Inst::new(0));
@@ -1082,14 +1973,17 @@ impl<'a, F: Function> Env<'a, F> {
             // location in the code and by the bundle we're
             // considering. This has the effect of spreading
             // demand more evenly across registers.
-            let scan_offset = self.ranges[self.bundles[bundle].ranges[0].index]
-                .range
-                .from
-                .inst()
-                .index()
-                + bundle.index();
+            let scan_offset = self.reg_scan_offset(
+                self.ranges[self.bundles[bundle].ranges[0].index]
+                    .range
+                    .from
+                    .inst()
+                    .index()
+                    + bundle.index(),
+            );
 
             self.stats.process_bundle_reg_probe_start_any += 1;
+            let custom_order = self.custom_reg_order(class, hint_reg, fixed_preg);
             for preg in RegTraversalIter::new(
                 self.env,
                 class,
@@ -1097,7 +1991,14 @@ impl<'a, F: Function> Env<'a, F> {
                 PReg::invalid(),
                 scan_offset,
                 fixed_preg,
-            ) {
+            )
+            .with_order(self.func.reg_preference_order(class))
+            .with_custom_order(custom_order.as_deref())
+            .with_subclass(self.bundle_subclass_regs(bundle))
+            .with_excluded(self.bundle_reserved_regs(bundle))
+            .with_deferred(self.bundle_crossed_clobbers(bundle))
+            .with_already_saved(self.saved_regs())
+            {
                 self.stats.process_bundle_reg_probes_any += 1;
                 let preg_idx = PRegIndex::new(preg.index());
                 trace!("trying preg {:?}", preg_idx);
@@ -1108,8 +2009,9 @@ impl<'a, F: Function> Env<'a, F> {
                 ) {
                     (Some(a), Some(b)) => Some(core::cmp::max(a, b)),
                     _ => None,
-                };
-                match self.try_to_allocate_bundle_to_reg(bundle, preg_idx, scan_limit_cost) {
+                }
+                .map(|cost| core::cmp::max(cost, self.options.min_high_cost_threshold));
+                match self.probe_with_cache(bundle, preg_idx, scan_limit_cost) {
                     AllocRegResult::Allocated(alloc) => {
                         self.stats.process_bundle_reg_success_any += 1;
                         trace!(" -> allocated to any {:?}", preg_idx);
@@ -1124,23 +2026,31 @@ impl<'a, F: Function> Env<'a, F> {
                             first_conflict_point
                         );
 
+                        self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Conflict {
+                            bundle,
+                            preg,
+                            point: first_conflict_point,
+                            fixed: false,
+                        });
+
                         let conflict_cost = self.maximum_spill_weight_in_bundle_set(&bundles);
 
-                        if lowest_cost_evict_conflict_cost.is_none()
-                            || conflict_cost < lowest_cost_evict_conflict_cost.unwrap()
-                        {
+                        // The set to evict is chosen with the hinted
+                        // register favored according to the objective
+                        // weights, but whether to evict at all is
+                        // decided on its actual cost.
+                        let evict_rank = if preg == hint_reg {
+                            self.hinted_evict_rank(conflict_cost)
+                        } else {
+                            conflict_cost
+                        };
+                        if lowest_evict_rank.is_none() || evict_rank < lowest_evict_rank.unwrap() {
+                            lowest_evict_rank = Some(evict_rank);
                             lowest_cost_evict_conflict_cost = Some(conflict_cost);
                             lowest_cost_evict_conflict_set = Some(bundles);
                         }
 
-                        let loop_depth = self.cfginfo.approx_loop_depth
-                            [self.cfginfo.insn_block[first_conflict_point.inst().index()].index()];
-                        let move_cost = spill_weight_from_constraint(
-                            OperandConstraint::Reg,
-                            loop_depth as usize,
-                            /* is_def = */ true,
-                        )
-                        .to_int();
+                        let move_cost = self.split_move_cost(first_conflict_point);
                         if lowest_cost_split_conflict_cost.is_none()
                             || (conflict_cost + move_cost)
                                 < lowest_cost_split_conflict_cost.unwrap()
@@ -1153,14 +2063,14 @@ impl<'a, F: Function> Env<'a, F> {
                     AllocRegResult::ConflictWithFixed(max_cost, point) => {
                         trace!(" -> conflict with fixed alloc; cost of other bundles up to point is {}, conflict at {:?}", max_cost, point);
 
-                        let loop_depth = self.cfginfo.approx_loop_depth
-                            [self.cfginfo.insn_block[point.inst().index()].index()];
-                        let move_cost = spill_weight_from_constraint(
-                            OperandConstraint::Reg,
-                            loop_depth as usize,
-                            /* is_def = */ true,
-                        )
-                        .to_int();
+                        self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Conflict {
+                            bundle,
+                            preg,
+                            point,
+                            fixed: true,
+                        });
+
+                        let move_cost = self.split_move_cost(point);
 
                         if lowest_cost_split_conflict_cost.is_none()
                             || (max_cost + move_cost) < lowest_cost_split_conflict_cost.unwrap()
@@ -1209,45 +2119,82 @@ impl<'a, F: Function> Env<'a, F> {
             // the regalloc.rs fuzzer depends on the register
             // allocator to correctly reject impossible-to-allocate
             // programs in order to discard invalid test cases.
-            if self.minimal_bundle(bundle)
-                && (attempts >= 2
+            if self.unsplittable_bundle(bundle)
+                && (round_attempts >= 2
                     || lowest_cost_evict_conflict_cost.is_none()
                     || lowest_cost_evict_conflict_cost.unwrap() >= our_spill_weight)
             {
-                if let Requirement::Register = req {
+                // An unsplittable bundle that is not minimal at the
+                // configured granularity gets another round as one.
+                if !self.minimal_bundle(bundle) {
+                    self.promote_to_minimal(bundle);
+                    round_attempts = 0;
+                    continue;
+                }
+
+                // A pinned bundle can only ever have its own register,
+                // so that is the only one checked.
+                let pinned = self.spillsets[self.bundles[bundle].spillset].pinned;
+                if matches!(req, Requirement::Register) || pinned.is_some() {
                     // Check if this is a too-many-live-registers situation.
                     let range = self.bundles[bundle].ranges[0].range;
                     trace!("checking for too many live regs");
                     let mut min_bundles_assigned = 0;
                     let mut fixed_assigned = 0;
                     let mut total_regs = 0;
+                    let subclass_regs = self.bundle_subclass_regs(bundle);
+                    let reserved_regs = self.bundle_reserved_regs(bundle);
                     for preg in self.env.preferred_regs_by_class[class as u8 as usize]
                         .iter()
                         .chain(self.env.non_preferred_regs_by_class[class as u8 as usize].iter())
+                        .filter(
+                            |&&preg| !matches!(subclass_regs, Some(regs) if !regs.contains(preg)),
+                        )
+                        .filter(|&&preg| !matches!(pinned, Some(pin) if pin != preg))
+                        .filter(|&&preg| !reserved_regs.contains(preg))
                     {
                         trace!(" -> PR {:?}", preg);
                         let start = LiveRangeKey::from_range(&CodeRange {
                             from: range.from.prev(),
                             to: range.from.prev(),
                         });
-                        for (key, lr) in self.pregs[preg.index()].allocations.btree.range(start..) {
-                            let preg_range = key.to_range();
-                            if preg_range.to <= range.from {
-                                continue;
-                            }
-                            if preg_range.from >= range.to {
-                                break;
-                            }
-                            if lr.is_valid() {
-                                if self.minimal_bundle(self.ranges[*lr].bundle) {
-                                    trace!("  -> min bundle {:?}", lr);
-                                    min_bundles_assigned += 1;
+                        // A register is also taken by whatever holds
+                        // a register overlapping it.
+                        for &scan_reg in core::iter::once(preg).chain(self.env.aliases(*preg)) {
+                            let btree = &self.pregs[scan_reg.index()].allocations.btree;
+                            for (key, lr) in btree.range(start..) {
+                                let preg_range = key.to_range();
+                                if preg_range.to <= range.from {
+                                    continue;
+                                }
+                                if preg_range.from >= range.to {
+                                    break;
+                                }
+                                if lr.is_valid() {
+                                    let other = self.ranges[*lr].bundle;
+                                    if self.minimal_bundle(other)
+                                        && self.bundles[other].cached_fixed()
+                                    {
+                                        // The bundle of a fixed-register
+                                        // operand is a fixed demand too.
+                                        trace!("  -> fixed min bundle {:?}", lr);
+                                        fixed_assigned += 1;
+                                    } else if self.minimal_bundle(other) {
+                                        trace!("  -> min bundle {:?}", lr);
+                                        min_bundles_assigned += 1;
+                                    } else if self.spillsets[self.bundles[other].spillset]
+                                        .pinned
+                                        .is_some()
+                                    {
+                                        trace!("  -> pinned bundle {:?}", lr);
+                                        fixed_assigned += 1;
+                                    } else {
+                                        trace!("  -> non-min bundle {:?}", lr);
+                                    }
                                 } else {
-                                    trace!("  -> non-min bundle {:?}", lr);
+                                    trace!("  -> fixed bundle");
+                                    fixed_assigned += 1;
                                 }
-                            } else {
-                                trace!("  -> fixed bundle");
-                                fixed_assigned += 1;
                             }
                         }
                         total_regs += 1;
@@ -1259,13 +2206,48 @@ impl<'a, F: Function> Env<'a, F> {
                         min_bundles_assigned
                     );
                     if min_bundles_assigned + fixed_assigned >= total_regs {
-                        return Err(RegAllocError::TooManyLiveRegs);
+                        return Err(RegAllocError::TooManyLiveRegs {
+                            point: range.from,
+                            class,
+                            fixed: fixed_assigned,
+                            minimal: min_bundles_assigned,
+                            total: total_regs,
+                        });
                     }
                 }
 
                 panic!("Could not allocate minimal bundle, but the allocation problem should be possible to solve");
             }
 
+            // A bundle whose values have used up their split budget
+            // may not be split further: evict the conflicting bundles
+            // if we may (see `may_evict`), and otherwise give up.
+            if !self.unsplittable_bundle(bundle) && self.split_budget_exhausted(bundle) {
+                match (
+                    lowest_cost_evict_conflict_set,
+                    lowest_cost_evict_conflict_cost,
+                ) {
+                    (Some(set), Some(cost)) if self.may_evict(our_spill_weight, cost) => {
+                        trace!(" -> split budget exhausted; evicting {:?}", set);
+                        self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Evict {
+                            bundle,
+                            count: set.len(),
+                        });
+                        self.stats.evict_bundle_event += 1;
+                        for &bundle in &set {
+                            self.invalidate_probes(bundle);
+                            self.evict_bundle(bundle);
+                            self.stats.evict_bundle_count += 1;
+                        }
+                        continue;
+                    }
+                    _ => {
+                        let vreg = self.ranges[self.bundles[bundle].ranges[0].index].vreg;
+                        return Err(RegAllocError::SplitLimitExceeded(self.vreg(vreg)));
+                    }
+                }
+            }
+
             // If our bundle's weight is less than or equal to(*) the
             // evict cost, choose to split.  Also pick splitting if
             // we're on our second or more attempt and we didn't
@@ -1279,50 +2261,134 @@ impl<'a, F: Function> Env<'a, F> {
             // wins, and the other splits.
             //
             // Note that we don't split if the bundle is minimal.
-            if !self.minimal_bundle(bundle)
+            //
+            // The "equal to" rule, widened by the commit threshold,
+            // is checked first (see `may_evict`); where eviction is
+            // allowed, the choice is made under the configured
+            // objective weights (see `prefer_split_over_evict`).
+            if !self.unsplittable_bundle(bundle)
                 && (attempts >= 2
-                    || lowest_cost_evict_conflict_cost.is_none()
-                    || our_spill_weight <= lowest_cost_evict_conflict_cost.unwrap())
+                    || match lowest_cost_evict_conflict_cost {
+                        None => true,
+                        Some(cost) => {
+                            !self.may_evict(our_spill_weight, cost)
+                                || self.prefer_split_over_evict(our_spill_weight, cost)
+                        }
+                    })
             {
                 trace!(
                     " -> deciding to split: our spill weight is {}",
                     self.bundle_spill_weight(bundle)
                 );
+                // With no bundles to evict, only fixed uses or clobbers
+                // were in the way.
+                let reason = match lowest_cost_evict_conflict_cost {
+                    Some(_) => SpillReason::LostEvictions,
+                    None => SpillReason::FixedConflict,
+                };
+
+                // A member of an allocation group takes the rest of
+                // the group down with it.
+                if let Some(group) = self.spillsets[self.bundles[bundle].spillset].group {
+                    self.spill_allocation_group(group);
+                    self.split_into_minimal_bundles(bundle, reg_hint, reason);
+                    return Ok(());
+                }
+
                 let bundle_start = self.bundles[bundle].ranges[0].range.from;
                 let mut split_at_point =
                     core::cmp::max(lowest_cost_split_conflict_point, bundle_start);
                 let requeue_with_reg = lowest_cost_split_conflict_reg;
 
-                // Adjust `split_at_point` if it is within a deeper loop
-                // than the bundle start -- hoist it to just before the
-                // first loop header it encounters.
+                // Adjust `split_at_point` if it is within hotter code
+                // (a deeper loop, without frequencies) than the bundle
+                // start -- hoist it to just before the first hotter
+                // block it encounters.
                 let bundle_start_depth = self.cfginfo.approx_loop_depth
                     [self.cfginfo.insn_block[bundle_start.inst().index()].index()];
-                let split_at_depth = self.cfginfo.approx_loop_depth
-                    [self.cfginfo.insn_block[split_at_point.inst().index()].index()];
-                if split_at_depth > bundle_start_depth {
+                let bundle_start_freq =
+                    self.block_frequency(self.cfginfo.insn_block[bundle_start.inst().index()]);
+                let split_at_freq =
+                    self.block_frequency(self.cfginfo.insn_block[split_at_point.inst().index()]);
+                if split_at_freq > bundle_start_freq {
                     for block in (self.cfginfo.insn_block[bundle_start.inst().index()].index() + 1)
                         ..=self.cfginfo.insn_block[split_at_point.inst().index()].index()
                     {
-                        if self.cfginfo.approx_loop_depth[block] > bundle_start_depth {
+                        if self.block_frequency(Block::new(block)) > bundle_start_freq {
                             split_at_point = self.cfginfo.block_entry[block];
                             break;
                         }
                     }
                 }
 
+                // Likewise, if the split point is in a cold block but
+                // the bundle starts in a hot one, split at the entry
+                // to the first cold block instead, so that the cold
+                // part of the bundle bears the spill.
+                let bundle_start_block = self.cfginfo.insn_block[bundle_start.inst().index()];
+                let split_at_block = self.cfginfo.insn_block[split_at_point.inst().index()];
+                if self.func.is_cold_block(split_at_block)
+                    && !self.func.is_cold_block(bundle_start_block)
+                {
+                    for block in (bundle_start_block.index() + 1)..=split_at_block.index() {
+                        if self.func.is_cold_block(Block::new(block)) {
+                            split_at_point = self.cfginfo.block_entry[block];
+                            break;
+                        }
+                    }
+                }
+
+                // Don't split within a loop that the bundle should
+                // stay in a register across: split at the entry of
+                // the outermost such loop instead, if the bundle
+                // starts before it. Otherwise the request cannot be
+                // honored, and the split goes ahead as planned.
+                if let Some(loop_entry) = self
+                    .bundle_resident_loops(bundle)
+                    .into_iter()
+                    .filter(|loop_range| {
+                        loop_range.contains_point(split_at_point) && bundle_start < loop_range.from
+                    })
+                    .map(|loop_range| loop_range.from)
+                    .min()
+                {
+                    split_at_point = loop_entry;
+                }
+
+                // Give the caller's split hook, if any, a chance to
+                // move the split point, keeping it within the bundle.
+                if let Some(SplitHook(hook)) = self.split_hook.as_mut() {
+                    let bundle_end = self.bundles[bundle].ranges.last().unwrap().range.to;
+                    let proposal = SplitProposal {
+                        bundle_from: bundle_start,
+                        bundle_to: bundle_end,
+                        point: split_at_point,
+                        bundle_from_loop_depth: bundle_start_depth,
+                        point_loop_depth: self.cfginfo.approx_loop_depth
+                            [self.cfginfo.insn_block[split_at_point.inst().index()].index()],
+                    };
+                    split_at_point = hook(proposal).max(bundle_start).min(bundle_end.prev());
+                }
+
                 self.split_and_requeue_bundle(
                     bundle,
                     split_at_point,
                     requeue_with_reg,
                     /* should_trim = */ true,
+                    reason,
                 );
                 return Ok(());
             } else {
                 // Evict all bundles in `conflicting bundles` and try again.
+                let set = lowest_cost_evict_conflict_set.unwrap();
+                self.note_vreg_trace(bundle, |_, bundle| VregTraceEvent::Evict {
+                    bundle,
+                    count: set.len(),
+                });
                 self.stats.evict_bundle_event += 1;
-                for &bundle in &lowest_cost_evict_conflict_set.unwrap() {
+                for &bundle in &set {
                     trace!(" -> evicting {:?}", bundle);
+                    self.invalidate_probes(bundle);
                     self.evict_bundle(bundle);
                     self.stats.evict_bundle_count += 1;
                 }
@@ -0,0 +1,244 @@
+//! Re-allocation after a change to one instruction's operands.
+
+use super::{
+    Edits, Env, LiveBundleIndex, LiveRangeFlag, LiveRangeKey, LiveRangeListEntry, UseList,
+    VRegIndex,
+};
+use crate::{
+    Allocation, Block, CodeRange, Function, Inst, OperandConstraint, OperandKind, OperandPos,
+    ProgPoint, RegAllocError,
+};
+use alloc::vec::Vec;
+use smallvec::SmallVec;
+
+impl<'a, F: Function> Env<'a, F> {
+    /// Redo the allocation after the operand constraints of `inst`
+    /// have changed, re-processing only the bundles of the vregs it
+    /// mentions (and whatever they evict) and then the second chances
+    /// of spilled bundles, and regenerate the moves and other results
+    /// as `resolve_moves` does. See
+    /// `RetainedAllocation::reallocate_inst` for the requirements.
+    pub(crate) fn reallocate_inst(
+        &mut self,
+        func: &'a F,
+        inst: Inst,
+    ) -> Result<Edits, RegAllocError> {
+        self.debug_assert_same_function(func, inst);
+        let old_func = self.func;
+        self.func = func;
+
+        let mut vregs: SmallVec<[VRegIndex; 4]> = SmallVec::new();
+        for op in old_func.inst_operands(inst) {
+            if op.as_fixed_nonallocatable().is_none() && !op.is_unused() {
+                let vreg = VRegIndex::new(op.vreg().vreg());
+                if !vregs.contains(&vreg) {
+                    vregs.push(vreg);
+                }
+            }
+        }
+        trace!("reallocating inst{}: vregs {:?}", inst.index(), vregs);
+
+        // Spillsets of rematerialized vregs were marked as not
+        // needing a slot; undo that where they have spilled ranges,
+        // so that the choice is made again below.
+        let remat: Vec<VRegIndex> = self.remat_vregs.keys().cloned().collect();
+        for vreg in remat {
+            for entry in &self.vregs[vreg].ranges {
+                let bundle = self.ranges[entry.index].bundle;
+                if self.bundles[bundle].allocation.is_none() {
+                    let spillset = self.bundles[bundle].spillset;
+                    self.spillsets[spillset].required = true;
+                }
+            }
+        }
+
+        self.release_spilled_bundles();
+        for &vreg in &vregs {
+            self.reset_vreg_bundle(vreg, inst);
+        }
+
+        self.process_bundles()?;
+        self.try_allocating_regs_for_spilled_bundles();
+        self.allocate_spillslots();
+        self.resolve_moves()
+    }
+
+    /// Take all of the ranges of `vreg` out of their bundles and
+    /// replace them with fresh ones covering the same points, with
+    /// the uses at `inst` updated to the current operands, queued as
+    /// a single bundle in the same spillset.
+    ///
+    /// After splitting into minimal bundles, a vreg's ranges may
+    /// overlap (the spill bundle keeps the whole extent), so they
+    /// cannot simply be regrouped; their union is rebuilt instead.
+    fn reset_vreg_bundle(&mut self, vreg: VRegIndex, inst: Inst) {
+        let spillset = self.bundles[self.ranges[self.vregs[vreg].ranges[0].index].bundle].spillset;
+        let block = self.cfginfo.insn_block[inst.index()];
+
+        let mut extents: SmallVec<[CodeRange; 8]> = SmallVec::new();
+        let mut def_starts: SmallVec<[ProgPoint; 4]> = SmallVec::new();
+        let mut uses: UseList = SmallVec::new();
+        let old_ranges = core::mem::take(&mut self.vregs[vreg].ranges);
+        for entry in &old_ranges {
+            let lr = entry.index;
+            let range = self.ranges[lr].range;
+            let bundle = self.ranges[lr].bundle;
+            if let Some(preg) = self.bundles[bundle].allocation.as_reg() {
+                self.pregs[preg.index()]
+                    .allocations
+                    .btree
+                    .remove(&LiveRangeKey::from_range(&range));
+            }
+            self.bundles[bundle]
+                .ranges
+                .retain(|entry| entry.index != lr);
+            if self.bundles[bundle].ranges.is_empty() {
+                self.bundles[bundle].allocation = Allocation::none();
+            } else {
+                self.recompute_bundle_properties(bundle);
+            }
+
+            extents.push(range);
+            if self.ranges[lr].has_flag(LiveRangeFlag::StartsAtDef) {
+                def_starts.push(range.from);
+            }
+            uses.extend(self.ranges[lr].uses.drain(..));
+            self.ranges[lr].vreg = VRegIndex::invalid();
+            self.ranges[lr].bundle = LiveBundleIndex::invalid();
+        }
+
+        for u in uses.iter_mut() {
+            if u.pos.inst() != inst {
+                continue;
+            }
+            let operand = self.func.inst_operands(inst)[u.slot as usize];
+            let is_def = operand.kind() != OperandKind::Use;
+            let weight = self.spill_weight_in_block(operand.constraint(), block, is_def);
+            u.operand = operand;
+            u.weight = weight.to_bits();
+        }
+        uses.sort_unstable_by_key(|u| u.pos);
+
+        extents.sort_unstable_by_key(|range| range.from);
+        let mut merged: SmallVec<[CodeRange; 8]> = SmallVec::new();
+        for range in extents {
+            match merged.last_mut() {
+                Some(last) if range.from <= last.to => last.to = core::cmp::max(last.to, range.to),
+                _ => merged.push(range),
+            }
+        }
+
+        let bundle = self.bundles.add();
+        let mut uses = uses.into_iter().peekable();
+        for range in merged {
+            let lr = self.ranges.add(range);
+            self.ranges[lr].vreg = vreg;
+            self.ranges[lr].bundle = bundle;
+            while let Some(u) = uses.next_if(|u| range.contains_point(u.pos)) {
+                self.ranges[lr].uses.push(u);
+            }
+            if def_starts.contains(&range.from) {
+                self.ranges[lr].set_flag(LiveRangeFlag::StartsAtDef);
+            }
+            self.recompute_range_properties(lr);
+            let entry = LiveRangeListEntry { range, index: lr };
+            self.vregs[vreg].ranges.push(entry);
+            self.bundles[bundle].ranges.push(entry);
+        }
+        debug_assert!(uses.next().is_none());
+
+        self.bundles[bundle].spillset = spillset;
+        self.bundles[bundle].prio = self.compute_bundle_prio(bundle);
+        self.recompute_bundle_properties(bundle);
+        let hint = self.spillsets[spillset].reg_hint;
+        let prio = self.queue_prio(bundle);
+        self.allocation_queue.insert(bundle, prio, hint);
+    }
+
+    /// Free the registers that bundles got in their second chance,
+    /// so that they get it again after the others are processed (a
+    /// spill bundle must not be evicted and processed like the rest).
+    fn release_spilled_bundles(&mut self) {
+        for i in 0..self.spilled_bundles.len() {
+            let bundle = self.spilled_bundles[i];
+            let preg = match self.bundles[bundle].allocation.as_reg() {
+                Some(preg) => preg,
+                None => continue,
+            };
+            for entry in &self.bundles[bundle].ranges {
+                self.pregs[preg.index()]
+                    .allocations
+                    .btree
+                    .remove(&LiveRangeKey::from_range(&entry.range));
+            }
+            self.bundles[bundle].allocation = Allocation::none();
+        }
+    }
+
+    fn debug_assert_same_function(&self, func: &F, inst: Inst) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let old = self.func;
+        assert_eq!(func.num_insts(), old.num_insts());
+        assert_eq!(func.num_vregs(), old.num_vregs());
+        assert_eq!(func.num_blocks(), old.num_blocks());
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let (insns, old_insns) = (func.block_insns(block), old.block_insns(block));
+            assert_eq!(
+                (insns.first(), insns.len()),
+                (old_insns.first(), old_insns.len())
+            );
+            assert_eq!(func.block_succs(block), old.block_succs(block));
+            assert_eq!(func.block_preds(block), old.block_preds(block));
+            assert_eq!(func.block_params(block), old.block_params(block));
+        }
+        assert_eq!(func.inst_clobbers(inst), old.inst_clobbers(inst));
+        assert_eq!(
+            func.inst_forbidden_pregs(inst),
+            old.inst_forbidden_pregs(inst)
+        );
+
+        let ops = func.inst_operands(inst);
+        let old_ops = old.inst_operands(inst);
+        assert_eq!(ops.len(), old_ops.len());
+        for (op, old_op) in ops.iter().zip(old_ops) {
+            assert_eq!(op.vreg(), old_op.vreg());
+            assert_eq!(op.kind(), old_op.kind());
+            assert_eq!(op.pos(), old_op.pos());
+            assert_eq!(
+                op.as_fixed_nonallocatable(),
+                old_op.as_fixed_nonallocatable()
+            );
+        }
+        assert!(!self
+            .multi_fixed_reg_fixups
+            .iter()
+            .any(|fixup| fixup.pos.inst() == inst));
+        for &op in ops.iter().chain(old_ops) {
+            assert!(!matches!(
+                op.constraint(),
+                OperandConstraint::Reuse(_)
+                    | OperandConstraint::ReuseEither(..)
+                    | OperandConstraint::Tied(_)
+            ));
+        }
+        for (i, op) in ops.iter().enumerate() {
+            let preg = match op.constraint() {
+                OperandConstraint::FixedReg(preg) => preg,
+                _ => continue,
+            };
+            assert!(ops
+                .iter()
+                .enumerate()
+                .all(|(j, other)| j == i || other.vreg() != op.vreg()));
+            if op.kind() == OperandKind::Use && op.pos() == OperandPos::Early {
+                assert!(!func.inst_clobbers(inst).contains(preg));
+                assert!(!ops.iter().any(|other| other.kind() == OperandKind::Def
+                    && other.pos() == OperandPos::Late
+                    && other.constraint() == op.constraint()));
+            }
+        }
+    }
+}
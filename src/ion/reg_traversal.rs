@@ -1,4 +1,4 @@
-use crate::{MachineEnv, PReg, RegClass};
+use crate::{MachineEnv, PReg, PRegSet, RegClass};
 
 /// This iterator represents a traversal through all allocatable
 /// registers of a given class, in a certain order designed to
@@ -13,18 +13,38 @@ use crate::{MachineEnv, PReg, RegClass};
 ///   prferred registers; then, non-preferred registers. (In normal
 ///   usage, these consist of caller-save and callee-save registers
 ///   respectively, to minimize clobber-saves; but they need not.)
+///   Non-preferred registers the prologue saves anyway (see
+///   `MachineEnv::already_saved`, or `with_already_saved`) are tried
+///   before the rest.
+/// - If the function supplies its own register preference order,
+///   that order replaces both groups and is scanned from the start,
+///   without any offset.
+/// - If a custom order is given (`RegallocOptions::reg_traversal_order`),
+///   it replaces all of the above, hints included.
+/// - If the value is restricted to a register subclass, registers
+///   (including hints) outside it are skipped.
+/// - Excluded registers (e.g. those reserved within a loop the value
+///   is live in) are skipped likewise.
+/// - Deferred registers (e.g. those clobbered by calls the value is
+///   live across) are skipped in a first traversal as above, and
+///   then tried in a second one.
 
 pub struct RegTraversalIter<'a> {
-    env: &'a MachineEnv,
-    class: usize,
+    preferred: &'a [PReg],
+    non_preferred: &'a [PReg],
     hints: [Option<PReg>; 2],
     hint_idx: usize,
     pref_idx: usize,
     non_pref_idx: usize,
     offset_pref: usize,
     offset_non_pref: usize,
+    already_saved: PRegSet,
     is_fixed: bool,
     fixed: Option<PReg>,
+    subclass: Option<PRegSet>,
+    excluded: PRegSet,
+    deferred: PRegSet,
+    second_pass: bool,
 }
 
 impl<'a> RegTraversalIter<'a> {
@@ -53,28 +73,105 @@ impl<'a> RegTraversalIter<'a> {
         }
         let hints = [hint_reg, hint2_reg];
         let class = class as u8 as usize;
-        let offset_pref = if env.preferred_regs_by_class[class].len() > 0 {
-            offset % env.preferred_regs_by_class[class].len()
+        let preferred = &env.preferred_regs_by_class[class][..];
+        let non_preferred = &env.non_preferred_regs_by_class[class][..];
+        let offset_pref = if preferred.len() > 0 {
+            offset % preferred.len()
         } else {
             0
         };
-        let offset_non_pref = if env.non_preferred_regs_by_class[class].len() > 0 {
-            offset % env.non_preferred_regs_by_class[class].len()
+        let offset_non_pref = if non_preferred.len() > 0 {
+            offset % non_preferred.len()
         } else {
             0
         };
         Self {
-            env,
-            class,
+            preferred,
+            non_preferred,
             hints,
             hint_idx: 0,
             pref_idx: 0,
-            non_pref_idx: 0,
+            // With nothing already saved, skip straight to the second
+            // pass over the non-preferred registers.
+            non_pref_idx: if env.already_saved == PRegSet::empty() {
+                non_preferred.len()
+            } else {
+                0
+            },
             offset_pref,
             offset_non_pref,
+            already_saved: env.already_saved,
             is_fixed: fixed.is_some(),
             fixed,
+            subclass: None,
+            excluded: PRegSet::empty(),
+            deferred: PRegSet::empty(),
+            second_pass: false,
+        }
+    }
+
+    /// Replace the `MachineEnv`'s register order with `order`, if
+    /// provided; see `Function::reg_preference_order`.
+    pub fn with_order(mut self, order: Option<&'a [PReg]>) -> Self {
+        if let Some(order) = order {
+            self.preferred = order;
+            self.non_preferred = &[];
+            self.offset_pref = 0;
+            self.offset_non_pref = 0;
+        }
+        self
+    }
+
+    /// Try exactly the registers in `order`, in that order, if
+    /// provided; see `RegallocOptions::reg_traversal_order`. A fixed
+    /// register is still the only one yielded.
+    pub fn with_custom_order(mut self, order: Option<&'a [PReg]>) -> Self {
+        if let Some(order) = order {
+            self.preferred = order;
+            self.non_preferred = &[];
+            self.offset_pref = 0;
+            self.offset_non_pref = 0;
+            self.non_pref_idx = 0;
+            self.hints = [None, None];
         }
+        self
+    }
+
+    /// Treat `saved` as the set of non-preferred registers the
+    /// prologue saves anyway, in place of the `MachineEnv`'s.
+    pub fn with_already_saved(mut self, saved: PRegSet) -> Self {
+        self.already_saved = saved;
+        if self.non_pref_idx == self.non_preferred.len() && saved != PRegSet::empty() {
+            self.non_pref_idx = 0;
+        }
+        self
+    }
+
+    /// Only yield registers in `subclass`, if provided; see
+    /// `Function::reg_subclass`. A fixed register is still yielded.
+    pub fn with_subclass(mut self, subclass: Option<PRegSet>) -> Self {
+        self.subclass = subclass;
+        self
+    }
+
+    /// Never yield registers in `excluded`, other than a fixed
+    /// register.
+    pub fn with_excluded(mut self, excluded: PRegSet) -> Self {
+        self.excluded = excluded;
+        self
+    }
+
+    /// Try registers in `deferred` only after all the others, other
+    /// than a fixed register.
+    pub fn with_deferred(mut self, deferred: PRegSet) -> Self {
+        self.deferred = deferred;
+        self
+    }
+
+    fn is_allowed(&self, reg: PReg) -> bool {
+        !self.excluded.contains(reg)
+            && !matches!(self.subclass, Some(regs) if !regs.contains(reg))
+            && self.deferred.contains(reg) == self.second_pass
     }
 }
 
@@ -95,29 +192,267 @@ impl<'a> core::iter::Iterator for RegTraversalIter<'a> {
                 idx
             }
         }
-        if self.hint_idx < 2 && self.hints[self.hint_idx].is_some() {
+        while self.hint_idx < 2 && self.hints[self.hint_idx].is_some() {
             let h = self.hints[self.hint_idx];
             self.hint_idx += 1;
+            if !self.is_allowed(h.unwrap()) {
+                continue;
+            }
             return h;
         }
-        while self.pref_idx < self.env.preferred_regs_by_class[self.class].len() {
-            let arr = &self.env.preferred_regs_by_class[self.class][..];
+        while self.pref_idx < self.preferred.len() {
+            let arr = self.preferred;
             let r = arr[wrap(self.pref_idx + self.offset_pref, arr.len())];
             self.pref_idx += 1;
-            if Some(r) == self.hints[0] || Some(r) == self.hints[1] {
+            if Some(r) == self.hints[0] || Some(r) == self.hints[1] || !self.is_allowed(r) {
                 continue;
             }
             return Some(r);
         }
-        while self.non_pref_idx < self.env.non_preferred_regs_by_class[self.class].len() {
-            let arr = &self.env.non_preferred_regs_by_class[self.class][..];
-            let r = arr[wrap(self.non_pref_idx + self.offset_non_pref, arr.len())];
+        // Two passes over the non-preferred registers: first those
+        // already saved, then the rest.
+        while self.non_pref_idx < 2 * self.non_preferred.len() {
+            let arr = self.non_preferred;
+            let first_pass = self.non_pref_idx < arr.len();
+            let r = arr[wrap(
+                self.non_pref_idx % arr.len() + self.offset_non_pref,
+                arr.len(),
+            )];
             self.non_pref_idx += 1;
-            if Some(r) == self.hints[0] || Some(r) == self.hints[1] {
+            if self.already_saved.contains(r) != first_pass
+                || Some(r) == self.hints[0]
+                || Some(r) == self.hints[1]
+                || !self.is_allowed(r)
+            {
                 continue;
             }
             return Some(r);
         }
+        if !self.second_pass && self.deferred != PRegSet::empty() {
+            self.second_pass = true;
+            self.hint_idx = 0;
+            self.pref_idx = 0;
+            self.non_pref_idx = if self.already_saved == PRegSet::empty() {
+                self.non_preferred.len()
+            } else {
+                0
+            };
+            return self.next();
+        }
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn preference_order_overrides_env() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        // Callee-saved registers are preferred by the environment...
+        let env = MachineEnv {
+            preferred_regs_by_class: [vec![r(4), r(5)], vec![], vec![]],
+            non_preferred_regs_by_class: [vec![r(0), r(1)], vec![], vec![]],
+            scratch_by_class: [None, None, None],
+            fixed_stack_slots: vec![],
+            reg_aliases: vec![],
+            reg_subclasses: vec![],
+            already_saved: PRegSet::empty(),
+        };
+        let default: Vec<PReg> = RegTraversalIter::new(
+            &env,
+            RegClass::Int,
+            PReg::invalid(),
+            PReg::invalid(),
+            0,
+            None,
+        )
+        .collect();
+        assert_eq!(default, vec![r(4), r(5), r(0), r(1)]);
+
+        // ...but a leaf function fills the caller-saved ones first.
+        let leaf_order = [r(0), r(1), r(4), r(5)];
+        let leaf: Vec<PReg> = RegTraversalIter::new(
+            &env,
+            RegClass::Int,
+            PReg::invalid(),
+            PReg::invalid(),
+            1,
+            None,
+        )
+        .with_order(Some(&leaf_order[..]))
+        .collect();
+        assert_eq!(leaf, leaf_order.to_vec());
+
+        // A hint still comes first, and is not repeated.
+        let hinted: Vec<PReg> =
+            RegTraversalIter::new(&env, RegClass::Int, r(4), PReg::invalid(), 0, None)
+                .with_order(Some(&leaf_order[..]))
+                .collect();
+        assert_eq!(hinted, vec![r(4), r(0), r(1), r(5)]);
+    }
+
+    #[test]
+    fn subclass_restricts_traversal() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let env = MachineEnv {
+            preferred_regs_by_class: [vec![r(0), r(1), r(2), r(3)], vec![], vec![]],
+            non_preferred_regs_by_class: [vec![r(4), r(5)], vec![], vec![]],
+            scratch_by_class: [None, None, None],
+            fixed_stack_slots: vec![],
+            reg_aliases: vec![],
+            reg_subclasses: vec![],
+            already_saved: PRegSet::empty(),
+        };
+        let mut addressing = PRegSet::empty();
+        for i in [1, 3, 5] {
+            addressing.add(r(i));
+        }
+
+        // A hint outside the subclass is skipped, one inside is kept.
+        let regs: Vec<PReg> = RegTraversalIter::new(&env, RegClass::Int, r(0), r(5), 0, None)
+            .with_subclass(Some(addressing))
+            .collect();
+        assert_eq!(regs, vec![r(5), r(1), r(3)]);
+
+        // A fixed register is yielded regardless.
+        let regs: Vec<PReg> = RegTraversalIter::new(
+            &env,
+            RegClass::Int,
+            PReg::invalid(),
+            PReg::invalid(),
+            0,
+            Some(r(2)),
+        )
+        .with_subclass(Some(addressing))
+        .collect();
+        assert_eq!(regs, vec![r(2)]);
+    }
+
+    #[test]
+    fn custom_order_replaces_scan() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let env = MachineEnv {
+            preferred_regs_by_class: [vec![r(0), r(1), r(2)], vec![], vec![]],
+            non_preferred_regs_by_class: [vec![r(3), r(4)], vec![], vec![]],
+            scratch_by_class: [None, None, None],
+            fixed_stack_slots: vec![],
+            reg_aliases: vec![],
+            reg_subclasses: vec![],
+            already_saved: PRegSet::empty(),
+        };
+        let custom = [r(4), r(2), r(0)];
+        let mut excluded = PRegSet::empty();
+        excluded.add(r(2));
+
+        // Neither the hint nor the offset applies, and excluded
+        // registers are still skipped.
+        let regs: Vec<PReg> = RegTraversalIter::new(&env, RegClass::Int, r(1), r(3), 2, None)
+            .with_custom_order(Some(&custom[..]))
+            .with_excluded(excluded)
+            .collect();
+        assert_eq!(regs, vec![r(4), r(0)]);
+
+        // A fixed register is yielded alone.
+        let regs: Vec<PReg> = RegTraversalIter::new(
+            &env,
+            RegClass::Int,
+            PReg::invalid(),
+            PReg::invalid(),
+            0,
+            Some(r(1)),
+        )
+        .with_custom_order(Some(&custom[..]))
+        .collect();
+        assert_eq!(regs, vec![r(1)]);
+    }
+
+    #[test]
+    fn deferred_regs_come_last() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let env = MachineEnv {
+            preferred_regs_by_class: [vec![r(0), r(1), r(2)], vec![], vec![]],
+            non_preferred_regs_by_class: [vec![r(3), r(4)], vec![], vec![]],
+            scratch_by_class: [None, None, None],
+            fixed_stack_slots: vec![],
+            reg_aliases: vec![],
+            reg_subclasses: vec![],
+            already_saved: PRegSet::empty(),
+        };
+        let mut clobbered = PRegSet::empty();
+        clobbered.add(r(0));
+        clobbered.add(r(1));
+
+        // A deferred hint is deferred too, but still leads the
+        // deferred registers.
+        let regs: Vec<PReg> =
+            RegTraversalIter::new(&env, RegClass::Int, r(1), PReg::invalid(), 0, None)
+                .with_deferred(clobbered)
+                .collect();
+        assert_eq!(regs, vec![r(2), r(3), r(4), r(1), r(0)]);
+
+        // A fixed register is yielded alone.
+        let regs: Vec<PReg> = RegTraversalIter::new(
+            &env,
+            RegClass::Int,
+            PReg::invalid(),
+            PReg::invalid(),
+            0,
+            Some(r(0)),
+        )
+        .with_deferred(clobbered)
+        .collect();
+        assert_eq!(regs, vec![r(0)]);
+    }
+
+    #[test]
+    fn already_saved_regs_come_first() {
+        let r = |i| PReg::new(i, RegClass::Int);
+        let mut env = MachineEnv {
+            preferred_regs_by_class: [vec![r(0), r(1)], vec![], vec![]],
+            non_preferred_regs_by_class: [vec![r(2), r(3), r(4), r(5)], vec![], vec![]],
+            scratch_by_class: [None, None, None],
+            fixed_stack_slots: vec![],
+            reg_aliases: vec![],
+            reg_subclasses: vec![],
+            already_saved: PRegSet::empty(),
+        };
+        env.already_saved.add(r(3));
+        env.already_saved.add(r(5));
+
+        let regs: Vec<PReg> = RegTraversalIter::new(
+            &env,
+            RegClass::Int,
+            PReg::invalid(),
+            PReg::invalid(),
+            0,
+            None,
+        )
+        .collect();
+        assert_eq!(regs, vec![r(0), r(1), r(3), r(5), r(2), r(4)]);
+
+        // The scan offset still applies within each group, and a
+        // hint is neither overridden nor repeated.
+        let regs: Vec<PReg> =
+            RegTraversalIter::new(&env, RegClass::Int, r(2), PReg::invalid(), 1, None).collect();
+        assert_eq!(regs, vec![r(2), r(1), r(0), r(3), r(5), r(4)]);
+
+        // The set can be replaced, including when the environment's
+        // is empty.
+        env.already_saved = PRegSet::empty();
+        let regs: Vec<PReg> = RegTraversalIter::new(
+            &env,
+            RegClass::Int,
+            PReg::invalid(),
+            PReg::invalid(),
+            0,
+            None,
+        )
+        .with_already_saved(PRegSet::empty().with(r(4)))
+        .collect();
+        assert_eq!(regs, vec![r(0), r(1), r(4), r(2), r(3), r(5)]);
+    }
+}
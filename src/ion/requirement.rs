@@ -12,8 +12,12 @@
 
 //! Requirements computation.
 
-use super::{Env, LiveBundleIndex};
-use crate::{Function, Inst, Operand, OperandConstraint, PReg, ProgPoint};
+use super::{CodeRange, Env, LiveBundleIndex, VregTraceEvent};
+use crate::{Function, Inst, Operand, OperandConstraint, PReg, PRegSet, ProgPoint, VReg};
+use alloc::vec::Vec;
+
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
 
 pub struct RequirementConflict;
 
@@ -56,12 +60,20 @@ impl RequirementConflictAt {
     }
 }
 
+/// The combined constraint of all the uses in a bundle.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub enum Requirement {
+    /// Must be in this physical register.
     FixedReg(PReg),
+    /// Must be in this fixed stack slot (a `PReg` from
+    /// `MachineEnv::fixed_stack_slots`).
     FixedStack(PReg),
+    /// Must be in some register.
     Register,
+    /// Must be in a spillslot.
     Stack,
+    /// May be anywhere.
     Any,
 }
 impl Requirement {
@@ -115,25 +127,93 @@ impl<'a, F: Function> Env<'a, F> {
                     Requirement::FixedReg(preg)
                 }
             }
-            OperandConstraint::Reg | OperandConstraint::Reuse(_) => Requirement::Register,
+            OperandConstraint::Reg
+            | OperandConstraint::Reuse(_)
+            | OperandConstraint::ReuseEither(..)
+            | OperandConstraint::Tied(_) => Requirement::Register,
             OperandConstraint::Stack => Requirement::Stack,
             OperandConstraint::Any => Requirement::Any,
         }
     }
 
+    /// Record the outcome of `compute_requirement` for `bundle` if
+    /// `RegallocOptions::collect_bundle_requirements` is set, and in
+    /// the vreg trace if it holds the traced vreg.
+    pub fn note_bundle_requirement(
+        &mut self,
+        bundle: LiveBundleIndex,
+        req: &Result<Requirement, RequirementConflictAt>,
+    ) {
+        let requirement = req.map_err(|conflict| conflict.suggested_split_point());
+        self.note_vreg_trace(bundle, |this, id| VregTraceEvent::Process {
+            bundle: id,
+            range: CodeRange {
+                from: this.bundles[bundle].ranges.first().unwrap().range.from,
+                to: this.bundles[bundle].ranges.last().unwrap().range.to,
+            },
+            requirement,
+        });
+        if !self.options.collect_bundle_requirements {
+            return;
+        }
+        let mut vregs: Vec<VReg> = self.bundles[bundle]
+            .ranges
+            .iter()
+            .map(|entry| self.vreg(self.ranges[entry.index].vreg))
+            .collect();
+        vregs.sort_unstable();
+        vregs.dedup();
+        let req = req.map_err(|conflict| conflict.suggested_split_point());
+        self.bundle_requirements.push((vregs, req));
+    }
+
     pub fn compute_requirement(
         &self,
         bundle: LiveBundleIndex,
     ) -> Result<Requirement, RequirementConflictAt> {
-        let mut req = Requirement::Any;
+        // A pinned vreg requires its register throughout.
+        let mut req = match self.spillsets[self.bundles[bundle].spillset].pinned {
+            Some(preg) if self.pregs[preg.index()].is_stack => Requirement::FixedStack(preg),
+            Some(preg) => Requirement::FixedReg(preg),
+            None => Requirement::Any,
+        };
         let mut last_pos = ProgPoint::before(Inst::new(0));
         trace!("compute_requirement: {:?}", bundle);
+        // A fixed register outside the bundle's subclass, or one kept
+        // free by a loop the bundle overlaps, must not be shared with
+        // any other use, so that those other uses can still be given
+        // a register the bundle may use.
+        let subclass_regs = self.bundle_subclass_regs(bundle);
+        let reserved_regs = self.bundle_reserved_regs(bundle);
+        let restricted = subclass_regs.is_some() || reserved_regs != PRegSet::empty();
+        let mut seen_fixed_outside = false;
+        let mut seen_other = false;
         let ranges = &self.bundles[bundle].ranges;
         for entry in ranges {
             trace!(" -> LR {:?}: {:?}", entry.index, entry.range);
             for u in &self.ranges[entry.index].uses {
                 trace!("  -> use {:?}", u);
                 let r = self.requirement_from_operand(u.operand);
+                if restricted {
+                    match r {
+                        Requirement::FixedReg(preg) if reserved_regs.contains(preg) => {
+                            // The bundle must not hold this register
+                            // anywhere in the loop, even without
+                            // other uses; split off the fixed use.
+                            seen_fixed_outside = true;
+                            seen_other = true;
+                        }
+                        Requirement::FixedReg(preg) if matches!(subclass_regs, Some(regs) if !regs.contains(preg)) =>
+                        {
+                            seen_fixed_outside = true;
+                        }
+                        _ => seen_other = true,
+                    }
+                    if seen_fixed_outside && seen_other && !self.unsplittable_bundle(bundle) {
+                        trace!("     -> fixed register not otherwise allowed; conflict");
+                        return Err(RequirementConflictAt::Other(u.pos));
+                    }
+                }
                 req = req.merge(r).map_err(|_| {
                     trace!("     -> conflict");
                     if req.is_stack() && r.is_reg() {
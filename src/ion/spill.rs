@@ -13,10 +13,17 @@
 //! Spillslot allocation.
 
 use super::{
-    AllocRegResult, Env, LiveRangeKey, PReg, PRegIndex, RegTraversalIter, SpillSetIndex,
-    SpillSlotData, SpillSlotIndex,
+    AllocRegResult, CodeRange, Env, LiveRangeFlag, LiveRangeKey, PReg, PRegIndex, RegTraversalIter,
+    SpillSetIndex, SpillSlotData, SpillSlotIndex, VRegIndex,
 };
-use crate::{ion::data_structures::SpillSetRanges, Allocation, Function, SpillSlot};
+use crate::{
+    ion::data_structures::SpillSetRanges, Allocation, Block, Function, FxHashMap, FxHashSet, Inst,
+    OperandConstraint, OperandKind, PRegSet, ReftypeSpillPolicy, RegClass, RematInfo, SpillRegion,
+    SpillSlot, VReg,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use smallvec::SmallVec;
 
 impl<'a, F: Function> Env<'a, F> {
     pub fn try_allocating_regs_for_spilled_bundles(&mut self) {
@@ -37,10 +44,25 @@ impl<'a, F: Function> Env<'a, F> {
                 .ranges
                 .sort_unstable_by_key(|entry| entry.range.from);
 
+            // Spilled allocation groups stay spilled.
+            if self.in_spilled_group(bundle) {
+                self.spillsets[self.bundles[bundle].spillset].required = true;
+                continue;
+            }
+
             let mut success = false;
             self.stats.spill_bundle_reg_probes += 1;
+            let scan_offset = self.reg_scan_offset(bundle.index());
+            let pinned = self.spillsets[self.bundles[bundle].spillset].pinned;
+            let custom_order = self.custom_reg_order(class, hint, pinned);
             for preg in
-                RegTraversalIter::new(self.env, class, hint, PReg::invalid(), bundle.index(), None)
+                RegTraversalIter::new(self.env, class, hint, PReg::invalid(), scan_offset, pinned)
+                    .with_order(self.func.reg_preference_order(class))
+                    .with_custom_order(custom_order.as_deref())
+                    .with_subclass(self.bundle_subclass_regs(bundle))
+                    .with_excluded(self.bundle_reserved_regs(bundle))
+                    .with_deferred(self.bundle_crossed_clobbers(bundle))
+                    .with_already_saved(self.saved_regs())
             {
                 trace!("trying bundle {:?} to preg {:?}", bundle, preg);
                 let preg_idx = PRegIndex::new(preg.index());
@@ -68,10 +90,10 @@ impl<'a, F: Function> Env<'a, F> {
         spillslot: SpillSlotIndex,
         spillset: SpillSetIndex,
     ) -> bool {
-        !self.spillslots[spillslot.index()]
-            .ranges
-            .btree
-            .contains_key(&LiveRangeKey::from_range(&self.spillsets[spillset].range))
+        let slot_ranges = &self.spillslots[spillslot.index()].ranges.btree;
+        !self.spillset_ranges[spillset.index()]
+            .iter()
+            .any(|range| slot_ranges.contains_key(&LiveRangeKey::from_range(range)))
     }
 
     pub fn allocate_spillset_to_spillslot(
@@ -81,31 +103,270 @@ impl<'a, F: Function> Env<'a, F> {
     ) {
         self.spillsets[spillset].slot = spillslot;
 
-        let res = self.spillslots[spillslot.index()].ranges.btree.insert(
-            LiveRangeKey::from_range(&self.spillsets[spillset].range),
-            spillset,
-        );
+        for range in &self.spillset_ranges[spillset.index()] {
+            let res = self.spillslots[spillslot.index()]
+                .ranges
+                .btree
+                .insert(LiveRangeKey::from_range(range), spillset);
+            debug_assert!(res.is_none());
+        }
+    }
+
+    /// Collect the live ranges of every vreg in each spillset. A
+    /// spillslot is reserved for all of these, so that a vreg's slot
+    /// still holds its value after a reload; two spillsets share a
+    /// slot only if none of their ranges overlap.
+    fn compute_spillset_ranges(&mut self) {
+        let mut spillset_ranges: Vec<SmallVec<[CodeRange; 4]>> =
+            vec![SmallVec::new(); self.spillsets.len()];
+        for vreg in self.vregs.iter() {
+            for entry in &vreg.ranges {
+                let bundle = self.ranges[entry.index].bundle;
+                if bundle.is_valid() {
+                    spillset_ranges[self.bundles[bundle].spillset.index()].push(entry.range);
+                }
+            }
+        }
+        // A spillset may hold several vregs; merge their ranges
+        // wherever they touch.
+        for ranges in &mut spillset_ranges {
+            ranges.sort_unstable_by_key(|range| range.from);
+            let mut merged: SmallVec<[CodeRange; 4]> = SmallVec::new();
+            for &range in ranges.iter() {
+                match merged.last_mut() {
+                    Some(last) if range.from <= last.to => last.to = last.to.max(range.to),
+                    _ => merged.push(range),
+                }
+            }
+            *ranges = merged;
+        }
+        self.spillset_ranges = spillset_ranges;
+    }
+
+    /// The spillset holding `vreg`, if it has any live ranges.
+    fn vreg_spillset(&self, vreg: VReg) -> Option<SpillSetIndex> {
+        let entry = self.vregs[VRegIndex::new(vreg.vreg())].ranges.first()?;
+        Some(self.bundles[self.ranges[entry.index].bundle].spillset)
+    }
+
+    /// Try to put `spillset` in the slot of a spillset it has a stack
+    /// affinity with. Returns whether it was placed.
+    fn try_allocating_spillslot_by_affinity(
+        &mut self,
+        spillset: SpillSetIndex,
+        partners: &[SpillSetIndex],
+    ) -> bool {
+        for &partner in partners {
+            let spillslot = self.spillsets[partner].slot;
+            if !spillslot.is_valid()
+                || self.spillsets[partner].class != self.spillsets[spillset].class
+                || self.spillsets[partner].region != self.spillsets[spillset].region
+            {
+                continue;
+            }
+            if self.spillslot_can_fit_spillset(spillslot, spillset) {
+                trace!(" -> sharing spillslot with affine spillset {:?}", partner);
+                self.allocate_spillset_to_spillslot(spillset, spillslot);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Find the vregs that `Function::is_rematerializable` allows to
+    /// be recomputed rather than reloaded, with their defining
+    /// instructions. Whether each one actually is depends on how it
+    /// is spilled; see `select_rematerialized_spillsets`.
+    pub fn compute_remat_candidates(&mut self) {
+        let mut candidates: FxHashMap<VRegIndex, Inst> = FxHashMap::default();
+        let mut excluded: FxHashSet<VRegIndex> = FxHashSet::default();
+        for block in 0..self.func.num_blocks() {
+            let block = Block::new(block);
+            for &param in self.func.block_params(block) {
+                excluded.insert(VRegIndex::new(param.vreg()));
+            }
+            let last = self.func.block_insns(block).last();
+            if self.func.is_branch(last) {
+                for i in 0..self.func.block_succs(block).len() {
+                    for &arg in self.func.branch_blockparams(block, last, i) {
+                        excluded.insert(VRegIndex::new(arg.vreg()));
+                    }
+                }
+            }
+        }
+        for &(vreg, _) in self.func.entry_live_regs() {
+            excluded.insert(VRegIndex::new(vreg.vreg()));
+        }
+        for &vreg in self.func.reftype_vregs() {
+            excluded.insert(VRegIndex::new(vreg.vreg()));
+        }
+
+        for inst in 0..self.func.num_insts() {
+            let inst = Inst::new(inst);
+            let remat = self.func.is_rematerializable(inst);
+            for op in self.func.inst_operands(inst) {
+                if op.kind() != OperandKind::Def {
+                    continue;
+                }
+                let vreg = VRegIndex::new(op.vreg().vreg());
+                // A vreg with more than one def has no single
+                // instruction recomputing it.
+                if candidates.remove(&vreg).is_some() || excluded.contains(&vreg) {
+                    excluded.insert(vreg);
+                    continue;
+                }
+                if remat == Some(RematInfo { vreg: op.vreg() }) && self.can_rematerialize(inst) {
+                    candidates.insert(vreg, inst);
+                } else {
+                    excluded.insert(vreg);
+                }
+            }
+        }
+
+        trace!("remat candidates: {:?}", candidates);
+        self.remat_vregs = candidates;
+    }
+
+    /// Does `inst` do nothing but define one vreg in a register,
+    /// so that it can be executed again anywhere?
+    fn can_rematerialize(&self, inst: Inst) -> bool {
+        let operands = self.func.inst_operands(inst);
+        operands.len() == 1
+            && matches!(
+                operands[0].constraint(),
+                OperandConstraint::Reg | OperandConstraint::Any
+            )
+            && self.func.inst_clobbers(inst) == PRegSet::empty()
+            && self.func.inst_forbidden_pregs(inst) == PRegSet::empty()
+            && !self.func.spill_all_at(inst)
+            && !self.func.is_branch(inst)
+            && !self.func.is_ret(inst)
+            && !self.func.requires_refs_on_stack(inst)
+    }
+
+    /// Decide which spilled values are rematerialized: those whose
+    /// spillsets would hold nothing but candidate vregs (see
+    /// `compute_remat_candidates`) between their uses. Such a
+    /// spillset gets no slot, its spilled ranges no allocation, and
+    /// every move out of them becomes an `Edit::Remat`.
+    ///
+    /// A spilled range with a use (including one starting at its
+    /// def, which holds the def) keeps its value in the slot, and so
+    /// does one flagged `StartsAtDef`: no move ever fills it, so
+    /// only a def could. A vreg is rematerialized only if every one
+    /// of its spilled ranges is, as there is nothing to store into a
+    /// slot from a range that is nowhere, and it must never be in a
+    /// stack register either.
+    fn select_rematerialized_spillsets(&mut self) {
+        if self.remat_vregs.is_empty() {
+            return;
+        }
+        let mut vregs: FxHashSet<VRegIndex> = self.remat_vregs.keys().cloned().collect();
+        // Go by each vreg's list of ranges: the empty ranges split
+        // off into spill bundles do not record their vreg.
+        let mut spillsets: FxHashMap<SpillSetIndex, SmallVec<[VRegIndex; 2]>> =
+            FxHashMap::default();
+        for vreg in 0..self.vregs.len() {
+            let vreg = VRegIndex::new(vreg);
+            for entry in &self.vregs[vreg].ranges {
+                let range = &self.ranges[entry.index];
+                let bundle = &self.bundles[range.bundle];
+                if let Some(preg) = bundle.allocation.as_reg() {
+                    if self.pregs[preg.index()].is_stack {
+                        vregs.remove(&vreg);
+                    }
+                    continue;
+                }
+                if bundle.allocation.is_some() || !self.spillsets[bundle.spillset].required {
+                    continue;
+                }
+                if !range.uses.is_empty() || range.has_flag(LiveRangeFlag::StartsAtDef) {
+                    vregs.remove(&vreg);
+                }
+                spillsets.entry(bundle.spillset).or_default().push(vreg);
+            }
+        }
+
+        // Rule out spillsets holding any vreg ruled out, and then the
+        // vregs in them, until nothing changes.
+        loop {
+            let mut changed = false;
+            spillsets.retain(|_, members| {
+                if members.iter().all(|vreg| vregs.contains(vreg)) {
+                    return true;
+                }
+                for vreg in members.iter() {
+                    changed |= vregs.remove(vreg);
+                }
+                false
+            });
+            if !changed {
+                break;
+            }
+        }
 
-        debug_assert!(res.is_none());
+        for &spillset in spillsets.keys() {
+            trace!("spillset {:?} is rematerialized", spillset);
+            self.spillsets[spillset].required = false;
+        }
+        self.remat_vregs.retain(|vreg, _| vregs.contains(vreg));
+        trace!("rematerialized vregs: {:?}", self.remat_vregs);
     }
 
     pub fn allocate_spillslots(&mut self) {
         const MAX_ATTEMPTS: usize = 10;
 
+        let mut affinities: FxHashMap<SpillSetIndex, SmallVec<[SpillSetIndex; 2]>> =
+            FxHashMap::default();
+        for &(a, b) in self.func.stack_affinities() {
+            if let (Some(a), Some(b)) = (self.vreg_spillset(a), self.vreg_spillset(b)) {
+                if a != b {
+                    affinities.entry(a).or_default().push(b);
+                    affinities.entry(b).or_default().push(a);
+                }
+            }
+        }
+
+        // Slot-backed references need a slot wherever they are live
+        // at a safepoint, even if they never otherwise spill.
+        if self.options.reftype_spill_policy == ReftypeSpillPolicy::AlwaysBackedBySlot {
+            for &vreg in self.func.reftype_vregs() {
+                for entry in &self.vregs[VRegIndex::new(vreg.vreg())].ranges {
+                    let range = self.ranges[entry.index].range;
+                    if !self.safepoints_in(range).is_empty() {
+                        let spillset = self.bundles[self.ranges[entry.index].bundle].spillset;
+                        self.spillsets[spillset].required = true;
+                    }
+                }
+            }
+        }
+
+        self.select_rematerialized_spillsets();
+        self.compute_spillset_ranges();
+
         for spillset in 0..self.spillsets.len() {
             trace!("allocate spillslot: {}", spillset);
             let spillset = SpillSetIndex::new(spillset);
-            if !self.spillsets[spillset].required {
+            // Spillsets placed by an earlier call keep their slots.
+            if !self.spillsets[spillset].required || self.spillsets[spillset].slot.is_valid() {
                 continue;
             }
+            if let Some(partners) = affinities.get(&spillset) {
+                if self.try_allocating_spillslot_by_affinity(spillset, partners) {
+                    continue;
+                }
+            }
             let class = self.spillsets[spillset].class as usize;
+            let region = self.spillsets[spillset].region;
             // Try a few existing spillslots.
-            let mut i = self.slots_by_class[class].probe_start;
+            let mut i = self.slots_by_region_and_class[region as usize][class].probe_start;
             let mut success = false;
             // Never probe the same element more than once: limit the
             // attempt count to the number of slots in existence.
-            for _attempt in 0..core::cmp::min(self.slots_by_class[class].slots.len(), MAX_ATTEMPTS)
-            {
+            let num_slots = self.slots_by_region_and_class[region as usize][class]
+                .slots
+                .len();
+            for _attempt in 0..core::cmp::min(num_slots, MAX_ATTEMPTS) {
                 // Note: this indexing of `slots` is always valid
                 // because either the `slots` list is empty and the
                 // iteration limit above consequently means we don't
@@ -113,16 +374,16 @@ impl<'a, F: Function> Env<'a, F> {
                 // in-bounds (because it is made so below when we add
                 // a slot, and it always takes on the last index `i`
                 // after this loop).
-                let spillslot = self.slots_by_class[class].slots[i];
+                let spillslot = self.slots_by_region_and_class[region as usize][class].slots[i];
 
                 if self.spillslot_can_fit_spillset(spillslot, spillset) {
                     self.allocate_spillset_to_spillslot(spillset, spillslot);
                     success = true;
-                    self.slots_by_class[class].probe_start = i;
+                    self.slots_by_region_and_class[region as usize][class].probe_start = i;
                     break;
                 }
 
-                i = self.slots_by_class[class].next_index(i);
+                i = self.slots_by_region_and_class[region as usize][class].next_index(i);
             }
 
             if !success {
@@ -132,24 +393,49 @@ impl<'a, F: Function> Env<'a, F> {
                     ranges: SpillSetRanges::new(),
                     alloc: Allocation::none(),
                     slots: self.func.spillslot_size(self.spillsets[spillset].class) as u32,
+                    region,
+                    class: self.spillsets[spillset].class,
                 });
-                self.slots_by_class[class].slots.push(spillslot);
-                self.slots_by_class[class].probe_start = self.slots_by_class[class].slots.len() - 1;
+                let slots = &mut self.slots_by_region_and_class[region as usize][class];
+                slots.slots.push(spillslot);
+                slots.probe_start = slots.slots.len() - 1;
 
                 self.allocate_spillset_to_spillslot(spillset, spillslot);
             }
         }
 
-        // Assign actual slot indices to spillslots.
-        for i in 0..self.spillslots.len() {
-            self.spillslots[i].alloc = self.allocate_spillslot(self.spillslots[i].slots);
+        // Assign actual slot indices to spillslots, keeping the slots
+        // of each class together within their region. (Slots are
+        // only ever shared within a class; see above.)
+        for region in 0..self.slots_by_region_and_class.len() {
+            for class in 0..3 {
+                for j in 0..self.slots_by_region_and_class[region][class].slots.len() {
+                    let i = self.slots_by_region_and_class[region][class].slots[j].index();
+                    if !self.spillslots[i].alloc.is_none() {
+                        continue;
+                    }
+                    self.spillslots[i].alloc = self.allocate_spillslot_in_region(
+                        self.spillslots[i].class,
+                        self.spillslots[i].region,
+                    );
+                }
+            }
         }
 
         trace!("spillslot allocator done");
     }
 
-    pub fn allocate_spillslot(&mut self, size: u32) -> Allocation {
-        let mut offset = self.num_spillslots;
+    pub fn allocate_spillslot(&mut self, class: RegClass) -> Allocation {
+        self.allocate_spillslot_in_region(class, SpillRegion::Frame)
+    }
+
+    pub fn allocate_spillslot_in_region(
+        &mut self,
+        class: RegClass,
+        region: SpillRegion,
+    ) -> Allocation {
+        let size = self.func.spillslot_size(class) as u32;
+        let mut offset = self.num_spillslots_by_region[region as usize];
         // Align up to `size`.
         debug_assert!(size.is_power_of_two());
         offset = (offset + size - 1) & !(size - 1);
@@ -159,7 +445,9 @@ impl<'a, F: Function> Env<'a, F> {
             offset
         };
         offset += size;
-        self.num_spillslots = offset;
-        Allocation::stack(SpillSlot::new(slot as usize))
+        self.num_spillslots_by_region[region as usize] = offset;
+        let slot = SpillSlot::new(slot as usize).with_region(region);
+        self.spillslot_classes.push((slot, class));
+        Allocation::stack(slot)
     }
 }
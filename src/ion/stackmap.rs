@@ -12,18 +12,21 @@
 
 //! Stackmap computation.
 
+use alloc::vec;
 use alloc::vec::Vec;
 
 use super::{Env, ProgPoint, VRegIndex};
-use crate::{ion::data_structures::u64_key, Function};
+use crate::{
+    ion::data_structures::u64_key, Allocation, Function, Inst, ReftypeSpillPolicy, RegAllocError,
+};
 
 impl<'a, F: Function> Env<'a, F> {
-    pub fn compute_stackmaps(&mut self) {
+    pub fn compute_stackmaps(&mut self) -> Result<(), RegAllocError> {
         // For each ref-typed vreg, iterate through ranges and find
-        // safepoints in-range. Add the SpillSlot to the stackmap.
+        // safepoints in-range. Add the allocation to the stackmap.
 
         if self.func.reftype_vregs().is_empty() {
-            return;
+            return Ok(());
         }
 
         // Given `safepoints_per_vreg` from the liveness computation,
@@ -34,46 +37,117 @@ impl<'a, F: Function> Env<'a, F> {
 
         trace!("safepoints_per_vreg = {:?}", self.safepoints_per_vreg);
 
+        let policy = self.options.reftype_spill_policy;
+
         for vreg in self.func.reftype_vregs() {
             trace!("generating safepoint info for vreg {}", vreg);
             let vreg = VRegIndex::new(vreg.vreg());
-            let mut safepoints: Vec<ProgPoint> = self
-                .safepoints_per_vreg
-                .get(&vreg.index())
-                .unwrap()
+            // Liveness recorded the safepoints of every ref it knew
+            // of. One it did not know of (the function gave different
+            // refs then) may be live over any of them, so no stackmap
+            // can be trusted.
+            let live_over = match self.safepoints_per_vreg.get(&vreg.index()) {
+                Some(live_over) => live_over,
+                None => {
+                    return Err(RegAllocError::RefNotOnStackAtSafepoint(
+                        self.vreg(vreg),
+                        Inst::invalid(),
+                    ))
+                }
+            };
+            let mut safepoints: Vec<ProgPoint> = live_over
                 .iter()
                 .map(|&inst| ProgPoint::before(inst))
                 .collect();
             safepoints.sort_unstable();
             trace!(" -> live over safepoints: {:?}", safepoints);
+            // The stack location the ref is in at each safepoint, to
+            // check that it is in one at all of them when it must be,
+            // and in no more than one.
+            let mut on_stack: Vec<Option<Allocation>> = vec![None; safepoints.len()];
 
-            let mut safepoint_idx = 0;
             for entry in &self.vregs[vreg].ranges {
                 let range = entry.range;
                 let alloc = self.get_alloc_for_range(entry.index);
-
-                if !alloc.as_stack().is_some() {
+                // A range of a spill bundle that never needed a slot
+                // holds the value nowhere; there is nothing to report.
+                if alloc.is_none() {
                     continue;
                 }
 
-                trace!(" -> range {:?}: alloc {}", range, alloc);
-                while safepoint_idx < safepoints.len() && safepoints[safepoint_idx] < range.to {
-                    if safepoints[safepoint_idx] < range.from {
-                        safepoint_idx += 1;
-                        continue;
+                // Unless references may stay in registers, only
+                // stack allocations are reported; a fixed stack-slot
+                // preg is one. Slot-backed references in registers
+                // also report their slot, which was stored to just
+                // before the safepoint.
+                let in_reg =
+                    matches!(alloc.as_reg(), Some(preg) if !self.pregs[preg.index()].is_stack);
+                let backing_slot = match policy {
+                    ReftypeSpillPolicy::StackOnly if in_reg => continue,
+                    ReftypeSpillPolicy::AlwaysBackedBySlot if in_reg => {
+                        let spillset = self.bundles[self.ranges[entry.index].bundle].spillset;
+                        let slot = self.spillsets[spillset].slot;
+                        if slot.is_valid() {
+                            Some(self.spillslots[slot.index()].alloc)
+                        } else {
+                            None
+                        }
                     }
+                    _ => None,
+                };
 
+                // Ranges may overlap (e.g. a register copy for a
+                // fixed-register use alongside the spillslot), so each
+                // one finds its own first safepoint.
+                trace!(" -> range {:?}: alloc {}", range, alloc);
+                let mut safepoint_idx = safepoints.partition_point(|&pos| pos < range.from);
+                while safepoint_idx < safepoints.len() && safepoints[safepoint_idx] < range.to {
                     trace!("    -> covers safepoint {:?}", safepoints[safepoint_idx]);
 
-                    self.safepoint_slots
-                        .push((safepoints[safepoint_idx], alloc));
+                    // Overlapping ranges in the same slot may both reach
+                    // a safepoint; the slot is reported once. A copy in
+                    // a fixed stack-slot preg (for a fixed use) may be
+                    // live alongside the spillslot; both are reported.
+                    for alloc in core::iter::once(alloc).chain(backing_slot) {
+                        let is_stack = match alloc.as_reg() {
+                            Some(preg) => self.pregs[preg.index()].is_stack,
+                            None => alloc.is_stack(),
+                        };
+                        if is_stack {
+                            match on_stack[safepoint_idx] {
+                                Some(slot) if slot == alloc => continue,
+                                Some(slot) => {
+                                    debug_assert!(
+                                        slot.is_reg() || alloc.is_reg(),
+                                        "{} in two spillslots at {:?}",
+                                        self.vreg(vreg),
+                                        safepoints[safepoint_idx]
+                                    );
+                                }
+                                None => on_stack[safepoint_idx] = Some(alloc),
+                            }
+                        }
+                        self.safepoint_slots
+                            .push((safepoints[safepoint_idx], alloc));
+                    }
                     safepoint_idx += 1;
                 }
             }
+
+            if policy == ReftypeSpillPolicy::StackOnly {
+                if let Some(idx) = on_stack.iter().position(|on_stack| on_stack.is_none()) {
+                    return Err(RegAllocError::RefNotOnStackAtSafepoint(
+                        self.vreg(vreg),
+                        safepoints[idx].inst(),
+                    ));
+                }
+            }
         }
 
         self.safepoint_slots
             .sort_unstable_by_key(|(progpoint, slot)| u64_key(progpoint.to_index(), slot.bits()));
+        self.safepoint_slots.dedup();
         trace!("final safepoint slots info: {:?}", self.safepoint_slots);
+        Ok(())
     }
 }
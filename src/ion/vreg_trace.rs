@@ -0,0 +1,164 @@
+//! Allocation trace of a single vreg.
+
+use super::{Env, LiveBundleIndex, Requirement, VRegIndex};
+use crate::{Allocation, CodeRange, Function, PReg, ProgPoint, VReg};
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "enable-serde")]
+use serde::{Deserialize, Serialize};
+
+/// One allocation decision about a bundle holding (some of) the live
+/// ranges of the vreg given by `RegallocOptions::trace_vreg`. Bundles
+/// are identified by number; the numbers mean nothing outside the
+/// trace, but are stable within it, and the bundles made by a split
+/// get new ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum VregTraceEvent {
+    /// The bundle, spanning `range`, was taken from the queue. Its
+    /// uses require `requirement`, or conflict at the given point.
+    Process {
+        bundle: u32,
+        range: CodeRange,
+        requirement: Result<Requirement, ProgPoint>,
+    },
+    /// `preg` was probed for the bundle but is taken from `point`
+    /// on, by other bundles or (if `fixed`) by a fixed reservation.
+    Conflict {
+        bundle: u32,
+        preg: PReg,
+        point: ProgPoint,
+        fixed: bool,
+    },
+    /// The bundle was given `preg`.
+    Allocated { bundle: u32, preg: PReg },
+    /// The bundle evicted `count` other bundles to make room.
+    Evict { bundle: u32, count: usize },
+    /// The bundle lost its register to another bundle and was
+    /// requeued.
+    Evicted { bundle: u32 },
+    /// The bundle was split at `at`.
+    Split { bundle: u32, at: ProgPoint },
+    /// The bundle was broken up into minimal bundles around its
+    /// uses, with the rest going to its spill bundle.
+    SplitMinimal { bundle: u32 },
+    /// The bundle was left for the stack (or, if it has no uses
+    /// needing a register, a later second-chance register).
+    Spilled { bundle: u32 },
+}
+
+/// The allocation of one vreg, as recorded when
+/// `RegallocOptions::trace_vreg` is set: every decision made about
+/// the bundles holding its live ranges, in order, and where each of
+/// its live ranges ended up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct VregTrace {
+    /// The traced vreg.
+    pub vreg: VReg,
+    /// Decisions, in the order they were made.
+    pub events: Vec<VregTraceEvent>,
+    /// The final location of each live range, in program order.
+    pub placement: Vec<(CodeRange, Allocation)>,
+}
+
+impl fmt::Display for VregTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:", self.vreg)?;
+        for event in &self.events {
+            match *event {
+                VregTraceEvent::Process {
+                    bundle,
+                    range,
+                    requirement,
+                } => {
+                    write!(
+                        f,
+                        "  bundle{}: process {:?}..{:?}, ",
+                        bundle, range.from, range.to
+                    )?;
+                    match requirement {
+                        Ok(req) => writeln!(f, "requires {:?}", req)?,
+                        Err(point) => writeln!(f, "conflicting uses at {:?}", point)?,
+                    }
+                }
+                VregTraceEvent::Conflict {
+                    bundle,
+                    preg,
+                    point,
+                    fixed,
+                } => writeln!(
+                    f,
+                    "  bundle{}: {} taken at {:?}{}",
+                    bundle,
+                    preg,
+                    point,
+                    if fixed { " (fixed)" } else { "" }
+                )?,
+                VregTraceEvent::Allocated { bundle, preg } => {
+                    writeln!(f, "  bundle{}: allocated {}", bundle, preg)?
+                }
+                VregTraceEvent::Evict { bundle, count } => {
+                    writeln!(f, "  bundle{}: evicts {} bundle(s)", bundle, count)?
+                }
+                VregTraceEvent::Evicted { bundle } => writeln!(f, "  bundle{}: evicted", bundle)?,
+                VregTraceEvent::Split { bundle, at } => {
+                    writeln!(f, "  bundle{}: split at {:?}", bundle, at)?
+                }
+                VregTraceEvent::SplitMinimal { bundle } => {
+                    writeln!(f, "  bundle{}: split into minimal bundles", bundle)?
+                }
+                VregTraceEvent::Spilled { bundle } => writeln!(f, "  bundle{}: spilled", bundle)?,
+            }
+        }
+        for &(range, alloc) in &self.placement {
+            writeln!(f, "  {:?}..{:?}: {}", range.from, range.to, alloc)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, F: Function> Env<'a, F> {
+    /// Does `bundle` hold a live range of the traced vreg?
+    fn traces_bundle(&self, bundle: LiveBundleIndex) -> bool {
+        let vreg = match self.trace_vreg {
+            Some(vreg) => vreg,
+            None => return false,
+        };
+        let vreg = VRegIndex::new(vreg.vreg());
+        self.bundles[bundle]
+            .ranges
+            .iter()
+            .any(|entry| self.ranges[entry.index].vreg == vreg)
+    }
+
+    /// If `bundle` holds a live range of the traced vreg, record the
+    /// event built by `event` from the bundle's number.
+    pub fn note_vreg_trace(
+        &mut self,
+        bundle: LiveBundleIndex,
+        event: impl FnOnce(&Self, u32) -> VregTraceEvent,
+    ) {
+        if self.traces_bundle(bundle) {
+            let event = event(self, bundle.raw_u32());
+            self.vreg_trace_events.push(event);
+        }
+    }
+
+    /// Assemble the trace of the traced vreg, if any, once
+    /// allocation is complete.
+    pub fn vreg_trace(&self) -> Option<VregTrace> {
+        let vreg = self.trace_vreg?;
+        let placement = self.vregs[VRegIndex::new(vreg.vreg())]
+            .ranges
+            .iter()
+            .map(|entry| (entry.range, self.get_alloc_for_range(entry.index)))
+            .collect();
+        Some(VregTrace {
+            vreg,
+            events: self.vreg_trace_events.clone(),
+            placement,
+        })
+    }
+}
@@ -46,12 +46,16 @@ pub(crate) mod ion;
 pub(crate) mod moves;
 pub(crate) mod postorder;
 pub mod ssa;
+pub mod stability;
 
 #[macro_use]
 mod index;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 pub use index::{Block, Inst, InstRange};
+pub use ion::data_structures::LiveBundleIndex;
+pub use ion::{CodeRange, Requirement, RetainedAllocation, Stats, VregTrace, VregTraceEvent};
 
 pub mod checker;
 
@@ -61,6 +65,9 @@ pub mod fuzzing;
 #[cfg(feature = "enable-serde")]
 pub mod serialize;
 
+#[cfg(test)]
+mod tests;
+
 #[cfg(feature = "enable-serde")]
 use serde::{Deserialize, Serialize};
 
@@ -363,6 +370,9 @@ impl core::fmt::Display for VReg {
 /// The allocator is responsible for allocating indices in this space,
 /// and will specify how many spillslots have been used when the
 /// allocation is completed.
+///
+/// A spillslot may also live in a storage region other than the
+/// frame (see `SpillRegion`); each region has its own index space.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct SpillSlot {
@@ -380,16 +390,35 @@ impl SpillSlot {
         SpillSlot { bits: slot as u32 }
     }
 
-    /// Get the spillslot index for this spillslot.
+    /// Get the spillslot index for this spillslot, within its region.
     #[inline(always)]
     pub fn index(self) -> usize {
         (self.bits & 0x00ffffff) as usize
     }
 
-    /// Get the spillslot `offset` slots away.
+    /// Get the same spillslot index in the given region.
+    #[inline(always)]
+    pub fn with_region(self, region: SpillRegion) -> Self {
+        SpillSlot {
+            bits: (self.bits & 0x00ffffff) | ((region as u32) << 24),
+        }
+    }
+
+    /// Get the storage region this spillslot lives in.
+    #[inline(always)]
+    pub fn region(self) -> SpillRegion {
+        match (self.bits >> 24) & 3 {
+            1 => SpillRegion::Tls,
+            2 => SpillRegion::Custom,
+            // 3 only occurs in the invalid spillslot.
+            _ => SpillRegion::Frame,
+        }
+    }
+
+    /// Get the spillslot `offset` slots away, in the same region.
     #[inline(always)]
     pub fn plus(self, offset: usize) -> Self {
-        SpillSlot::new(self.index() + offset)
+        SpillSlot::new(self.index() + offset).with_region(self.region())
     }
 
     /// Get the invalid spillslot, used for initializing data structures.
@@ -413,7 +442,33 @@ impl SpillSlot {
 
 impl core::fmt::Display for SpillSlot {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        write!(f, "stack{}", self.index())
+        match self.region() {
+            SpillRegion::Frame => write!(f, "stack{}", self.index()),
+            SpillRegion::Tls => write!(f, "tls{}", self.index()),
+            SpillRegion::Custom => write!(f, "custom{}", self.index()),
+        }
+    }
+}
+
+/// The storage region a spillslot lives in, which tells the client
+/// which addressing mode to use for spills and fills. The allocator
+/// keeps a separate index space for each region; see
+/// `Function::spill_region` and `Output::num_spillslots_by_region`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum SpillRegion {
+    /// The stack frame (the default).
+    Frame = 0,
+    /// Thread-local storage.
+    Tls = 1,
+    /// A client-defined region.
+    Custom = 2,
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for SpillRegion {
+    fn default() -> Self {
+        SpillRegion::Frame
     }
 }
 
@@ -440,6 +495,22 @@ pub enum OperandConstraint {
     FixedReg(PReg),
     /// On defs only: reuse a use's register.
     Reuse(usize),
+    /// On defs only: reuse the register of either of two uses, for
+    /// commutative two-address instructions. The allocator picks one,
+    /// preferring an input that is dead after the instruction; the
+    /// reused input is the one whose allocation equals the def's.
+    /// Both indices must be at most 3.
+    ReuseEither(usize, usize),
+    /// In a register shared by every operand of the instruction tied
+    /// to the same group (at most 8 groups, numbered from 0), for
+    /// instructions where more than two operands must share one, such
+    /// as accumulating forms that also read their accumulator as a
+    /// plain input. The uses of a group must be of a single vreg, at
+    /// the early position, as one register cannot hold two values;
+    /// otherwise allocation fails with `RegAllocError::TiedOperands`.
+    /// A group may have one def, at the late position, which then
+    /// reuses their register as with `Reuse`.
+    Tied(usize),
 }
 
 impl core::fmt::Display for OperandConstraint {
@@ -450,6 +521,8 @@ impl core::fmt::Display for OperandConstraint {
             Self::Stack => write!(f, "stack"),
             Self::FixedReg(preg) => write!(f, "fixed({})", preg),
             Self::Reuse(idx) => write!(f, "reuse({})", idx),
+            Self::ReuseEither(a, b) => write!(f, "reuse({}|{})", a, b),
+            Self::Tied(group) => write!(f, "tied({})", group),
         }
     }
 }
@@ -510,6 +583,20 @@ pub enum OperandPos {
 /// make those input(s) "late uses" or those output(s) "early defs" so
 /// that the conflict (overlap) is properly accounted for. See
 /// comments on the constructors below for more.
+///
+/// An allocation always names a whole physical register, and a def
+/// claims that entire register for the defined vreg's live range.
+/// Narrow registers that are parts of wider ones are described with
+/// `MachineEnv::reg_aliases`; a def into a narrow register then
+/// excludes other values from the wide registers containing it, but
+/// not from the other narrow parts of those. If the machine instead
+/// writes the whole enclosing register (e.g. a 32-bit write on
+/// x86-64, which zeroes the upper half), the def should be made with
+/// `Operand::reg_full_def`. Conversely, if a narrow write *preserves*
+/// the rest of its destination and that is meaningful, the
+/// instruction reads its destination, and should be expressed as a
+/// use of the old value plus a reused-input def
+/// (`Operand::reg_reuse_def`).
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct Operand {
@@ -524,9 +611,13 @@ pub struct Operand {
     /// The constraints are encoded as follows:
     /// - 1xxxxxx => FixedReg(preg)
     /// - 01xxxxx => Reuse(index)
+    /// - 001aabb => ReuseEither(a, b)
+    /// - 0001ggg => Tied(group)
     /// - 0000000 => Any
     /// - 0000001 => Reg
     /// - 0000010 => Stack
+    /// - 0000011 => Reg, for a full-register def
+    /// - 00001xx => as 00000xx, for a conditional def or a kill use
     /// - _ => Unused for now
     bits: u32,
 }
@@ -552,6 +643,14 @@ impl Operand {
                 debug_assert!(which <= 31);
                 0b0100000 | which as u32
             }
+            OperandConstraint::ReuseEither(a, b) => {
+                debug_assert!(a <= 3 && b <= 3 && a != b);
+                0b0010000 | (a as u32) << 2 | b as u32
+            }
+            OperandConstraint::Tied(group) => {
+                debug_assert!(group <= 7);
+                0b0001000 | group as u32
+            }
         };
         let class_field = vreg.class() as u8 as u32;
         let pos_field = pos as u8 as u32;
@@ -666,6 +765,47 @@ impl Operand {
         )
     }
 
+    /// Create an `Operand` that designates a def of a vreg that must
+    /// reuse the register assigned to one of two inputs, `a` or `b`,
+    /// of a commutative instruction; see
+    /// `OperandConstraint::ReuseEither`. Both inputs must be
+    /// constrained to a register.
+    #[inline(always)]
+    pub fn reg_reuse_either_def(vreg: VReg, a: usize, b: usize) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::ReuseEither(a, b),
+            OperandKind::Def,
+            OperandPos::Late,
+        )
+    }
+
+    /// Create an `Operand` that designates a use of a vreg in the
+    /// register shared by the operands tied to `group`; see
+    /// `OperandConstraint::Tied`.
+    #[inline(always)]
+    pub fn reg_tied_use(vreg: VReg, group: usize) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::Tied(group),
+            OperandKind::Use,
+            OperandPos::Early,
+        )
+    }
+
+    /// Create an `Operand` that designates a def of a vreg in the
+    /// register shared by the operands tied to `group`, which it
+    /// takes over from their input; see `OperandConstraint::Tied`.
+    #[inline(always)]
+    pub fn reg_tied_def(vreg: VReg, group: usize) -> Self {
+        Operand::new(
+            vreg,
+            OperandConstraint::Tied(group),
+            OperandKind::Def,
+            OperandPos::Late,
+        )
+    }
+
     /// Create an `Operand` that designates a use of a vreg and
     /// ensures that it is placed in the given, fixed PReg at the
     /// use. It is guaranteed that the `Allocation` resulting for this
@@ -742,6 +882,121 @@ impl Operand {
         )
     }
 
+    /// Create an `Operand` that designates a conditional def of a
+    /// vreg: one that the instruction may or may not actually write
+    /// (e.g. the destination of a predicated move). The allocator and
+    /// the checker handle it exactly like a `Def` with the same
+    /// constraint and position: the allocation is reserved at the def,
+    /// the vreg's range starts there, and nothing else may be assumed
+    /// to survive in it. If the instruction does not write it, the
+    /// vreg's value afterward is unspecified (it is whatever the
+    /// allocation held before). `constraint` must be `Any`, `Reg` or
+    /// `Stack`.
+    #[inline(always)]
+    pub fn conditional_def(vreg: VReg, constraint: OperandConstraint, pos: OperandPos) -> Self {
+        debug_assert!(matches!(
+            constraint,
+            OperandConstraint::Any | OperandConstraint::Reg | OperandConstraint::Stack
+        ));
+        let op = Operand::new(vreg, constraint, OperandKind::Def, pos);
+        Operand {
+            bits: op.bits | (0b0000100 << 25),
+        }
+    }
+
+    /// Create an `Operand` that designates a conditional def of a
+    /// vreg that must be in a register, and that occurs at the
+    /// "after" point. See [`Operand::conditional_def`].
+    #[inline(always)]
+    pub fn reg_conditional_def(vreg: VReg) -> Self {
+        Operand::conditional_def(vreg, OperandConstraint::Reg, OperandPos::Late)
+    }
+
+    /// Create an `Operand` that designates a def of a vreg that must
+    /// be in a register, occurring at the "after" point, and that
+    /// writes the whole of each wider register containing that
+    /// register (see `MachineEnv::reg_aliases`). Besides the register
+    /// and its aliases, as for any def, this excludes other values
+    /// from the other registers overlapping those wider registers at
+    /// the def, e.g. from `ah` for a def into `al` that writes all of
+    /// `rax`. Use [`Operand::early_clobber`] to make the def early.
+    #[inline(always)]
+    pub fn reg_full_def(vreg: VReg) -> Self {
+        let op = Operand::reg_def(vreg);
+        Operand {
+            bits: op.bits | (0b0000010 << 25),
+        }
+    }
+
+    /// Create an `Operand` that designates a use of a vreg that is
+    /// its definite last use (a "kill"), even if liveness analysis
+    /// would otherwise keep the vreg live afterward. The vreg's live
+    /// range is truncated at this use, freeing its allocation for
+    /// other values. `constraint` must be `Any`, `Reg` or `Stack`.
+    ///
+    /// It is the caller's responsibility that the vreg really is
+    /// dead after a kill: any later use of it (without an
+    /// intervening def) reads an unspecified value. The checker
+    /// reports such a use as an error whenever the vreg's allocation
+    /// was given to another value in between.
+    #[inline(always)]
+    pub fn kill(vreg: VReg, constraint: OperandConstraint, pos: OperandPos) -> Self {
+        debug_assert!(matches!(
+            constraint,
+            OperandConstraint::Any | OperandConstraint::Reg | OperandConstraint::Stack
+        ));
+        let op = Operand::new(vreg, constraint, OperandKind::Use, pos);
+        Operand {
+            bits: op.bits | (0b0000100 << 25),
+        }
+    }
+
+    /// Create an `Operand` that designates a kill of a vreg that must
+    /// be in a register, and that occurs at the "before" point. See
+    /// [`Operand::kill`].
+    #[inline(always)]
+    pub fn reg_use_kill(vreg: VReg) -> Self {
+        Operand::kill(vreg, OperandConstraint::Reg, OperandPos::Early)
+    }
+
+    /// Make this def an early clobber: one the instruction may write
+    /// before it has finished reading its inputs, so that it must not
+    /// share a register with any of them (e.g. the status result of
+    /// ARM's `strex`). This moves the def to the "before" point (see
+    /// [`Operand::reg_def_at_start`]), where it overlaps every input,
+    /// and keeps its constraint. A reused-input def cannot be an
+    /// early clobber, since it shares its input's register by
+    /// definition.
+    #[inline(always)]
+    pub fn early_clobber(self) -> Self {
+        debug_assert_eq!(self.kind(), OperandKind::Def);
+        debug_assert!(!matches!(
+            self.constraint(),
+            OperandConstraint::Reuse(_)
+                | OperandConstraint::ReuseEither(..)
+                | OperandConstraint::Tied(_)
+        ));
+        Operand {
+            bits: self.bits & !(1 << 23),
+        }
+    }
+
+    /// Create an `Operand` for an optional operand slot that is not in
+    /// use. It names no value, is ignored by the allocator and
+    /// always receives `Allocation::none()`. Any operand whose vreg
+    /// is `VReg::invalid()` is treated the same way, unless it is a
+    /// fixed non-allocatable operand; such an operand must not have a
+    /// `Reuse` constraint or be the target of one.
+    #[inline(always)]
+    pub fn unused(class: RegClass) -> Self {
+        Operand::new(
+            VReg::new(VReg::MAX, class),
+            OperandConstraint::Any,
+            OperandKind::Use,
+            OperandPos::Early,
+        )
+    }
+
     /// Create an `Operand` that always results in an assignment to the
     /// given fixed `preg`, *without* tracking liveranges in that
     /// `preg`. Must only be used for non-allocatable registers.
@@ -812,16 +1067,39 @@ impl Operand {
             OperandConstraint::FixedReg(PReg::new(constraint_field & 0b0111111, self.class()))
         } else if constraint_field & 0b0100000 != 0 {
             OperandConstraint::Reuse(constraint_field & 0b0011111)
+        } else if constraint_field & 0b0010000 != 0 {
+            OperandConstraint::ReuseEither((constraint_field >> 2) & 0b11, constraint_field & 0b11)
+        } else if constraint_field & 0b0001000 != 0 {
+            OperandConstraint::Tied(constraint_field & 0b0000111)
         } else {
-            match constraint_field {
+            match constraint_field & !0b0000100 {
                 0 => OperandConstraint::Any,
-                1 => OperandConstraint::Reg,
+                1 | 3 => OperandConstraint::Reg,
                 2 => OperandConstraint::Stack,
                 _ => unreachable!(),
             }
         }
     }
 
+    /// Is this a conditional def (see [`Operand::conditional_def`])?
+    #[inline(always)]
+    pub fn is_conditional_def(self) -> bool {
+        self.kind() == OperandKind::Def && (self.bits >> 25) & 0b1111100 == 0b0000100
+    }
+
+    /// Is this a full-register def (see [`Operand::reg_full_def`])?
+    #[inline(always)]
+    pub fn is_full_reg_def(self) -> bool {
+        self.kind() == OperandKind::Def && (self.bits >> 25) & 0b1111111 == 0b0000011
+    }
+
+    /// Is this a kill, i.e. a definite last use (see
+    /// [`Operand::kill`])?
+    #[inline(always)]
+    pub fn is_kill(self) -> bool {
+        self.kind() == OperandKind::Use && (self.bits >> 25) & 0b1111100 == 0b0000100
+    }
+
     /// If this operand is for a fixed non-allocatable register (see
     /// [`Operand::fixed`]), then returns the physical register that it will
     /// be assigned to.
@@ -833,6 +1111,14 @@ impl Operand {
         }
     }
 
+    /// Is this an unused optional operand (see [`Operand::unused`]),
+    /// i.e. one naming `VReg::invalid()` that is not a fixed
+    /// non-allocatable register?
+    #[inline(always)]
+    pub fn is_unused(self) -> bool {
+        self.vreg().vreg() == VReg::MAX && self.as_fixed_nonallocatable().is_none()
+    }
+
     /// Get the raw 32-bit encoding of this operand's fields.
     #[inline(always)]
     pub fn bits(self) -> u32 {
@@ -848,6 +1134,18 @@ impl Operand {
     }
 }
 
+/// The indices of the uses among `operands` that are tied to
+/// `group`; see `OperandConstraint::Tied`.
+pub(crate) fn tied_uses(operands: &[Operand], group: usize) -> impl Iterator<Item = usize> + '_ {
+    operands
+        .iter()
+        .enumerate()
+        .filter(move |(_, op)| {
+            op.kind() == OperandKind::Use && op.constraint() == OperandConstraint::Tied(group)
+        })
+        .map(|(i, _)| i)
+}
+
 impl core::fmt::Debug for Operand {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         core::fmt::Display::fmt(self, f)
@@ -859,12 +1157,22 @@ impl core::fmt::Display for Operand {
         if let Some(preg) = self.as_fixed_nonallocatable() {
             return write!(f, "Fixed: {preg}");
         }
+        if self.is_unused() {
+            return write!(f, "Unused");
+        }
+        let kind = match self.kind() {
+            OperandKind::Def if self.is_conditional_def() => "CondDef",
+            OperandKind::Def if self.is_full_reg_def() => "FullDef",
+            OperandKind::Def => "Def",
+            OperandKind::Use if self.is_kill() => "Kill",
+            OperandKind::Use => "Use",
+        };
         match (self.kind(), self.pos()) {
             (OperandKind::Def, OperandPos::Late) | (OperandKind::Use, OperandPos::Early) => {
-                write!(f, "{:?}", self.kind())?;
+                write!(f, "{kind}")?;
             }
             _ => {
-                write!(f, "{:?}@{:?}", self.kind(), self.pos())?;
+                write!(f, "{kind}@{:?}", self.pos())?;
             }
         }
         write!(
@@ -1079,11 +1387,55 @@ pub trait Function {
     /// reference-typed values in registers if there were a way to
     /// denote their locations and if this were acceptable to the
     /// client. Usually garbage-collector implementations want to see
-    /// roots on the stack, so we do that for now.
+    /// roots on the stack, so we do that by default; see
+    /// `RegallocOptions::reftype_spill_policy`.
     fn requires_refs_on_stack(&self, _: Inst) -> bool {
         false
     }
 
+    /// Determine whether a block is cold, e.g. an exception handler or
+    /// a slow path. Uses in cold blocks have a much lower spill
+    /// weight, so values used only there readily give up their
+    /// registers to values on the hot path, and a split of a value
+    /// that runs from hot into cold code is placed at the boundary.
+    /// The move a split would introduce is priced the same way, so
+    /// when choosing where to split a value, a register whose
+    /// conflict is first met in a cold block is favoured. Whether to
+    /// split or evict at all still depends on spill weights alone.
+    fn is_cold_block(&self, _: Block) -> bool {
+        false
+    }
+
+    /// How often a block executes relative to the function's entry,
+    /// e.g. from profile data: 1.0 for once per call, 100.0 for a
+    /// loop body that typically runs a hundred times. Where given,
+    /// this replaces the estimate from loop depth (four times per
+    /// level of nesting) in spill weights and so in the cost of
+    /// moves, and in keeping splits out of hotter code than the
+    /// value starts in. Returning `None` for some blocks mixes the
+    /// two scales, so it is best to give a frequency for all of them
+    /// or none.
+    fn block_frequency(&self, _: Block) -> Option<f32> {
+        None
+    }
+
+    /// Determine whether an instruction requires *all* values that
+    /// are live across it to be in spillslots, e.g. a barrier or
+    /// fence pseudo-instruction whose ABI mandates that nothing is
+    /// kept in registers.
+    ///
+    /// This is stronger than clobbering every register: no
+    /// allocatable register may hold a value at either the early or
+    /// the late point of the instruction, so such an instruction
+    /// must not have register-constrained operands (operands with an
+    /// `Any` or `Stack` constraint are fine, as are fixed
+    /// non-allocatable ones); otherwise allocation fails with
+    /// `RegAllocError::RegOperandAtSpillAll`. After the instruction,
+    /// all allocatable registers are considered to hold garbage.
+    fn spill_all_at(&self, _: Inst) -> bool {
+        false
+    }
+
     // --------------------------
     // Instruction register slots
     // --------------------------
@@ -1123,6 +1475,35 @@ pub trait Function {
     /// value(s).
     fn inst_clobbers(&self, insn: Inst) -> PRegSet;
 
+    /// Registers that may not be used at all at an instruction, for
+    /// constraints that depend on its operands and so fit no static
+    /// clobber set, such as a temp the instruction needs only for
+    /// some combinations of operands. Neither an operand of the
+    /// instruction nor a value live across it is placed in them, and
+    /// after the instruction they are considered to hold garbage.
+    ///
+    /// Unlike a clobber, a forbidden register cannot take an input of
+    /// the instruction either. It must not be one that an operand of
+    /// the instruction is fixed to.
+    fn inst_forbidden_pregs(&self, _: Inst) -> PRegSet {
+        PRegSet::empty()
+    }
+
+    /// Registers reserved over part of the function: no value is
+    /// allocated to the register anywhere in the given range, as if
+    /// it were missing from the `MachineEnv` there. This is for
+    /// registers that become reserved partway through, such as a
+    /// frame pointer set up after the prologue. Where a range covers
+    /// an instruction's after-point, the register is considered to
+    /// hold garbage after that instruction.
+    ///
+    /// A range must not cover an operand fixed to its register.
+    /// Ranges for the same register may overlap, and may overlap
+    /// clobbers and forbidden registers.
+    fn reserved_preg_ranges(&self) -> &[(PReg, CodeRange)] {
+        &[]
+    }
+
     /// Get the number of `VReg` in use in this function.
     fn num_vregs(&self) -> usize;
 
@@ -1140,10 +1521,33 @@ pub trait Function {
     ///   will be included in a list in the `Output` below, so that
     ///   pointer-inspecting/updating functionality (such as a moving
     ///   garbage collector) may observe and edit their values.
+    /// - A vreg defined at the early point of a safepoint instruction
+    ///   is live at that safepoint, even if it has no other uses: it
+    ///   is placed in a SpillSlot and included in the list above. Such
+    ///   a def must therefore have an `Any` or `Stack` constraint.
     fn reftype_vregs(&self) -> &[VReg] {
         &[]
     }
 
+    /// Get the VRegs that are live on entry to the function, together
+    /// with the physical register each one occupies at that point.
+    ///
+    /// This is useful for e.g. tail calls or other custom calling
+    /// conventions where the caller has already placed values in
+    /// registers. Each listed vreg is treated as if it were defined
+    /// in its register just before the first instruction of the entry
+    /// block, and it may then be used without any other def; if the
+    /// allocator chooses a different location, a move is inserted
+    /// before the first instruction. Vregs that are live-in to the
+    /// entry block but not listed here still produce
+    /// `RegAllocError::EntryLivein`. A vreg may be listed only once,
+    /// and no two vregs in the same register, which must be of the
+    /// vreg's class; otherwise allocation fails with
+    /// `RegAllocError::InvalidEntryLiveReg`.
+    fn entry_live_regs(&self) -> &[(VReg, PReg)] {
+        &[]
+    }
+
     /// Get the VRegs for which we should generate value-location
     /// metadata for debugging purposes. This can be used to generate
     /// e.g. DWARF with valid prgram-point ranges for each value
@@ -1157,6 +1561,12 @@ pub trait Function {
     /// subset of the requested ranges -- if the value is not live for
     /// the entire requested ranges.
     ///
+    /// The output follows the value through splits and spills: there
+    /// is one tuple for each maximal stretch over which the value is
+    /// held in a given location. A value may be in more than one
+    /// location at once, e.g. in its spillslot and in a register it
+    /// has been reloaded into.
+    ///
     /// The instruction indices imply a program point just *before*
     /// the instruction.
     ///
@@ -1186,6 +1596,22 @@ pub trait Function {
         false
     }
 
+    /// Pairs of vregs that should share a spillslot if both are
+    /// spilled. The hint is only taken when the two values are of the
+    /// same class and their spilled extents do not overlap; otherwise
+    /// they get slots of their own as usual.
+    fn stack_affinities(&self) -> &[(VReg, VReg)] {
+        &[]
+    }
+
+    /// The region `vreg` is spilled to. Values in different regions
+    /// never share a spillslot. Spillslots the allocator needs for
+    /// its own temporaries (e.g. to break move cycles) are always in
+    /// the frame.
+    fn spill_region(&self, _vreg: VReg) -> SpillRegion {
+        SpillRegion::Frame
+    }
+
     // -----------
     // Misc config
     // -----------
@@ -1203,6 +1629,150 @@ pub trait Function {
     fn allow_multiple_vreg_defs(&self) -> bool {
         false
     }
+
+    /// Override the order in which registers of the given class are
+    /// tried when allocating this function. If `Some`, the registers
+    /// are tried strictly in the given order, instead of the
+    /// `MachineEnv`'s preferred-then-non-preferred scan. For example,
+    /// a leaf function may list caller-saved registers first so that
+    /// it needs no callee-saved registers (and hence no prologue
+    /// saves) when pressure is low.
+    ///
+    /// The slice should contain the same registers as the
+    /// `MachineEnv`'s preferred and non-preferred lists for this
+    /// class; only the order may differ.
+    fn reg_preference_order(&self, _class: RegClass) -> Option<&[PReg]> {
+        None
+    }
+
+    /// Restrict `vreg` to the registers of a subclass, given as an
+    /// index into `MachineEnv::reg_subclasses`. This applies wherever
+    /// the allocator picks a register for the vreg; operands with a
+    /// fixed-register constraint still get their fixed register.
+    fn reg_subclass(&self, _vreg: VReg) -> Option<usize> {
+        None
+    }
+
+    /// Pin `vreg` to a physical register for its whole lifetime, e.g.
+    /// for an argument or return value the ABI places in one, in
+    /// place of a fixed-register constraint on each of its operands.
+    /// Every bundle containing the vreg requires the register, and
+    /// outweighs any bundle not fixed to it, so that no other value
+    /// is allocated there while the vreg is live.
+    ///
+    /// The register must be allocatable and of the vreg's class, and
+    /// every operand of the vreg must accept it: no stack constraint,
+    /// and no fixed constraint naming another register. Nothing else
+    /// may need the register while the vreg is live, whether as a
+    /// fixed operand, a clobber or another pinned vreg; the
+    /// allocator then has to split the vreg around the conflict, and
+    /// may fail with `RegAllocError::TooManyLiveRegs`.
+    fn vreg_fixed_preg(&self, _vreg: VReg) -> Option<PReg> {
+        None
+    }
+
+    /// The most times any bundle containing `vreg` may be split
+    /// while looking for a register, for values where the moves a
+    /// split introduces are never worth it. Once the budget is used
+    /// up, the allocator evicts lighter conflicting bundles instead,
+    /// and fails with `RegAllocError::SplitLimitExceeded` if it
+    /// cannot. Splits forced by conflicting operand constraints are
+    /// still made. `None` (the default) leaves the allocator's own
+    /// limit in place.
+    fn max_splits(&self, _vreg: VReg) -> Option<u32> {
+        None
+    }
+
+    /// Put `vreg` in an allocation group: vregs returning the same
+    /// `Some(id)` are kept in registers or spilled together, e.g.
+    /// the accumulators of a vectorized loop, where spilling only
+    /// some of them would thrash. Once any member must be split for
+    /// lack of a register, every member is spilled, keeping
+    /// registers only across the uses and defs that require one;
+    /// members are also never given a register afterward for their
+    /// spilled stretches.
+    ///
+    /// This is a strong preference rather than a guarantee: members
+    /// are still split wherever their own operand constraints
+    /// conflict, and bundles of different groups (or of a group and
+    /// no group) are never merged, even across moves. As with an
+    /// expired `RegallocOptions::time_budget`, a spilled group can
+    /// make allocation fail with `RegAllocError::TooManyLiveRegs`
+    /// where a member needs a register across a whole instruction.
+    fn allocation_group(&self, _vreg: VReg) -> Option<u32> {
+        None
+    }
+
+    /// Keep some registers of a class free within the loop headed by
+    /// `loop_header`, e.g. for a later scheduler that
+    /// software-pipelines the loop body. Returning `Some((class, k))`
+    /// reserves the last `k` registers of `class` in allocation order
+    /// (see `reg_preference_order`): no vreg is allocated to them
+    /// anywhere in the loop, except for operands with a
+    /// fixed-register constraint.
+    ///
+    /// Only called for loop headers, i.e. blocks with a backedge
+    /// predecessor. The loop consists of the header and the blocks
+    /// that follow it up to the end of its (approximate) nesting
+    /// level.
+    fn loop_reg_reserve(&self, _loop_header: Block) -> Option<(RegClass, usize)> {
+        None
+    }
+
+    /// Vregs to keep in a register across a whole loop, each with
+    /// the loop's header (as for `loop_reg_reserve`), e.g. the hot
+    /// induction variable of a loop. Such a vreg outweighs every
+    /// other value in the loop when registers run short, and is not
+    /// split inside the loop, so the pressure falls on other values.
+    ///
+    /// This is a strong preference rather than a guarantee: where it
+    /// cannot be honored, e.g. because fixed-register constraints or
+    /// clobbers in the loop leave no register free throughout, or
+    /// the vreg's own constraints conflict there, allocation goes
+    /// ahead regardless and the request is listed in
+    /// `Output::loop_resident_fallbacks`. So is a request whose
+    /// block is not a loop header. If honoring the requests leaves
+    /// too few registers for other values, allocation is redone
+    /// without them, and all are listed.
+    fn loop_resident(&self) -> &[(VReg, Block)] {
+        &[]
+    }
+
+    /// How many instructions ahead of `insn` a value it reads should
+    /// be loaded, to hide the latency of the load. When a value
+    /// spilled to a spillslot must be reloaded into a register for
+    /// `insn`, the reload is placed up to `inst_latency(insn) - 1`
+    /// instructions earlier, as far as that is legal: within the same
+    /// block, after the value has been stored to the slot, and while
+    /// the register is otherwise free. The default of 1 places
+    /// reloads immediately before the instruction.
+    fn inst_latency(&self, _insn: Inst) -> u32 {
+        1
+    }
+
+    /// Can the value defined by `insn` be recomputed by executing
+    /// `insn` again, e.g. because it materializes a constant? If so,
+    /// a value that would be spilled is instead recomputed where it
+    /// would be reloaded, with an `Edit::Remat`, and not stored at
+    /// all. The instruction must read no vregs and define only the
+    /// vreg named in the returned `RematInfo`, with a `Reg` or `Any`
+    /// constraint; otherwise the answer is ignored. So is it for a
+    /// reference-typed vreg, one passed as a block parameter, and one
+    /// that some instruction reads from the stack, all of which keep
+    /// a spillslot.
+    fn is_rematerializable(&self, _insn: Inst) -> Option<RematInfo> {
+        None
+    }
+}
+
+/// How an instruction's result can be recomputed; see
+/// `Function::is_rematerializable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct RematInfo {
+    /// The vreg defined by the instruction, whose value executing the
+    /// instruction again recomputes.
+    pub vreg: VReg,
 }
 
 /// A position before or after an instruction at which we can make an
@@ -1326,6 +1896,50 @@ pub enum Edit {
     /// are the same if the vreg changes; this allows proper metadata
     /// tracking even when moves are elided.
     Move { from: Allocation, to: Allocation },
+
+    /// Execute instruction `inst` again, putting the value of `vreg`
+    /// it defines in the register `to`, in place of a reload of a
+    /// spilled value; see `Function::is_rematerializable`.
+    Remat {
+        inst: Inst,
+        vreg: VReg,
+        to: Allocation,
+    },
+}
+
+impl Edit {
+    /// Classify this edit by where its source and destination
+    /// allocations live.
+    pub fn kind(&self) -> MoveKind {
+        match *self {
+            Edit::Move { from, to } => match (from.is_stack(), to.is_stack()) {
+                (false, true) => MoveKind::Spill,
+                (true, false) => MoveKind::Reload,
+                (true, true) => MoveKind::StackToStack,
+                (false, false) => MoveKind::RegToReg,
+            },
+            Edit::Remat { .. } => MoveKind::Remat,
+        }
+    }
+}
+
+/// The kind of data movement performed by an [`Edit`]; see
+/// [`Edit::kind`]. A fixed stack slot given to the allocator as a
+/// `PReg` (see `MachineEnv::fixed_stack_slots`) counts as a
+/// register here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum MoveKind {
+    /// A register to a spillslot.
+    Spill = 0,
+    /// A spillslot to a register.
+    Reload = 1,
+    /// A spillslot to another spillslot.
+    StackToStack = 2,
+    /// A register to a register.
+    RegToReg = 3,
+    /// A rematerialization into a register.
+    Remat = 4,
 }
 
 /// Wrapper around either an original instruction or an inserted edit.
@@ -1335,6 +1949,20 @@ pub enum InstOrEdit<'a> {
     Edit(&'a Edit),
 }
 
+/// An item in the linear instruction stream produced by
+/// [`Output::into_vcode_stream`].
+#[derive(Clone, Debug)]
+pub enum StreamItem<'a> {
+    /// An original instruction, with the allocations for its
+    /// operands (in operand order).
+    Inst {
+        inst: Inst,
+        operand_allocs: &'a [Allocation],
+    },
+    /// An edit inserted by the allocator.
+    Move(Edit),
+}
+
 /// Iterator over the instructions and edits in a block.
 pub struct OutputIter<'a> {
     /// List of edits starting at the first for the current block.
@@ -1415,17 +2043,173 @@ pub struct MachineEnv {
     /// `PReg`s in this list cannot be used as an allocatable or scratch
     /// register.
     pub fixed_stack_slots: Vec<PReg>,
+
+    /// Registers that overlap each other, indexed by `PReg::index()`:
+    /// `reg_aliases[p.index()]` lists the registers that share storage
+    /// with `p` (e.g. the narrow registers making up a wide vector
+    /// register, possibly in another class). Writing a register
+    /// clobbers all of its aliases, so the allocator never keeps
+    /// values in two aliasing registers at once.
+    ///
+    /// The relation must be symmetric. This may be empty, or shorter
+    /// than `PReg::NUM_INDEX`, if no (or only low-numbered) registers
+    /// have aliases.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub reg_aliases: Vec<Vec<PReg>>,
+
+    /// Register subclasses: each entry is a subset of the allocatable
+    /// registers of one class (e.g. the integer registers usable for
+    /// memory addressing). A vreg placed in a subclass by
+    /// `Function::reg_subclass` only ever gets registers from that
+    /// subset, but otherwise competes for them with all other vregs of
+    /// its class.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub reg_subclasses: Vec<PRegSet>,
+
+    /// Non-preferred registers that the prologue saves regardless
+    /// (e.g. callee-saved registers it always spills), so that using
+    /// them costs nothing extra. They are tried right after the
+    /// preferred registers, ahead of the other non-preferred ones.
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub already_saved: PRegSet,
+}
+
+impl MachineEnv {
+    /// The registers that overlap `preg`; see `reg_aliases`.
+    #[inline(always)]
+    pub fn aliases(&self, preg: PReg) -> &[PReg] {
+        match self.reg_aliases.get(preg.index()) {
+            Some(aliases) => &aliases[..],
+            None => &[],
+        }
+    }
+
+    /// The registers, other than `preg` and its aliases, that overlap
+    /// a register aliasing `preg`: those a full-register def into
+    /// `preg` also writes (see `Operand::reg_full_def`). May contain
+    /// duplicates.
+    pub fn full_register_parts(&self, preg: PReg) -> impl Iterator<Item = PReg> + '_ {
+        let aliases = self.aliases(preg);
+        aliases
+            .iter()
+            .flat_map(move |&wide| self.aliases(wide).iter().cloned())
+            .filter(move |&part| part != preg && !aliases.contains(&part))
+    }
+
+    /// The set of all registers the allocator may assign, across all
+    /// classes, whether preferred or not.
+    pub fn allocatable_regs(&self) -> PRegSet {
+        let mut set = PRegSet::empty();
+        for regs in self
+            .preferred_regs_by_class
+            .iter()
+            .chain(self.non_preferred_regs_by_class.iter())
+        {
+            for &reg in regs {
+                set.add(reg);
+            }
+        }
+        set
+    }
+}
+
+/// A split of a bundle (a group of live ranges allocated together)
+/// made during allocation, as recorded when
+/// `RegallocOptions::collect_split_events` is set. Bundles are
+/// identified by number; the numbers mean nothing outside one
+/// allocation, but are stable within it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct SplitEvent {
+    /// The bundle that was split.
+    pub bundle: u32,
+    /// The point at which it was split, where a move may now be
+    /// needed, or `None` if it was broken up into minimal bundles
+    /// around each of its uses.
+    pub at: Option<ProgPoint>,
+    /// The bundles its ranges went to. A split in two keeps the
+    /// ranges before the split point in `bundle`; ranges without
+    /// uses may go to the spill bundle, which is listed with only
+    /// the ranges this split gave it.
+    pub pieces: Vec<SplitPiece>,
+}
+
+/// One of the bundles resulting from a `SplitEvent`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct SplitPiece {
+    /// The bundle.
+    pub bundle: u32,
+    /// Whether this is the spill bundle, which holds ranges without
+    /// uses and is allocated last, to a register if one is free and
+    /// otherwise to the stack.
+    pub spill: bool,
+    /// The ranges it was given, with their vregs.
+    pub ranges: Vec<(VReg, CodeRange)>,
+}
+
+/// Why some of a vreg's live ranges were sent to the spill path, as
+/// recorded when `RegallocOptions::collect_spill_reasons` is set.
+/// Ranges on the spill path still get a register at the end if one
+/// happens to be free; only ranges that did not are reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub enum SpillReason {
+    /// The ranges had no uses that needed a register.
+    NoUses,
+    /// A use required the value to be on the stack.
+    StackRequired,
+    /// The bundle holding the ranges found no free register and was
+    /// not heavy enough to evict what was in its way in any of them,
+    /// so it was split; the stretches away from its uses went to the
+    /// spill bundle.
+    LostEvictions,
+    /// As `LostEvictions`, but only fixed-register uses or clobbers
+    /// were in the way, so there was nothing to evict.
+    FixedConflict,
+    /// The bundle's own uses had constraints no single location
+    /// satisfies, so it was split between them.
+    ConflictingConstraints,
+    /// The bundle was split for spanning more than
+    /// `RegallocOptions::max_unsplit_range_length` instructions.
+    RangeTooLong,
+    /// The bundle had been split as often as allowed (see
+    /// `Function::max_splits`), so it was split into minimal bundles
+    /// around its uses.
+    SplitBudgetExhausted,
+    /// `RegallocOptions::time_budget` had run out, so the bundle was
+    /// split into minimal bundles around its uses.
+    TimeBudget,
+    /// With `RegallocOptions::single_pass`, the bundle found no free
+    /// register, so it was split into minimal bundles around its uses.
+    SinglePass,
+    /// Another member of the value's allocation group (see
+    /// `Function::allocation_group`) lost its register, so the bundle
+    /// was split into minimal bundles around its uses.
+    SpilledGroup,
 }
 
 /// The output of the register allocator.
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct Output {
-    /// How many spillslots are needed in the frame?
+    /// How many spillslots are needed in the frame? Spilled values
+    /// of the same class share a slot where their live ranges do not
+    /// overlap, so this is usually less than the number of spilled
+    /// values.
     pub num_spillslots: usize,
 
+    /// How many spillslots are needed in each region, indexed by
+    /// `SpillRegion`. The `Frame` entry equals `num_spillslots`.
+    pub num_spillslots_by_region: [usize; 3],
+
     /// Edits (insertions or removals). Guaranteed to be sorted by
     /// program point.
+    ///
+    /// Edits at the same program point must be performed in the
+    /// order given, as they are a sequentialization of a parallel
+    /// move. That order is deterministic: it depends only on the
+    /// input function, the machine environment and the options.
     pub edits: Vec<(ProgPoint, Edit)>,
 
     /// Allocations for each operand. Mapping from instruction to
@@ -1436,21 +2220,94 @@ pub struct Output {
     pub inst_alloc_offsets: Vec<u32>,
 
     /// Safepoint records: at a given program point, a reference-typed value
-    /// lives in the given Allocation. Currently these are guaranteed to be
-    /// stack slots, but in the future an option may be added to allow
-    /// reftype value to be kept in registers at safepoints.
+    /// lives in the given Allocation. These are stack slots, or pregs
+    /// from `MachineEnv::fixed_stack_slots`, unless
+    /// `RegallocOptions::reftype_spill_policy` allows references in
+    /// registers at safepoints.
     pub safepoint_slots: Vec<(ProgPoint, Allocation)>,
 
     /// Debug info: a labeled value (as applied to vregs by
     /// `Function::debug_value_labels()` on the input side) is located
     /// in the given allocation from the first program point
     /// (inclusive) to the second (exclusive). Guaranteed to be sorted
-    /// by label and program point, and the ranges are guaranteed to
-    /// be disjoint.
+    /// by label and program point. Ranges for one label in the same
+    /// allocation are disjoint and never abut, but a value that is
+    /// spilled and reloaded is in its spillslot and a register at the
+    /// same time, so ranges in different allocations may overlap.
     pub debug_locations: Vec<(u32, ProgPoint, ProgPoint, Allocation)>,
 
-    /// Internal stats from the allocator.
-    pub stats: ion::Stats,
+    /// Bundle splits made during allocation, in the order they
+    /// occurred. Only populated when
+    /// `RegallocOptions::collect_split_events` is set; see
+    /// `split_history`.
+    pub split_events: Vec<SplitEvent>,
+
+    /// For each block, the location of every value live at its
+    /// entry, sorted by block and then vreg. A value may appear more
+    /// than once if it is both in a register and in its spillslot.
+    /// Only populated when `RegallocOptions::collect_block_entry_state`
+    /// is set; see `block_reg_state`.
+    pub block_entry_locations: Vec<(Block, VReg, Allocation)>,
+
+    /// What occupies each physical register once allocation is done,
+    /// sorted by register index and then position: each range
+    /// allocated to it with the vreg of the live range holding it,
+    /// or `None` for a fixed reservation (a clobber or fixed-register
+    /// operand). Only populated when `RegallocOptions::collect_preg_maps`
+    /// is set; see `preg_map`.
+    pub preg_maps: Vec<(PReg, CodeRange, Option<VReg>)>,
+
+    /// For each vreg, the stretches of code over which it is held in
+    /// a spillslot, sorted by vreg and then position. Abutting
+    /// stretches in the same slot are coalesced. See `spilled_vregs`.
+    pub spilled_ranges: Vec<(VReg, SpillSlot, CodeRange)>,
+
+    /// Every live range of every vreg, as finally split, with its
+    /// allocation, sorted by vreg and then start. Abutting ranges may
+    /// share an allocation, and a value that is spilled and reloaded
+    /// is in its spillslot and a register at the same time, so ranges
+    /// of one vreg in different allocations may overlap. A
+    /// rematerialized value is in `Allocation::none()` while spilled
+    /// (see `Function::is_rematerializable`). See `ranges`.
+    pub live_ranges: Vec<(VReg, CodeRange, Allocation)>,
+
+    /// The register class of every spillslot used, in allocation
+    /// order. A slot only ever holds values of its own class, and
+    /// the slots of each class are numbered contiguously within
+    /// their region, apart from scratch slots allocated late for
+    /// move resolution. See `spillslots_of_class`.
+    pub spillslot_classes: Vec<(SpillSlot, RegClass)>,
+
+    /// For each instruction, the largest number of registers of each
+    /// class (indexed by `RegClass`) holding a value at either of
+    /// its program points. See `pressure_profile`.
+    pub live_regs_by_inst: Vec<[u32; 3]>,
+
+    /// For each bundle processed, in processing order: the vregs in
+    /// it, and the requirement computed from the constraints of its
+    /// uses, or the point where two of those constraints conflict
+    /// (at which the bundle is then split). Only populated when
+    /// `RegallocOptions::collect_bundle_requirements` is set.
+    pub bundle_requirements: Vec<(Vec<VReg>, Result<Requirement, ProgPoint>)>,
+
+    /// The requests from `Function::loop_resident` that could not be
+    /// honored: the vreg was not kept in a single register throughout
+    /// the loop. Sorted by vreg and then loop header.
+    pub loop_resident_fallbacks: Vec<(VReg, Block)>,
+
+    /// For each vreg with ranges that were sent to the spill path and
+    /// did not get a register there, each reason they were sent
+    /// there, sorted by vreg and then reason. Only
+    /// populated when `RegallocOptions::collect_spill_reasons` is
+    /// set.
+    pub spill_reasons: Vec<(VReg, SpillReason)>,
+
+    /// The allocation decisions made for the vreg given by
+    /// `RegallocOptions::trace_vreg`, if any.
+    pub vreg_trace: Option<VregTrace>,
+
+    /// Counters describing the allocator's work; see `Stats`.
+    pub stats: Stats,
 }
 
 impl Output {
@@ -1465,6 +2322,22 @@ impl Output {
         &self.allocs[start..end]
     }
 
+    /// The locations of the references live across the safepoint
+    /// `inst`, for emitting its stack map: the run of
+    /// `safepoint_slots` at that instruction. These are spillslots or
+    /// fixed stack-slot pregs unless
+    /// `RegallocOptions::reftype_spill_policy` allows registers.
+    /// Empty if `inst` is not a safepoint or no reference is live
+    /// across it.
+    pub fn slots_at_safepoint(&self, inst: Inst) -> impl Iterator<Item = Allocation> + '_ {
+        let pos = ProgPoint::before(inst);
+        let start = self.safepoint_slots.partition_point(|&(p, _)| p < pos);
+        self.safepoint_slots[start..]
+            .iter()
+            .take_while(move |&&(p, _)| p == pos)
+            .map(|&(_, alloc)| alloc)
+    }
+
     /// Returns an iterator over the instructions and edits in a block, in
     /// order.
     pub fn block_insts_and_edits(&self, func: &impl Function, block: Block) -> OutputIter<'_> {
@@ -1488,6 +2361,585 @@ impl Output {
         let edits = &self.edits[edit_idx..];
         OutputIter { inst_range, edits }
     }
+
+    /// List the moves in `edits` whose source and destination are
+    /// the same. The allocator does not produce these, so this is
+    /// normally empty; it is useful for checking edits that have been
+    /// post-processed, and a backend may skip emitting any it finds.
+    pub fn self_moves(&self) -> Vec<(ProgPoint, Allocation)> {
+        self.edits
+            .iter()
+            .filter_map(|&(pos, ref edit)| match *edit {
+                Edit::Move { from, to } if from == to => Some((pos, from)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Re-serialize the edits at `pos` using `scratch` as the
+    /// temporary for breaking cycles. The moves at `pos` are taken
+    /// together as the parallel move they implement: each location
+    /// written at `pos` receives the value that its final source held
+    /// before the first move, and the result is a sequence of moves
+    /// with the same combined effect. `scratch` must hold no live
+    /// value at `pos`; whatever the edits leave in it is not
+    /// preserved. As with the allocator's own parallel moves, the
+    /// result may contain stack-to-stack moves, which the backend
+    /// must lower itself. Rematerializations at `pos` are not moves
+    /// and are left out.
+    pub fn resolve_moves_at(&self, pos: ProgPoint, scratch: PReg) -> Vec<(Allocation, Allocation)> {
+        let start = self.edits.partition_point(|&(p, _)| p < pos);
+        let end = self.edits.partition_point(|&(p, _)| p <= pos);
+
+        // Track, for each location written so far, the location whose
+        // original value it now holds.
+        let mut origin: Vec<(Allocation, Allocation)> = Vec::new();
+        for (_, edit) in &self.edits[start..end] {
+            let (from, to) = match *edit {
+                Edit::Move { from, to } => (from, to),
+                Edit::Remat { .. } => continue,
+            };
+            let src = origin
+                .iter()
+                .find(|&&(dst, _)| dst == from)
+                .map_or(from, |&(_, src)| src);
+            match origin.iter_mut().find(|(dst, _)| *dst == to) {
+                Some(entry) => entry.1 = src,
+                None => origin.push((to, src)),
+            }
+        }
+
+        let scratch = Allocation::reg(scratch);
+        let mut parallel_moves = moves::ParallelMoves::new();
+        for (dst, src) in origin {
+            if dst == src || dst == scratch {
+                continue;
+            }
+            debug_assert_ne!(src, scratch, "scratch register is live at {pos:?}");
+            parallel_moves.add(src, dst, ());
+        }
+        parallel_moves
+            .resolve()
+            .with_scratch(scratch)
+            .into_iter()
+            .map(|(from, to, ())| (from, to))
+            .collect()
+    }
+
+    /// Renumber spillslots, for example to follow a frame layout of
+    /// the caller's choosing. Every stack allocation (in `allocs`,
+    /// `edits`, `safepoint_slots`, `debug_locations`,
+    /// `block_entry_locations`, `spilled_ranges`, `live_ranges`,
+    /// `spillslot_classes` and the placement in `vreg_trace`) is
+    /// rewritten from slot `s` to `f(s)`, and the spillslot count of
+    /// each region becomes one more than the largest new slot index
+    /// in it. `f` must map distinct slots to distinct slots and
+    /// preserve their register class; it may move slots between
+    /// regions.
+    pub fn remap_spillslots(&mut self, f: impl Fn(SpillSlot) -> SpillSlot) {
+        let remap = |alloc: &mut Allocation| {
+            if let Some(slot) = alloc.as_stack() {
+                *alloc = Allocation::stack(f(slot));
+            }
+        };
+        for alloc in &mut self.allocs {
+            remap(alloc);
+        }
+        for (_, edit) in &mut self.edits {
+            match edit {
+                Edit::Move { from, to } => {
+                    remap(from);
+                    remap(to);
+                }
+                Edit::Remat { to, .. } => remap(to),
+            }
+        }
+        for (_, alloc) in &mut self.safepoint_slots {
+            remap(alloc);
+        }
+        self.safepoint_slots
+            .sort_unstable_by_key(|&(pos, alloc)| (pos, alloc.bits()));
+        for (_, _, _, alloc) in &mut self.debug_locations {
+            remap(alloc);
+        }
+        for (_, _, alloc) in &mut self.block_entry_locations {
+            remap(alloc);
+        }
+        for (_, slot, _) in &mut self.spilled_ranges {
+            *slot = f(*slot);
+        }
+        for (_, _, alloc) in &mut self.live_ranges {
+            remap(alloc);
+        }
+        if let Some(trace) = &mut self.vreg_trace {
+            for (_, alloc) in &mut trace.placement {
+                remap(alloc);
+            }
+        }
+        for (slot, _) in &mut self.spillslot_classes {
+            *slot = f(*slot);
+        }
+        let mut num_spillslots_by_region = [0; 3];
+        for region in [SpillRegion::Frame, SpillRegion::Tls, SpillRegion::Custom] {
+            for slot in 0..self.num_spillslots_by_region[region as usize] {
+                let slot = f(SpillSlot::new(slot).with_region(region));
+                let count = &mut num_spillslots_by_region[slot.region() as usize];
+                *count = core::cmp::max(*count, slot.index() + 1);
+            }
+        }
+        self.num_spillslots_by_region = num_spillslots_by_region;
+        self.num_spillslots = num_spillslots_by_region[SpillRegion::Frame as usize];
+    }
+
+    /// Estimate the dynamic cost of the spill traffic in this
+    /// allocation: every move that loads from or stores to the stack
+    /// counts once per stack access, weighted by the frequency of the
+    /// block it is in (see `Function::block_frequency`), or else by
+    /// four to the power of the block's (approximate) loop depth, up
+    /// to a depth of 10. A stack-to-stack move thus counts twice.
+    ///
+    /// `func` must be the function this output was produced for.
+    pub fn estimated_spill_cost<F: Function>(&self, func: &F) -> f64 {
+        let cfginfo = cfg::CFGInfo::new(func).expect("function was already allocated");
+        let mut cost = 0.0;
+        for (pos, edit) in &self.edits {
+            let accesses = match *edit {
+                Edit::Move { from, to } => from.is_stack() as u32 + to.is_stack() as u32,
+                Edit::Remat { to, .. } => to.is_stack() as u32,
+            };
+            if accesses == 0 {
+                continue;
+            }
+            let block = cfginfo.insn_block[pos.inst().index()];
+            let weight = func.block_frequency(block).unwrap_or_else(|| {
+                ion::liveranges::loop_depth_frequency(
+                    cfginfo.approx_loop_depth[block.index()] as usize,
+                )
+            });
+            cost += weight as f64 * accesses as f64;
+        }
+        cost
+    }
+
+    /// Returns the whole function as a single linear stream, in
+    /// block order, of its instructions with their operand
+    /// allocations and the edits to emit between them. Edits at the
+    /// `Before` point of an instruction come ahead of it and those at
+    /// the `After` point follow it, so a backend can emit code by
+    /// simply walking the stream.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn into_vcode_stream<'a, F: Function>(
+        &'a self,
+        func: &'a F,
+    ) -> impl Iterator<Item = StreamItem<'a>> + 'a {
+        (0..func.num_blocks()).flat_map(move |block| {
+            self.block_insts_and_edits(func, Block::new(block))
+                .map(move |item| match item {
+                    InstOrEdit::Inst(inst) => StreamItem::Inst {
+                        inst,
+                        operand_allocs: self.inst_allocs(inst),
+                    },
+                    InstOrEdit::Edit(edit) => StreamItem::Move(edit.clone()),
+                })
+        })
+    }
+
+    /// Render the allocation as a textual listing, one line per
+    /// instruction with each operand followed by its allocation, and
+    /// with inserted edits interleaved as comments at the program
+    /// points where they occur. Intended for diffing against other
+    /// allocators' dumps.
+    pub fn to_annotated_listing(&self, func: &impl Function) -> String {
+        use core::fmt::Write;
+
+        let mut s = String::new();
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let _ = write!(s, "block{}:", block.index());
+            let succs = func.block_succs(block);
+            if !succs.is_empty() {
+                let _ = write!(s, " ; succs:");
+                for succ in succs {
+                    let _ = write!(s, " block{}", succ.index());
+                }
+            }
+            s.push('\n');
+            for item in self.block_insts_and_edits(func, block) {
+                match item {
+                    InstOrEdit::Inst(inst) => {
+                        let _ = write!(s, "  inst{}:", inst.index());
+                        let ops = func.inst_operands(inst);
+                        let allocs = self.inst_allocs(inst);
+                        for (i, (op, alloc)) in ops.iter().zip(allocs.iter()).enumerate() {
+                            let sep = if i == 0 { " " } else { ", " };
+                            let _ = write!(s, "{}{} => {}", sep, op, alloc);
+                        }
+                        s.push('\n');
+                    }
+                    InstOrEdit::Edit(Edit::Move { from, to }) => {
+                        let _ = writeln!(s, "    ; move {} -> {}", from, to);
+                    }
+                    InstOrEdit::Edit(Edit::Remat { inst, vreg, to }) => {
+                        let _ = writeln!(s, "    ; remat inst{} {} -> {}", inst.index(), vreg, to);
+                    }
+                }
+            }
+        }
+        s
+    }
+
+    /// Render the allocation as a columnar listing for reading, in
+    /// the manner of a disassembly: a line per instruction and per
+    /// edit, giving its program point, what it is, and for an
+    /// instruction each operand with its allocation, in columns
+    /// aligned across the whole function, then its clobbers. Each
+    /// block starts with a header naming its predecessors and
+    /// successors, edits name the vreg they carry where it is known,
+    /// and the listing ends with each spillslot and the vregs it
+    /// holds, over which stretches of code.
+    pub fn dump(&self, func: &impl Function) -> String {
+        use alloc::format;
+        use core::fmt::Write;
+
+        let point = |pos: ProgPoint| {
+            let suffix = match pos.pos() {
+                InstPosition::Before => "pre",
+                InstPosition::After => "post",
+            };
+            format!("inst{}-{}", pos.inst().index(), suffix)
+        };
+        let cells = |inst: Inst| -> Vec<String> {
+            func.inst_operands(inst)
+                .iter()
+                .zip(self.inst_allocs(inst))
+                .map(|(op, alloc)| format!("{} => {}", op, alloc))
+                .collect()
+        };
+        let mut widths: Vec<usize> = Vec::new();
+        for inst in 0..func.num_insts() {
+            for (i, cell) in cells(Inst::new(inst)).iter().enumerate() {
+                if widths.len() <= i {
+                    widths.push(0);
+                }
+                widths[i] = core::cmp::max(widths[i], cell.len());
+            }
+        }
+        // The vreg held in `alloc` at `pos`, if the ranges say.
+        let vreg_in = |alloc: Allocation, pos: ProgPoint| {
+            self.live_ranges
+                .iter()
+                .find(|&&(_, range, a)| a == alloc && range.contains_point(pos))
+                .map(|&(vreg, ..)| vreg)
+        };
+        let blocks = |blocks: &[Block]| {
+            if blocks.is_empty() {
+                return String::from("-");
+            }
+            let names: Vec<String> = blocks
+                .iter()
+                .map(|block| format!("block{}", block.index()))
+                .collect();
+            names.join(" ")
+        };
+
+        let mut s = String::new();
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            let _ = writeln!(
+                s,
+                "block{}:  ; preds: {}  succs: {}",
+                block.index(),
+                blocks(func.block_preds(block)),
+                blocks(func.block_succs(block))
+            );
+            for inst in func.block_insns(block).iter() {
+                let edits_at = |s: &mut String, pos: ProgPoint| {
+                    let start = self.edits.partition_point(|&(p, _)| p < pos);
+                    let end = self.edits.partition_point(|&(p, _)| p <= pos);
+                    for (_, edit) in &self.edits[start..end] {
+                        let (line, vreg) = match *edit {
+                            Edit::Move { from, to } => (
+                                format!("{:<7}{} -> {}", "move", from, to),
+                                vreg_in(to, pos).or_else(|| vreg_in(from, pos)),
+                            ),
+                            Edit::Remat { inst, vreg, to } => (
+                                format!("{:<7}inst{} -> {}", "remat", inst.index(), to),
+                                Some(vreg),
+                            ),
+                        };
+                        let _ = write!(s, "  {:<12}{}", point(pos), line);
+                        if let Some(vreg) = vreg {
+                            let _ = write!(s, "  ; {}", vreg);
+                        }
+                        s.push('\n');
+                    }
+                };
+                edits_at(&mut s, ProgPoint::before(inst));
+                let kind = if func.is_branch(inst) {
+                    "br"
+                } else if func.is_ret(inst) {
+                    "ret"
+                } else {
+                    "op"
+                };
+                let mut line = format!("  {:<12}{:<7}", format!("inst{}", inst.index()), kind);
+                for (i, cell) in cells(inst).iter().enumerate() {
+                    let _ = write!(line, "{:<width$}  ", cell, width = widths[i]);
+                }
+                let clobbers = func.inst_clobbers(inst);
+                if clobbers != PRegSet::empty() {
+                    let _ = write!(line, "; clobbers:");
+                    for preg in clobbers {
+                        let _ = write!(line, " {}", preg);
+                    }
+                }
+                s.push_str(line.trim_end());
+                s.push('\n');
+                edits_at(&mut s, ProgPoint::after(inst));
+            }
+        }
+
+        if !self.spillslot_classes.is_empty() {
+            s.push_str("spillslots:\n");
+        }
+        for &(slot, class) in &self.spillslot_classes {
+            let _ = write!(
+                s,
+                "  {:<12}{:<7}",
+                format!("{}", slot),
+                format!("{:?}", class)
+            );
+            let held = self
+                .spilled_ranges
+                .iter()
+                .filter(|&&(_, spilled, _)| spilled == slot);
+            for (i, &(vreg, _, range)) in held.enumerate() {
+                let sep = if i == 0 { "" } else { ", " };
+                let _ = write!(
+                    s,
+                    "{}{} {}..{}",
+                    sep,
+                    vreg,
+                    point(range.from),
+                    point(range.to)
+                );
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    /// The values held in registers at the entry of `block`, sorted
+    /// by vreg. Requires `RegallocOptions::collect_block_entry_state`.
+    pub fn block_reg_state(&self, block: Block) -> Vec<(PReg, VReg)> {
+        self.block_entry_locations(block)
+            .iter()
+            .filter_map(|&(_, vreg, alloc)| Some((alloc.as_reg()?, vreg)))
+            .collect()
+    }
+
+    /// Every value that is held in a spillslot somewhere, with the
+    /// slot and each stretch of code over which it is there. This
+    /// is the memory-side counterpart of the register allocations in
+    /// `allocs`, for generating spill metadata without scanning them.
+    pub fn spilled_vregs(&self) -> impl Iterator<Item = (VReg, SpillSlot, CodeRange)> + '_ {
+        self.spilled_ranges.iter().cloned()
+    }
+
+    /// Every live range of every vreg with the allocation holding it
+    /// there: the finest-grained view of where each value is, from
+    /// which the per-operand `allocs`, the moves between ranges and
+    /// views such as `spilled_vregs` all follow.
+    pub fn ranges(&self) -> impl Iterator<Item = (VReg, CodeRange, Allocation)> + '_ {
+        self.live_ranges.iter().cloned()
+    }
+
+    /// Where `vreg` lives over its lifetime: each stretch of code
+    /// with the allocation holding the value there, in order of
+    /// position, as finally split and spilled. This is the list of
+    /// `ranges` for one vreg, for building location lists for debug
+    /// info. Stretches in which a rematerialized value is held
+    /// nowhere are left out; see `ranges` for the rest of the caveats.
+    pub fn vreg_locations(&self, vreg: VReg) -> Vec<(CodeRange, Allocation)> {
+        let start = self
+            .live_ranges
+            .partition_point(|&(v, ..)| v.vreg() < vreg.vreg());
+        self.live_ranges[start..]
+            .iter()
+            .take_while(|&&(v, ..)| v.vreg() == vreg.vreg())
+            .filter(|&&(_, _, alloc)| !alloc.is_none())
+            .map(|&(_, range, alloc)| (range, alloc))
+            .collect()
+    }
+
+    /// Which vreg holds `preg` at `pos`, as finally allocated? `None`
+    /// if the register is free there, or held only by a fixed
+    /// reservation such as a clobber. This is the inverse of
+    /// `vreg_locations`, for explaining allocation decisions.
+    pub fn occupant_of(&self, preg: PReg, pos: ProgPoint) -> Option<VReg> {
+        self.live_ranges
+            .iter()
+            .find(|&&(_, range, alloc)| alloc == Allocation::reg(preg) && range.contains_point(pos))
+            .map(|&(vreg, ..)| vreg)
+    }
+
+    /// Are `a` and `b` ever live at the same point, by the
+    /// allocator's own liveness? If not, they could have shared a
+    /// register throughout, e.g. for deciding whether to coalesce
+    /// them.
+    pub fn vregs_interfere(&self, a: VReg, b: VReg) -> bool {
+        let ranges = |vreg: VReg| -> Vec<CodeRange> {
+            let start = self
+                .live_ranges
+                .partition_point(|&(v, ..)| v.vreg() < vreg.vreg());
+            self.live_ranges[start..]
+                .iter()
+                .take_while(|&&(v, ..)| v.vreg() == vreg.vreg())
+                .map(|&(_, range, _)| range)
+                .collect()
+        };
+        ion::sorted_ranges_overlap(&ranges(a), &ranges(b))
+    }
+
+    /// The splits of bundles holding ranges of `vreg`, in the order
+    /// they were made; following the pieces from one to the next
+    /// shows how it came to be in the places `ranges` reports.
+    /// Requires `RegallocOptions::collect_split_events`.
+    pub fn split_history(&self, vreg: VReg) -> Vec<SplitEvent> {
+        self.split_events
+            .iter()
+            .filter(|event| {
+                event
+                    .pieces
+                    .iter()
+                    .any(|piece| piece.ranges.iter().any(|&(v, _)| v == vreg))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// How many distinct instructions have at least one edit
+    /// before or after them: a rough measure of the code growth due
+    /// to allocation.
+    pub fn inst_with_edits_count(&self) -> usize {
+        let mut count = 0;
+        let mut last = None;
+        for &(pos, _) in &self.edits {
+            if last != Some(pos.inst()) {
+                count += 1;
+                last = Some(pos.inst());
+            }
+        }
+        count
+    }
+
+    /// The number of edits of each kind, indexed by `MoveKind as
+    /// usize`.
+    pub fn edit_histogram(&self) -> [usize; 5] {
+        let mut counts = [0; 5];
+        for (_, edit) in &self.edits {
+            counts[edit.kind() as usize] += 1;
+        }
+        counts
+    }
+
+    /// A downsampled register-pressure series for `class`: the
+    /// instructions are split into `buckets` contiguous, nearly
+    /// equal stretches, and each entry is the largest number of
+    /// registers of `class` holding a value anywhere in its stretch
+    /// (see `live_regs_by_inst`). If there are fewer instructions
+    /// than buckets, some buckets are empty and report zero.
+    pub fn pressure_profile(&self, class: RegClass, buckets: usize) -> Vec<usize> {
+        let num_insts = self.live_regs_by_inst.len();
+        (0..buckets)
+            .map(|b| {
+                let start = b * num_insts / buckets;
+                let end = (b + 1) * num_insts / buckets;
+                self.live_regs_by_inst[start..end]
+                    .iter()
+                    .map(|live| live[class as usize] as usize)
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// The spillslots that hold values of `class`, in allocation
+    /// order. No slot appears for more than one class.
+    pub fn spillslots_of_class(&self, class: RegClass) -> impl Iterator<Item = SpillSlot> + '_ {
+        self.spillslot_classes
+            .iter()
+            .filter(move |&&(_, c)| c == class)
+            .map(|&(slot, _)| slot)
+    }
+
+    /// The values held in spillslots at the entry of `block`, sorted
+    /// by vreg. Requires `RegallocOptions::collect_block_entry_state`.
+    pub fn block_spill_state(&self, block: Block) -> Vec<(SpillSlot, VReg)> {
+        self.block_entry_locations(block)
+            .iter()
+            .filter_map(|&(_, vreg, alloc)| Some((alloc.as_stack()?, vreg)))
+            .collect()
+    }
+
+    /// List what occupies `preg`, in program order: each range
+    /// allocated to it with the vreg holding it, or `None` for a
+    /// fixed reservation, exactly as the allocator's own map for the
+    /// register held them at the end. This shows whether a conflict
+    /// reported while allocating was real. Requires
+    /// `RegallocOptions::collect_preg_maps`.
+    pub fn preg_map(&self, preg: PReg) -> Vec<(CodeRange, Option<VReg>)> {
+        let start = self
+            .preg_maps
+            .partition_point(|&(p, ..)| p.index() < preg.index());
+        self.preg_maps[start..]
+            .iter()
+            .take_while(|&&(p, ..)| p == preg)
+            .map(|&(_, range, vreg)| (range, vreg))
+            .collect()
+    }
+
+    fn block_entry_locations(&self, block: Block) -> &[(Block, VReg, Allocation)] {
+        let start = self
+            .block_entry_locations
+            .binary_search_by(|&(b, _, _)| {
+                if b < block {
+                    core::cmp::Ordering::Less
+                } else {
+                    core::cmp::Ordering::Greater
+                }
+            })
+            .unwrap_err();
+        let len = self.block_entry_locations[start..]
+            .iter()
+            .take_while(|&&(b, _, _)| b == block)
+            .count();
+        &self.block_entry_locations[start..start + len]
+    }
+
+    /// For the given spill slot, count how many reload edits move it
+    /// into each physical register. The result is sorted by
+    /// descending count, with ties broken by register index.
+    pub fn reload_targets(&self, slot: SpillSlot) -> Vec<(PReg, usize)> {
+        let mut targets: Vec<(PReg, usize)> = Vec::new();
+        for (_, edit) in &self.edits {
+            let (from, to) = match edit {
+                Edit::Move { from, to } => (from, to),
+                Edit::Remat { .. } => continue,
+            };
+            if from.as_stack() != Some(slot) {
+                continue;
+            }
+            if let Some(preg) = to.as_reg() {
+                match targets.iter_mut().find(|(p, _)| *p == preg) {
+                    Some((_, count)) => *count += 1,
+                    None => targets.push((preg, 1)),
+                }
+            }
+        }
+        targets.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.index().cmp(&b.0.index())));
+        targets
+    }
 }
 
 /// An error that prevents allocation.
@@ -1514,9 +2966,60 @@ pub enum RegAllocError {
     /// places a use after the edge moves occur; insert an edge block
     /// to avoid the situation.
     DisallowedBranchArg(Inst),
+    /// A reference-typed VReg is defined at the early point of a
+    /// safepoint instruction with a register constraint. Such a
+    /// value is live at the safepoint, so it must be placed on the
+    /// stack; give the def an `Any` or `Stack` constraint instead.
+    RefDefAtSafepoint(VReg, Inst),
+    /// A reference-typed VReg is live at the given safepoint but is
+    /// not in a spillslot there, although
+    /// `RegallocOptions::reftype_spill_policy` is `StackOnly`. This
+    /// is an internal error: the stackmap would be missing the
+    /// reference, so it is reported rather than returned incomplete.
+    /// The instruction is invalid if liveness recorded no safepoints
+    /// for the VReg at all, because `Function::reftype_vregs` did not
+    /// list it then.
+    RefNotOnStackAtSafepoint(VReg, Inst),
     /// Too many pinned VRegs + Reg-constrained Operands are live at
     /// once, making allocation impossible.
-    TooManyLiveRegs,
+    TooManyLiveRegs {
+        /// The point at which a value needing a register found none
+        /// free: the start of the minimal bundle that failed, at an
+        /// instruction where one of its operands requires a register.
+        point: ProgPoint,
+        /// The register class that ran out.
+        class: RegClass,
+        /// Number of fixed reservations (clobbers and fixed-register
+        /// operands) overlapping the failing point.
+        fixed: usize,
+        /// Number of minimal bundles already holding a register at
+        /// the failing point.
+        minimal: usize,
+        /// Total number of allocatable registers in the class.
+        total: usize,
+    },
+    /// A bundle of the given VReg has used up the split budget given
+    /// by `Function::max_splits` and could not evict the bundles
+    /// occupying the registers it needs.
+    SplitLimitExceeded(VReg),
+    /// The given bundle, holding ranges of the given VReg, could not
+    /// be placed within the number of attempts allowed by
+    /// `RegallocOptions::max_bundle_attempts`. The bundle is numbered
+    /// as in `SplitEvent` and `VregTrace`.
+    AllocationBudgetExceeded(LiveBundleIndex, VReg),
+    /// The uses tied to one group at the given instruction are of
+    /// different vregs; see `OperandConstraint::Tied`.
+    TiedOperands(Inst),
+    /// `RegallocOptions::objective` has a weight that is zero,
+    /// negative or not finite.
+    InvalidObjectiveWeights,
+    /// An instruction for which `Function::spill_all_at` holds has an
+    /// operand that must be in an allocatable register.
+    RegOperandAtSpillAll(Inst),
+    /// The given VReg is listed in `Function::entry_live_regs` twice,
+    /// in a register another entry takes or of another class, or is
+    /// out of range.
+    InvalidEntryLiveReg(VReg),
 }
 
 impl core::fmt::Display for RegAllocError {
@@ -1528,21 +3031,646 @@ impl core::fmt::Display for RegAllocError {
 #[cfg(feature = "std")]
 impl std::error::Error for RegAllocError {}
 
+/// Run the allocator in single-pass mode, trading allocation quality
+/// for speed. This is `run` with `RegallocOptions::single_pass` set.
+pub fn run_fast<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+) -> Result<Output, RegAllocError> {
+    let options = RegallocOptions {
+        single_pass: true,
+        ..*options
+    };
+    ion::run(func, env, &options, None)
+}
+
+/// A split point chosen by the allocator, as passed to the hook given
+/// to [`run_with_split_hook`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SplitProposal {
+    /// Start of the bundle being split (inclusive).
+    pub bundle_from: ProgPoint,
+    /// End of the bundle being split (exclusive).
+    pub bundle_to: ProgPoint,
+    /// The proposed split point, after hoisting out of loops and
+    /// cold blocks.
+    pub point: ProgPoint,
+    /// Approximate loop depth of the block where the bundle starts.
+    pub bundle_from_loop_depth: u32,
+    /// Approximate loop depth of the block containing `point`.
+    pub point_loop_depth: u32,
+}
+
+/// A bundle waiting in the allocation queue, as passed to a
+/// [`QueueTieBreak`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueuedBundle {
+    /// From the start of the bundle's first live range to the end of
+    /// its last. The bundle may have holes in between.
+    pub range: CodeRange,
+    /// The number of uses (including defs) in the bundle.
+    pub num_uses: usize,
+    /// The vreg of the bundle's first live range.
+    pub vreg: VReg,
+}
+
+/// Orders bundles of equal priority in the allocation queue: of two
+/// such bundles, the one that compares greater is processed first;
+/// bundles that compare equal keep the default order. See
+/// [`run_with_queue_tie_break`].
+pub type QueueTieBreak<'a> = dyn Fn(&QueuedBundle, &QueuedBundle) -> core::cmp::Ordering + 'a;
+
 /// Run the allocator.
 pub fn run<F: Function>(
     func: &F,
     env: &MachineEnv,
     options: &RegallocOptions,
 ) -> Result<Output, RegAllocError> {
-    ion::run(func, env, options.verbose_log, options.validate_ssa)
+    ion::run(func, env, options, None)
+}
+
+/// Run the allocator as [`run`] does, and also keep its state, through
+/// which allocations can then be changed and the moves and other
+/// results regenerated from them (see [`RetainedAllocation`]). All of
+/// the allocator's data is kept alive with it, so this is only worth
+/// it when such changes are to be made.
+pub fn run_retained<'a, F: Function>(
+    func: &'a F,
+    env: &'a MachineEnv,
+    options: &RegallocOptions,
+) -> Result<(Output, RetainedAllocation<'a, F>), RegAllocError> {
+    ion::run_retained(func, env, options)
+}
+
+/// Run the allocator, calling `split_hook` each time a bundle is about
+/// to be split. The hook may return a different split point; it is
+/// clamped to `bundle_from..bundle_to` before being applied. Returning
+/// `proposal.point` unchanged gives the same result as [`run`].
+pub fn run_with_split_hook<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    split_hook: &mut dyn FnMut(SplitProposal) -> ProgPoint,
+) -> Result<Output, RegAllocError> {
+    ion::run(func, env, options, Some(split_hook))
+}
+
+/// Run the allocator, using `tie_break` to decide which of several
+/// queued bundles with the same priority is processed first. The
+/// priority itself is unchanged, but the order in which bundles are
+/// processed determines which of them get registers, so this affects
+/// the allocation as much as the priority does. As with
+/// `RegallocOptions::scan_seed`, a program at the very limit of the
+/// available registers may (rarely) fail with
+/// `RegAllocError::TooManyLiveRegs` under an order where the default
+/// one succeeds. A tie-break that returns `Ordering::Equal` for
+/// every pair gives the same result as [`run`]. Each time the queue
+/// is popped, all bundles at the top priority are compared, so this
+/// is slower than [`run`] and intended for experiments.
+pub fn run_with_queue_tie_break<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    tie_break: &QueueTieBreak,
+) -> Result<Output, RegAllocError> {
+    ion::run_with_queue_tie_break(func, env, options, tie_break)
+}
+
+/// Compute, for each register class, the largest number of vregs
+/// live at any single program point. This is a lower bound on the
+/// number of registers of that class needed to allocate `func`
+/// without spilling; fixed-register constraints and clobbers are not
+/// taken into account. The result is indexed by `RegClass as usize`.
+pub fn min_regs_required<F: Function>(func: &F) -> Result<[usize; 3], RegAllocError> {
+    ion::min_regs_required(func)
+}
+
+/// Combine the results of allocating the same function separately
+/// for each register class into one `Output`.
+///
+/// Each part is tagged with the class it allocated, and is expected
+/// to come from a view of the function in which the operands of
+/// every other class are replaced by [`Operand::unused`], so that all
+/// parts share one operand layout and each operand is allocated in
+/// the part for its class only. The register files of the classes
+/// must be disjoint (no aliases between them).
+///
+/// Spillslots are renumbered so that each part's slots follow those
+/// of the parts before it, in every region, starting at a multiple
+/// of the part's `Function::spillslot_size` so that its slots stay
+/// aligned as they were allocated. Edits at the same
+/// program point touch disjoint locations, so they are kept in part
+/// order. The allocator statistics are not combined; `stats` is left
+/// at its default.
+pub fn merge_outputs<F: Function>(func: &F, parts: Vec<(RegClass, Output)>) -> Output {
+    let mut merged: Option<Output> = None;
+    for (class, mut part) in parts {
+        let out = match merged.as_mut() {
+            Some(out) => out,
+            None => {
+                part.stats = Stats::default();
+                merged = Some(part);
+                continue;
+            }
+        };
+        assert_eq!(
+            out.inst_alloc_offsets, part.inst_alloc_offsets,
+            "parts must share one operand layout"
+        );
+        debug_assert!(part.spillslot_classes.iter().all(|&(_, c)| c == class));
+
+        let size = func.spillslot_size(class);
+        let base = out
+            .num_spillslots_by_region
+            .map(|count| match count % size {
+                0 => count,
+                rem => count + size - rem,
+            });
+        part.remap_spillslots(|slot| {
+            let region = slot.region();
+            SpillSlot::new(base[region as usize] + slot.index()).with_region(region)
+        });
+        // The remapped counts include the slots placed before the
+        // part's own, unless it has none in a region.
+        for (count, remapped) in out
+            .num_spillslots_by_region
+            .iter_mut()
+            .zip(part.num_spillslots_by_region)
+        {
+            *count = core::cmp::max(*count, remapped);
+        }
+        out.num_spillslots = out.num_spillslots_by_region[SpillRegion::Frame as usize];
+
+        for (alloc, &other) in out.allocs.iter_mut().zip(&part.allocs) {
+            if alloc.is_none() {
+                *alloc = other;
+            }
+        }
+        for (live, other) in out
+            .live_regs_by_inst
+            .iter_mut()
+            .zip(&part.live_regs_by_inst)
+        {
+            live[class as usize] = other[class as usize];
+        }
+        out.edits.extend(part.edits);
+        out.safepoint_slots.extend(part.safepoint_slots);
+        out.debug_locations.extend(part.debug_locations);
+        out.split_events.extend(part.split_events);
+        out.block_entry_locations.extend(part.block_entry_locations);
+        out.preg_maps.extend(part.preg_maps);
+        out.spilled_ranges.extend(part.spilled_ranges);
+        out.live_ranges.extend(part.live_ranges);
+        out.spillslot_classes.extend(part.spillslot_classes);
+        out.bundle_requirements.extend(part.bundle_requirements);
+        out.loop_resident_fallbacks
+            .extend(part.loop_resident_fallbacks);
+        out.spill_reasons.extend(part.spill_reasons);
+        // Only the part for the traced vreg's class saw its ranges.
+        if out.vreg_trace.is_none()
+            || matches!(&part.vreg_trace, Some(trace) if trace.vreg.class() == class)
+        {
+            out.vreg_trace = part.vreg_trace;
+        }
+    }
+    let mut out = merged.expect("at least one part to merge");
+
+    // Restore the orderings that each part guarantees on its own.
+    // The edit sort is stable, keeping each part's sequence of moves
+    // at a program point intact.
+    out.edits.sort_by_key(|&(pos, _)| pos);
+    out.safepoint_slots
+        .sort_unstable_by_key(|&(pos, alloc)| (pos, alloc.bits()));
+    out.debug_locations
+        .sort_unstable_by_key(|&(label, from, to, alloc)| (label, from, to, alloc.bits()));
+    out.block_entry_locations
+        .sort_by_key(|&(block, vreg, _)| (block, vreg));
+    out.preg_maps
+        .sort_by_key(|&(preg, range, _)| (preg.index(), range.from));
+    out.spilled_ranges
+        .sort_by_key(|&(vreg, _, range)| (vreg, range.from));
+    out.live_ranges
+        .sort_by_key(|&(vreg, range, _)| (vreg, range.from));
+    // Requests naming a non-header block are reported by every part.
+    out.loop_resident_fallbacks.sort_unstable();
+    out.loop_resident_fallbacks.dedup();
+    out.spill_reasons.sort_unstable();
+    out
 }
 
 /// Options for allocation.
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug)]
 pub struct RegallocOptions {
     /// Add extra verbosity to debug logs.
     pub verbose_log: bool,
 
     /// Run the SSA validator before allocating registers.
     pub validate_ssa: bool,
+
+    /// If set, any bundle spanning more than this many instructions
+    /// is split at a block boundary before we try to find a register
+    /// for it, so that very long live ranges are handled as smaller
+    /// pieces. These proactive splits count against the per-value
+    /// split budget; once that is exhausted, long bundles are
+    /// allocated as usual. `None` (the default) means no cap.
+    pub max_unsplit_range_length: Option<usize>,
+
+    /// Relative importance of spills versus register-to-register
+    /// moves when deciding whether and where to split. See
+    /// [`ObjectiveWeights::move_cost_scale`] to keep split moves out
+    /// of hot blocks.
+    pub objective: ObjectiveWeights,
+
+    /// The cost model giving the spill weight of each use, in place
+    /// of the built-in one. `None` (the default) uses the built-in
+    /// model.
+    pub spill_weight_model: Option<&'static dyn SpillWeightModel>,
+
+    /// The order in which registers are tried for a bundle, in place
+    /// of the built-in scan (see `RegScanMode`) and of
+    /// `Function::reg_preference_order`. `None` (the default) uses
+    /// the built-in scan.
+    pub reg_traversal_order: Option<&'static dyn RegTraversalOrder>,
+
+    /// Margin by which a bundle's spill weight must exceed that of
+    /// the bundles it conflicts with before it evicts them; within
+    /// the margin the incumbents keep their register and the new
+    /// bundle is split instead. This reduces churn from evictions
+    /// that gain little. The default of `0` evicts on any strictly
+    /// greater weight.
+    pub commit_threshold: u32,
+
+    /// Floor on the cost limit beyond which probing a register for a
+    /// bundle stops early. Once a conflict has been found, the scan
+    /// of each further register gives up as soon as the conflicts
+    /// there cost more than the cheapest seen so far; with a floor,
+    /// it only gives up once they also cost more than this, so that
+    /// the first few registers scanned do not cut the search short
+    /// on small costs. Giving up early only skips registers that
+    /// could not be chosen anyway, so this changes how much is
+    /// scanned rather than the allocation; it is meant for
+    /// experimentation. The default of `0` leaves the limit as
+    /// computed.
+    pub min_high_cost_threshold: u32,
+
+    /// Limit on the time spent in the main allocation loop. Once it
+    /// is exceeded, the allocator stops backtracking: every bundle
+    /// still waiting in the queue is split directly into minimal
+    /// bundles around its uses, with the rest of it spilled. The
+    /// result is still a correct allocation, but of lower quality.
+    /// Only honored when the `std` feature is enabled.
+    pub time_budget: Option<core::time::Duration>,
+
+    /// Limit on the number of attempts to place any one bundle, each
+    /// of which probes the registers and then evicts, splits or
+    /// spills. Allocation fails with
+    /// `RegAllocError::AllocationBudgetExceeded` once a bundle has
+    /// used them all, bounding the work done on pathological inputs
+    /// even in release builds. A bundle's pieces after a split each
+    /// start afresh. `None` (the default) means no limit.
+    pub max_bundle_attempts: Option<usize>,
+
+    /// Record every bundle split in `Output::split_events`, with the
+    /// ranges of the resulting bundles.
+    pub collect_split_events: bool,
+
+    /// After allocation, recombine adjacent register-allocated pieces
+    /// of the same split value when one of the two registers is free
+    /// over the other piece, removing the move between them.
+    pub remerge: bool,
+
+    /// Store a value that is spilled anywhere to its spillslot once,
+    /// immediately after its def, rather than at each point where it
+    /// moves from a register to the stack. Later moves into the slot
+    /// are then unnecessary and are not emitted. Reference-typed
+    /// values are always stored where they move to the stack.
+    pub eager_spill_defs: bool,
+
+    /// Where the scan for a free register starts: rotated by the
+    /// bundle and its position (`Spread`, the default) or always at
+    /// the hint and then the first register (`PackLow`). Either may
+    /// be perturbed reproducibly from a seed with `scan_seed`.
+    pub scan_mode: RegScanMode,
+
+    /// Where reference-typed values may be at safepoints, and so
+    /// which locations `Output::safepoint_slots` reports.
+    pub reftype_spill_policy: ReftypeSpillPolicy,
+
+    /// Perturb where the scan for a free register starts, by an
+    /// amount derived deterministically from this seed and from the
+    /// position the scan would otherwise start at. Each seed gives a
+    /// reproducible allocation; varying it explores different
+    /// register assignments, e.g. to fuzz the allocator or code that
+    /// consumes its output for order dependence. For programs at the
+    /// very limit of the available registers, a seed may (rarely)
+    /// lead to `RegAllocError::TooManyLiveRegs` where the unperturbed
+    /// scan succeeds. `None` (the default) leaves the scan order
+    /// unperturbed.
+    pub scan_seed: Option<u64>,
+
+    /// Record the location of each value live at the entry of each
+    /// block in `Output::block_entry_locations`.
+    pub collect_block_entry_state: bool,
+
+    /// Record what occupies each physical register at the end of
+    /// allocation in `Output::preg_maps`, for debugging conflicts.
+    pub collect_preg_maps: bool,
+
+    /// Record the requirement computed for each bundle in
+    /// `Output::bundle_requirements`, for debugging constraints.
+    pub collect_bundle_requirements: bool,
+
+    /// Record why each vreg's ranges were sent to the spill path in
+    /// `Output::spill_reasons`, for tracking down unexpected spills.
+    pub collect_spill_reasons: bool,
+
+    /// Record, in `Output::vreg_trace`, every allocation decision
+    /// made about the bundles holding this vreg's live ranges, and
+    /// where each range ended up. Only this vreg's bundles are
+    /// followed, so this is cheap even for large functions.
+    pub trace_vreg: Option<VReg>,
+
+    /// When splitting a bundle, move the stretches before its first
+    /// use and after its last use, where it is merely live, into the
+    /// spill bundle (the default). When unset, the value instead stays
+    /// in its register across those stretches, which avoids spill
+    /// bundles and the moves around them at the cost of holding the
+    /// register longer. A value that has been split too many times is
+    /// still broken up around its uses with the rest spilled.
+    pub trim_empty_regions: bool,
+
+    /// Allocate in a single greedy pass: each bundle that needs a
+    /// register takes the first one that is free over its whole
+    /// extent, or is otherwise immediately broken up around its uses
+    /// with the rest spilled. Bundles are never evicted to make room
+    /// for them; instead, they are taken in order of spill weight
+    /// rather than size, so that those that would have won an
+    /// eviction come first. This saves the allocator's backtracking
+    /// but can produce more spills; see [`run_fast`].
+    pub single_pass: bool,
+
+    /// Allocate in two phases: first only the bundles that are
+    /// minimal or have a fixed-register constraint, since these
+    /// constrain everything else; then, with the bundles that got a
+    /// register in the first phase frozen so that other non-minimal
+    /// bundles cannot evict them, all the rest.
+    pub two_phase: bool,
+
+    /// Keep down the number of distinct non-preferred (normally
+    /// callee-saved) registers used, each of which costs a save and
+    /// restore in the prologue and epilogue: once a non-preferred
+    /// register holds any value, it is tried before the other
+    /// non-preferred registers, as for those in
+    /// `MachineEnv::already_saved`. Preferred registers are still
+    /// tried first.
+    pub minimize_callee_saved: bool,
+
+    /// For a bundle that is live across instructions with clobbers
+    /// (normally calls), try the registers none of those
+    /// instructions clobber (normally the callee-saved ones) before
+    /// the rest, instead of the usual preferred-first scan. The
+    /// clobbered registers can never hold the whole bundle, so this
+    /// mostly saves probing them, and breaks ties between equally
+    /// good choices in favor of registers that survive the calls.
+    pub prefer_callee_saved_across_calls: bool,
+
+    /// Which bundles count as minimal: these get a spill weight above
+    /// every other bundle's (`MinimalBundleGranularity` explains
+    /// which), and so are never evicted by ordinary bundles. A bundle
+    /// covering a single instruction can never be split, whatever
+    /// this says.
+    pub minimal_bundle_granularity: MinimalBundleGranularity,
+
+    /// For each class, an allocatable register to try first as the
+    /// scratch for breaking a cycle of moves (or for a
+    /// stack-to-stack move), where it is free, before scanning the
+    /// others. A dedicated `MachineEnv::scratch_by_class` register
+    /// still takes precedence. `None` (the default) keeps the scan.
+    pub preferred_scratch_by_class: [Option<PReg>; 3],
+}
+
+impl Default for RegallocOptions {
+    fn default() -> Self {
+        Self {
+            verbose_log: false,
+            validate_ssa: false,
+            max_unsplit_range_length: None,
+            objective: ObjectiveWeights::default(),
+            spill_weight_model: None,
+            reg_traversal_order: None,
+            commit_threshold: 0,
+            min_high_cost_threshold: 0,
+            time_budget: None,
+            max_bundle_attempts: None,
+            collect_split_events: false,
+            remerge: false,
+            eager_spill_defs: false,
+            scan_mode: RegScanMode::default(),
+            reftype_spill_policy: ReftypeSpillPolicy::default(),
+            scan_seed: None,
+            collect_block_entry_state: false,
+            collect_preg_maps: false,
+            collect_bundle_requirements: false,
+            collect_spill_reasons: false,
+            trace_vreg: None,
+            trim_empty_regions: true,
+            single_pass: false,
+            two_phase: false,
+            minimize_callee_saved: false,
+            prefer_callee_saved_across_calls: false,
+            minimal_bundle_granularity: MinimalBundleGranularity::default(),
+            preferred_scratch_by_class: [None, None, None],
+        }
+    }
+}
+
+/// Relative weights of the two kinds of cost the allocator trades
+/// off against each other.
+///
+/// When a bundle cannot be placed, the allocator either evicts the
+/// conflicting bundles (which may then be spilled) or splits the
+/// current bundle (which introduces register-to-register moves at the
+/// split points). Lowering `reg_move` relative to `spill` makes the
+/// allocator split more eagerly instead of evicting. A bundle never
+/// evicts a conflict set at least as heavy as itself, regardless of
+/// the weights, so that allocation always terminates; as any lighter
+/// set is already evicted by default, raising `reg_move` does not
+/// turn splits into evictions, but makes split points with expensive
+/// moves less attractive when choosing where to split.
+///
+/// The weights also set how eagerly a bundle is coalesced with the
+/// values it is moved to or from: when choosing which bundles to
+/// evict, the cost of evicting from the bundle's hinted register is
+/// scaled by `spill / reg_move`, so that raising `reg_move` favors
+/// taking the hinted register, saving a move, over evicting lighter
+/// bundles elsewhere.
+///
+/// Only the ratio matters; the default of `1.0` for both reproduces
+/// the unweighted behavior. Both weights must be finite and greater
+/// than zero, or allocation fails with
+/// `RegAllocError::InvalidObjectiveWeights`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ObjectiveWeights {
+    /// Weight applied to the spill cost of evicted bundles.
+    pub spill: f32,
+    /// Weight applied to the cost of moves introduced by splitting.
+    pub reg_move: f32,
+}
+
+impl ObjectiveWeights {
+    /// Are both weights finite and greater than zero?
+    pub fn is_valid(&self) -> bool {
+        [self.spill, self.reg_move]
+            .iter()
+            .all(|w| w.is_finite() && *w > 0.0)
+    }
+
+    /// Weights that scale the cost of each move introduced by
+    /// splitting by `scale`, leaving spill cost as is. Values below
+    /// `1.0` make splitting more attractive than evicting. Values
+    /// above it weigh the move at each candidate split point more
+    /// heavily against the weight of the bundles in conflict there,
+    /// so that a bundle splits where the move lands in a colder block
+    /// even if that means splitting around heavier bundles. They do
+    /// not make a bundle evict more: it still splits rather than
+    /// evict a conflict set at least as heavy as itself.
+    pub fn move_cost_scale(scale: f32) -> Self {
+        Self {
+            spill: 1.0,
+            reg_move: scale,
+        }
+    }
+}
+
+impl Default for ObjectiveWeights {
+    fn default() -> Self {
+        Self {
+            spill: 1.0,
+            reg_move: 1.0,
+        }
+    }
+}
+
+/// A cost model for spilling, giving the spill weight of each use of
+/// a value: how much keeping the value in a register at that use is
+/// worth. A bundle's weight, which decides whether it may evict
+/// others, is the sum of the weights of its uses divided by its
+/// length. Uses in cold blocks are scaled down afterwards.
+///
+/// The provided method is the allocator's built-in model, so an
+/// implementation need only override it where it differs.
+pub trait SpillWeightModel: core::fmt::Debug {
+    /// The spill weight of a use (or def, if `is_def`) with the given
+    /// constraint, in a block at the given (approximate) loop depth.
+    fn use_spill_weight(
+        &self,
+        constraint: OperandConstraint,
+        loop_depth: usize,
+        is_def: bool,
+    ) -> f32 {
+        ion::liveranges::spill_weight_from_constraint(constraint, loop_depth, is_def).to_f32()
+    }
+
+    /// As `use_spill_weight`, for a use in a block with a known
+    /// execution frequency (see `Function::block_frequency`), which is
+    /// used instead of the loop depth.
+    fn use_spill_weight_at_frequency(
+        &self,
+        constraint: OperandConstraint,
+        frequency: f32,
+        is_def: bool,
+    ) -> f32 {
+        ion::liveranges::spill_weight_at_frequency(constraint, frequency, is_def).to_f32()
+    }
+}
+
+/// A custom order in which the allocator tries registers when
+/// looking for one to hold a bundle, e.g. for a target where some
+/// registers give shorter instruction encodings than others.
+///
+/// A register fixed by an operand constraint is always the only
+/// candidate, and the order is not consulted for it. Registers
+/// outside the value's subclass (`Function::reg_subclass`) or
+/// reserved in a loop it is live in are skipped.
+pub trait RegTraversalOrder: core::fmt::Debug {
+    /// The registers of `class` to try, most desirable first, for a
+    /// bundle whose hint (usually the register it or a related value
+    /// was last allocated to) is `hint`, or `PReg::invalid()` if it
+    /// has none. Registers left out are never tried.
+    fn candidates(&self, class: RegClass, hint: PReg) -> Vec<PReg>;
+}
+
+/// Where reference-typed values live at safepoints (instructions
+/// for which `Function::requires_refs_on_stack` is true).
+///
+/// In every policy, a reference-typed value defined early by the
+/// safepoint itself is defined on the stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReftypeSpillPolicy {
+    /// Every live reference is in a spillslot (or a fixed stack-slot
+    /// preg) at each safepoint, and the stackmap lists only these.
+    StackOnly,
+    /// Live references may stay in registers across safepoints; the
+    /// stackmap lists each one's location, register or spillslot.
+    /// The client must be able to find (and, for a moving collector,
+    /// update) references in registers.
+    RegisterAllowed,
+    /// As `RegisterAllowed`, but a reference in a register at a
+    /// safepoint is also stored to its spillslot just before it, and
+    /// the stackmap lists both locations, so that every live
+    /// reference has a memory location. The register copy is still
+    /// the one used afterward.
+    AlwaysBackedBySlot,
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for ReftypeSpillPolicy {
+    fn default() -> Self {
+        ReftypeSpillPolicy::StackOnly
+    }
+}
+
+/// The order in which registers are tried when looking for one that
+/// is free for a bundle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegScanMode {
+    /// Start each scan at an offset derived from the bundle and its
+    /// position in the code, spreading demand evenly across
+    /// registers to reduce contention.
+    Spread,
+    /// Always start at the first preferred register and fill
+    /// registers densely in `MachineEnv` order. This touches fewer
+    /// registers, which suits small functions: fewer callee-saves
+    /// and better locality in the allocator's own data structures.
+    PackLow,
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for RegScanMode {
+    fn default() -> Self {
+        RegScanMode::Spread
+    }
+}
+
+/// How finely `RegallocOptions::minimal_bundle_granularity` draws
+/// the line between minimal bundles and the rest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MinimalBundleGranularity {
+    /// A bundle is minimal if it covers a single instruction, either
+    /// one program point of it or both.
+    Inst,
+    /// A bundle is minimal only if it covers a single program point.
+    /// One spanning both points of an instruction, e.g. a use that
+    /// stays live into the next instruction, first competes on its
+    /// ordinary spill weight, evicting only lighter bundles; it
+    /// becomes minimal only if it cannot be placed that way.
+    ProgPoint,
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for MinimalBundleGranularity {
+    fn default() -> Self {
+        MinimalBundleGranularity::Inst
+    }
 }
@@ -453,3 +453,40 @@ where
         (self.is_stack_alloc)(src) && (self.is_stack_alloc)(dst)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::RegClass;
+
+    fn resolve_in_order(moves: &[(Allocation, Allocation)]) -> MoveVec<()> {
+        let mut parallel_moves = ParallelMoves::new();
+        for &(from, to) in moves {
+            parallel_moves.add(from, to, ());
+        }
+        match parallel_moves.resolve() {
+            MoveVecWithScratch::NoScratch(moves) | MoveVecWithScratch::Scratch(moves) => moves,
+        }
+    }
+
+    #[test]
+    fn resolution_ignores_insertion_order() {
+        let r = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+        // A three-element cycle plus a chain hanging off of it.
+        let moves = [
+            (r(0), r(1)),
+            (r(1), r(2)),
+            (r(2), r(0)),
+            (r(2), r(3)),
+            (r(3), r(4)),
+        ];
+        let expected = resolve_in_order(&moves);
+        let mut permuted = moves;
+        for _ in 0..moves.len() {
+            permuted.rotate_left(1);
+            assert_eq!(resolve_in_order(&permuted), expected);
+            permuted.reverse();
+            assert_eq!(resolve_in_order(&permuted), expected);
+        }
+    }
+}
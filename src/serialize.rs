@@ -3,7 +3,10 @@ use core::fmt;
 use alloc::{format, string::ToString, vec::Vec};
 use serde::{Deserialize, Serialize};
 
-use crate::{Block, Function, Inst, InstRange, MachineEnv, Operand, PRegSet, RegClass, VReg};
+use crate::{
+    Block, CodeRange, Function, Inst, InstRange, MachineEnv, Operand, PReg, PRegSet, RegClass,
+    RematInfo, SpillRegion, VReg,
+};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 enum InstOpcode {
@@ -27,7 +30,35 @@ struct InstData {
     op: InstOpcode,
     operands: Vec<Operand>,
     clobbers: PRegSet,
+    #[serde(default)]
+    forbidden: PRegSet,
     is_safepoint: bool,
+    #[serde(default)]
+    spill_all: bool,
+    #[serde(default = "default_latency")]
+    latency: u32,
+    #[serde(default)]
+    remat: Option<RematInfo>,
+}
+
+/// The default of `Function::inst_latency`, for data serialized
+/// before it was recorded.
+fn default_latency() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct VRegData {
+    #[serde(default)]
+    spill_region: SpillRegion,
+    #[serde(default)]
+    subclass: Option<usize>,
+    #[serde(default)]
+    fixed_preg: Option<PReg>,
+    #[serde(default)]
+    max_splits: Option<u32>,
+    #[serde(default)]
+    allocation_group: Option<u32>,
 }
 
 /// A wrapper around a `Function` and `MachineEnv` that can be serialized and
@@ -46,18 +77,54 @@ pub struct SerializableFunction {
     block_succs: Vec<Vec<Block>>,
     block_params_in: Vec<Vec<VReg>>,
     block_params_out: Vec<Vec<Vec<VReg>>>,
+    #[serde(default)]
+    cold_blocks: Vec<bool>,
+    #[serde(default)]
+    block_frequencies: Vec<Option<f32>>,
+    #[serde(default)]
+    loop_reg_reserve: Vec<Option<(RegClass, usize)>>,
     num_vregs: usize,
+    #[serde(default)]
+    vregs: Vec<VRegData>,
     reftype_vregs: Vec<VReg>,
+    #[serde(default)]
+    entry_live_regs: Vec<(VReg, PReg)>,
     debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    #[serde(default)]
+    reserved_preg_ranges: Vec<(PReg, CodeRange)>,
     spillslot_size: Vec<usize>,
     multi_spillslot_named_by_last_slot: bool,
+    #[serde(default)]
+    stack_affinities: Vec<(VReg, VReg)>,
     allow_multiple_vreg_defs: bool,
+    #[serde(default)]
+    reg_preference_order: Vec<Option<Vec<PReg>>>,
+    #[serde(default)]
+    loop_resident: Vec<(VReg, Block)>,
 }
 
 impl SerializableFunction {
     /// Creates a new `SerializableFunction` from an arbitrary `Function` and
     /// `MachineEnv`.
     pub fn new(func: &impl Function, machine_env: MachineEnv) -> Self {
+        // The per-vreg hooks take a `VReg`, so recover the class of
+        // each one from where it is mentioned.
+        let mut vregs: Vec<VReg> = (0..func.num_vregs())
+            .map(|i| VReg::new(i, RegClass::Int))
+            .collect();
+        for i in 0..func.num_blocks() {
+            let block = Block::new(i);
+            for &vreg in func.block_params(block) {
+                vregs[vreg.vreg()] = vreg;
+            }
+            for inst in func.block_insns(block).iter() {
+                for op in func.inst_operands(inst) {
+                    if let Some(vreg) = vregs.get_mut(op.vreg().vreg()) {
+                        *vreg = op.vreg();
+                    }
+                }
+            }
+        }
         Self {
             machine_env,
             entry_block: func.entry_block(),
@@ -75,7 +142,11 @@ impl SerializableFunction {
                         op,
                         operands: func.inst_operands(inst).to_vec(),
                         clobbers: func.inst_clobbers(inst),
+                        forbidden: func.inst_forbidden_pregs(inst),
                         is_safepoint: func.requires_refs_on_stack(inst),
+                        spill_all: func.spill_all_at(inst),
+                        latency: func.inst_latency(inst),
+                        remat: func.is_rematerializable(inst),
                     }
                 })
                 .collect(),
@@ -112,9 +183,30 @@ impl SerializableFunction {
                         .collect()
                 })
                 .collect(),
+            cold_blocks: (0..func.num_blocks())
+                .map(|i| func.is_cold_block(Block::new(i)))
+                .collect(),
+            block_frequencies: (0..func.num_blocks())
+                .map(|i| func.block_frequency(Block::new(i)))
+                .collect(),
+            loop_reg_reserve: (0..func.num_blocks())
+                .map(|i| func.loop_reg_reserve(Block::new(i)))
+                .collect(),
             num_vregs: func.num_vregs(),
+            vregs: vregs
+                .into_iter()
+                .map(|vreg| VRegData {
+                    spill_region: func.spill_region(vreg),
+                    subclass: func.reg_subclass(vreg),
+                    fixed_preg: func.vreg_fixed_preg(vreg),
+                    max_splits: func.max_splits(vreg),
+                    allocation_group: func.allocation_group(vreg),
+                })
+                .collect(),
             reftype_vregs: func.reftype_vregs().to_vec(),
+            entry_live_regs: func.entry_live_regs().to_vec(),
             debug_value_labels: func.debug_value_labels().to_vec(),
+            reserved_preg_ranges: func.reserved_preg_ranges().to_vec(),
             spillslot_size: [
                 func.spillslot_size(RegClass::Int),
                 func.spillslot_size(RegClass::Float),
@@ -122,7 +214,13 @@ impl SerializableFunction {
             ]
             .to_vec(),
             multi_spillslot_named_by_last_slot: func.multi_spillslot_named_by_last_slot(),
+            stack_affinities: func.stack_affinities().to_vec(),
             allow_multiple_vreg_defs: func.allow_multiple_vreg_defs(),
+            reg_preference_order: [RegClass::Int, RegClass::Float, RegClass::Vector]
+                .iter()
+                .map(|&class| func.reg_preference_order(class).map(|order| order.to_vec()))
+                .collect(),
+            loop_resident: func.loop_resident().to_vec(),
         }
     }
 
@@ -177,6 +275,21 @@ impl Function for SerializableFunction {
         self.insts[insn.index()].is_safepoint
     }
 
+    fn is_cold_block(&self, block: Block) -> bool {
+        self.cold_blocks
+            .get(block.index())
+            .copied()
+            .unwrap_or(false)
+    }
+
+    fn block_frequency(&self, block: Block) -> Option<f32> {
+        self.block_frequencies.get(block.index()).copied().flatten()
+    }
+
+    fn spill_all_at(&self, insn: Inst) -> bool {
+        self.insts[insn.index()].spill_all
+    }
+
     fn inst_operands(&self, insn: Inst) -> &[Operand] {
         &self.insts[insn.index()].operands[..]
     }
@@ -185,6 +298,10 @@ impl Function for SerializableFunction {
         self.insts[insn.index()].clobbers
     }
 
+    fn inst_forbidden_pregs(&self, insn: Inst) -> PRegSet {
+        self.insts[insn.index()].forbidden
+    }
+
     fn num_vregs(&self) -> usize {
         self.num_vregs
     }
@@ -193,10 +310,18 @@ impl Function for SerializableFunction {
         &self.reftype_vregs[..]
     }
 
+    fn entry_live_regs(&self) -> &[(VReg, PReg)] {
+        &self.entry_live_regs[..]
+    }
+
     fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
         &self.debug_value_labels[..]
     }
 
+    fn reserved_preg_ranges(&self) -> &[(PReg, CodeRange)] {
+        &self.reserved_preg_ranges[..]
+    }
+
     fn spillslot_size(&self, regclass: RegClass) -> usize {
         self.spillslot_size[regclass as usize]
     }
@@ -205,9 +330,62 @@ impl Function for SerializableFunction {
         self.multi_spillslot_named_by_last_slot
     }
 
+    fn stack_affinities(&self) -> &[(VReg, VReg)] {
+        &self.stack_affinities[..]
+    }
+
+    fn spill_region(&self, vreg: VReg) -> SpillRegion {
+        self.vregs
+            .get(vreg.vreg())
+            .map_or(SpillRegion::Frame, |data| data.spill_region)
+    }
+
     fn allow_multiple_vreg_defs(&self) -> bool {
         self.allow_multiple_vreg_defs
     }
+
+    fn reg_preference_order(&self, class: RegClass) -> Option<&[PReg]> {
+        self.reg_preference_order
+            .get(class as usize)
+            .and_then(|order| order.as_deref())
+    }
+
+    fn reg_subclass(&self, vreg: VReg) -> Option<usize> {
+        self.vregs.get(vreg.vreg()).and_then(|data| data.subclass)
+    }
+
+    fn vreg_fixed_preg(&self, vreg: VReg) -> Option<PReg> {
+        self.vregs.get(vreg.vreg()).and_then(|data| data.fixed_preg)
+    }
+
+    fn max_splits(&self, vreg: VReg) -> Option<u32> {
+        self.vregs.get(vreg.vreg()).and_then(|data| data.max_splits)
+    }
+
+    fn allocation_group(&self, vreg: VReg) -> Option<u32> {
+        self.vregs
+            .get(vreg.vreg())
+            .and_then(|data| data.allocation_group)
+    }
+
+    fn loop_reg_reserve(&self, loop_header: Block) -> Option<(RegClass, usize)> {
+        self.loop_reg_reserve
+            .get(loop_header.index())
+            .copied()
+            .flatten()
+    }
+
+    fn loop_resident(&self) -> &[(VReg, Block)] {
+        &self.loop_resident[..]
+    }
+
+    fn inst_latency(&self, insn: Inst) -> u32 {
+        self.insts[insn.index()].latency
+    }
+
+    fn is_rematerializable(&self, insn: Inst) -> Option<RematInfo> {
+        self.insts[insn.index()].remat
+    }
 }
 
 impl fmt::Debug for SerializableFunction {
@@ -284,14 +462,19 @@ impl fmt::Debug for SerializableFunction {
                     .map(|op| op.to_string())
                     .collect();
                 let ops = ops.join(", ");
-                let clobbers = if self.inst_clobbers(inst) == PRegSet::empty() {
+                let clobbers: Vec<_> = self
+                    .inst_clobbers(inst)
+                    .into_iter()
+                    .map(|preg| format!("Clobber: {preg}"))
+                    .chain(
+                        self.inst_forbidden_pregs(inst)
+                            .into_iter()
+                            .map(|preg| format!("Forbidden: {preg}")),
+                    )
+                    .collect();
+                let clobbers = if clobbers.is_empty() {
                     format!("")
                 } else {
-                    let clobbers: Vec<_> = self
-                        .inst_clobbers(inst)
-                        .into_iter()
-                        .map(|preg| format!("Clobber: {preg}"))
-                        .collect();
                     format!(", {}", clobbers.join(", "))
                 };
                 write!(
@@ -14,6 +14,9 @@ use crate::{Block, Function, Inst, OperandKind, RegAllocError, VReg};
 pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllocError> {
     // For every block param and inst def, check that this is the only def.
     let mut defined_in = vec![Block::invalid(); f.num_vregs()];
+    for &(vreg, _) in f.entry_live_regs() {
+        defined_in[vreg.vreg()] = f.entry_block();
+    }
     for block in 0..f.num_blocks() {
         let block = Block::new(block);
         let mut def = |vreg: VReg, inst| {
@@ -30,6 +33,9 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
         }
         for inst in f.block_insns(block).iter() {
             for operand in f.inst_operands(inst) {
+                if operand.as_fixed_nonallocatable().is_some() || operand.is_unused() {
+                    continue;
+                }
                 if let OperandKind::Def = operand.kind() {
                     def(operand.vreg(), inst)?;
                 }
@@ -46,13 +52,16 @@ pub fn validate_ssa<F: Function>(f: &F, cfginfo: &CFGInfo) -> Result<(), RegAllo
         let block = Block::new(block);
         local.clear();
         local.extend(f.block_params(block));
+        if block == f.entry_block() {
+            local.extend(f.entry_live_regs().iter().map(|&(vreg, _)| vreg));
+        }
 
         for iix in f.block_insns(block).iter() {
             let operands = f.inst_operands(iix);
             for operand in operands {
                 // Fixed registers uses will likely not be SSA, but they also
                 // won't receive assignments.
-                if operand.as_fixed_nonallocatable().is_some() {
+                if operand.as_fixed_nonallocatable().is_some() || operand.is_unused() {
                     continue;
                 }
 
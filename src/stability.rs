@@ -0,0 +1,281 @@
+/*
+ * Released under the terms of the Apache 2.0 license with LLVM
+ * exception. See `LICENSE` for details.
+ */
+
+//! Measuring how sensitive an allocation is to small changes in its
+//! input, e.g. for reproducible-build investigations.
+
+use alloc::vec::Vec;
+
+use crate::{
+    run, Block, CodeRange, Function, Inst, InstRange, MachineEnv, Operand, PReg, PRegSet,
+    ProgPoint, RegAllocError, RegClass, RegallocOptions, RematInfo, SpillRegion, VReg,
+};
+
+/// A small change to a function that should not affect its
+/// allocation much.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Perturbation {
+    /// Insert an instruction with no operands (a nop) just before
+    /// the given instruction, in the same block.
+    InsertNop(Inst),
+}
+
+/// How much the operand allocations of a function changed under each
+/// of a list of perturbations; see [`allocation_stability`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StabilityReport {
+    /// Number of operands in the unperturbed function.
+    pub operands: usize,
+    /// For each perturbation, in order, how many of those operands
+    /// were allocated differently in the perturbed function.
+    pub changed: Vec<usize>,
+}
+
+impl StabilityReport {
+    /// The fraction of operands whose allocation changed under the
+    /// `i`th perturbation.
+    pub fn changed_fraction(&self, i: usize) -> f64 {
+        if self.operands == 0 {
+            return 0.0;
+        }
+        self.changed[i] as f64 / self.operands as f64
+    }
+
+    /// The largest fraction of operands changed by any perturbation.
+    pub fn max_changed_fraction(&self) -> f64 {
+        (0..self.changed.len())
+            .map(|i| self.changed_fraction(i))
+            .fold(0.0, f64::max)
+    }
+}
+
+/// Allocate `func`, and then each variant of it given by
+/// `perturbations`, and report how many operand allocations differ
+/// between the base allocation and each variant. Operands are
+/// matched up by their original instruction, so a perturbation that
+/// only shifts instruction indices changes nothing by itself.
+pub fn allocation_stability<F: Function>(
+    func: &F,
+    env: &MachineEnv,
+    options: &RegallocOptions,
+    perturbations: &[Perturbation],
+) -> Result<StabilityReport, RegAllocError> {
+    let base = run(func, env, options)?;
+    let operands = (0..func.num_insts())
+        .map(|inst| base.inst_allocs(Inst::new(inst)).len())
+        .sum();
+    let mut changed = Vec::with_capacity(perturbations.len());
+    for &perturbation in perturbations {
+        let Perturbation::InsertNop(at) = perturbation;
+        let perturbed = NopInserted::new(func, at);
+        let out = run(&perturbed, env, options)?;
+        changed.push(
+            (0..func.num_insts())
+                .map(|inst| {
+                    let inst = Inst::new(inst);
+                    base.inst_allocs(inst)
+                        .iter()
+                        .zip(out.inst_allocs(perturbed.map_inst(inst)))
+                        .filter(|(a, b)| a != b)
+                        .count()
+                })
+                .sum(),
+        );
+    }
+    Ok(StabilityReport { operands, changed })
+}
+
+/// `func` with a nop inserted before instruction `at`.
+struct NopInserted<'a, F: Function> {
+    func: &'a F,
+    at: Inst,
+    debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    reserved_preg_ranges: Vec<(PReg, CodeRange)>,
+}
+
+impl<'a, F: Function> NopInserted<'a, F> {
+    fn new(func: &'a F, at: Inst) -> Self {
+        assert!(at.index() < func.num_insts());
+        let mut this = NopInserted {
+            func,
+            at,
+            debug_value_labels: Vec::new(),
+            reserved_preg_ranges: Vec::new(),
+        };
+        this.debug_value_labels = func
+            .debug_value_labels()
+            .iter()
+            .map(|&(vreg, from, to, label)| (vreg, this.map_inst(from), this.map_inst(to), label))
+            .collect();
+        this.reserved_preg_ranges = func
+            .reserved_preg_ranges()
+            .iter()
+            .map(|&(preg, range)| {
+                let map_point = |p: ProgPoint| ProgPoint::new(this.map_inst(p.inst()), p.pos());
+                let range = CodeRange {
+                    from: map_point(range.from),
+                    to: map_point(range.to),
+                };
+                (preg, range)
+            })
+            .collect();
+        this
+    }
+
+    /// The index of `func`'s instruction `inst` in this function.
+    fn map_inst(&self, inst: Inst) -> Inst {
+        if inst.index() < self.at.index() {
+            inst
+        } else {
+            inst.next()
+        }
+    }
+
+    /// The instruction of `func` at `inst`, or `None` for the nop.
+    fn orig_inst(&self, inst: Inst) -> Option<Inst> {
+        match inst.index().cmp(&self.at.index()) {
+            core::cmp::Ordering::Less => Some(inst),
+            core::cmp::Ordering::Equal => None,
+            core::cmp::Ordering::Greater => Some(inst.prev()),
+        }
+    }
+}
+
+impl<'a, F: Function> Function for NopInserted<'a, F> {
+    fn num_insts(&self) -> usize {
+        self.func.num_insts() + 1
+    }
+    fn num_blocks(&self) -> usize {
+        self.func.num_blocks()
+    }
+    fn entry_block(&self) -> Block {
+        self.func.entry_block()
+    }
+    fn block_insns(&self, block: Block) -> InstRange {
+        // The block containing `at` also contains the nop.
+        let range = self.func.block_insns(block);
+        let map_end = |inst: Inst| {
+            if inst.index() <= self.at.index() {
+                inst
+            } else {
+                inst.next()
+            }
+        };
+        let from = range.first();
+        let to = Inst::new(from.index() + range.len());
+        InstRange::new(map_end(from), map_end(to))
+    }
+    fn block_succs(&self, block: Block) -> &[Block] {
+        self.func.block_succs(block)
+    }
+    fn block_preds(&self, block: Block) -> &[Block] {
+        self.func.block_preds(block)
+    }
+    fn block_params(&self, block: Block) -> &[VReg] {
+        self.func.block_params(block)
+    }
+    fn is_ret(&self, insn: Inst) -> bool {
+        matches!(self.orig_inst(insn), Some(insn) if self.func.is_ret(insn))
+    }
+    fn is_branch(&self, insn: Inst) -> bool {
+        matches!(self.orig_inst(insn), Some(insn) if self.func.is_branch(insn))
+    }
+    fn branch_blockparams(&self, block: Block, insn: Inst, succ_idx: usize) -> &[VReg] {
+        match self.orig_inst(insn) {
+            Some(insn) => self.func.branch_blockparams(block, insn, succ_idx),
+            None => &[],
+        }
+    }
+    fn requires_refs_on_stack(&self, insn: Inst) -> bool {
+        matches!(self.orig_inst(insn), Some(insn) if self.func.requires_refs_on_stack(insn))
+    }
+    fn is_cold_block(&self, block: Block) -> bool {
+        self.func.is_cold_block(block)
+    }
+    fn block_frequency(&self, block: Block) -> Option<f32> {
+        self.func.block_frequency(block)
+    }
+    fn spill_all_at(&self, insn: Inst) -> bool {
+        matches!(self.orig_inst(insn), Some(insn) if self.func.spill_all_at(insn))
+    }
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        match self.orig_inst(insn) {
+            Some(insn) => self.func.inst_operands(insn),
+            None => &[],
+        }
+    }
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        match self.orig_inst(insn) {
+            Some(insn) => self.func.inst_clobbers(insn),
+            None => PRegSet::empty(),
+        }
+    }
+    fn inst_forbidden_pregs(&self, insn: Inst) -> PRegSet {
+        match self.orig_inst(insn) {
+            Some(insn) => self.func.inst_forbidden_pregs(insn),
+            None => PRegSet::empty(),
+        }
+    }
+    fn reserved_preg_ranges(&self) -> &[(PReg, CodeRange)] {
+        &self.reserved_preg_ranges
+    }
+    fn num_vregs(&self) -> usize {
+        self.func.num_vregs()
+    }
+    fn reftype_vregs(&self) -> &[VReg] {
+        self.func.reftype_vregs()
+    }
+    fn entry_live_regs(&self) -> &[(VReg, PReg)] {
+        self.func.entry_live_regs()
+    }
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        &self.debug_value_labels
+    }
+    fn spillslot_size(&self, regclass: RegClass) -> usize {
+        self.func.spillslot_size(regclass)
+    }
+    fn multi_spillslot_named_by_last_slot(&self) -> bool {
+        self.func.multi_spillslot_named_by_last_slot()
+    }
+    fn stack_affinities(&self) -> &[(VReg, VReg)] {
+        self.func.stack_affinities()
+    }
+    fn spill_region(&self, vreg: VReg) -> SpillRegion {
+        self.func.spill_region(vreg)
+    }
+    fn allow_multiple_vreg_defs(&self) -> bool {
+        self.func.allow_multiple_vreg_defs()
+    }
+    fn reg_preference_order(&self, class: RegClass) -> Option<&[PReg]> {
+        self.func.reg_preference_order(class)
+    }
+    fn reg_subclass(&self, vreg: VReg) -> Option<usize> {
+        self.func.reg_subclass(vreg)
+    }
+    fn vreg_fixed_preg(&self, vreg: VReg) -> Option<PReg> {
+        self.func.vreg_fixed_preg(vreg)
+    }
+    fn max_splits(&self, vreg: VReg) -> Option<u32> {
+        self.func.max_splits(vreg)
+    }
+    fn allocation_group(&self, vreg: VReg) -> Option<u32> {
+        self.func.allocation_group(vreg)
+    }
+    fn loop_reg_reserve(&self, loop_header: Block) -> Option<(RegClass, usize)> {
+        self.func.loop_reg_reserve(loop_header)
+    }
+    fn loop_resident(&self) -> &[(VReg, Block)] {
+        self.func.loop_resident()
+    }
+    fn inst_latency(&self, insn: Inst) -> u32 {
+        match self.orig_inst(insn) {
+            Some(insn) => self.func.inst_latency(insn),
+            None => 1,
+        }
+    }
+    fn is_rematerializable(&self, insn: Inst) -> Option<RematInfo> {
+        self.func.is_rematerializable(self.orig_inst(insn)?)
+    }
+}
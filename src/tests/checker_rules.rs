@@ -0,0 +1,128 @@
+//! Tests of the checker's own rules, run against allocations that
+//! were altered by hand.
+
+use super::*;
+use crate::checker::{Checker, CheckerError};
+
+#[test]
+fn test_interfering_vregs() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 and v1 are live together at inst2, and inst3 needs v0 in
+    // two registers at once.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v(0), r(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![
+                Operand::reg_fixed_use(v(0), r(0)),
+                Operand::reg_fixed_use(v(0), r(1)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert_eq!(out.inst_allocs(Inst::new(0))[0], Allocation::reg(r(0)));
+    // A value held in two registers at once is no interference.
+    assert!(!out.edits.is_empty());
+    let mut checker = Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    // Putting v1 in v0's register destroys v0 while it is live.
+    let mut wrong = out.clone();
+    for inst in [1, 2] {
+        let offset = wrong.inst_alloc_offsets[inst] as usize;
+        let slot = if inst == 1 { 0 } else { 1 };
+        wrong.allocs[offset + slot] = Allocation::reg(r(0));
+    }
+    let mut checker = Checker::new(&func, &env);
+    checker.prepare(&wrong);
+    let errors = checker.run().unwrap_err();
+    assert!(errors.errors().iter().any(|error| matches!(
+        *error,
+        CheckerError::InterferingVRegs { point, alloc, live, other }
+            if point == ProgPoint::after(Inst::new(1))
+                && alloc == Allocation::reg(r(0))
+                && live == v(0)
+                && other == v(1)
+    )));
+}
+
+#[test]
+fn test_edit_source_validation() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is defined in r0 and used in r1, so it is moved between them.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v(0), r(0))],
+            vec![],
+            vec![Operand::reg_fixed_use(v(0), r(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 1,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert_eq!(out.edits.len(), 1);
+    let (pos, _) = out.edits[0];
+    let a = |i| Allocation::reg(r(i));
+    let mv = |from, to| Edit::Move { from, to };
+    let check = |out: &Output| {
+        let mut checker = Checker::new(&func, &env).with_edit_source_validation();
+        checker.prepare(out);
+        checker.run()
+    };
+    check(&out).unwrap();
+
+    // A move from the never-written r2 is blamed instead of the use.
+    let mut wrong = out.clone();
+    wrong.edits[0].1 = mv(a(2), a(1));
+    let mut checker = Checker::new(&func, &env);
+    checker.prepare(&wrong);
+    let errors = checker.run().unwrap_err();
+    assert!(matches!(
+        errors.errors(),
+        [CheckerError::UnknownValueInAllocation { inst, alloc, .. }]
+            if *inst == Inst::new(2) && *alloc == a(1)
+    ));
+    let errors = check(&wrong).unwrap_err();
+    assert!(matches!(
+        errors.errors(),
+        [CheckerError::UnestablishedEditSource { point, from, into }]
+            if *point == ProgPoint::after(Inst::new(1))
+                && *from == a(2)
+                && *into == a(1)
+    ));
+
+    // Through a chain of moves via the equally empty slot 0, the
+    // first one is blamed.
+    let mut wrong = out.clone();
+    let s0 = Allocation::stack(SpillSlot::new(0));
+    wrong.edits = vec![(pos, mv(a(2), s0)), (pos, mv(s0, a(1)))];
+    let errors = check(&wrong).unwrap_err();
+    assert!(matches!(
+        errors.errors(),
+        [CheckerError::UnestablishedEditSource { from, into, .. }]
+            if *from == a(2) && *into == a(1)
+    ));
+
+    // A move from an empty register that nothing reads is fine.
+    let mut unread = out.clone();
+    unread
+        .edits
+        .push((ProgPoint::before(Inst::new(3)), mv(a(2), a(0))));
+    check(&unread).unwrap();
+}
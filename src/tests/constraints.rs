@@ -0,0 +1,1519 @@
+//! Tests of operand constraints and the `Function` hooks that shape
+//! allocation.
+
+use super::*;
+
+#[test]
+fn test_conditional_def_operand() {
+    let v = VReg::new(3, RegClass::Float);
+    let op = Operand::reg_conditional_def(v);
+    assert!(op.is_conditional_def());
+    assert_eq!(op.vreg(), v);
+    assert_eq!(op.kind(), OperandKind::Def);
+    assert_eq!(op.pos(), OperandPos::Late);
+    assert_eq!(op.constraint(), OperandConstraint::Reg);
+
+    let op = Operand::conditional_def(v, OperandConstraint::Stack, OperandPos::Early);
+    assert!(op.is_conditional_def());
+    assert_eq!(op.constraint(), OperandConstraint::Stack);
+    assert_eq!(Operand::from_bits(op.bits()), op);
+
+    assert!(!Operand::reg_def(v).is_conditional_def());
+    assert!(!Operand::any_def(v).is_conditional_def());
+    let p = PReg::new(4, RegClass::Float);
+    assert!(!Operand::reg_fixed_def(v, p).is_conditional_def());
+    assert!(!Operand::reg_reuse_def(v, 4).is_conditional_def());
+    assert!(!Operand::reg_reuse_either_def(v, 1, 0).is_conditional_def());
+}
+
+#[test]
+fn test_unused_operand() {
+    let op = Operand::unused(RegClass::Float);
+    assert!(op.is_unused());
+    assert_eq!(op.class(), RegClass::Float);
+    assert!(op.as_fixed_nonallocatable().is_none());
+
+    let op = Operand::reg_def(VReg::invalid());
+    assert!(op.is_unused());
+
+    let op = Operand::fixed_nonallocatable(PReg::new(1, RegClass::Int));
+    assert!(!op.is_unused());
+    assert!(!Operand::reg_use(VReg::new(0, RegClass::Int)).is_unused());
+}
+
+#[test]
+fn test_loop_reg_reserve() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // block0: define v0, v1; block1 (loop header): define v2 and
+    // use it with v1; block2: backedge; block3: use everything.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![],
+            vec![Operand::reg_use(v(0)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+            vec![],
+            vec![
+                Operand::reg_use(v(0)),
+                Operand::reg_use(v(1)),
+                Operand::reg_use(v(2)),
+                Operand::reg_def(v(3)),
+            ],
+            vec![Operand::reg_use(v(3))],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(3)),
+            InstRange::new(Inst::new(3), Inst::new(6)),
+            InstRange::new(Inst::new(6), Inst::new(7)),
+            InstRange::new(Inst::new(7), Inst::new(9)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0), Block::new(2)],
+            vec![Block::new(1)],
+            vec![Block::new(1)],
+        ],
+        succs: vec![
+            vec![Block::new(1)],
+            vec![Block::new(2), Block::new(3)],
+            vec![Block::new(1)],
+            vec![],
+        ],
+        num_vregs: 4,
+        loop_reg_reserve: Some((Block::new(1), RegClass::Int, 2)),
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+
+    // The last two registers in allocation order are kept free
+    // throughout blocks 1 and 2.
+    let reserved = |alloc: Allocation| match alloc.as_reg() {
+        Some(reg) => reg.hw_enc() >= 2,
+        None => false,
+    };
+    for inst in 3..7 {
+        for &alloc in out.inst_allocs(Inst::new(inst)) {
+            assert!(!reserved(alloc), "inst{}: {}", inst, alloc);
+        }
+    }
+    for &(pos, ref edit) in &out.edits {
+        let to = match *edit {
+            Edit::Move { to, .. } | Edit::Remat { to, .. } => to,
+        };
+        if (3..7).contains(&pos.inst().index()) {
+            assert!(!reserved(to), "{:?}: {:?}", pos, edit);
+        }
+    }
+}
+
+#[test]
+fn test_spill_all_at() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // inst2 is a barrier across which v0 and v1 are live; it is also
+    // a safepoint, and v0 a reference.
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::any_use(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        reftype_vregs: vec![v(0)],
+        safepoints: vec![Inst::new(2)],
+        spill_all: vec![Inst::new(2)],
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    // Both values are in spillslots across the barrier, even with
+    // registers to spare, and the stackmap lists v0's.
+    assert!(out.inst_allocs(Inst::new(2))[0].is_stack());
+    for vreg in [v(0), v(1)] {
+        for pos in [
+            ProgPoint::before(Inst::new(2)),
+            ProgPoint::after(Inst::new(2)),
+        ] {
+            let held: Vec<Allocation> = out
+                .vreg_locations(vreg)
+                .into_iter()
+                .filter(|(range, _)| range.contains_point(pos))
+                .map(|(_, alloc)| alloc)
+                .collect();
+            assert!(!held.is_empty());
+            assert!(held.iter().all(|alloc| alloc.is_stack()), "{:?}", held);
+        }
+    }
+    let v0_slot = out
+        .vreg_locations(v(0))
+        .into_iter()
+        .find(|(range, _)| range.contains_point(ProgPoint::before(Inst::new(2))))
+        .unwrap()
+        .1;
+    let allocs: Vec<Allocation> = out.slots_at_safepoint(Inst::new(2)).collect();
+    assert_eq!(allocs, vec![v0_slot]);
+
+    // Keeping the values in registers there, as the allocator
+    // otherwise would, is wrong.
+    let without_refs = |spill_all| TestFunc {
+        insts: func.insts.clone(),
+        blocks: func.blocks.clone(),
+        preds: func.preds.clone(),
+        succs: func.succs.clone(),
+        num_vregs: 2,
+        spill_all,
+        ..Default::default()
+    };
+    let barrier = without_refs(vec![Inst::new(2)]);
+    let plain = without_refs(vec![]);
+    let out = run(&plain, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.edits.is_empty());
+    let mut checker = checker::Checker::new(&barrier, &env);
+    checker.prepare(&out);
+    assert!(checker.run().is_err());
+
+    // An operand needing a register can't be given one there.
+    func.insts[2] = vec![Operand::reg_use(v(1))];
+    assert!(matches!(
+        run(&func, &env, &RegallocOptions::default()),
+        Err(RegAllocError::RegOperandAtSpillAll(inst)) if inst == Inst::new(2)
+    ));
+    func.insts[2] = vec![Operand::reg_fixed_use(v(1), PReg::new(0, RegClass::Int))];
+    assert!(matches!(
+        run(&func, &env, &RegallocOptions::default()),
+        Err(RegAllocError::RegOperandAtSpillAll(inst)) if inst == Inst::new(2)
+    ));
+}
+
+#[test]
+fn test_entry_live_regs() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 and v1 arrive in r1 and r2.
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_use(v(0)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(3))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        entry_live_regs: vec![(v(0), r(1)), (v(1), r(2))],
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    // They are used where they are, with no shuffling on entry.
+    assert!(out.edits.is_empty());
+    assert_eq!(out.inst_allocs(Inst::new(0))[0], Allocation::reg(r(1)));
+    assert_eq!(out.inst_allocs(Inst::new(1))[0], Allocation::reg(r(2)));
+
+    // The checker knows where they are: reading v0 from another
+    // register without a move is caught.
+    let mut wrong = out.clone();
+    wrong.allocs[wrong.inst_alloc_offsets[0] as usize] = Allocation::reg(r(0));
+    wrong.allocs[wrong.inst_alloc_offsets[0] as usize + 1] = Allocation::reg(r(1));
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&wrong);
+    assert!(checker.run().is_err());
+
+    // Needed elsewhere, v0 is moved there on entry.
+    func.insts[0][0] = Operand::reg_fixed_use(v(0), r(0));
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    assert_eq!(out.edits.len(), 1);
+
+    for entries in [
+        vec![(v(0), r(1)), (v(0), r(2))],
+        vec![(v(0), r(1)), (v(1), r(1))],
+        vec![(v(0), r(1)), (v(3), r(2))],
+        vec![(v(0), PReg::new(1, RegClass::Float))],
+    ] {
+        let bad = entries[entries.len() - 1].0;
+        func.entry_live_regs = entries;
+        assert!(matches!(
+            run(&func, &env, &RegallocOptions::default()),
+            Err(RegAllocError::InvalidEntryLiveReg(vreg)) if vreg == bad
+        ));
+    }
+}
+
+#[test]
+fn test_spill_regions() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is a reference spilled to a custom (scanned) region and
+    // v1 is spilled to thread-local storage: both must be on the
+    // stack across inst2, which also defines two values with only
+    // two registers available.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2)), Operand::reg_def(v(3))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(2)), Operand::reg_use(v(3))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(6))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 4,
+        reftype_vregs: vec![v(0)],
+        safepoints: vec![Inst::new(2)],
+        spill_regions: vec![SpillRegion::Custom, SpillRegion::Tls],
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+
+    assert_eq!(out.num_spillslots, out.num_spillslots_by_region[0]);
+    assert_eq!(out.num_spillslots_by_region[1..], [1, 1]);
+    assert!(!out.safepoint_slots.is_empty());
+    for &(_, alloc) in &out.safepoint_slots {
+        assert_eq!(alloc.as_stack().unwrap().region(), SpillRegion::Custom);
+    }
+    let stack_regions: Vec<SpillRegion> = out
+        .edits
+        .iter()
+        .flat_map(|(_, edit)| match *edit {
+            Edit::Move { from, to } => [from, to],
+            Edit::Remat { to, .. } => [Allocation::none(), to],
+        })
+        .filter_map(|alloc| alloc.as_stack())
+        .map(|slot| slot.region())
+        .collect();
+    assert!(stack_regions.contains(&SpillRegion::Custom));
+    assert!(stack_regions.contains(&SpillRegion::Tls));
+}
+
+#[test]
+fn test_max_splits() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is used densely from inst2 to inst8; v1 and v2 are live
+    // across that stretch but only used at inst7, where all three
+    // values need one of the two registers.
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::any_use(v(1)), Operand::any_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(10))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        max_splits: vec![Some(0)],
+        ..Default::default()
+    };
+    let env = int_env(2);
+    // Make splitting look cheap, so that v0 would rather split
+    // than evict.
+    let options = RegallocOptions {
+        objective: ObjectiveWeights {
+            spill: 1.0,
+            reg_move: 0.001,
+        },
+        ..RegallocOptions::default()
+    };
+
+    // With no split budget, v0 evicts v1 and v2 and stays in one
+    // register from its def to its last use.
+    let out = run(&func, &env, &options).unwrap();
+    let reg = out.inst_allocs(Inst::new(2))[0];
+    assert!(reg.is_reg());
+    for inst in [3, 4, 5, 6, 8] {
+        assert_eq!(out.inst_allocs(Inst::new(inst))[0], reg);
+    }
+    for &(pos, ref edit) in &out.edits {
+        let (from, to) = match *edit {
+            Edit::Move { from, to } => (from, to),
+            Edit::Remat { .. } => panic!("unexpected remat: {:?}", edit),
+        };
+        if (2..9).contains(&pos.inst().index()) {
+            assert!(from != reg && to != reg, "{:?}: {} -> {}", pos, from, to);
+        }
+    }
+
+    // Eviction obeys the commit threshold here as everywhere: with
+    // the incumbents always kept, v0 is stuck.
+    let committed = RegallocOptions {
+        commit_threshold: u32::MAX,
+        ..options
+    };
+    assert!(matches!(
+        run(&func, &env, &committed),
+        Err(RegAllocError::SplitLimitExceeded(vreg)) if vreg == v(0)
+    ));
+
+    // If v1 and v2 are the heavier values, v0 can neither split
+    // nor evict them.
+    func.insts[7] = vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))];
+    func.insts[3..7].fill(vec![]);
+    func.max_splits = vec![Some(0), Some(0), Some(0)];
+    assert!(matches!(
+        run(&func, &env, &options),
+        Err(RegAllocError::SplitLimitExceeded(_))
+    ));
+}
+
+#[test]
+fn test_already_saved() {
+    let r = |i| PReg::new(i, RegClass::Int);
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Three values live at once with one preferred register: two
+    // of them need callee-saved registers.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![
+                Operand::reg_use(v(0)),
+                Operand::reg_use(v(1)),
+                Operand::reg_use(v(2)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let mut env = int_env(1);
+    env.non_preferred_regs_by_class[0] = vec![r(1), r(2), r(3), r(4)];
+    env.already_saved.add(r(2));
+    env.already_saved.add(r(4));
+
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut used: Vec<usize> = out
+        .inst_allocs(Inst::new(3))
+        .iter()
+        .map(|alloc| alloc.as_reg().unwrap().hw_enc())
+        .collect();
+    used.sort_unstable();
+    assert_eq!(used, vec![0, 2, 4]);
+}
+
+#[test]
+fn test_reuse_either() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // inst2 computes v2 = v0 + v1, where v0 is still needed
+    // afterward but v1 is not.
+    let func = |def: Operand| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![def, Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let check = |func: &TestFunc, out: &Output| {
+        let mut checker = checker::Checker::new(func, &env);
+        checker.prepare(out);
+        checker.run().unwrap();
+    };
+
+    // Always reusing the first input needs a copy of v0.
+    let first = func(Operand::reg_reuse_def(v(2), 1));
+    let out = run(&first, &env, &RegallocOptions::default()).unwrap();
+    check(&first, &out);
+    assert_eq!(out.edits.len(), 1);
+
+    // Reusing either lets v2 take over v1's register instead.
+    let def = Operand::reg_reuse_either_def(v(2), 1, 2);
+    assert_eq!(def.constraint(), OperandConstraint::ReuseEither(1, 2));
+    let either = func(def);
+    let out = run(&either, &env, &RegallocOptions::default()).unwrap();
+    check(&either, &out);
+    assert!(out.edits.is_empty());
+    let allocs = out.inst_allocs(Inst::new(2));
+    assert_eq!(allocs[0], allocs[2]);
+    assert_ne!(allocs[0], allocs[1]);
+}
+
+#[test]
+fn test_kill() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let op = Operand::reg_use_kill(v(3));
+    assert!(op.is_kill());
+    assert!(!op.is_conditional_def());
+    assert_eq!(op.kind(), OperandKind::Use);
+    assert_eq!(op.constraint(), OperandConstraint::Reg);
+    assert_eq!(Operand::from_bits(op.bits()), op);
+    assert!(!Operand::reg_use(v(3)).is_kill());
+    assert!(!Operand::reg_conditional_def(v(3)).is_kill());
+
+    let env = int_env(1);
+    let check = |func: &TestFunc, out: &Output| {
+        let mut checker = checker::Checker::new(func, &env);
+        checker.prepare(out);
+        checker.run()
+    };
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+
+    // A correct kill changes nothing.
+    let plain = run(&func, &env, &RegallocOptions::default()).unwrap();
+    func.insts[1] = vec![Operand::reg_use_kill(v(0))];
+    let killed = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert_eq!(plain.allocs, killed.allocs);
+    assert_eq!(plain.edits.len(), killed.edits.len());
+    check(&func, &killed).unwrap();
+
+    // With a later use, v0 must be spilled around v1 when
+    // its use is ordinary; a mistaken kill instead frees its
+    // register at inst1, and the checker catches the clobbered
+    // value at the later use.
+    func.insts[4] = vec![Operand::reg_use(v(0))];
+    func.insts.push(vec![]);
+    func.blocks = vec![InstRange::new(Inst::new(0), Inst::new(6))];
+    func.insts[1] = vec![Operand::reg_use(v(0))];
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    check(&func, &out).unwrap();
+    func.insts[1] = vec![Operand::reg_use_kill(v(0))];
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(check(&func, &out).is_err());
+}
+
+#[test]
+fn test_spillslot_classes() {
+    let i = |n| VReg::new(n, RegClass::Int);
+    let f = |n| VReg::new(n, RegClass::Float);
+    // With one register per class, v0 and then v2 are spilled,
+    // over disjoint ranges, so they could share a slot if their
+    // classes were ignored.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(i(0))],
+            vec![Operand::reg_def(i(1))],
+            vec![Operand::reg_use(i(1))],
+            vec![Operand::reg_use(i(0))],
+            vec![Operand::reg_def(f(2))],
+            vec![Operand::reg_def(f(3))],
+            vec![Operand::reg_use(f(3))],
+            vec![Operand::reg_use(f(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(9))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 4,
+        ..Default::default()
+    };
+    let mut env = int_env(1);
+    env.preferred_regs_by_class[RegClass::Float as usize] = vec![PReg::new(0, RegClass::Float)];
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+
+    let int_slots: Vec<_> = out.spillslots_of_class(RegClass::Int).collect();
+    let float_slots: Vec<_> = out.spillslots_of_class(RegClass::Float).collect();
+    assert!(!int_slots.is_empty() && !float_slots.is_empty());
+    assert!(int_slots.iter().all(|slot| !float_slots.contains(slot)));
+    for (vreg, slot, _) in out.spilled_vregs() {
+        assert!(out.spillslots_of_class(vreg.class()).any(|s| s == slot));
+    }
+}
+
+#[test]
+fn test_allocation_group() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 and v1 are live across v2's uses, where only one of them
+    // fits alongside v2 in the two registers.
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(9))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let spilled_at = |out: &Output, inst: usize| -> Vec<VReg> {
+        let pos = ProgPoint::before(Inst::new(inst));
+        out.spilled_vregs()
+            .filter(|(_, _, range)| range.contains_point(pos))
+            .map(|(vreg, _, _)| vreg)
+            .collect()
+    };
+
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert_eq!(spilled_at(&out, 5).len(), 1);
+
+    // As a group, both are spilled, though one would fit.
+    func.allocation_groups = vec![Some(7), Some(7)];
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut spilled = spilled_at(&out, 5);
+    spilled.sort();
+    assert_eq!(spilled, [v(0), v(1)]);
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+}
+
+#[test]
+fn test_minimize_callee_saved() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // A chain of values, each live until four more are defined.
+    let mut insts = vec![];
+    for i in 0..24 {
+        let mut ops = vec![Operand::reg_def(v(i))];
+        if i >= 4 {
+            ops.push(Operand::reg_use(v(i - 4)));
+        }
+        insts.push(ops);
+    }
+    for i in 20..24 {
+        insts.push(vec![Operand::reg_use(v(i))]);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 24,
+        ..Default::default()
+    };
+    let mut env = int_env(2);
+    env.non_preferred_regs_by_class[RegClass::Int as usize] =
+        (2..8).map(|i| PReg::new(i, RegClass::Int)).collect();
+    let callee_saved_used = |out: &Output| {
+        let mut used = PRegSet::empty();
+        for alloc in &out.allocs {
+            match alloc.as_reg() {
+                Some(preg) if preg.hw_enc() >= 2 => used.add(preg),
+                _ => {}
+            }
+        }
+        (2..8)
+            .filter(|&i| used.contains(PReg::new(i, RegClass::Int)))
+            .count()
+    };
+
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let default = callee_saved_used(&out);
+    let options = RegallocOptions {
+        minimize_callee_saved: true,
+        ..RegallocOptions::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+    let minimized = callee_saved_used(&out);
+    assert!(minimized < default, "{} vs {}", minimized, default);
+    // Four values live at once, two of them in preferred registers.
+    assert_eq!(minimized, 2);
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+}
+
+#[test]
+fn test_early_clobber() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Like ARM's `strex v2, v0, [v1]`: the status result v2 may
+    // be written before the value v0 and address v1 are read, so
+    // must not share a register with either, even though both
+    // die at the instruction.
+    let func_with = |status: Operand| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![status, Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let options = RegallocOptions::default();
+
+    let early_clobber = Operand::reg_def(v(2)).early_clobber();
+    assert_eq!(early_clobber, Operand::reg_def_at_start(v(2)));
+    let func = func_with(early_clobber);
+    let out = run(&func, &env, &options).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    let allocs = out.inst_allocs(Inst::new(2));
+    assert!(allocs[0].is_reg());
+    assert_ne!(allocs[0], allocs[1]);
+    assert_ne!(allocs[0], allocs[2]);
+
+    // An ordinary def may take one of the inputs' registers, and
+    // with only two must.
+    let out = run(&func_with(Operand::reg_def(v(2))), &int_env(2), &options).unwrap();
+    let allocs = out.inst_allocs(Inst::new(2));
+    assert!(allocs[0] == allocs[1] || allocs[0] == allocs[2]);
+}
+
+#[test]
+fn test_loop_resident() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // block0: define v0..v2; block1 (loop header): v0 may be
+    // read from anywhere, while v1 and v2 must be in registers,
+    // and inst5 needs a temporary as well; block2: backedge;
+    // block3: use everything.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(2)), Operand::reg_temp(v(4))],
+            vec![Operand::any_use(v(0)), Operand::reg_use(v(1))],
+            vec![],
+            vec![],
+            vec![
+                Operand::reg_use(v(0)),
+                Operand::reg_use(v(1)),
+                Operand::reg_def(v(3)),
+            ],
+            vec![Operand::reg_use(v(2)), Operand::reg_use(v(3))],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(4)),
+            InstRange::new(Inst::new(4), Inst::new(8)),
+            InstRange::new(Inst::new(8), Inst::new(9)),
+            InstRange::new(Inst::new(9), Inst::new(12)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0), Block::new(2)],
+            vec![Block::new(1)],
+            vec![Block::new(1)],
+        ],
+        succs: vec![
+            vec![Block::new(1)],
+            vec![Block::new(2), Block::new(3)],
+            vec![Block::new(1)],
+            vec![],
+        ],
+        num_vregs: 5,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let options = RegallocOptions::default();
+    let run_checked = |func: &TestFunc| {
+        let out = run(func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+    // Does any edit in the loop (insts 4 to 8) move v0's register,
+    // as allocated at inst6, in or out?
+    let moves_in_loop = |out: &Output| {
+        let reg = out.inst_allocs(Inst::new(6))[0];
+        assert!(reg.is_reg());
+        out.edits.iter().any(|&(pos, ref edit)| {
+            (4..9).contains(&pos.inst().index())
+                && matches!(*edit, Edit::Move { from, to } if from == reg || to == reg)
+        })
+    };
+
+    // Left to itself, the allocator keeps v1 and v2 in registers
+    // across inst5, and reloads v0 in the loop.
+    let mut func = func;
+    let out = run_checked(&func);
+    assert!(moves_in_loop(&out));
+
+    // Asked to, it keeps v0 in one register, spilling v1 instead.
+    func.loop_resident = vec![(v(0), Block::new(1))];
+    let out = run_checked(&func);
+    assert!(!moves_in_loop(&out));
+    assert!(out.loop_resident_fallbacks.is_empty());
+
+    // Block 2 is not a loop header.
+    func.loop_resident = vec![(v(0), Block::new(2))];
+    let out = run_checked(&func);
+    assert_eq!(out.loop_resident_fallbacks, vec![(v(0), Block::new(2))]);
+}
+
+#[test]
+fn test_inst_latency() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is spilled around insts 2 and 3, where v1 and v2 take
+    // both registers, and reloaded for its use at inst6; insts 4
+    // and 5 leave a register free.
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(8))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let options = RegallocOptions::default();
+    let reload = |func: &TestFunc| {
+        let out = run(func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        let reloads: Vec<_> = out
+            .edits
+            .iter()
+            .filter(|(_, edit)| edit.kind() == MoveKind::Reload)
+            .map(|&(pos, _)| pos)
+            .collect();
+        assert_eq!(reloads.len(), 1);
+        reloads[0]
+    };
+
+    assert_eq!(reload(&func), ProgPoint::before(Inst::new(6)));
+    // A latency of 2 moves the reload one instruction earlier.
+    func.latency = vec![1, 1, 1, 1, 1, 1, 2, 1];
+    assert_eq!(reload(&func), ProgPoint::before(Inst::new(5)));
+    // No further than the register is free.
+    func.latency[6] = 10;
+    assert_eq!(reload(&func), ProgPoint::before(Inst::new(4)));
+}
+
+#[test]
+fn test_rematerialization() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is spilled around insts 2 and 3, where v1 and v2 take
+    // both registers, and needed again at inst6; it is defined by
+    // inst0 alone.
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(8))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let options = RegallocOptions::default();
+    let allocate = |func: &TestFunc| {
+        let out = run(func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+
+    let out = allocate(&func);
+    assert_eq!(out.num_spillslots, 1);
+    assert_eq!(out.edit_histogram()[MoveKind::Reload as usize], 1);
+
+    // Rematerialized, v0 needs neither a store nor a slot, and is
+    // recomputed where it was reloaded.
+    func.remat = vec![None; 8];
+    func.remat[0] = Some(RematInfo { vreg: v(0) });
+    let mut out = allocate(&func);
+    assert_eq!(out.num_spillslots, 0);
+    let histogram = out.edit_histogram();
+    assert_eq!(histogram[MoveKind::Spill as usize], 0);
+    assert_eq!(histogram[MoveKind::Reload as usize], 0);
+    assert_eq!(histogram[MoveKind::Remat as usize], 1);
+    let remats: Vec<_> = out
+        .edits
+        .iter()
+        .filter(|(_, edit)| matches!(edit, Edit::Remat { .. }))
+        .collect();
+    assert_eq!(remats.len(), 1);
+    let (pos, ref edit) = *remats[0];
+    assert_eq!(pos, ProgPoint::before(Inst::new(6)));
+    assert!(matches!(
+        *edit,
+        Edit::Remat { inst, vreg, to } if inst == Inst::new(0)
+            && vreg == v(0)
+            && to == out.inst_allocs(Inst::new(6))[0]
+    ));
+
+    // The checker rejects recomputing a value with any other
+    // instruction.
+    for (_, edit) in &mut out.edits {
+        if let Edit::Remat { inst, .. } = edit {
+            *inst = Inst::new(1);
+        }
+    }
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    assert!(checker.run().is_err());
+
+    // An instruction reading a vreg cannot be executed again
+    // anywhere, so its value is spilled as usual.
+    func.remat[0] = None;
+    func.remat[2] = Some(RematInfo { vreg: v(2) });
+    let out = allocate(&func);
+    assert!(out
+        .edits
+        .iter()
+        .all(|(_, edit)| !matches!(edit, Edit::Remat { .. })));
+}
+
+#[test]
+fn test_prefer_callee_saved_across_calls() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is live across two calls that clobber the caller-saved
+    // r0 and r1; v1 and v2 are not.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1))],
+            vec![],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(8))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        clobbers: (0..8)
+            .map(|i| match i {
+                1 | 4 => PRegSet::empty().with(r(0)).with(r(1)),
+                _ => PRegSet::empty(),
+            })
+            .collect(),
+
+        ..Default::default()
+    };
+    let mut env = int_env(2);
+    env.non_preferred_regs_by_class[0] = vec![r(2)];
+    let run_with = |prefer| {
+        let options = RegallocOptions {
+            prefer_callee_saved_across_calls: prefer,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+
+    let off = run_with(false);
+    let on = run_with(true);
+    // v0 ends up in the callee-saved register either way, but
+    // without probing the clobbered ones first.
+    assert_eq!(on.allocs[0], Allocation::reg(r(2)));
+    assert_eq!(on.allocs, off.allocs);
+    assert_eq!(on.edits.len(), 0);
+    assert!(on.stats.process_bundle_reg_probes_any < off.stats.process_bundle_reg_probes_any);
+}
+
+#[test]
+fn test_inst_forbidden_pregs() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is used by inst1 and live across it, together with v1,
+    // which inst1 defines; r0 is forbidden at inst1.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(2))],
+            vec![
+                Operand::reg_use(v(0)),
+                Operand::reg_use(v(2)),
+                Operand::reg_def(v(1)),
+            ],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        forbidden: vec![
+            PRegSet::empty(),
+            PRegSet::empty().with(r(0)),
+            PRegSet::empty(),
+            PRegSet::empty(),
+        ],
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    // No operand of inst1 is in r0, and nothing that stays in r0
+    // around inst1 survives it (which the checker verifies).
+    for &alloc in out.inst_allocs(Inst::new(1)) {
+        assert_ne!(alloc.as_reg(), Some(r(0)));
+    }
+}
+
+#[test]
+fn test_tied_operands() {
+    for group in 0..8 {
+        for kind in [OperandKind::Use, OperandKind::Def] {
+            let op = Operand::new(
+                VReg::new(3, RegClass::Int),
+                OperandConstraint::Tied(group),
+                kind,
+                OperandPos::Early,
+            );
+            assert_eq!(op.constraint(), OperandConstraint::Tied(group));
+            assert!(!op.is_kill() && !op.is_conditional_def());
+        }
+    }
+
+    let v = |i| VReg::new(i, RegClass::Int);
+    // inst1 reads v0 twice and defines v2 in the same register,
+    // while v0 stays live for inst2, so the def needs a copy of
+    // v0 that both tied uses must read.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(1))],
+            vec![
+                Operand::reg_tied_use(v(0), 1),
+                Operand::reg_use(v(1)),
+                Operand::reg_tied_use(v(0), 1),
+                Operand::reg_tied_def(v(2), 1),
+            ],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    for num_regs in [3, 4] {
+        let env = int_env(num_regs);
+        let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+
+        let allocs = out.inst_allocs(Inst::new(1));
+        assert!(allocs[0].is_reg());
+        assert_eq!(allocs[0], allocs[2]);
+        assert_eq!(allocs[0], allocs[3]);
+        assert_ne!(allocs[0], allocs[1]);
+    }
+
+    // Three distinct vregs tied together, as an accumulating
+    // instruction whose inputs were not coalesced beforehand,
+    // cannot share a register and are rejected.
+    let mut func = func;
+    func.insts[1] = vec![
+        Operand::reg_tied_use(v(0), 1),
+        Operand::reg_tied_use(v(1), 1),
+        Operand::reg_tied_def(v(2), 1),
+    ];
+    for options in [
+        RegallocOptions::default(),
+        RegallocOptions {
+            single_pass: true,
+            ..RegallocOptions::default()
+        },
+    ] {
+        assert!(matches!(
+            run(&func, &int_env(4), &options),
+            Err(RegAllocError::TiedOperands(inst)) if inst == Inst::new(1)
+        ));
+    }
+}
+
+#[test]
+fn test_block_frequency() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is live from block0 to the end of block2, across a
+    // clobber of the only register in block2.
+    let func = |frequency: Vec<f32>| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(2)),
+            InstRange::new(Inst::new(2), Inst::new(4)),
+            InstRange::new(Inst::new(4), Inst::new(10)),
+        ],
+        preds: vec![vec![], vec![Block::new(0)], vec![Block::new(1)]],
+        succs: vec![vec![Block::new(1)], vec![Block::new(2)], vec![]],
+        num_vregs: 1,
+        frequency,
+        clobbers: (0..10)
+            .map(|i| match i {
+                6 => PRegSet::empty().with(r(0)),
+                _ => PRegSet::empty(),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let env = int_env(1);
+    // Without frequencies all blocks are at the same depth and v0
+    // is split at the clobber; with block2 hot, the split moves
+    // to its entry. Either way the reload is in block2, which
+    // weighs in the estimated cost accordingly.
+    for (frequency, split_at, cost) in [
+        (vec![], ProgPoint::after(Inst::new(6)), 2.0),
+        (
+            vec![1.0, 1.0, 100.0],
+            ProgPoint::before(Inst::new(4)),
+            101.0,
+        ),
+    ] {
+        let func = func(frequency);
+        let mut points = vec![];
+        let mut hook = |proposal: SplitProposal| {
+            points.push(proposal.point);
+            proposal.point
+        };
+        let out = run_with_split_hook(&func, &env, &RegallocOptions::default(), &mut hook).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        assert_eq!(points, vec![split_at]);
+        assert_eq!(out.estimated_spill_cost(&func), cost);
+    }
+}
+
+#[test]
+fn test_cold_blocks() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is used on the path through block1 and v1, three times, on
+    // the path through block2; v2 in block0 leaves room for only one
+    // of them in a register across it.
+    let func = |cold_blocks: Vec<Block>| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(2))],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+            vec![Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(1))],
+            vec![],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(4)),
+            InstRange::new(Inst::new(4), Inst::new(6)),
+            InstRange::new(Inst::new(6), Inst::new(10)),
+            InstRange::new(Inst::new(10), Inst::new(11)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0)],
+            vec![Block::new(0)],
+            vec![Block::new(1), Block::new(2)],
+        ],
+        succs: vec![
+            vec![Block::new(1), Block::new(2)],
+            vec![Block::new(3)],
+            vec![Block::new(3)],
+            vec![],
+        ],
+        num_vregs: 3,
+        cold_blocks,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    // v1 has more uses, so it keeps its register and v0 is reloaded
+    // in block1; once block2 is cold, v1 is the one spilled, and the
+    // reload moves there.
+    for (cold_blocks, reload_insts) in [(vec![], 4..6), (vec![Block::new(2)], 6..10)] {
+        let func = func(cold_blocks);
+        let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        let reloads: Vec<_> = out
+            .edits
+            .iter()
+            .filter(|(_, edit)| matches!(edit, Edit::Move { from, .. } if from.is_stack()))
+            .map(|(pos, _)| pos.inst().index())
+            .collect();
+        assert_eq!(reloads.len(), 1);
+        assert!(reload_insts.contains(&reloads[0]), "{:?}", out.edits);
+    }
+}
+
+#[test]
+fn test_reserved_preg_ranges() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 and v1 are live from their defs to inst5, with two
+    // registers; r0 is reserved over part of that stretch, over a
+    // clobber of it at inst3.
+    let reserved = CodeRange {
+        from: ProgPoint::after(Inst::new(1)),
+        to: ProgPoint::before(Inst::new(4)),
+    };
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(7))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.edits.is_empty());
+
+    // The reservation holds by itself, and around a clobber
+    // within it.
+    func.reserved = vec![(r(0), reserved)];
+    for clobber in [None, Some(3)] {
+        func.clobbers = (0..7)
+            .map(|i| match clobber {
+                Some(c) if c == i => PRegSet::empty().with(r(0)),
+                _ => PRegSet::empty(),
+            })
+            .collect();
+        let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        // Only one of the values fits in r1 across the reservation.
+        assert!(!out.spilled_ranges.is_empty());
+        for (vreg, range, alloc) in out.ranges() {
+            assert!(
+                alloc != Allocation::reg(r(0)) || !range.overlaps(&reserved),
+                "{} in {} over {:?}",
+                vreg,
+                alloc,
+                range
+            );
+        }
+    }
+}
+
+#[test]
+fn test_reg_aliases() {
+    let f = |i| PReg::new(i, RegClass::Float);
+    let x = |i| PReg::new(i, RegClass::Vector);
+    // One vector register overlapping a pair of float registers.
+    let mut env = int_env(0);
+    env.preferred_regs_by_class = [vec![], vec![f(0), f(1)], vec![x(0)]];
+    env.reg_aliases = vec![vec![]; PReg::NUM_INDEX];
+    for narrow in [f(0), f(1)] {
+        env.reg_aliases[narrow.index()].push(x(0));
+        env.reg_aliases[x(0).index()].push(narrow);
+    }
+    let fv = |i| VReg::new(i, RegClass::Float);
+    let xv = |i| VReg::new(i, RegClass::Vector);
+    let func = |last: Vec<Operand>| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(fv(0))],
+            vec![Operand::reg_def(xv(1))],
+            vec![Operand::reg_use(xv(1))],
+            last,
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+
+    // While v1 holds the vector register, v0 cannot stay in
+    // either of the float registers it overlaps.
+    let func1 = func(vec![Operand::reg_use(fv(0))]);
+    let out = run(&func1, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func1, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    assert_eq!(out.inst_allocs(Inst::new(2))[0], Allocation::reg(x(0)));
+    for pos in [
+        ProgPoint::after(Inst::new(1)),
+        ProgPoint::before(Inst::new(2)),
+    ] {
+        let held: Vec<_> = out
+            .vreg_locations(fv(0))
+            .into_iter()
+            .filter(|(range, _)| range.contains_point(pos))
+            .map(|(_, alloc)| alloc)
+            .collect();
+        assert!(!held.is_empty());
+        assert!(held.iter().all(|alloc| alloc.is_stack()), "{:?}", held);
+    }
+
+    // Needing both in registers at once is rejected cleanly.
+    let mut func2 = func(vec![]);
+    func2.insts[2] = vec![Operand::reg_use(xv(1)), Operand::reg_use(fv(0))];
+    assert!(matches!(
+        run(&func2, &env, &RegallocOptions::default()),
+        Err(RegAllocError::TooManyLiveRegs { .. })
+    ));
+}
+
+#[test]
+fn test_reg_aliases_block_wide() {
+    let f = |i| PReg::new(i, RegClass::Float);
+    let x = |i| PReg::new(i, RegClass::Vector);
+    // Each vector register overlaps a pair of float registers; only
+    // the first float register is allocatable.
+    let mut env = int_env(0);
+    env.preferred_regs_by_class = [vec![], vec![f(0)], vec![x(0), x(1)]];
+    env.reg_aliases = vec![vec![]; PReg::NUM_INDEX];
+    for (wide, narrow) in [(x(0), f(0)), (x(0), f(1)), (x(1), f(2)), (x(1), f(3))] {
+        env.reg_aliases[narrow.index()].push(wide);
+        env.reg_aliases[wide.index()].push(narrow);
+    }
+    assert_eq!(env.aliases(f(0)), &[x(0)]);
+    assert_eq!(env.aliases(x(1)), &[f(2), f(3)]);
+    assert!(env.aliases(PReg::new(0, RegClass::Int)).is_empty());
+
+    let fv = VReg::new(0, RegClass::Float);
+    let xv = VReg::new(1, RegClass::Vector);
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(fv)],
+            vec![Operand::reg_def(xv)],
+            vec![Operand::reg_use(xv), Operand::reg_use(fv)],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    // v0 holds the narrow f0 throughout, which keeps v1 out of the
+    // wide x0 overlapping it.
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.edits.is_empty());
+    assert_eq!(out.inst_allocs(Inst::new(0)), &[Allocation::reg(f(0))]);
+    assert_eq!(out.inst_allocs(Inst::new(1)), &[Allocation::reg(x(1))]);
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    // The checker knows that writing x0 destroys f0.
+    let mut wrong = out.clone();
+    for inst in [1, 2] {
+        let offset = wrong.inst_alloc_offsets[inst] as usize;
+        wrong.allocs[offset] = Allocation::reg(x(0));
+    }
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&wrong);
+    assert!(checker.run().is_err());
+}
+
+#[test]
+fn test_full_reg_def() {
+    let v = VReg::new(3, RegClass::Int);
+    let op = Operand::reg_full_def(v);
+    assert!(op.is_full_reg_def());
+    assert!(!op.is_conditional_def());
+    assert_eq!(op.kind(), OperandKind::Def);
+    assert_eq!(op.pos(), OperandPos::Late);
+    assert_eq!(op.constraint(), OperandConstraint::Reg);
+    assert_eq!(Operand::from_bits(op.bits()), op);
+    assert!(!Operand::reg_def(v).is_full_reg_def());
+    assert!(!Operand::reg_use(v).is_full_reg_def());
+
+    let r = |i| PReg::new(i, RegClass::Int);
+    // rax (not allocatable) with its two byte registers al and ah.
+    let (rax, al, ah) = (r(0), r(1), r(2));
+    let mut env = int_env(0);
+    env.preferred_regs_by_class[0] = vec![al, ah];
+    env.reg_aliases = vec![vec![]; PReg::NUM_INDEX];
+    for narrow in [al, ah] {
+        env.reg_aliases[narrow.index()].push(rax);
+        env.reg_aliases[rax.index()].push(narrow);
+    }
+    assert_eq!(env.full_register_parts(al).collect::<Vec<_>>(), vec![ah]);
+
+    let v = |i| VReg::new(i, RegClass::Int);
+    let func = |def: Operand| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![def],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let held_in_reg = |out: &Output| {
+        out.vreg_locations(v(0)).into_iter().any(|(range, alloc)| {
+            range.contains_point(ProgPoint::after(Inst::new(1))) && alloc.is_reg()
+        })
+    };
+
+    // A byte-width def leaves the other byte register to v0.
+    let narrow = func(Operand::reg_def(v(1)));
+    let out = run(&narrow, &env, &RegallocOptions::default()).unwrap();
+    assert!(held_in_reg(&out));
+
+    // A def writing all of rax excludes v0 from both halves.
+    let full = func(Operand::reg_full_def(v(1)));
+    let mut checker = checker::Checker::new(&full, &env);
+    checker.prepare(&out);
+    assert!(checker.run().is_err());
+    let out = run(&full, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&full, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    assert!(!held_in_reg(&out));
+}
+
+#[test]
+fn test_vreg_fixed_preg() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is live throughout, with v1 and v2 competing for the
+    // registers in the middle.
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        pinned: vec![Some(r(2))],
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    // v0 stays in r2 from its def to its use, and the others keep
+    // out of it.
+    let locations = out.vreg_locations(v(0));
+    assert!(!locations.is_empty());
+    assert!(locations
+        .iter()
+        .all(|&(_, alloc)| alloc == Allocation::reg(r(2))));
+    for inst in 1..3 {
+        assert!(!out
+            .inst_allocs(Inst::new(inst))
+            .contains(&Allocation::reg(r(2))));
+    }
+    assert!(out.edits.is_empty());
+
+    // With fewer registers, v1 and v2 share the one register that
+    // v0 leaves free.
+    let env = int_env(2);
+    func.insts[1] = vec![Operand::reg_def(v(1))];
+    func.insts[2] = vec![Operand::reg_use(v(1)), Operand::reg_def(v(2))];
+    func.insts[3] = vec![Operand::reg_use(v(2)), Operand::reg_use(v(0))];
+    func.pinned = vec![Some(r(1))];
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    assert!(out
+        .vreg_locations(v(0))
+        .iter()
+        .all(|&(_, alloc)| alloc == Allocation::reg(r(1))));
+    assert!(out.spilled_ranges.iter().all(|&(vreg, ..)| vreg != v(0)));
+
+    // A use of another value fixed to the pinned register while v0
+    // is live overrides the pin: v0 is split around it.
+    func.insts[2] = vec![Operand::reg_fixed_use(v(1), r(1)), Operand::reg_def(v(2))];
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    assert!(!out.edits.is_empty());
+}
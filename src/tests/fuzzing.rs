@@ -0,0 +1,341 @@
+//! Tests of the fuzzing utilities.
+
+use super::*;
+use crate::fuzzing::func::{machine_env, random_function};
+
+#[test]
+fn test_random_function() {
+    let env = machine_env();
+    let mut saw_stack_constraint = false;
+    for seed in 0..20 {
+        let func = random_function(seed);
+        // The same seed always gives the same function.
+        assert_eq!(
+            alloc::format!("{:?}", func),
+            alloc::format!("{:?}", random_function(seed))
+        );
+        saw_stack_constraint |= (0..func.num_insts()).any(|i| {
+            func.inst_operands(Inst::new(i))
+                .iter()
+                .any(|op| op.constraint() == OperandConstraint::Stack)
+        });
+
+        let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+    }
+    assert!(saw_stack_constraint);
+}
+
+#[test]
+fn test_time_budget_exhausted() {
+    let env = machine_env();
+    let options = RegallocOptions {
+        time_budget: Some(core::time::Duration::ZERO),
+        ..RegallocOptions::default()
+    };
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+    }
+}
+
+#[test]
+fn test_run_fast() {
+    let env = machine_env();
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let out = run_fast(&func, &env, &RegallocOptions::default()).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+    }
+}
+
+#[test]
+fn test_remerge() {
+    let env = machine_env();
+    let options = RegallocOptions {
+        remerge: true,
+        ..RegallocOptions::default()
+    };
+    let mut remerged = 0;
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let base = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&func, &env, &options).unwrap();
+        // Remerged bundles neither interfere with anything else in
+        // their register (which the checker catches) nor add moves.
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        assert!(out.edits.len() <= base.edits.len(), "seed {}", seed);
+        remerged += out.stats.remerged_bundles;
+    }
+    assert!(remerged > 0);
+}
+
+#[test]
+fn test_eager_spill_defs() {
+    let env = machine_env();
+    let options = RegallocOptions {
+        eager_spill_defs: true,
+        ..RegallocOptions::default()
+    };
+    let stores = |out: &Output| {
+        out.edits
+            .iter()
+            .filter(|(_, edit)| match edit {
+                Edit::Move { from, to } => from.is_reg() && to.is_stack(),
+                _ => false,
+            })
+            .count()
+    };
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let base = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        // Each spilled def is stored at most once.
+        assert!(stores(&out) <= stores(&base), "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_debug_locations() {
+    let env = machine_env();
+    let mut saw_labels = false;
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let locs = &out.debug_locations;
+        saw_labels |= !locs.is_empty();
+        for (i, &(label, from, to, alloc)) in locs.iter().enumerate() {
+            assert!(from < to, "seed {}: {:?}", seed, locs[i]);
+            // Each record lies within the ranges requested for its
+            // label, which may come from several vregs.
+            let mut covered = from;
+            while let Some(&(_, _, end, _)) =
+                func.debug_value_labels()
+                    .iter()
+                    .find(|&&(_, start, end, l)| {
+                        l == label
+                            && ProgPoint::before(start) <= covered
+                            && covered < ProgPoint::before(end)
+                    })
+            {
+                covered = ProgPoint::before(end);
+            }
+            assert!(to <= covered, "seed {}: {:?}", seed, locs[i]);
+            // Records for a label in one allocation neither overlap
+            // nor abut.
+            for &(label2, from2, _, alloc2) in &locs[i + 1..] {
+                if label2 != label {
+                    break;
+                }
+                assert!(from <= from2, "seed {}", seed);
+                if alloc2 == alloc {
+                    assert!(to < from2, "seed {}: {:?}", seed, locs[i]);
+                }
+            }
+        }
+    }
+    assert!(saw_labels);
+}
+
+#[test]
+fn test_block_entry_state() {
+    let env = machine_env();
+    let options = RegallocOptions {
+        collect_block_entry_state: true,
+        ..RegallocOptions::default()
+    };
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let out = run(&func, &env, &options).unwrap();
+        for block in 0..func.num_blocks() {
+            let block = Block::new(block);
+            // The entry state is exactly the set of ranges covering
+            // the block's first instruction.
+            let entry = ProgPoint::before(func.block_insns(block).first());
+            let mut expected: Vec<_> = out
+                .ranges()
+                .filter(|(_, range, _)| range.from <= entry && entry < range.to)
+                .collect();
+            expected.sort_unstable_by_key(|&(vreg, _, alloc)| (vreg.vreg(), alloc));
+            expected.dedup_by_key(|&mut (vreg, _, alloc)| (vreg, alloc));
+            let mut state: Vec<_> = out
+                .block_reg_state(block)
+                .into_iter()
+                .map(|(preg, vreg)| (vreg, Allocation::reg(preg)))
+                .chain(
+                    out.block_spill_state(block)
+                        .into_iter()
+                        .map(|(slot, vreg)| (vreg, Allocation::stack(slot))),
+                )
+                .collect();
+            state.sort_unstable_by_key(|&(vreg, alloc)| (vreg.vreg(), alloc));
+            assert_eq!(
+                state,
+                expected
+                    .iter()
+                    .filter(|(_, _, alloc)| !alloc.is_none())
+                    .map(|&(vreg, _, alloc)| (vreg, alloc))
+                    .collect::<Vec<_>>(),
+                "seed {} {:?}",
+                seed,
+                block
+            );
+        }
+    }
+}
+
+#[test]
+fn test_preg_maps() {
+    let env = machine_env();
+    let options = RegallocOptions {
+        collect_preg_maps: true,
+        ..RegallocOptions::default()
+    };
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let out = run(&func, &env, &options).unwrap();
+        // Each register's entries are in order and disjoint, and
+        // those naming a vreg are exactly its ranges in registers.
+        for pair in out.preg_maps.windows(2) {
+            let ((a, ra, _), (b, rb, _)) = (pair[0], pair[1]);
+            assert!(a.index() < b.index() || (a == b && ra.to <= rb.from));
+        }
+        let mut held: Vec<_> = out
+            .preg_maps
+            .iter()
+            .filter_map(|&(preg, range, vreg)| Some((vreg?, range, Allocation::reg(preg))))
+            .collect();
+        let mut in_regs: Vec<_> = out
+            .ranges()
+            .filter(|&(_, _, alloc)| alloc.is_reg())
+            .collect();
+        held.sort_unstable_by_key(|&(vreg, range, alloc)| (vreg, range.from, alloc));
+        in_regs.sort_unstable_by_key(|&(vreg, range, alloc)| (vreg, range.from, alloc));
+        assert_eq!(held, in_regs);
+    }
+}
+
+#[test]
+fn test_trim_empty_regions() {
+    let env = machine_env();
+    let options = RegallocOptions {
+        trim_empty_regions: false,
+        ..RegallocOptions::default()
+    };
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let out = run(&func, &env, &options).unwrap();
+        // Values held in registers across use-free stretches never
+        // overlap anything else there.
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+    }
+}
+
+#[test]
+fn test_min_regs_required() {
+    let env = machine_env();
+    for seed in 0..50 {
+        let func = random_function(seed);
+        let min = min_regs_required(&func).unwrap();
+        let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+        // No more values are ever in registers at once than are live
+        // at once.
+        for live in &out.live_regs_by_inst {
+            for class in 0..3 {
+                assert!(live[class] as usize <= min[class], "seed {}", seed);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_remap_spillslots() {
+    // The indices of every spillslot mentioned anywhere in `out`, found
+    // through its `Debug` rendering so that no field can be missed.
+    fn slot_indices(out: &Output) -> Vec<usize> {
+        let text = alloc::format!("{:?}", out);
+        let mut indices = vec![];
+        for prefix in ["stack", "tls", "custom", "SpillSlot { bits: "] {
+            for (at, _) in text.match_indices(prefix) {
+                let digits: String = text[at + prefix.len()..]
+                    .chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect();
+                if let Ok(n) = digits.parse::<usize>() {
+                    indices.push(n & 0x00ff_ffff);
+                }
+            }
+        }
+        indices
+    }
+
+    let env = machine_env();
+    let mut remapped = 0;
+    for seed in 0..20 {
+        let func = random_function(seed);
+        let base = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let traced = match base.spilled_ranges.first() {
+            Some(&(traced, _, _)) => traced,
+            None => continue,
+        };
+        let options = RegallocOptions {
+            collect_block_entry_state: true,
+            collect_split_events: true,
+            trace_vreg: Some(traced),
+            ..RegallocOptions::default()
+        };
+        let mut out = run(&func, &env, &options).unwrap();
+        let before = slot_indices(&out);
+        assert!(before.iter().any(|&i| i < 1000), "seed {}", seed);
+
+        out.remap_spillslots(|slot| slot.plus(1000));
+        assert!(
+            slot_indices(&out).iter().all(|&i| i >= 1000),
+            "seed {}",
+            seed
+        );
+        assert_eq!(out.num_spillslots, base.num_spillslots + 1000);
+        assert!(out
+            .safepoint_slots
+            .windows(2)
+            .all(|w| (w[0].0, w[0].1.bits()) <= (w[1].0, w[1].1.bits())));
+        remapped += 1;
+    }
+    assert!(remapped > 0);
+}
+
+#[test]
+fn test_no_self_moves() {
+    let env = machine_env();
+    for options in [
+        RegallocOptions::default(),
+        RegallocOptions {
+            eager_spill_defs: true,
+            remerge: true,
+            ..RegallocOptions::default()
+        },
+        RegallocOptions {
+            trim_empty_regions: false,
+            ..RegallocOptions::default()
+        },
+    ] {
+        for seed in 0..30 {
+            let out = run(&random_function(seed), &env, &options).unwrap();
+            assert!(out.self_moves().is_empty(), "seed {}", seed);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! End-to-end tests of the allocator over small hand-written
+//! functions, grouped by what they exercise.
+
+use crate::*;
+use alloc::vec;
+use alloc::vec::Vec;
+
+mod checker_rules;
+mod constraints;
+#[cfg(feature = "fuzzing")]
+mod fuzzing;
+mod options;
+mod output;
+mod stackmaps;
+
+/// A small function for end-to-end tests: one list of operands
+/// per instruction, with the last instruction of each block being
+/// its terminator.
+#[derive(Default)]
+struct TestFunc {
+    insts: Vec<Vec<Operand>>,
+    blocks: Vec<InstRange>,
+    preds: Vec<Vec<Block>>,
+    succs: Vec<Vec<Block>>,
+    num_vregs: usize,
+    loop_reg_reserve: Option<(Block, RegClass, usize)>,
+    reftype_vregs: Vec<VReg>,
+    debug_value_labels: Vec<(VReg, Inst, Inst, u32)>,
+    safepoints: Vec<Inst>,
+    spill_all: Vec<Inst>,
+    spill_regions: Vec<SpillRegion>,
+    max_splits: Vec<Option<u32>>,
+    allocation_groups: Vec<Option<u32>>,
+    loop_resident: Vec<(VReg, Block)>,
+    latency: Vec<u32>,
+    remat: Vec<Option<RematInfo>>,
+    frequency: Vec<f32>,
+    cold_blocks: Vec<Block>,
+    clobbers: Vec<PRegSet>,
+    forbidden: Vec<PRegSet>,
+    reserved: Vec<(PReg, CodeRange)>,
+    pinned: Vec<Option<PReg>>,
+    entry_live_regs: Vec<(VReg, PReg)>,
+    slot_sizes: Vec<usize>,
+    stack_affinities: Vec<(VReg, VReg)>,
+}
+
+impl Function for TestFunc {
+    fn num_insts(&self) -> usize {
+        self.insts.len()
+    }
+    fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+    fn entry_block(&self) -> Block {
+        Block::new(0)
+    }
+    fn block_insns(&self, block: Block) -> InstRange {
+        self.blocks[block.index()]
+    }
+    fn block_succs(&self, block: Block) -> &[Block] {
+        &self.succs[block.index()]
+    }
+    fn block_preds(&self, block: Block) -> &[Block] {
+        &self.preds[block.index()]
+    }
+    fn block_params(&self, _block: Block) -> &[VReg] {
+        &[]
+    }
+    fn is_ret(&self, insn: Inst) -> bool {
+        insn.index() == self.insts.len() - 1
+    }
+    fn is_branch(&self, insn: Inst) -> bool {
+        !self.is_ret(insn) && self.blocks.iter().any(|range| range.last() == insn)
+    }
+    fn branch_blockparams(&self, _block: Block, _insn: Inst, _succ_idx: usize) -> &[VReg] {
+        &[]
+    }
+    fn inst_operands(&self, insn: Inst) -> &[Operand] {
+        &self.insts[insn.index()]
+    }
+    fn num_vregs(&self) -> usize {
+        self.num_vregs
+    }
+    fn spillslot_size(&self, regclass: RegClass) -> usize {
+        self.slot_sizes.get(regclass as usize).copied().unwrap_or(1)
+    }
+    fn loop_reg_reserve(&self, loop_header: Block) -> Option<(RegClass, usize)> {
+        match self.loop_reg_reserve {
+            Some((header, class, count)) if header == loop_header => Some((class, count)),
+            _ => None,
+        }
+    }
+    fn reftype_vregs(&self) -> &[VReg] {
+        &self.reftype_vregs
+    }
+    fn debug_value_labels(&self) -> &[(VReg, Inst, Inst, u32)] {
+        &self.debug_value_labels
+    }
+    fn requires_refs_on_stack(&self, insn: Inst) -> bool {
+        self.safepoints.contains(&insn)
+    }
+    fn spill_all_at(&self, insn: Inst) -> bool {
+        self.spill_all.contains(&insn)
+    }
+    fn spill_region(&self, vreg: VReg) -> SpillRegion {
+        self.spill_regions
+            .get(vreg.vreg())
+            .cloned()
+            .unwrap_or_default()
+    }
+    fn max_splits(&self, vreg: VReg) -> Option<u32> {
+        self.max_splits.get(vreg.vreg()).cloned().flatten()
+    }
+    fn allocation_group(&self, vreg: VReg) -> Option<u32> {
+        self.allocation_groups.get(vreg.vreg()).cloned().flatten()
+    }
+    fn loop_resident(&self) -> &[(VReg, Block)] {
+        &self.loop_resident
+    }
+    fn inst_latency(&self, insn: Inst) -> u32 {
+        self.latency.get(insn.index()).cloned().unwrap_or(1)
+    }
+    fn is_rematerializable(&self, insn: Inst) -> Option<RematInfo> {
+        self.remat.get(insn.index()).cloned().flatten()
+    }
+    fn block_frequency(&self, block: Block) -> Option<f32> {
+        self.frequency.get(block.index()).copied()
+    }
+    fn is_cold_block(&self, block: Block) -> bool {
+        self.cold_blocks.contains(&block)
+    }
+    fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+        self.clobbers
+            .get(insn.index())
+            .copied()
+            .unwrap_or(PRegSet::empty())
+    }
+    fn inst_forbidden_pregs(&self, insn: Inst) -> PRegSet {
+        self.forbidden
+            .get(insn.index())
+            .copied()
+            .unwrap_or(PRegSet::empty())
+    }
+    fn reserved_preg_ranges(&self) -> &[(PReg, CodeRange)] {
+        &self.reserved
+    }
+    fn vreg_fixed_preg(&self, vreg: VReg) -> Option<PReg> {
+        self.pinned.get(vreg.vreg()).cloned().flatten()
+    }
+    fn entry_live_regs(&self) -> &[(VReg, PReg)] {
+        &self.entry_live_regs
+    }
+    fn stack_affinities(&self) -> &[(VReg, VReg)] {
+        &self.stack_affinities
+    }
+}
+
+fn int_env(num_regs: usize) -> MachineEnv {
+    MachineEnv {
+        preferred_regs_by_class: [
+            (0..num_regs).map(|i| PReg::new(i, RegClass::Int)).collect(),
+            vec![],
+            vec![],
+        ],
+        non_preferred_regs_by_class: [vec![], vec![], vec![]],
+        scratch_by_class: [None, None, None],
+        fixed_stack_slots: vec![],
+        reg_aliases: vec![],
+        reg_subclasses: vec![],
+        already_saved: PRegSet::empty(),
+    }
+}
@@ -0,0 +1,1584 @@
+//! Tests of the `RegallocOptions` knobs.
+
+use super::*;
+
+#[test]
+fn test_allocation_stability() {
+    use crate::stability::{allocation_stability, Perturbation};
+    let v = |i| VReg::new(i, RegClass::Int);
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let perturbations: Vec<_> = (0..5)
+        .map(|i| Perturbation::InsertNop(Inst::new(i)))
+        .collect();
+    let env = int_env(4);
+
+    // Packing registers from the bottom does not depend on
+    // instruction indices, so nops change nothing...
+    let options = RegallocOptions {
+        scan_mode: RegScanMode::PackLow,
+        ..RegallocOptions::default()
+    };
+    let report = allocation_stability(&func, &env, &options, &perturbations).unwrap();
+    assert_eq!(report.operands, 6);
+    assert_eq!(report.changed, vec![0; 5]);
+    assert_eq!(report.max_changed_fraction(), 0.0);
+
+    // ...but the default scan offset is derived from them.
+    let options = RegallocOptions::default();
+    let report = allocation_stability(&func, &env, &options, &perturbations).unwrap();
+    assert_eq!(report.changed.len(), 5);
+    assert!(report.changed.iter().all(|&n| n <= report.operands));
+    assert!(report.max_changed_fraction() > 0.0);
+}
+
+#[test]
+fn test_scan_seed() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let mut insts = vec![];
+    for i in 0..12 {
+        let mut ops = vec![Operand::reg_def(v(i))];
+        if i >= 3 {
+            ops.push(Operand::reg_use(v(i - 3)));
+        }
+        insts.push(ops);
+    }
+    for i in 9..12 {
+        insts.push(vec![Operand::reg_use(v(i))]);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 12,
+        ..Default::default()
+    };
+    let env = int_env(6);
+    let run_with_seed = |scan_seed| {
+        let options = RegallocOptions {
+            scan_seed,
+            ..RegallocOptions::default()
+        };
+        run(&func, &env, &options).unwrap()
+    };
+
+    let base = run_with_seed(None);
+    let mut distinct = 0;
+    for seed in 0..8 {
+        let out = run_with_seed(Some(seed));
+        assert_eq!(out.allocs, run_with_seed(Some(seed)).allocs);
+        if out.allocs != base.allocs {
+            distinct += 1;
+        }
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+    }
+    assert!(distinct > 0);
+}
+
+#[test]
+fn test_trace_vreg() {
+    use alloc::format;
+    use alloc::string::ToString;
+
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Three values are live at once with two registers, so one of
+    // them has to give way.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(6))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let base = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(base.vreg_trace.is_none());
+
+    let traces: Vec<VregTrace> = (0..3)
+        .map(|vreg| {
+            let options = RegallocOptions {
+                trace_vreg: Some(v(vreg)),
+                ..RegallocOptions::default()
+            };
+            let out = run(&func, &env, &options).unwrap();
+            assert_eq!(out.allocs, base.allocs);
+            out.vreg_trace.unwrap()
+        })
+        .collect();
+    for (vreg, trace) in traces.iter().enumerate() {
+        assert_eq!(trace.vreg, v(vreg));
+        assert!(matches!(
+            trace.events.first(),
+            Some(VregTraceEvent::Process { .. })
+        ));
+        assert!(trace
+            .events
+            .iter()
+            .any(|event| matches!(event, VregTraceEvent::Allocated { .. })));
+        assert!(!trace.placement.is_empty());
+        assert!(trace.to_string().starts_with(&format!("{}:", v(vreg))));
+    }
+
+    // Whichever value gave way ran into a conflict first.
+    let spilled: Vec<&VregTrace> = traces
+        .iter()
+        .filter(|trace| trace.placement.iter().any(|(_, alloc)| alloc.is_stack()))
+        .collect();
+    assert!(!spilled.is_empty());
+    for trace in spilled {
+        assert!(trace.events.iter().any(|event| matches!(
+            event,
+            VregTraceEvent::Conflict { .. }
+                | VregTraceEvent::Evicted { .. }
+                | VregTraceEvent::Split { .. }
+                | VregTraceEvent::SplitMinimal { .. }
+        )));
+    }
+}
+
+#[test]
+fn test_queue_tie_break() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Each of v0..v8 is live for exactly three instructions, so
+    // their bundles have equal priority, and with too few
+    // registers to go around the order decides which are spilled.
+    let mut insts = vec![];
+    for i in 0..12 {
+        let mut ops = vec![];
+        if i < 9 {
+            ops.push(Operand::reg_def(v(i)));
+        }
+        if i >= 3 {
+            ops.push(Operand::any_use(v(i - 3)));
+        }
+        insts.push(ops);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 9,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let options = RegallocOptions::default();
+    let run_with = |tie_break: &QueueTieBreak| {
+        let out = run_with_queue_tie_break(&func, &env, &options, tie_break).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+
+    let base = run(&func, &env, &options).unwrap();
+    let equal = run_with(&|_, _| core::cmp::Ordering::Equal);
+    assert_eq!(equal.allocs, base.allocs);
+
+    let earliest_first = run_with(&|a, b| b.range.from.cmp(&a.range.from));
+    let latest_first = run_with(&|a, b| a.range.from.cmp(&b.range.from));
+    assert_ne!(earliest_first.allocs, latest_first.allocs);
+}
+
+#[test]
+fn test_split_hook() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is live across a clobber of the only register at inst4, so
+    // it is split once.
+    let mut clobbers = vec![PRegSet::empty(); 8];
+    clobbers[4].add(r(0));
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(8))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 1,
+        clobbers,
+        ..Default::default()
+    };
+    let env = int_env(1);
+    let options = RegallocOptions {
+        collect_split_events: true,
+        ..RegallocOptions::default()
+    };
+    let run_with = |point: Option<ProgPoint>| {
+        let mut proposals = vec![];
+        let mut hook = |proposal: SplitProposal| {
+            proposals.push(proposal);
+            point.unwrap_or(proposal.point)
+        };
+        let out = run_with_split_hook(&func, &env, &options, &mut hook).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        let split_at: Vec<_> = out.split_events.iter().map(|event| event.at).collect();
+        (out, proposals, split_at)
+    };
+
+    // Returning the proposal unchanged is the same as no hook.
+    let base = run(&func, &env, &options).unwrap();
+    let (out, proposals, split_at) = run_with(None);
+    assert_eq!(out.allocs, base.allocs);
+    assert_eq!(
+        alloc::format!("{:?}", out.edits),
+        alloc::format!("{:?}", base.edits)
+    );
+    assert_eq!(
+        proposals,
+        vec![SplitProposal {
+            bundle_from: ProgPoint::after(Inst::new(0)),
+            bundle_to: ProgPoint::after(Inst::new(6)),
+            point: ProgPoint::after(Inst::new(4)),
+            bundle_from_loop_depth: 0,
+            point_loop_depth: 0,
+        }]
+    );
+    assert_eq!(split_at, vec![Some(ProgPoint::before(Inst::new(5)))]);
+
+    // The hook can move the split anywhere in the bundle, and points
+    // outside it are clamped to it.
+    let (_, _, split_at) = run_with(Some(ProgPoint::before(Inst::new(2))));
+    assert_eq!(split_at, vec![Some(ProgPoint::before(Inst::new(2)))]);
+    let (_, _, split_at) = run_with(Some(ProgPoint::before(Inst::new(100))));
+    assert_eq!(split_at, vec![Some(ProgPoint::before(Inst::new(6)))]);
+}
+
+#[test]
+fn test_max_bundle_attempts() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Each vreg is used three and six instructions after its def,
+    // with too few registers to keep them all in one place, so
+    // some bundles need more than one attempt.
+    let mut insts = vec![];
+    for i in 0..12 {
+        let mut ops = vec![Operand::reg_def(v(i))];
+        if i >= 3 {
+            ops.push(Operand::reg_use(v(i - 3)));
+        }
+        if i >= 6 {
+            ops.push(Operand::reg_use(v(i - 6)));
+        }
+        insts.push(ops);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 12,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let unlimited = run(&func, &env, &RegallocOptions::default()).unwrap();
+
+    // Find the smallest cap that suffices; every smaller one fails.
+    let mut max_attempts = 0;
+    let out = loop {
+        let options = RegallocOptions {
+            max_bundle_attempts: Some(max_attempts),
+            ..RegallocOptions::default()
+        };
+        match run(&func, &env, &options) {
+            Ok(out) => break out,
+            Err(RegAllocError::AllocationBudgetExceeded(bundle, vreg)) => {
+                assert!(bundle.is_valid());
+                assert!(vreg.vreg() < func.num_vregs);
+                max_attempts += 1;
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    };
+    assert!(max_attempts >= 2);
+    // A sufficient cap changes nothing.
+    assert_eq!(out.allocs, unlimited.allocs);
+    assert_eq!(out.edits.len(), unlimited.edits.len());
+}
+
+#[test]
+fn test_spill_weight_model() {
+    #[derive(Debug)]
+    struct Builtin;
+    impl SpillWeightModel for Builtin {}
+    // Fixed-register uses are much more valuable than any other.
+    #[derive(Debug)]
+    struct FixedHeavy;
+    impl SpillWeightModel for FixedHeavy {
+        fn use_spill_weight(
+            &self,
+            constraint: OperandConstraint,
+            _loop_depth: usize,
+            _is_def: bool,
+        ) -> f32 {
+            match constraint {
+                OperandConstraint::FixedReg(_) => 100000.0,
+                _ => 1.0,
+            }
+        }
+    }
+    static BUILTIN: Builtin = Builtin;
+    static FIXED_HEAVY: FixedHeavy = FixedHeavy;
+
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v1 and v2 are live across the uses of v0, with only two
+    // registers, so one of them is spilled there. v2 has a
+    // fixed-register use, and is otherwise the lighter one.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![
+                Operand::reg_use(v(1)),
+                Operand::reg_fixed_use(v(2), PReg::new(1, RegClass::Int)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(8))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let run_with = |model: Option<&'static dyn SpillWeightModel>| {
+        let options = RegallocOptions {
+            spill_weight_model: model,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+    let spilled = |out: &Output| {
+        let mut vregs: Vec<_> = out
+            .ranges()
+            .filter(|&(_, _, alloc)| alloc.is_stack())
+            .map(|(vreg, _, _)| vreg)
+            .collect();
+        vregs.dedup();
+        vregs
+    };
+
+    let out = run_with(None);
+    assert_eq!(spilled(&out), vec![v(2)]);
+    // The provided method is the built-in model.
+    let builtin = run_with(Some(&BUILTIN));
+    assert_eq!(builtin.allocs, out.allocs);
+    assert_eq!(spilled(&builtin), vec![v(2)]);
+    // Under the other model, v1 is spilled instead.
+    let out = run_with(Some(&FIXED_HEAVY));
+    assert_eq!(spilled(&out), vec![v(1)]);
+}
+
+#[test]
+fn test_reg_traversal_order() {
+    // Try the highest-numbered registers first, skipping r0.
+    #[derive(Debug)]
+    struct Descending;
+    impl RegTraversalOrder for Descending {
+        fn candidates(&self, class: RegClass, _hint: PReg) -> Vec<PReg> {
+            (1..4).rev().map(|i| PReg::new(i, class)).collect()
+        }
+    }
+    static DESCENDING: Descending = Descending;
+
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_fixed_def(v(2), r(0))],
+            vec![
+                Operand::reg_use(v(0)),
+                Operand::reg_use(v(1)),
+                Operand::reg_use(v(2)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let options = RegallocOptions {
+        reg_traversal_order: Some(&DESCENDING),
+        ..RegallocOptions::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    let regs: Vec<_> = out
+        .ranges()
+        .map(|(vreg, _, alloc)| (vreg, alloc.as_reg().unwrap()))
+        .collect();
+    for (vreg, preg) in regs {
+        if vreg == v(2) {
+            // The fixed register is honored though not listed.
+            assert_eq!(preg, r(0));
+        } else {
+            assert!(preg == r(3) || preg == r(2));
+        }
+    }
+    assert_eq!(out.edits.len(), 0);
+}
+
+#[test]
+fn test_spillslot_reuse() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let stack = |vreg, kind| Operand::new(vreg, OperandConstraint::Stack, kind, OperandPos::Late);
+    // block0 branches to block1 and block2, which join at block3.
+    // v0 is live in block0 and block2, around v1 in block1, so
+    // the extents of the two overlap but their live ranges do not.
+    let func = TestFunc {
+        insts: vec![
+            vec![stack(v(0), OperandKind::Def)],
+            vec![],
+            vec![stack(v(1), OperandKind::Def)],
+            vec![stack(v(1), OperandKind::Use)],
+            vec![],
+            vec![stack(v(0), OperandKind::Use)],
+            vec![],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(2)),
+            InstRange::new(Inst::new(2), Inst::new(5)),
+            InstRange::new(Inst::new(5), Inst::new(7)),
+            InstRange::new(Inst::new(7), Inst::new(8)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0)],
+            vec![Block::new(0)],
+            vec![Block::new(1), Block::new(2)],
+        ],
+        succs: vec![
+            vec![Block::new(1), Block::new(2)],
+            vec![Block::new(3)],
+            vec![Block::new(3)],
+            vec![],
+        ],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    // Both values live in the one slot.
+    assert_eq!(out.num_spillslots, 1);
+    assert_eq!(out.allocs[0], out.allocs[1]);
+    assert!(out.allocs[0].is_stack());
+}
+
+#[test]
+fn test_cached_probes() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Every vreg is live across the whole function, with too few
+    // registers for all of them, so bundles evict each other and
+    // try again.
+    let mut insts = vec![];
+    for i in 0..8 {
+        insts.push(vec![Operand::reg_def(v(i))]);
+    }
+    for i in 0..8 {
+        insts.push(vec![Operand::reg_use(v(i)), Operand::reg_use(v(7 - i))]);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 8,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    assert!(out.stats.process_bundle_reg_probes_cached > 0);
+    assert!(out.stats.process_bundle_reg_probes_cached < out.stats.process_bundle_reg_probes_any);
+}
+
+#[test]
+fn test_minimal_bundle_granularity() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is defined and then used by three instructions, which
+    // also use each other's results.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0)), Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let mach_env = int_env(2);
+
+    for granularity in [
+        MinimalBundleGranularity::Inst,
+        MinimalBundleGranularity::ProgPoint,
+    ] {
+        let options = RegallocOptions {
+            minimal_bundle_granularity: granularity,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &mach_env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &mach_env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+
+        // Break v0 up around its uses: the piece for the def
+        // covers one program point, the rest two.
+        let cfginfo = cfg::CFGInfo::new(&func).unwrap();
+        let mut env = ion::data_structures::Env::new(&func, &mach_env, cfginfo, &options);
+        env.init().unwrap();
+        let v0 = ion::data_structures::VRegIndex::new(0);
+        let bundle = env.ranges[env.vregs[v0].ranges[0].index].bundle;
+        env.split_into_minimal_bundles(bundle, PReg::invalid(), SpillReason::SplitBudgetExhausted);
+        let spill = env.spillsets[env.bundles[bundle].spillset].spill_bundle;
+        let mut pieces = vec![];
+        for entry in &env.vregs[v0].ranges {
+            let piece = env.ranges[entry.index].bundle;
+            if piece != spill {
+                pieces.push((entry.range, piece));
+            }
+        }
+        pieces.sort_by_key(|&(range, _)| range.from);
+        assert_eq!(pieces.len(), 4);
+        for (range, piece) in pieces {
+            assert!(env.unsplittable_bundle(piece));
+            let one_point = range.to == range.from.next();
+            assert_eq!(
+                env.minimal_bundle(piece),
+                one_point || granularity == MinimalBundleGranularity::Inst
+            );
+        }
+    }
+}
+
+#[test]
+fn test_minimal_promotion_attempts() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v1 is defined in r1 but needed in r0 at inst3. At the
+    // program-point granularity the piece of v1 there is not
+    // minimal, and is only placed once promoted to minimal.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v(1), r(1))],
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(1)), Operand::reg_fixed_def(v(2), r(0))],
+            vec![Operand::reg_fixed_use(v(1), r(0)), Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let options = |granularity, max_attempts| RegallocOptions {
+        minimal_bundle_granularity: granularity,
+        max_bundle_attempts: Some(max_attempts),
+        ..RegallocOptions::default()
+    };
+    assert!(run(&func, &env, &options(MinimalBundleGranularity::Inst, 2)).is_ok());
+
+    // The round after the promotion still counts against the cap.
+    assert!(matches!(
+        run(&func, &env, &options(MinimalBundleGranularity::ProgPoint, 2)),
+        Err(RegAllocError::AllocationBudgetExceeded(_, vreg)) if vreg == v(1)
+    ));
+    let out = run(
+        &func,
+        &env,
+        &options(MinimalBundleGranularity::ProgPoint, 3),
+    )
+    .unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+}
+
+#[test]
+fn test_too_many_live_regs_location() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // inst2 needs three values in registers at once, with only
+    // two available.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![
+                Operand::reg_use(v(0)),
+                Operand::reg_use(v(1)),
+                Operand::reg_use(v(2)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    match run(&func, &int_env(2), &RegallocOptions::default()) {
+        Err(RegAllocError::TooManyLiveRegs {
+            point,
+            class,
+            fixed,
+            minimal,
+            total,
+        }) => {
+            assert_eq!(point, ProgPoint::before(Inst::new(2)));
+            assert_eq!(class, RegClass::Int);
+            assert_eq!((fixed, minimal, total), (0, 2, 2));
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+
+    // With the other two values fixed to the two registers, the
+    // failure is down to the fixed constraints instead.
+    let r = |i| PReg::new(i, RegClass::Int);
+    let func = TestFunc {
+        insts: vec![
+            func.insts[0].clone(),
+            func.insts[1].clone(),
+            vec![
+                Operand::reg_fixed_use(v(0), r(0)),
+                Operand::reg_fixed_use(v(1), r(1)),
+                Operand::reg_use(v(2)),
+            ],
+            vec![],
+        ],
+        ..func
+    };
+    match run(&func, &int_env(2), &RegallocOptions::default()) {
+        Err(RegAllocError::TooManyLiveRegs {
+            fixed,
+            minimal,
+            total,
+            ..
+        }) => assert_eq!((fixed, minimal, total), (2, 0, 2)),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_preferred_scratch() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // inst1 needs v0 and v1 swapped between p0 and p1, which
+    // takes a scratch register to break the cycle.
+    let func = TestFunc {
+        insts: vec![
+            vec![
+                Operand::reg_fixed_def(v(0), r(0)),
+                Operand::reg_fixed_def(v(1), r(1)),
+            ],
+            vec![
+                Operand::reg_fixed_use(v(0), r(1)),
+                Operand::reg_fixed_use(v(1), r(0)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(3))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let scratch_regs = |options: &RegallocOptions| {
+        let out = run(&func, &env, options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        let mut regs = vec![];
+        for (_, edit) in &out.edits {
+            if let Edit::Move { to, .. } = *edit {
+                let to = to.as_reg().unwrap();
+                if to != r(0) && to != r(1) {
+                    regs.push(to);
+                }
+            }
+        }
+        regs
+    };
+
+    let default = scratch_regs(&RegallocOptions::default());
+    assert_eq!(default.len(), 1);
+    let preferred = if default[0] == r(3) { r(2) } else { r(3) };
+    let options = RegallocOptions {
+        preferred_scratch_by_class: [Some(preferred), None, None],
+        ..RegallocOptions::default()
+    };
+    assert_eq!(scratch_regs(&options), vec![preferred]);
+
+    // A preferred register that holds a value there is passed
+    // over.
+    let options = RegallocOptions {
+        preferred_scratch_by_class: [Some(r(0)), None, None],
+        ..RegallocOptions::default()
+    };
+    assert_eq!(scratch_regs(&options), default);
+}
+
+#[test]
+fn test_move_cost_scale() {
+    assert_eq!(
+        ObjectiveWeights::move_cost_scale(1.0),
+        ObjectiveWeights::default()
+    );
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is live around the loop in blocks 1 and 2 while v1 and v2
+    // take both registers in it, so it has to split or spill
+    // somewhere; however expensive moves are made, that should
+    // not be inside the loop.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(2)),
+            InstRange::new(Inst::new(2), Inst::new(5)),
+            InstRange::new(Inst::new(5), Inst::new(6)),
+            InstRange::new(Inst::new(6), Inst::new(8)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0), Block::new(2)],
+            vec![Block::new(1)],
+            vec![Block::new(1)],
+        ],
+        succs: vec![
+            vec![Block::new(1)],
+            vec![Block::new(2), Block::new(3)],
+            vec![Block::new(1)],
+            vec![],
+        ],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    for scale in [1.0, 8.0, 1000.0] {
+        let options = RegallocOptions {
+            objective: ObjectiveWeights::move_cost_scale(scale),
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        // The spill and reload stay outside the loop.
+        assert_eq!(out.edits.len(), 2);
+        for &(pos, _) in &out.edits {
+            assert!(!(2..6).contains(&pos.inst().index()), "{:?}", pos);
+        }
+    }
+
+    // v0 is lighter than both v1 and v2, so must split. Splitting
+    // where it first meets v1, in the warm block 1, is cheapest
+    // by default; once moves are expensive enough, it splits
+    // just after it meets the heavier v2 instead, in block 2.
+    let mut insts = vec![vec![]; 16];
+    insts[1] = vec![Operand::reg_def(v(0))];
+    insts[3] = vec![Operand::reg_def(v(1))];
+    insts[4] = vec![Operand::reg_use(v(1))];
+    insts[5] = vec![Operand::reg_def(v(2))];
+    for i in [6, 7, 9, 10, 11, 13] {
+        insts[i] = vec![Operand::reg_use(v(2))];
+    }
+    insts[8] = vec![Operand::reg_use(v(0))];
+    insts[12] = vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))];
+    insts[14] = vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))];
+    let func = TestFunc {
+        insts,
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(3)),
+            InstRange::new(Inst::new(3), Inst::new(5)),
+            InstRange::new(Inst::new(5), Inst::new(16)),
+        ],
+        preds: vec![vec![], vec![Block::new(0)], vec![Block::new(1)]],
+        succs: vec![vec![Block::new(1)], vec![Block::new(2)], vec![]],
+        num_vregs: 3,
+        frequency: vec![1.0, 2.0, 1.0],
+        ..Default::default()
+    };
+    let first_split = |scale| {
+        let options = RegallocOptions {
+            objective: ObjectiveWeights::move_cost_scale(scale),
+            trace_vreg: Some(v(0)),
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        let trace = out.vreg_trace.unwrap();
+        trace
+            .events
+            .iter()
+            .find_map(|event| match *event {
+                VregTraceEvent::Split { at, .. } => Some(at.inst()),
+                _ => None,
+            })
+            .unwrap()
+    };
+    assert_eq!(first_split(1.0), Inst::new(3));
+    assert_eq!(first_split(1000.0), Inst::new(6));
+}
+
+#[test]
+fn test_objective_weights() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // With one register, the long and lightly used v0 takes it
+    // first; v1, used densely in the middle, then has to either
+    // evict it or split.
+    let mut insts = vec![vec![]; 12];
+    insts[0] = vec![Operand::reg_def(v(0))];
+    insts[3] = vec![Operand::reg_def(v(1))];
+    insts[4] = vec![Operand::reg_use(v(1))];
+    insts[5] = vec![Operand::reg_use(v(1))];
+    insts[6] = vec![Operand::reg_use(v(1))];
+    insts[10] = vec![Operand::reg_use(v(0))];
+    let func = TestFunc {
+        insts,
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(12))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(1);
+    let first_decision = |objective| {
+        let options = RegallocOptions {
+            objective,
+            trace_vreg: Some(v(1)),
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out.vreg_trace
+            .unwrap()
+            .events
+            .into_iter()
+            .find(|event| {
+                matches!(
+                    event,
+                    VregTraceEvent::Evict { .. } | VregTraceEvent::Split { .. }
+                )
+            })
+            .unwrap()
+    };
+    // By default the heavier v1 evicts v0; once moves are cheap
+    // enough relative to spills, it splits instead.
+    assert!(matches!(
+        first_decision(ObjectiveWeights::default()),
+        VregTraceEvent::Evict { .. }
+    ));
+    assert!(matches!(
+        first_decision(ObjectiveWeights {
+            spill: 1.0,
+            reg_move: 0.01,
+        }),
+        VregTraceEvent::Split { .. }
+    ));
+
+    for (spill, reg_move) in [
+        (0.0, 1.0),
+        (1.0, 0.0),
+        (-1.0, 1.0),
+        (1.0, f32::NAN),
+        (f32::INFINITY, 1.0),
+    ] {
+        let options = RegallocOptions {
+            objective: ObjectiveWeights { spill, reg_move },
+            ..RegallocOptions::default()
+        };
+        assert!(!options.objective.is_valid());
+        assert!(matches!(
+            run(&func, &env, &options),
+            Err(RegAllocError::InvalidObjectiveWeights)
+        ));
+    }
+}
+
+#[test]
+fn test_min_high_cost_threshold() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Five values with overlapping lifetimes compete for three
+    // registers, so that probes find conflicts of various costs.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(0)), Operand::reg_def(v(3))],
+            vec![Operand::reg_use(v(1)), Operand::reg_def(v(4))],
+            vec![Operand::reg_use(v(2)), Operand::reg_use(v(3))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(4))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(9))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 5,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    // Never giving up early scans more but allocates the same.
+    let base = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let options = RegallocOptions {
+        min_high_cost_threshold: u32::MAX,
+        ..RegallocOptions::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    assert_eq!(out.allocs, base.allocs);
+    assert_eq!(
+        alloc::format!("{:?}", out.edits),
+        alloc::format!("{:?}", base.edits)
+    );
+    assert!(!out.edits.is_empty());
+}
+
+#[test]
+fn test_spill_reasons() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let stack_use = Operand::new(
+        v(4),
+        OperandConstraint::Stack,
+        OperandKind::Use,
+        OperandPos::Early,
+    );
+    // v0 loses its register to v1 and v2, v3 is never needed in a
+    // register, and v4 is used from the stack.
+    let func = TestFunc {
+        insts: vec![
+            vec![
+                Operand::reg_def(v(0)),
+                Operand::any_def(v(3)),
+                Operand::any_def(v(4)),
+            ],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0)), Operand::any_use(v(3)), stack_use],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 5,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.spill_reasons.is_empty());
+
+    let options = RegallocOptions {
+        collect_spill_reasons: true,
+        ..RegallocOptions::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+    assert_eq!(
+        out.spill_reasons,
+        vec![
+            (v(0), SpillReason::LostEvictions),
+            (v(3), SpillReason::NoUses),
+            (v(4), SpillReason::StackRequired),
+        ]
+    );
+
+    // In single-pass mode, v0 goes straight to minimal bundles.
+    let single_pass = RegallocOptions {
+        single_pass: true,
+        ..options
+    };
+    let out = run(&func, &env, &single_pass).unwrap();
+    assert!(out.spill_reasons.contains(&(v(0), SpillReason::SinglePass)));
+
+    // v1 needs v0's fixed register while v0 is live; v0 is split
+    // around it, but both pieces stay in registers, so nothing is
+    // spilled and there is no reason to report.
+    let r0 = PReg::new(0, RegClass::Int);
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v(0), r0)],
+            vec![Operand::reg_fixed_def(v(1), r0)],
+            vec![Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+    assert_eq!(out.num_spillslots, 0);
+    assert!(out.spill_reasons.is_empty());
+}
+
+#[test]
+fn test_max_unsplit_range_length() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is live from the entry block to the exit block, across eight
+    // blocks that each need both registers for a pair of locals.
+    let mut insts = vec![vec![Operand::reg_def(v(0))], vec![]];
+    let mut blocks = vec![InstRange::new(Inst::new(0), Inst::new(2))];
+    let mut num_vregs = 1;
+    for _ in 0..8 {
+        let start = insts.len();
+        let (a, b) = (v(num_vregs), v(num_vregs + 1));
+        insts.push(vec![Operand::reg_def(a), Operand::reg_def(b)]);
+        insts.push(vec![Operand::reg_use(a), Operand::reg_use(b)]);
+        insts.push(vec![]);
+        blocks.push(InstRange::new(Inst::new(start), Inst::new(start + 3)));
+        num_vregs += 2;
+    }
+    let start = insts.len();
+    insts.push(vec![Operand::reg_use(v(0))]);
+    insts.push(vec![]);
+    blocks.push(InstRange::new(Inst::new(start), Inst::new(start + 2)));
+    let n = blocks.len();
+    let func = TestFunc {
+        insts,
+        blocks,
+        preds: (0..n)
+            .map(|b| {
+                if b == 0 {
+                    vec![]
+                } else {
+                    vec![Block::new(b - 1)]
+                }
+            })
+            .collect(),
+        succs: (0..n)
+            .map(|b| {
+                if b + 1 == n {
+                    vec![]
+                } else {
+                    vec![Block::new(b + 1)]
+                }
+            })
+            .collect(),
+        num_vregs,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let run_with_cap = |max_unsplit_range_length| {
+        let options = RegallocOptions {
+            max_unsplit_range_length,
+            collect_spill_reasons: true,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+
+    // Uncapped, v0 wins a register over its whole extent, then is
+    // evicted by the first block's locals and everything is probed
+    // again.
+    let base = run_with_cap(None);
+    assert_eq!(base.stats.evict_bundle_count, 1);
+
+    // Capped, v0 is split at a block boundary before probing, so
+    // the locals find free registers at the first try and nothing is
+    // evicted, for the same spill and reload.
+    let capped = run_with_cap(Some(4));
+    assert_eq!(capped.stats.evict_bundle_count, 0);
+    assert!(
+        capped.stats.process_bundle_reg_probes_any < base.stats.process_bundle_reg_probes_any,
+        "{} vs {} probes",
+        capped.stats.process_bundle_reg_probes_any,
+        base.stats.process_bundle_reg_probes_any
+    );
+    assert_eq!(capped.edits.len(), base.edits.len());
+    assert!(capped
+        .spill_reasons
+        .contains(&(v(0), SpillReason::RangeTooLong)));
+
+    // A cap longer than the function changes nothing.
+    let long = run_with_cap(Some(func.insts.len()));
+    assert_eq!(long.stats.splits, base.stats.splits);
+    assert_eq!(long.allocs, base.allocs);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_time_budget() {
+    use core::time::Duration;
+    let v = |i| VReg::new(i, RegClass::Int);
+    // More values than registers around a loop, with a fixed-register
+    // use and a reused input, so the allocator has to evict and split.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(1))],
+            vec![],
+            vec![Operand::reg_def(v(2)), Operand::reg_def(v(3))],
+            vec![
+                Operand::reg_reuse_def(v(4), 1),
+                Operand::reg_use(v(2)),
+                Operand::reg_use(v(3)),
+            ],
+            vec![Operand::reg_fixed_use(v(4), PReg::new(0, RegClass::Int))],
+            vec![Operand::reg_use(v(1))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(2)),
+            InstRange::new(Inst::new(2), Inst::new(7)),
+            InstRange::new(Inst::new(7), Inst::new(8)),
+            InstRange::new(Inst::new(8), Inst::new(10)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0), Block::new(2)],
+            vec![Block::new(1)],
+            vec![Block::new(1)],
+        ],
+        succs: vec![
+            vec![Block::new(1)],
+            vec![Block::new(2), Block::new(3)],
+            vec![Block::new(1)],
+            vec![],
+        ],
+        num_vregs: 5,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let run_with_budget = |time_budget| {
+        let options = RegallocOptions {
+            time_budget,
+            collect_spill_reasons: true,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+
+    // A generous budget is never hit.
+    let base = run_with_budget(None);
+    let generous = run_with_budget(Some(Duration::from_secs(3600)));
+    assert_eq!(generous.stats.splits_timeout, 0);
+    assert_eq!(generous.allocs, base.allocs);
+    assert!(base.stats.evict_bundle_count > 0);
+
+    // An exhausted budget reduces every bundle taken from the queue
+    // to minimal bundles, without evicting anything, and the result
+    // still passes the checker.
+    let out = run_with_budget(Some(Duration::ZERO));
+    assert!(out.stats.splits_timeout > 0);
+    assert_eq!(out.stats.evict_bundle_count, 0);
+    assert!(out
+        .spill_reasons
+        .iter()
+        .any(|&(_, reason)| reason == SpillReason::TimeBudget));
+}
+
+#[test]
+fn test_run_fast() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let check = |func: &TestFunc, env: &MachineEnv, out: &Output| {
+        let mut checker = checker::Checker::new(func, env);
+        checker.prepare(out);
+        checker.run().unwrap();
+    };
+    let env = int_env(2);
+
+    // 32 values defined in turn and used in reverse order. The full
+    // allocator gives each value a register, only to evict and split
+    // it for the next, shorter one; a single pass spills each value
+    // that finds no free register straight away, for the same moves
+    // with a quarter fewer bundles processed.
+    let n = 32;
+    let mut insts: Vec<_> = (0..n).map(|i| vec![Operand::reg_def(v(i))]).collect();
+    insts.extend((0..n).rev().map(|i| vec![Operand::reg_use(v(i))]));
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: n,
+        ..Default::default()
+    };
+    let full = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let fast = run_fast(&func, &env, &RegallocOptions::default()).unwrap();
+    check(&func, &env, &full);
+    check(&func, &env, &fast);
+    assert_eq!(full.stats.evict_bundle_count, 30);
+    assert_eq!(fast.stats.evict_bundle_count, 0);
+    assert_eq!(fast.stats.splits, 0);
+    assert!(
+        fast.stats.process_bundle_count < full.stats.process_bundle_count,
+        "{} vs {} bundles processed",
+        fast.stats.process_bundle_count,
+        full.stats.process_bundle_count
+    );
+    assert_eq!(fast.edits.len(), full.edits.len());
+
+    // A chain of values each used by the next and then all used at
+    // the end. Queued by spill weight, the single pass places the
+    // values around their dense first uses before the long sparse
+    // stretches, and spills no more than the full allocator.
+    let n = 24;
+    let mut insts: Vec<_> = (0..n)
+        .map(|i| {
+            let mut ops = vec![Operand::reg_def(v(i))];
+            if i > 0 {
+                ops.push(Operand::reg_use(v(i - 1)));
+            }
+            ops
+        })
+        .collect();
+    insts.extend((0..n).map(|i| vec![Operand::reg_use(v(i))]));
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: n,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let full = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let fast = run_fast(&func, &env, &RegallocOptions::default()).unwrap();
+    check(&func, &env, &full);
+    check(&func, &env, &fast);
+    assert!(
+        fast.edits.len() <= full.edits.len() + 1,
+        "{} vs {} edits",
+        fast.edits.len(),
+        full.edits.len()
+    );
+}
+
+#[test]
+fn test_commit_threshold() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // 16 values defined in turn and used in reverse order, with two
+    // registers. Each value is only a little heavier than the one
+    // before it, so by default each evicts its predecessor in turn.
+    let n = 16;
+    let mut insts: Vec<_> = (0..n).map(|i| vec![Operand::reg_def(v(i))]).collect();
+    insts.extend((0..n).rev().map(|i| vec![Operand::reg_use(v(i))]));
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: n,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let run_with_threshold = |commit_threshold| {
+        let options = RegallocOptions {
+            commit_threshold,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+
+    // With a threshold, most of those marginal evictions give way to
+    // splitting the newcomer, for the same spills and reloads.
+    let base = run_with_threshold(0);
+    let out = run_with_threshold(1000);
+    assert_eq!(base.stats.evict_bundle_count, 14);
+    assert!(
+        out.stats.evict_bundle_count < base.stats.evict_bundle_count / 2,
+        "{} evictions",
+        out.stats.evict_bundle_count
+    );
+    assert_eq!(out.edits.len(), base.edits.len());
+}
+
+#[test]
+fn test_pack_low() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Six values, each dead before the next is defined.
+    let mut insts = vec![];
+    for i in 0..6 {
+        insts.push(vec![Operand::reg_def(v(i))]);
+        insts.push(vec![Operand::reg_use(v(i))]);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 6,
+        ..Default::default()
+    };
+    let env = int_env(8);
+    let regs_used = |scan_mode| {
+        let options = RegallocOptions {
+            scan_mode,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut regs = PRegSet::empty();
+        for alloc in &out.allocs {
+            regs.add(alloc.as_reg().unwrap());
+        }
+        regs.into_iter().count()
+    };
+
+    // The default scan spreads them over the registers; packing
+    // reuses the first register for all of them.
+    assert!(regs_used(RegScanMode::Spread) > 1);
+    assert_eq!(regs_used(RegScanMode::PackLow), 1);
+}
+
+#[test]
+fn test_two_phase() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 spans two short values fixed to r0 and r1.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_fixed_def(v(1), r(0))],
+            vec![Operand::reg_fixed_use(v(1), r(0))],
+            vec![Operand::reg_fixed_def(v(2), r(1))],
+            vec![Operand::reg_fixed_use(v(2), r(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(7))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let run_with = |two_phase| {
+        let options = RegallocOptions {
+            two_phase,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+
+    // Taken first for being longest, v0 lands in a register one of
+    // the fixed values needs and is evicted from it. Placing the
+    // fixed values first leaves v0 to find the free register
+    // directly, for the same allocation.
+    let base = run_with(false);
+    let out = run_with(true);
+    assert_eq!(base.stats.evict_bundle_count, 1);
+    assert_eq!(out.stats.evict_bundle_count, 0);
+    assert_eq!(out.stats.splits, 0);
+    assert_eq!(out.allocs, base.allocs);
+    assert_eq!(out.allocs[0], Allocation::reg(r(2)));
+}
+
+#[test]
+fn test_eager_spill_defs() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is used twice in a register before v1 and v2 force it out,
+    // then reloaded for its last use.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(7))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let run_with = |eager_spill_defs| {
+        let options = RegallocOptions {
+            eager_spill_defs,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+    let stores = |out: &Output| {
+        out.edits
+            .iter()
+            .filter_map(|(pos, edit)| match edit {
+                Edit::Move { from, to } if from.is_reg() && to.is_stack() => Some(*pos),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // By default the store happens where v0 leaves its register;
+    // eagerly it happens right after the def instead, with the
+    // reload unchanged.
+    let base = run_with(false);
+    let out = run_with(true);
+    assert_eq!(stores(&base), vec![ProgPoint::before(Inst::new(3))]);
+    assert_eq!(stores(&out), vec![ProgPoint::before(Inst::new(1))]);
+    assert_eq!(out.edits.len(), base.edits.len());
+    assert_eq!(
+        alloc::format!("{:?}", out.edits[1]),
+        alloc::format!("{:?}", base.edits[1])
+    );
+    assert_eq!(out.allocs, base.allocs);
+    assert_eq!(out.allocs[0], Allocation::reg(r(0)));
+}
+
+#[test]
+fn test_trim_empty_regions() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is used at inst1 and inst8, with v1 taking the only register
+    // over inst4 and inst5 in between.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+            vec![],
+            vec![Operand::reg_fixed_def(v(1), r(0))],
+            vec![Operand::reg_fixed_use(v(1), r(0))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(10))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(1);
+    let run_with = |trim_empty_regions| {
+        let options = RegallocOptions {
+            trim_empty_regions,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+    let store_at = |out: &Output| {
+        out.edits
+            .iter()
+            .find_map(|(pos, edit)| match edit {
+                Edit::Move { from, to } if from.is_reg() && to.is_stack() => Some(*pos),
+                _ => None,
+            })
+            .unwrap()
+    };
+
+    // Trimmed, the use-free stretch after inst1 goes to the spill
+    // bundle and v0 is stored right after its use. Untrimmed, v0
+    // stays in its register until v1 needs it.
+    let base = run_with(true);
+    let out = run_with(false);
+    assert_eq!(store_at(&base), ProgPoint::before(Inst::new(2)));
+    assert_eq!(store_at(&out), ProgPoint::before(Inst::new(4)));
+    assert_eq!(out.edits.len(), base.edits.len());
+    assert_eq!(out.allocs, base.allocs);
+}
@@ -0,0 +1,1670 @@
+//! Tests of the queries and renderings over `Output`.
+
+use super::*;
+
+#[test]
+fn test_resolve_moves_at() {
+    let func = TestFunc {
+        insts: vec![vec![], vec![]],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(2))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 0,
+        ..Default::default()
+    };
+    let mut out = run(&func, &int_env(8), &RegallocOptions::default()).unwrap();
+    let r = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+    let s = |i| Allocation::stack(SpillSlot::new(i));
+    let mv = |pos, from, to| (pos, Edit::Move { from, to });
+
+    // Apply `moves` in sequence to a state in which every location
+    // holds its own name, and return what `locs` end up holding.
+    let simulate = |moves: &[(Allocation, Allocation)], locs: &[Allocation]| {
+        let mut state: Vec<(Allocation, Allocation)> = vec![];
+        for &(from, to) in moves {
+            let val = state
+                .iter()
+                .find(|&&(loc, _)| loc == from)
+                .map_or(from, |&(_, val)| val);
+            state.retain(|&(loc, _)| loc != to);
+            state.push((to, val));
+        }
+        locs.iter()
+            .map(|&loc| {
+                state
+                    .iter()
+                    .find(|&&(l, _)| l == loc)
+                    .map_or(loc, |&(_, val)| val)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // A swap of r0 and r1 and a rotation of r4, r5 and s0, all
+    // through r2, with an unrelated move at a later point.
+    let p0 = ProgPoint::before(Inst::new(0));
+    let p1 = ProgPoint::before(Inst::new(1));
+    out.edits = vec![
+        mv(p0, r(0), r(2)),
+        mv(p0, r(1), r(0)),
+        mv(p0, r(2), r(1)),
+        mv(p0, r(4), r(2)),
+        mv(p0, r(5), r(4)),
+        mv(p0, s(0), r(5)),
+        mv(p0, r(2), s(0)),
+        mv(p1, r(3), r(6)),
+    ];
+    let locs = [r(0), r(1), r(4), r(5), s(0), r(6)];
+    let expected = [r(1), r(0), r(5), s(0), r(4), r(6)];
+
+    // Reusing the allocator's scratch: its final contents are
+    // dropped, and both cycles go through it.
+    let moves = out.resolve_moves_at(p0, PReg::new(2, RegClass::Int));
+    assert_eq!(simulate(&moves, &locs), expected);
+    assert_eq!(moves.len(), 7);
+    assert!(moves.iter().all(|&(_, to)| to != r(6)));
+
+    // With a different scratch, r2's final value is kept and the
+    // cycles go through r7 instead.
+    let moves = out.resolve_moves_at(p0, PReg::new(7, RegClass::Int));
+    assert_eq!(simulate(&moves, &[r(2)]), [r(4)]);
+    assert_eq!(simulate(&moves, &locs), expected);
+    assert!(moves.iter().any(|&(_, to)| to == r(7)));
+
+    // An acyclic chain needs no scratch.
+    out.edits = vec![mv(p0, r(1), r(2)), mv(p0, r(0), r(1))];
+    let moves = out.resolve_moves_at(p0, PReg::new(7, RegClass::Int));
+    assert_eq!(moves, [(r(1), r(2)), (r(0), r(1))]);
+    assert!(out
+        .resolve_moves_at(p1, PReg::new(7, RegClass::Int))
+        .is_empty());
+}
+
+#[test]
+fn test_spilled_vregs() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 must be on the stack while v1 and v2 take both
+    // registers.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+
+    let spilled: Vec<_> = out.spilled_vregs().collect();
+    assert!(!spilled.is_empty());
+    let (vreg, slot, range) = spilled[0];
+    assert_eq!(vreg, v(0));
+    assert!(range.contains_point(ProgPoint::after(Inst::new(1))));
+    assert!(range.contains_point(ProgPoint::before(Inst::new(2))));
+    // The value is stored to its slot and reloaded from it.
+    let slot = Allocation::stack(slot);
+    assert!(out
+        .edits
+        .iter()
+        .any(|(_, edit)| matches!(*edit, Edit::Move { to, .. } if to == slot)));
+    assert!(out
+        .edits
+        .iter()
+        .any(|(_, edit)| matches!(*edit, Edit::Move { from, .. } if from == slot)));
+    for pair in spilled.windows(2) {
+        let ((v1, s1, r1), (v2, s2, r2)) = (pair[0], pair[1]);
+        assert!(v1.vreg() < v2.vreg() || r1.to < r2.from || (r1.to == r2.from && s1 != s2));
+    }
+}
+
+#[test]
+fn test_code_range_overlaps() {
+    use crate::ion::data_structures::LiveRangeKey;
+    use core::cmp::Ordering;
+
+    let before = |i| ProgPoint::before(Inst::new(i));
+    let after = |i| ProgPoint::after(Inst::new(i));
+    let range = |from, to| CodeRange { from, to };
+
+    // Ranges are half-open at both positions of an instruction.
+    assert!(!range(before(1), after(1)).overlaps(&range(after(1), before(2))));
+    assert!(range(before(1), before(2)).overlaps(&range(after(1), before(2))));
+    assert!(!range(after(0), before(1)).overlaps(&range(before(1), after(1))));
+    assert!(range(after(0), after(1)).overlaps(&range(before(1), after(1))));
+
+    // Overlap agrees with both orderings used for interval maps.
+    let points: Vec<ProgPoint> = (0..3).flat_map(|i| [before(i), after(i)]).collect();
+    let ranges: Vec<CodeRange> = points
+        .iter()
+        .flat_map(|&from| {
+            points
+                .iter()
+                .filter(move |&&to| to > from)
+                .map(move |&to| range(from, to))
+        })
+        .collect();
+    for a in &ranges {
+        for b in &ranges {
+            let overlaps = a.overlaps(b);
+            assert_eq!(overlaps, b.overlaps(a));
+            assert_eq!(overlaps, a.cmp(b) == Ordering::Equal);
+            let (ka, kb) = (LiveRangeKey::from_range(a), LiveRangeKey::from_range(b));
+            assert_eq!(overlaps, ka == kb);
+            assert_eq!(a.cmp(b), ka.cmp(&kb));
+        }
+    }
+}
+
+#[test]
+fn test_edit_histogram() {
+    let r = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+    let s = |i| Allocation::stack(SpillSlot::new(i));
+    let edit = |from, to| Edit::Move { from, to };
+    assert_eq!(edit(r(0), s(0)).kind(), MoveKind::Spill);
+    assert_eq!(edit(s(0), r(0)).kind(), MoveKind::Reload);
+    assert_eq!(edit(s(0), s(1)).kind(), MoveKind::StackToStack);
+    assert_eq!(edit(r(0), r(1)).kind(), MoveKind::RegToReg);
+
+    let v = |i| VReg::new(i, RegClass::Int);
+    // With one register, v0 must be spilled around v1.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(1), &RegallocOptions::default()).unwrap();
+    let histogram = out.edit_histogram();
+    assert_eq!(histogram.iter().sum::<usize>(), out.edits.len());
+    assert!(histogram[MoveKind::Spill as usize] > 0);
+    assert!(histogram[MoveKind::Reload as usize] > 0);
+    assert_eq!(histogram[MoveKind::StackToStack as usize], 0);
+
+    let mut insts: Vec<Inst> = out.edits.iter().map(|&(pos, _)| pos.inst()).collect();
+    insts.dedup();
+    assert!(!insts.is_empty() && insts.len() <= out.edits.len());
+    assert_eq!(out.inst_with_edits_count(), insts.len());
+}
+
+#[test]
+fn test_inst_with_edits_count() {
+    let func = TestFunc {
+        insts: vec![vec![], vec![], vec![], vec![]],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 0,
+        ..Default::default()
+    };
+    let mut out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+    assert_eq!(out.inst_with_edits_count(), 0);
+
+    let r = |i| Allocation::reg(PReg::new(i, RegClass::Int));
+    let s = |i| Allocation::stack(SpillSlot::new(i));
+    let mv = |pos, from, to| (pos, Edit::Move { from, to });
+    // Edits before and after the same instruction count it once;
+    // inst 2 has none.
+    out.edits = vec![
+        mv(ProgPoint::before(Inst::new(1)), r(0), s(0)),
+        mv(ProgPoint::before(Inst::new(1)), r(1), r(0)),
+        mv(ProgPoint::after(Inst::new(1)), s(0), r(1)),
+        mv(ProgPoint::before(Inst::new(3)), r(0), r(1)),
+    ];
+    assert_eq!(out.inst_with_edits_count(), 2);
+    out.edits
+        .insert(0, mv(ProgPoint::after(Inst::new(0)), r(0), s(0)));
+    assert_eq!(out.inst_with_edits_count(), 3);
+}
+
+#[test]
+fn test_self_moves() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r0 = Allocation::reg(PReg::new(0, RegClass::Int));
+    // With one register, v0 must be spilled around v1.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let mut out = run(&func, &int_env(1), &RegallocOptions::default()).unwrap();
+    assert!(!out.edits.is_empty());
+    assert!(out.self_moves().is_empty());
+
+    // Edits rewritten after allocation may move a location to itself;
+    // these are listed, but rematerializations are not.
+    let at = ProgPoint::before(Inst::new(2));
+    out.edits.push((at, Edit::Move { from: r0, to: r0 }));
+    out.edits.push((
+        at,
+        Edit::Remat {
+            inst: Inst::new(0),
+            vreg: v(0),
+            to: r0,
+        },
+    ));
+    assert_eq!(out.self_moves(), vec![(at, r0)]);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "self-move")]
+fn test_self_move_assertion() {
+    let r0 = Allocation::reg(PReg::new(0, RegClass::Int));
+    let mut edits = ion::data_structures::Edits::with_capacity(1);
+    edits.add(
+        ion::data_structures::PosWithPrio {
+            prio: 0,
+            pos: ProgPoint::before(Inst::new(0)),
+        },
+        r0,
+        r0,
+    );
+}
+
+#[test]
+fn test_pressure_profile() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(6))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+    let per_inst: Vec<_> = out
+        .live_regs_by_inst
+        .iter()
+        .map(|live| live[RegClass::Int as usize] as usize)
+        .collect();
+    assert_eq!(per_inst, [1, 2, 2, 1, 1, 0]);
+    assert_eq!(out.pressure_profile(RegClass::Int, 6), per_inst);
+    assert_eq!(out.pressure_profile(RegClass::Int, 3), [2, 2, 1]);
+    assert_eq!(out.pressure_profile(RegClass::Int, 1), [2]);
+    assert_eq!(out.pressure_profile(RegClass::Float, 2), [0, 0]);
+    assert!(out.pressure_profile(RegClass::Int, 0).is_empty());
+}
+
+#[test]
+fn test_min_regs_required() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let func = |float: bool| {
+        let f = VReg::new(3, RegClass::Float);
+        let mut insts = vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+        ];
+        if float {
+            insts[0].push(Operand::reg_def(f));
+            insts[3].push(Operand::reg_use(f));
+        }
+        TestFunc {
+            insts,
+            blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+            preds: vec![vec![]],
+            succs: vec![vec![]],
+            num_vregs: 4,
+            ..Default::default()
+        }
+    };
+
+    // v0's use at inst2 is early and v2's def late, so at most two
+    // of v0, v1 and v2 are live at once.
+    assert_eq!(min_regs_required(&func(true)).unwrap(), [2, 1, 0]);
+    let func = func(false);
+    assert_eq!(min_regs_required(&func).unwrap(), [2, 0, 0]);
+
+    // That many registers are enough to allocate it without moves;
+    // with one fewer, inst3's two uses cannot both have one.
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+    assert!(out.edits.is_empty());
+    assert!(matches!(
+        run(&func, &int_env(1), &RegallocOptions::default()),
+        Err(RegAllocError::TooManyLiveRegs { .. })
+    ));
+}
+
+#[test]
+fn test_merge_outputs() {
+    let i = |n| VReg::new(n, RegClass::Int);
+    let f = |n| VReg::new(n, RegClass::Float);
+    // Three values of each class are live at once with two
+    // registers per class, so both classes spill. Float slots are
+    // two wide, so they must not start right after an odd number
+    // of int slots.
+    let insts = vec![
+        vec![Operand::reg_def(i(0)), Operand::reg_def(f(3))],
+        vec![Operand::reg_def(i(1)), Operand::reg_def(f(4))],
+        vec![Operand::reg_def(i(2)), Operand::reg_def(f(5))],
+        vec![Operand::reg_use(f(3)), Operand::reg_use(i(0))],
+        vec![
+            Operand::reg_use(i(1)),
+            Operand::reg_use(f(4)),
+            Operand::reg_use(i(2)),
+            Operand::reg_use(f(5)),
+        ],
+        vec![],
+    ];
+    let func_with = |insts: Vec<Vec<Operand>>| TestFunc {
+        insts,
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(6))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 6,
+        slot_sizes: vec![1, 2, 1],
+        ..Default::default()
+    };
+    let mut env = int_env(2);
+    env.preferred_regs_by_class[RegClass::Float as usize] =
+        (0..2).map(|n| PReg::new(n, RegClass::Float)).collect();
+
+    let run_parts = |options: &RegallocOptions| -> Vec<(RegClass, Output)> {
+        [RegClass::Int, RegClass::Float]
+            .iter()
+            .map(|&class| {
+                let view = func_with(
+                    insts
+                        .iter()
+                        .map(|ops| {
+                            ops.iter()
+                                .map(|&op| {
+                                    if op.class() == class {
+                                        op
+                                    } else {
+                                        Operand::unused(op.class())
+                                    }
+                                })
+                                .collect()
+                        })
+                        .collect(),
+                );
+                let out = run(&view, &env, options).unwrap();
+                assert!(out.num_spillslots > 0);
+                (class, out)
+            })
+            .collect()
+    };
+    let parts = run_parts(&RegallocOptions::default());
+    let int_slots = parts[0].1.num_spillslots;
+    assert_eq!(int_slots % 2, 1);
+    let num_spillslots = int_slots + 1 + parts[1].1.num_spillslots;
+
+    let func = func_with(insts.clone());
+    let merged = merge_outputs(&func, parts);
+    assert_eq!(merged.num_spillslots, num_spillslots);
+    assert!(merged
+        .spillslots_of_class(RegClass::Float)
+        .all(|slot| slot.index() % 2 == 0));
+    assert!(merged.allocs.iter().all(|alloc| !alloc.is_none()));
+    assert!(merged.edits.windows(2).all(|w| w[0].0 <= w[1].0));
+    assert!(merged.spillslots_of_class(RegClass::Int).next().is_some());
+    assert!(merged.spillslots_of_class(RegClass::Float).next().is_some());
+
+    // Each spilled stretch is where the merged ranges say the
+    // value is, in a slot of its own class.
+    for &(vreg, slot, range) in &merged.spilled_ranges {
+        assert!(merged
+            .vreg_locations(vreg)
+            .iter()
+            .any(|&(r, alloc)| alloc == Allocation::stack(slot) && r.contains_point(range.from)));
+        assert!(merged.spillslots_of_class(vreg.class()).any(|s| s == slot));
+    }
+
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&merged);
+    checker.run().unwrap();
+
+    // The trace of a spilled float value follows its slot to
+    // where the merge put it.
+    let traced = merged
+        .spilled_ranges
+        .iter()
+        .map(|&(vreg, ..)| vreg)
+        .find(|vreg| vreg.class() == RegClass::Float)
+        .unwrap();
+    let merged = merge_outputs(
+        &func,
+        run_parts(&RegallocOptions {
+            trace_vreg: Some(traced),
+            ..RegallocOptions::default()
+        }),
+    );
+    let trace = merged.vreg_trace.as_ref().unwrap();
+    assert_eq!(trace.vreg, traced);
+    let locations = merged.vreg_locations(traced);
+    assert!(trace.placement.iter().any(|(_, alloc)| alloc.is_stack()));
+    for placement in &trace.placement {
+        assert!(placement.1.is_none() || locations.contains(placement));
+    }
+}
+
+#[test]
+fn test_preg_map() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let p0 = PReg::new(0, RegClass::Int);
+    let p1 = PReg::new(1, RegClass::Int);
+    // v0 is needed in both p0 and p1 at inst1: it lives in p1,
+    // with p0 reserved there for a copy. v1 is defined into p0 at
+    // inst2.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![
+                Operand::reg_fixed_use(v(0), p0),
+                Operand::reg_fixed_use(v(0), p1),
+            ],
+            vec![Operand::reg_fixed_def(v(1), p0)],
+            vec![Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.preg_maps.is_empty());
+    let options = RegallocOptions {
+        collect_preg_maps: true,
+        ..RegallocOptions::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+
+    let range = |from, to| CodeRange { from, to };
+    assert_eq!(
+        out.preg_map(p0),
+        vec![
+            (
+                range(
+                    ProgPoint::before(Inst::new(1)),
+                    ProgPoint::after(Inst::new(1))
+                ),
+                None
+            ),
+            (
+                range(
+                    ProgPoint::after(Inst::new(2)),
+                    ProgPoint::after(Inst::new(3))
+                ),
+                Some(v(1))
+            ),
+        ]
+    );
+    assert_eq!(
+        out.preg_map(p1),
+        vec![(
+            range(
+                ProgPoint::after(Inst::new(0)),
+                ProgPoint::after(Inst::new(1))
+            ),
+            Some(v(0))
+        )]
+    );
+}
+
+#[test]
+fn test_output_ranges() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Each vreg is used three and six instructions after its def,
+    // with too few registers to keep them all in one place.
+    let mut insts = vec![];
+    for i in 0..12 {
+        let mut ops = vec![Operand::reg_def(v(i))];
+        if i >= 3 {
+            ops.push(Operand::reg_use(v(i - 3)));
+        }
+        if i >= 6 {
+            ops.push(Operand::reg_use(v(i - 6)));
+        }
+        insts.push(ops);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 12,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let ranges: Vec<_> = out.ranges().collect();
+
+    // Sorted by vreg and start.
+    for pair in ranges.windows(2) {
+        let ((v1, r1, _), (v2, r2, _)) = (pair[0], pair[1]);
+        assert!(v1 < v2 || (v1 == v2 && r1.from <= r2.from));
+    }
+    // Some vreg was split between a register and the stack.
+    assert!(ranges.iter().any(|&(_, _, alloc)| alloc.is_stack()));
+    // Every operand gets the allocation of a range holding it.
+    for (i, ops) in func.insts.iter().enumerate() {
+        let inst = Inst::new(i);
+        for (&op, &alloc) in ops.iter().zip(out.inst_allocs(inst)) {
+            let pos = match op.kind() {
+                OperandKind::Def => ProgPoint::after(inst),
+                OperandKind::Use => ProgPoint::before(inst),
+            };
+            assert!(
+                ranges.iter().any(|&(vreg, range, held)| {
+                    vreg == op.vreg() && range.contains_point(pos) && held == alloc
+                }),
+                "{} at {:?}",
+                op,
+                pos
+            );
+        }
+    }
+    // Each spilled stretch is made of stack ranges.
+    for (vreg, slot, range) in out.spilled_vregs() {
+        let covered: u32 = ranges
+            .iter()
+            .filter(|&&(v, r, alloc)| {
+                v == vreg && alloc == Allocation::stack(slot) && r.overlaps(&range)
+            })
+            .map(|&(_, r, _)| r.to.to_index() - r.from.to_index())
+            .sum();
+        assert_eq!(covered, range.to.to_index() - range.from.to_index());
+    }
+}
+
+#[test]
+fn test_resolve_moves() {
+    use alloc::format;
+
+    let v = |i| VReg::new(i, RegClass::Int);
+    let p0 = PReg::new(0, RegClass::Int);
+    let p1 = PReg::new(1, RegClass::Int);
+    // Each vreg is used three and six instructions after its def,
+    // with too few registers to keep them all in one place.
+    let mut insts = vec![];
+    for i in 0..12 {
+        let mut ops = vec![Operand::reg_def(v(i))];
+        if i >= 3 {
+            ops.push(Operand::reg_use(v(i - 3)));
+        }
+        if i >= 6 {
+            ops.push(Operand::reg_use(v(i - 6)));
+        }
+        insts.push(ops);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 12,
+        ..Default::default()
+    };
+    let mach_env = int_env(4);
+    let options = RegallocOptions::default();
+    let out = run(&func, &mach_env, &options).unwrap();
+
+    let (retained_out, mut retained) = run_retained(&func, &mach_env, &options).unwrap();
+    assert_eq!(format!("{:?}", retained_out), format!("{:?}", out));
+
+    // Running it again without changes gives the same results.
+    let again = retained.resolve_moves().unwrap();
+    assert_eq!(format!("{:?}", again), format!("{:?}", out));
+
+    // Exchange p0 and p1 everywhere, then regenerate the moves.
+    let env = &mut retained.env;
+    let swap = |alloc: Allocation| match alloc.as_reg() {
+        Some(preg) if preg == p0 => Allocation::reg(p1),
+        Some(preg) if preg == p1 => Allocation::reg(p0),
+        _ => alloc,
+    };
+    for bundle in env.bundles.iter_mut() {
+        bundle.allocation = swap(bundle.allocation);
+    }
+    let (first, rest) = env.pregs.split_at_mut(1);
+    core::mem::swap(&mut first[0].allocations, &mut rest[0].allocations);
+    let swapped = retained.resolve_moves().unwrap();
+    let mut checker = checker::Checker::new(&func, &mach_env);
+    checker.prepare(&swapped);
+    checker.run().unwrap();
+    let expected: Vec<_> = out.allocs.iter().map(|&alloc| swap(alloc)).collect();
+    assert_eq!(swapped.allocs, expected);
+    assert_ne!(swapped.allocs, out.allocs);
+}
+
+#[test]
+fn test_resolve_moves_alias_temps() {
+    use alloc::format;
+
+    let f = |i| PReg::new(i, RegClass::Float);
+    let x = |i| PReg::new(i, RegClass::Vector);
+    // x0 overlaps f0 and f1.
+    let mut env = int_env(0);
+    env.preferred_regs_by_class = [vec![], vec![f(0), f(1), f(2)], vec![x(0), x(1)]];
+    env.reg_aliases = vec![vec![]; PReg::NUM_INDEX];
+    for narrow in [f(0), f(1)] {
+        env.reg_aliases[narrow.index()].push(x(0));
+        env.reg_aliases[x(0).index()].push(narrow);
+    }
+    let fv = VReg::new(0, RegClass::Float);
+    let xv = VReg::new(1, RegClass::Vector);
+    // Before inst2, v0 moves out of f0 while v1 moves into x0, so
+    // v0 is first copied to a temporary spillslot.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(fv, f(0))],
+            vec![Operand::reg_fixed_def(xv, x(1))],
+            vec![
+                Operand::reg_fixed_use(fv, f(2)),
+                Operand::reg_fixed_use(xv, x(0)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let (out, mut retained) = run_retained(&func, &env, &RegallocOptions::default()).unwrap();
+    assert_eq!(out.num_spillslots, 1);
+    assert!(out.edits.iter().any(|(_, edit)| match edit {
+        Edit::Move { from, to } => *from == Allocation::reg(f(0)) && to.is_stack(),
+        _ => false,
+    }));
+
+    // Resolving again reuses the temporary rather than taking
+    // another spillslot each time.
+    for _ in 0..2 {
+        let again = retained.resolve_moves().unwrap();
+        assert_eq!(format!("{:?}", again), format!("{:?}", out));
+    }
+}
+
+#[test]
+fn test_reassign() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is live from inst0 to inst3, v1 from inst1 to inst2.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let (out, mut retained) = run_retained(&func, &env, &RegallocOptions::default()).unwrap();
+    let held = |out: &Output, vreg: VReg, inst: usize| {
+        out.vreg_locations(vreg)
+            .into_iter()
+            .find(|(range, _)| range.contains_point(ProgPoint::before(Inst::new(inst))))
+            .unwrap()
+            .1
+    };
+    let (v0_reg, v1_reg) = (held(&out, v(0), 2), held(&out, v(1), 2));
+    let free = (0..3)
+        .map(|i| Allocation::reg(r(i)))
+        .find(|&alloc| alloc != v0_reg && alloc != v1_reg)
+        .unwrap();
+
+    // Another vreg's register, another class, or a point where v0
+    // is not live are refused without changes.
+    let at = ProgPoint::before(Inst::new(2));
+    assert!(!retained.reassign(v(0), at, v1_reg.as_reg().unwrap()));
+    assert!(!retained.reassign(v(0), at, PReg::new(0, RegClass::Float)));
+    assert!(!retained.reassign(
+        v(0),
+        ProgPoint::before(Inst::new(4)),
+        free.as_reg().unwrap()
+    ));
+    let same = retained.resolve_moves().unwrap();
+    assert_eq!(same.allocs, out.allocs);
+
+    // A free register is taken, and the moves follow.
+    assert!(retained.reassign(v(0), at, free.as_reg().unwrap()));
+    let moved = retained.resolve_moves().unwrap();
+    assert_eq!(held(&moved, v(0), 2), free);
+    assert_eq!(held(&moved, v(1), 2), v1_reg);
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&moved);
+    checker.run().unwrap();
+}
+
+#[test]
+fn test_stats() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is spilled around insts 2 and 3, where v1 and v2 take
+    // both registers.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(6))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+    let stats: Stats = out.stats;
+    assert_eq!(stats.initial_liverange_count, 3);
+    assert!(stats.process_bundle_count >= stats.merged_bundle_count);
+    // Spilling v0 split its bundle.
+    assert!(stats.splits > 0);
+    assert!(stats.spill_bundle_count > 0);
+    assert!(stats.final_liverange_count > stats.initial_liverange_count);
+    assert!(stats.final_bundle_count > stats.merged_bundle_count);
+    assert_eq!(stats.edits_count, out.edits.len());
+}
+
+#[test]
+fn test_reallocate_inst() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let p3 = PReg::new(3, RegClass::Int);
+    // As in `test_resolve_moves`; the edited function instead
+    // wants v2 in p3 where inst 8 uses it.
+    let make_func = |fixed: bool| {
+        let mut insts = vec![];
+        for i in 0..12 {
+            let mut ops = vec![Operand::reg_def(v(i))];
+            if i >= 3 {
+                ops.push(Operand::reg_use(v(i - 3)));
+            }
+            if i >= 6 {
+                ops.push(if fixed && i == 8 {
+                    Operand::reg_fixed_use(v(i - 6), p3)
+                } else {
+                    Operand::reg_use(v(i - 6))
+                });
+            }
+            insts.push(ops);
+        }
+        insts.push(vec![]);
+        TestFunc {
+            blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+            insts,
+            preds: vec![vec![]],
+            succs: vec![vec![]],
+            num_vregs: 12,
+            ..Default::default()
+        }
+    };
+    let func = make_func(false);
+    let edited = make_func(true);
+    let mach_env = int_env(4);
+    let options = RegallocOptions {
+        collect_spill_reasons: true,
+        ..RegallocOptions::default()
+    };
+    let (out, mut retained) = run_retained(&func, &mach_env, &options).unwrap();
+    assert_ne!(
+        out.allocs[out.inst_alloc_offsets[8] as usize + 2],
+        Allocation::reg(p3)
+    );
+
+    let reallocated = retained.reallocate_inst(&edited, Inst::new(8)).unwrap();
+    let mut checker = checker::Checker::new(&edited, &mach_env);
+    checker.prepare(&reallocated);
+    checker.run().unwrap();
+    assert_eq!(
+        reallocated.allocs[out.inst_alloc_offsets[8] as usize + 2],
+        Allocation::reg(p3)
+    );
+    // The state is left consistent with the new allocation.
+    assert_eq!(
+        alloc::format!("{:?}", retained.resolve_moves().unwrap()),
+        alloc::format!("{:?}", reallocated)
+    );
+}
+
+#[test]
+fn test_split_events() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is live across a clobber of the only register at inst2.
+    let mut clobbers = vec![PRegSet::empty(); 5];
+    clobbers[2].add(r(0));
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 1,
+        clobbers,
+        ..Default::default()
+    };
+    let options = RegallocOptions {
+        collect_split_events: true,
+        ..RegallocOptions::default()
+    };
+    assert!(run(&func, &int_env(1), &RegallocOptions::default())
+        .unwrap()
+        .split_events
+        .is_empty());
+    let out = run(&func, &int_env(1), &options).unwrap();
+
+    // v0 is split once, into a piece for its def, one for its use
+    // and the spill bundle across the clobber in between.
+    assert_eq!(out.split_events.len(), out.stats.splits);
+    assert_eq!(out.split_events.len(), 1, "{:?}", out.split_events);
+    let event = &out.split_events[0];
+    let at = event.at.unwrap();
+    let (spill, kept): (Vec<_>, Vec<_>) = event.pieces.iter().partition(|piece| piece.spill);
+    assert_eq!(kept.len(), 2);
+    assert_eq!(kept[0].bundle, event.bundle);
+    assert!(kept[0].ranges.iter().all(|&(_, range)| range.to <= at));
+    assert!(kept[1].ranges.iter().all(|&(_, range)| range.from >= at));
+    assert!(spill[0].ranges[0]
+        .1
+        .contains_point(ProgPoint::after(Inst::new(2))));
+    assert!(event
+        .pieces
+        .iter()
+        .all(|piece| piece.ranges.iter().all(|&(vreg, _)| vreg == v(0))));
+}
+
+#[test]
+fn test_split_history() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // Each vreg is used three and six instructions after its def,
+    // with too few registers to keep them all in one place.
+    let mut insts = vec![];
+    for i in 0..12 {
+        let mut ops = vec![Operand::reg_def(v(i))];
+        if i >= 3 {
+            ops.push(Operand::reg_use(v(i - 3)));
+        }
+        if i >= 6 {
+            ops.push(Operand::reg_use(v(i - 6)));
+        }
+        insts.push(ops);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 12,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.split_events.is_empty());
+    let options = RegallocOptions {
+        collect_split_events: true,
+        ..RegallocOptions::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+    assert!(!out.split_events.is_empty());
+
+    for event in &out.split_events {
+        assert!(!event.pieces.is_empty());
+        let at = match event.at {
+            Some(at) => at,
+            None => continue,
+        };
+        // A split in two leaves the ranges before the split point
+        // in the original bundle and moves the rest.
+        for piece in &event.pieces {
+            for &(_, range) in &piece.ranges {
+                if piece.spill {
+                    continue;
+                }
+                if piece.bundle == event.bundle {
+                    assert!(range.to <= at);
+                } else {
+                    assert!(range.from >= at);
+                }
+            }
+        }
+    }
+
+    let split = out.split_events[0].pieces[0].ranges[0].0;
+    let history = out.split_history(split);
+    assert_eq!(history[0], out.split_events[0]);
+    for event in &history {
+        assert!(event
+            .pieces
+            .iter()
+            .any(|piece| piece.ranges.iter().any(|&(vreg, _)| vreg == split)));
+    }
+}
+
+#[cfg(feature = "enable-serde")]
+#[test]
+fn test_serde_round_trip() {
+    use alloc::format;
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+
+    for alloc in [
+        Allocation::none(),
+        Allocation::reg(r(3)),
+        Allocation::stack(SpillSlot::new(5)),
+    ] {
+        let bytes = bincode::serialize(&alloc).unwrap();
+        let back: Allocation = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(back, alloc);
+        assert_eq!(back.kind(), alloc.kind());
+    }
+
+    // v0 is live across inst2, which needs both registers, so it
+    // is spilled; v2 is rematerializable.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        remat: vec![None, Some(RematInfo { vreg: v(2) })],
+        forbidden: vec![PRegSet::empty(); 4]
+            .into_iter()
+            .chain([PRegSet::empty().with(r(0))])
+            .collect(),
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let options = RegallocOptions::default();
+    let out = run(&func, &env, &options).unwrap();
+    assert!(out
+        .allocs
+        .iter()
+        .chain(out.edits.iter().map(|(_, edit)| match edit {
+            Edit::Move { to, .. } | Edit::Remat { to, .. } => to,
+        }))
+        .any(|alloc| alloc.is_stack()));
+
+    let bytes = bincode::serialize(&out).unwrap();
+    let back: Output = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(format!("{:?}", back), format!("{:?}", out));
+
+    // A captured instance replays to the same result.
+    let captured = serialize::SerializableFunction::new(&func, env.clone());
+    let bytes = bincode::serialize(&captured).unwrap();
+    let replay: serialize::SerializableFunction = bincode::deserialize(&bytes).unwrap();
+    let replayed = run(&replay, replay.machine_env(), &options).unwrap();
+    assert_eq!(format!("{:?}", replayed), format!("{:?}", out));
+}
+
+#[test]
+fn test_dump() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is live across inst2, which needs both registers, so it
+    // is spilled and reloaded.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let dump = out.dump(&func);
+
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines[0], "block0:  ; preds: -  succs: -");
+    let inst_line = |i: usize| {
+        let name = alloc::format!("inst{} ", i);
+        *lines
+            .iter()
+            .find(|line| line.trim_start().starts_with(&name))
+            .unwrap()
+    };
+    // Operand columns line up between instructions.
+    assert_eq!(inst_line(0).find("Def: v1i"), inst_line(2).find("Use: v2i"));
+    assert!(inst_line(4).ends_with("ret"));
+
+    // The spill and reload of v0 are named as such, and its
+    // slot is listed.
+    let moves: Vec<&&str> = lines
+        .iter()
+        .filter(|line| line.contains(" move "))
+        .collect();
+    assert!(!moves.is_empty());
+    assert!(moves.iter().all(|line| line.ends_with("; v0")));
+    let slots = lines
+        .iter()
+        .position(|&line| line == "spillslots:")
+        .unwrap();
+    assert_eq!(out.spillslot_classes.len(), lines.len() - slots - 1);
+    assert!(lines[slots + 1].contains(" v0 "));
+}
+
+#[test]
+fn test_to_annotated_listing() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // As in `test_dump`, v0 is spilled and reloaded around inst2.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0)), Operand::reg_def(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    // Each edit comes after the instruction whose following point
+    // it is at, so the spill shows between inst0 and inst1.
+    let listing = out.to_annotated_listing(&func);
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "block0:",
+            "  inst0: Def: v0i reg => p0i, Def: v1i reg => p1i",
+            "    ; move p0i -> stack0",
+            "  inst1: Def: v2i reg => p0i",
+            "  inst2: Use: v1i reg => p1i, Use: v2i reg => p0i",
+            "    ; move stack0 -> p1i",
+            "  inst3: Use: v0i reg => p1i",
+            "  inst4:",
+        ]
+    );
+}
+
+#[test]
+fn test_vreg_locations() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is spilled while v1 and v2 take both registers, and
+    // reloaded for its use.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+
+    for vreg in 0..3 {
+        let locations = out.vreg_locations(v(vreg));
+        let ranges: Vec<_> = out
+            .ranges()
+            .filter(|&(r, ..)| r == v(vreg))
+            .map(|(_, range, alloc)| (range, alloc))
+            .collect();
+        assert_eq!(locations, ranges);
+    }
+
+    // v0 is in a register at its def and its use, and on the
+    // stack in between.
+    let locations = out.vreg_locations(v(0));
+    let at = |pos: ProgPoint| {
+        locations
+            .iter()
+            .filter(|(range, _)| range.contains_point(pos))
+            .map(|&(_, alloc)| alloc)
+            .collect::<Vec<_>>()
+    };
+    assert!(at(ProgPoint::after(Inst::new(0))).contains(&out.inst_allocs(Inst::new(0))[0]));
+    assert!(at(ProgPoint::before(Inst::new(3))).contains(&out.inst_allocs(Inst::new(3))[0]));
+    let between = at(ProgPoint::before(Inst::new(2)));
+    assert!(!between.is_empty());
+    assert!(between.iter().all(|alloc| alloc.is_stack()));
+    assert!(out.vreg_locations(VReg::new(7, RegClass::Int)).is_empty());
+}
+
+#[test]
+fn test_occupant_of() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    let mut clobbers = vec![PRegSet::empty(); 4];
+    clobbers[2].add(r(2));
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v(0), r(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        clobbers,
+        ..Default::default()
+    };
+    let mach_env = int_env(3);
+    let options = RegallocOptions::default();
+    let out = run(&func, &mach_env, &options).unwrap();
+    let v1_reg = out.inst_allocs(Inst::new(1))[0].as_reg().unwrap();
+    assert_eq!(
+        out.occupant_of(r(0), ProgPoint::after(Inst::new(0))),
+        Some(v(0))
+    );
+    assert_eq!(
+        out.occupant_of(r(0), ProgPoint::before(Inst::new(2))),
+        Some(v(0))
+    );
+    assert_eq!(
+        out.occupant_of(v1_reg, ProgPoint::before(Inst::new(2))),
+        Some(v(1))
+    );
+    assert_eq!(out.occupant_of(r(0), ProgPoint::before(Inst::new(3))), None);
+    // A clobbered register holds no vreg.
+    assert_eq!(out.occupant_of(r(2), ProgPoint::after(Inst::new(2))), None);
+}
+
+#[test]
+fn test_vregs_interfere() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 and v1 are live together at inst 2; v2 is defined only after
+    // v0's last use, so v0 and v2 could share a register.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(6))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(3), &RegallocOptions::default()).unwrap();
+
+    for (a, b, interfere) in [(0, 1, true), (1, 2, true), (0, 2, false)] {
+        assert_eq!(out.vregs_interfere(v(a), v(b)), interfere);
+        assert_eq!(out.vregs_interfere(v(b), v(a)), interfere);
+    }
+}
+
+#[test]
+fn test_reload_targets() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is spilled around each pair of values that needs both
+    // registers, and reloaded for fixed uses in r1, r0 and r1.
+    let mut insts = vec![vec![Operand::reg_def(v(0))]];
+    for (i, &reg) in [1, 0, 1].iter().enumerate() {
+        let (a, b) = (v(2 * i + 1), v(2 * i + 2));
+        insts.push(vec![Operand::reg_def(a), Operand::reg_def(b)]);
+        insts.push(vec![Operand::reg_use(a), Operand::reg_use(b)]);
+        insts.push(vec![Operand::reg_fixed_use(v(0), r(reg))]);
+    }
+    insts.push(vec![]);
+    let func = TestFunc {
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(insts.len()))],
+        insts,
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 7,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+    let slot = match out.edits[0].1 {
+        Edit::Move { to, .. } => to.as_stack().unwrap(),
+        Edit::Remat { .. } => unreachable!(),
+    };
+    assert_eq!(out.reload_targets(slot), vec![(r(1), 2), (r(0), 1)]);
+    assert!(out
+        .reload_targets(SpillSlot::new(slot.index() + 1))
+        .is_empty());
+}
+
+#[test]
+fn test_into_vcode_stream() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is live around the loop in blocks 1 and 2 while v1 and v2
+    // take both registers in it, so it is spilled and reloaded.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(2)),
+            InstRange::new(Inst::new(2), Inst::new(5)),
+            InstRange::new(Inst::new(5), Inst::new(6)),
+            InstRange::new(Inst::new(6), Inst::new(8)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0), Block::new(2)],
+            vec![Block::new(1)],
+            vec![Block::new(1)],
+        ],
+        succs: vec![
+            vec![Block::new(1)],
+            vec![Block::new(2), Block::new(3)],
+            vec![Block::new(1)],
+            vec![],
+        ],
+        num_vregs: 3,
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+    assert!(!out.edits.is_empty());
+
+    // Every instruction comes once, in order, with its allocations,
+    // and every edit comes once, in order, ahead of the instruction
+    // whose `Before` point it is at or after the one whose `After`
+    // point it is at.
+    let mut next_inst = 0;
+    let mut next_edit = 0;
+    for item in out.into_vcode_stream(&func) {
+        match item {
+            StreamItem::Inst {
+                inst,
+                operand_allocs,
+            } => {
+                assert_eq!(inst.index(), next_inst);
+                assert_eq!(operand_allocs, out.inst_allocs(inst));
+                next_inst += 1;
+            }
+            StreamItem::Move(edit) => {
+                let (pos, ref expected) = out.edits[next_edit];
+                assert_eq!(
+                    alloc::format!("{:?}", edit),
+                    alloc::format!("{:?}", expected)
+                );
+                let inst = match pos.pos() {
+                    InstPosition::Before => pos.inst().index(),
+                    InstPosition::After => pos.inst().index() + 1,
+                };
+                assert_eq!(inst, next_inst, "{:?}", pos);
+                next_edit += 1;
+            }
+        }
+    }
+    assert_eq!(next_inst, func.insts.len());
+    assert_eq!(next_edit, out.edits.len());
+}
+
+#[test]
+fn test_debug_locations() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let p0 = PReg::new(0, RegClass::Int);
+    // v0, labelled 7, is pushed out of its register by v1 and v2 and
+    // reloaded for its last two uses.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![Operand::reg_use(v(1)), Operand::reg_use(v(2))],
+            vec![Operand::reg_use(v(0))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(8))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        debug_value_labels: vec![(v(0), Inst::new(1), Inst::new(7), 7)],
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(2), &RegallocOptions::default()).unwrap();
+    let slot = Allocation::stack(SpillSlot::new(0));
+    assert_eq!(
+        alloc::format!("{:?}", out.edits),
+        alloc::format!(
+            "{:?}",
+            [
+                (
+                    ProgPoint::before(Inst::new(3)),
+                    Edit::Move {
+                        from: Allocation::reg(p0),
+                        to: slot,
+                    }
+                ),
+                (
+                    ProgPoint::before(Inst::new(5)),
+                    Edit::Move {
+                        from: slot,
+                        to: Allocation::reg(p0),
+                    }
+                ),
+            ]
+        )
+    );
+
+    // The label follows v0 into its slot and back. Its two uses after
+    // the reload are in one piece, so they give a single record.
+    assert_eq!(
+        out.debug_locations,
+        vec![
+            (
+                7,
+                ProgPoint::before(Inst::new(1)),
+                ProgPoint::before(Inst::new(3)),
+                Allocation::reg(p0)
+            ),
+            (
+                7,
+                ProgPoint::before(Inst::new(3)),
+                ProgPoint::before(Inst::new(5)),
+                slot
+            ),
+            (
+                7,
+                ProgPoint::before(Inst::new(5)),
+                ProgPoint::after(Inst::new(6)),
+                Allocation::reg(p0)
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_block_reg_state() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let p2 = PReg::new(2, RegClass::Int);
+    // v0 and v1 are live into block 1, where v2 and v3 leave room for
+    // only one of them in a register.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![],
+            vec![Operand::reg_def(v(2)), Operand::reg_def(v(3))],
+            vec![Operand::reg_use(v(2)), Operand::reg_use(v(3))],
+            vec![Operand::reg_use(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(3)),
+            InstRange::new(Inst::new(3), Inst::new(8)),
+        ],
+        preds: vec![vec![], vec![Block::new(0)]],
+        succs: vec![vec![Block::new(1)], vec![]],
+        num_vregs: 4,
+        ..Default::default()
+    };
+    let env = int_env(3);
+    let options = RegallocOptions {
+        collect_block_entry_state: true,
+        ..RegallocOptions::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+
+    // v0, used last, is the one spilled: stored after its def and
+    // reloaded for its use.
+    assert_eq!(out.allocs[1], Allocation::reg(p2));
+    assert!(matches!(
+        out.edits[0].1,
+        Edit::Move { from, to }
+            if from == Allocation::reg(p2) && to == Allocation::stack(SpillSlot::new(0))
+    ));
+    assert!(out.block_reg_state(Block::new(0)).is_empty());
+    assert!(out.block_spill_state(Block::new(0)).is_empty());
+    assert_eq!(out.block_reg_state(Block::new(1)), vec![(p2, v(1))]);
+    assert_eq!(
+        out.block_spill_state(Block::new(1)),
+        vec![(SpillSlot::new(0), v(0))]
+    );
+
+    // Nothing is recorded without the option.
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.block_reg_state(Block::new(1)).is_empty());
+    assert!(out.block_spill_state(Block::new(1)).is_empty());
+}
+
+#[test]
+fn test_estimated_spill_cost() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is live around the loop of block1 and block2, across a
+    // clobber of the only register at the loop header, and used in
+    // block1 and after the loop in block3.
+    let func = |clobbered: bool| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![
+            InstRange::new(Inst::new(0), Inst::new(2)),
+            InstRange::new(Inst::new(2), Inst::new(5)),
+            InstRange::new(Inst::new(5), Inst::new(6)),
+            InstRange::new(Inst::new(6), Inst::new(8)),
+        ],
+        preds: vec![
+            vec![],
+            vec![Block::new(0), Block::new(2)],
+            vec![Block::new(1)],
+            vec![Block::new(1)],
+        ],
+        succs: vec![
+            vec![Block::new(1)],
+            vec![Block::new(2), Block::new(3)],
+            vec![Block::new(1)],
+            vec![],
+        ],
+        num_vregs: 1,
+        clobbers: (0..8)
+            .map(|i| match i {
+                2 if clobbered => PRegSet::empty().with(r(0)),
+                _ => PRegSet::empty(),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let env = int_env(1);
+
+    // Without the clobber v0 stays in its register.
+    let func_free = func(false);
+    let out = run(&func_free, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.edits.is_empty());
+    assert_eq!(out.estimated_spill_cost(&func_free), 0.0);
+
+    // With it, v0 is stored in block0 and the loop's reload in
+    // block1 and store in block2 each count four times over.
+    let func = func(true);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+    let blocks: Vec<_> = out
+        .edits
+        .iter()
+        .map(|(pos, _)| match pos.inst().index() {
+            0..=1 => 0,
+            2..=4 => 1,
+            5 => 2,
+            _ => 3,
+        })
+        .collect();
+    assert_eq!(blocks, [0, 1, 2]);
+    assert_eq!(out.estimated_spill_cost(&func), 9.0);
+}
+
+#[test]
+fn test_bundle_requirements() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is defined in r1, then wanted on the stack and finally in
+    // any register: the fixed def conflicts with the stack use, so
+    // v0's bundle is split until every piece has one requirement.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v(0), r(1))],
+            vec![Operand::reg_def(v(1))],
+            vec![
+                Operand::new(
+                    v(0),
+                    OperandConstraint::Stack,
+                    OperandKind::Use,
+                    OperandPos::Early,
+                ),
+                Operand::any_use(v(1)),
+            ],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(2);
+
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.bundle_requirements.is_empty());
+
+    let options = RegallocOptions {
+        collect_bundle_requirements: true,
+        ..Default::default()
+    };
+    let out = run(&func, &env, &options).unwrap();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    // The whole of v0 conflicts first between its def and the stack
+    // use, then (once the def is split off) between the stack use and
+    // the register use; each remaining piece has a single requirement.
+    assert_eq!(
+        out.bundle_requirements,
+        vec![
+            (vec![v(0)], Err(ProgPoint::after(Inst::new(0)))),
+            (vec![v(0)], Err(ProgPoint::before(Inst::new(3)))),
+            (vec![v(0)], Ok(Requirement::Stack)),
+            (vec![v(1)], Ok(Requirement::Register)),
+            (vec![v(0)], Ok(Requirement::FixedReg(r(1)))),
+            (vec![v(0)], Ok(Requirement::Register)),
+        ]
+    );
+}
+
+#[test]
+fn test_peak_queue_len() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    let mut func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v(0), r(1))],
+            vec![Operand::reg_def(v(1))],
+            vec![Operand::any_use(v(0)), Operand::any_use(v(1))],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        ..Default::default()
+    };
+    let env = int_env(2);
+
+    // Without conflicts both bundles are queued up front and
+    // allocated whole.
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert_eq!(out.stats.merged_bundle_count, 2);
+    assert_eq!(out.stats.splits, 0);
+    assert_eq!(out.stats.peak_queue_len, 2);
+
+    // Wanting v0 on the stack conflicts with both its fixed def and
+    // its register use. v0 is popped and split in two while v1 waits
+    // (3 queued), then one of its pieces is popped and split again
+    // (4).
+    func.insts[2][0] = Operand::new(
+        v(0),
+        OperandConstraint::Stack,
+        OperandKind::Use,
+        OperandPos::Early,
+    );
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert_eq!(out.stats.merged_bundle_count, 2);
+    assert_eq!(out.stats.splits, 2);
+    assert_eq!(out.stats.peak_queue_len, 4);
+}
@@ -0,0 +1,509 @@
+//! Tests of reference-typed values and safepoint stackmaps.
+
+use super::*;
+
+#[test]
+fn test_reftype_spill_policy() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is a reference live across the safepoint at inst1, with
+    // registers to spare.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![Operand::reg_use(v(0))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 1,
+        reftype_vregs: vec![v(0)],
+        safepoints: vec![Inst::new(1)],
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let run_with_policy = |reftype_spill_policy| {
+        let options = RegallocOptions {
+            reftype_spill_policy,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker =
+            checker::Checker::new(&func, &env).with_reftype_spill_policy(reftype_spill_policy);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+    let at_safepoint = ProgPoint::before(Inst::new(1));
+
+    let out = run_with_policy(ReftypeSpillPolicy::StackOnly);
+    assert_eq!(out.safepoint_slots.len(), 1);
+    assert_eq!(out.safepoint_slots[0].0, at_safepoint);
+    assert!(out.safepoint_slots[0].1.is_stack());
+
+    let out = run_with_policy(ReftypeSpillPolicy::RegisterAllowed);
+    assert_eq!(out.safepoint_slots.len(), 1);
+    assert!(out.safepoint_slots[0].1.is_reg());
+    assert!(out.edits.is_empty());
+    assert_eq!(out.num_spillslots, 0);
+
+    // Wrong stackmaps are caught under the policy they were made
+    // for.
+    let check = |out: &Output, policy| {
+        let mut checker = checker::Checker::new(&func, &env).with_reftype_spill_policy(policy);
+        checker.prepare(out);
+        alloc::format!("{:?}", checker.run())
+    };
+    assert!(check(&out, ReftypeSpillPolicy::StackOnly).contains("RegisterInStackmap"));
+    // v0 is read from its register after the safepoint, which is
+    // stale if the stackmap does not list it.
+    let mut wrong = out.clone();
+    wrong.safepoint_slots.clear();
+    assert!(check(&wrong, ReftypeSpillPolicy::RegisterAllowed).contains("Err"));
+    assert_eq!(check(&wrong, ReftypeSpillPolicy::StackOnly), "Ok(())");
+
+    let out = run_with_policy(ReftypeSpillPolicy::AlwaysBackedBySlot);
+    let allocs: Vec<Allocation> = out.slots_at_safepoint(Inst::new(1)).collect();
+    assert_eq!(allocs.len(), 2);
+    let reg = *allocs.iter().find(|a| a.is_reg()).unwrap();
+    let slot = *allocs.iter().find(|a| a.is_stack()).unwrap();
+    assert_eq!(out.edits.len(), 1);
+    let (pos, ref edit) = out.edits[0];
+    assert_eq!(pos, at_safepoint);
+    assert!(matches!(*edit, Edit::Move { from, to } if (from, to) == (reg, slot)));
+    let mut wrong = out.clone();
+    wrong.safepoint_slots.retain(|&(_, alloc)| alloc != slot);
+    assert!(check(&wrong, ReftypeSpillPolicy::AlwaysBackedBySlot).contains("RefNotBackedBySlot"));
+    assert_eq!(check(&wrong, ReftypeSpillPolicy::RegisterAllowed), "Ok(())");
+}
+
+#[test]
+fn test_ref_not_on_stack_at_safepoint() {
+    let v0 = VReg::new(0, RegClass::Int);
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v0)],
+            vec![],
+            vec![Operand::reg_use(v0)],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 1,
+        reftype_vregs: vec![v0],
+        safepoints: vec![Inst::new(1)],
+        ..Default::default()
+    };
+    let mach_env = int_env(2);
+    let options = RegallocOptions::default();
+    let cfginfo = cfg::CFGInfo::new(&func).unwrap();
+    let mut env = ion::data_structures::Env::new(&func, &mach_env, cfginfo, &options);
+    env.init().unwrap();
+    env.run().unwrap();
+    assert_eq!(
+        env.safepoint_slots
+            .iter()
+            .filter(|(_, alloc)| alloc.is_stack())
+            .count(),
+        1
+    );
+
+    // Move v0 out of its spillslot at the safepoint: rather than
+    // omit it from the stackmap, resolution fails.
+    for bundle in env.bundles.iter_mut() {
+        if bundle.allocation.is_none() {
+            bundle.allocation = Allocation::reg(PReg::new(1, RegClass::Int));
+        }
+    }
+    assert!(matches!(
+        env.resolve_moves(),
+        Err(RegAllocError::RefNotOnStackAtSafepoint(vreg, inst))
+            if vreg == v0 && inst == Inst::new(1)
+    ));
+}
+
+#[test]
+fn test_ref_in_fixed_stack_preg_at_safepoint() {
+    let v0 = VReg::new(0, RegClass::Int);
+    let stack_preg = PReg::new(8, RegClass::Int);
+    // v0 is a reference held in a fixed stack-slot preg across the
+    // safepoint at inst1.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_fixed_def(v0, stack_preg)],
+            vec![],
+            vec![Operand::reg_fixed_use(v0, stack_preg)],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 1,
+        reftype_vregs: vec![v0],
+        safepoints: vec![Inst::new(1)],
+        ..Default::default()
+    };
+    let mut env = int_env(2);
+    env.fixed_stack_slots = vec![stack_preg];
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(out.edits.is_empty());
+    let allocs: Vec<Allocation> = out.slots_at_safepoint(Inst::new(1)).collect();
+    assert_eq!(allocs, vec![Allocation::reg(stack_preg)]);
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    // The checker scans the preg as a stack location, so leaving it
+    // out of the stackmap is caught.
+    let mut wrong = out.clone();
+    wrong.safepoint_slots.clear();
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&wrong);
+    assert!(checker.run().is_err());
+
+    // Moved to the preg early for a later fixed use, v0 is there at
+    // the safepoint as well as in its spillslot; both are listed.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v0)],
+            vec![Operand::reg_use(v0)],
+            vec![],
+            vec![Operand::reg_fixed_use(v0, stack_preg)],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(5))],
+        safepoints: vec![Inst::new(2)],
+        ..func
+    };
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    let allocs: Vec<Allocation> = out.slots_at_safepoint(Inst::new(2)).collect();
+    assert_eq!(allocs.len(), 2);
+    assert!(allocs.contains(&Allocation::reg(stack_preg)));
+    assert!(allocs.iter().any(|alloc| alloc.is_stack()));
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+}
+
+#[test]
+fn test_dead_ref_def_at_safepoint() {
+    let v0 = VReg::new(0, RegClass::Int);
+    let v1 = VReg::new(1, RegClass::Int);
+    // inst1 is a safepoint that defines the reference v0 early,
+    // which nothing then uses.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v1)],
+            vec![
+                Operand::reg_use(v1),
+                Operand::new(
+                    v0,
+                    OperandConstraint::Any,
+                    OperandKind::Def,
+                    OperandPos::Early,
+                ),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(3))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        reftype_vregs: vec![v0],
+        safepoints: vec![Inst::new(1)],
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    // v0 is given a spillslot, which the stackmap lists.
+    let def = out.inst_allocs(Inst::new(1))[1];
+    assert!(def.is_stack());
+    let allocs: Vec<Allocation> = out.slots_at_safepoint(Inst::new(1)).collect();
+    assert_eq!(allocs, vec![def]);
+    let mut checker = checker::Checker::new(&func, &env);
+    checker.prepare(&out);
+    checker.run().unwrap();
+
+    // Such a def can't be kept in a register.
+    let mut func = func;
+    func.insts[1][1] = Operand::new(
+        v0,
+        OperandConstraint::Reg,
+        OperandKind::Def,
+        OperandPos::Early,
+    );
+    assert!(matches!(
+        run(&func, &env, &RegallocOptions::default()),
+        Err(RegAllocError::RefDefAtSafepoint(vreg, inst))
+            if vreg == v0 && inst == Inst::new(1)
+    ));
+}
+
+#[test]
+fn test_stackmaps_without_safepoint_info() {
+    use core::cell::Cell;
+
+    // A function whose refs only appear from the given call on.
+    struct LateRefs {
+        func: TestFunc,
+        calls: Cell<usize>,
+        refs_from: usize,
+    }
+    impl Function for LateRefs {
+        fn num_insts(&self) -> usize {
+            self.func.num_insts()
+        }
+        fn num_blocks(&self) -> usize {
+            self.func.num_blocks()
+        }
+        fn entry_block(&self) -> Block {
+            self.func.entry_block()
+        }
+        fn block_insns(&self, block: Block) -> InstRange {
+            self.func.block_insns(block)
+        }
+        fn block_succs(&self, block: Block) -> &[Block] {
+            self.func.block_succs(block)
+        }
+        fn block_preds(&self, block: Block) -> &[Block] {
+            self.func.block_preds(block)
+        }
+        fn block_params(&self, block: Block) -> &[VReg] {
+            self.func.block_params(block)
+        }
+        fn is_ret(&self, insn: Inst) -> bool {
+            self.func.is_ret(insn)
+        }
+        fn is_branch(&self, insn: Inst) -> bool {
+            self.func.is_branch(insn)
+        }
+        fn branch_blockparams(&self, block: Block, insn: Inst, succ_idx: usize) -> &[VReg] {
+            self.func.branch_blockparams(block, insn, succ_idx)
+        }
+        fn inst_operands(&self, insn: Inst) -> &[Operand] {
+            self.func.inst_operands(insn)
+        }
+        fn inst_clobbers(&self, insn: Inst) -> PRegSet {
+            self.func.inst_clobbers(insn)
+        }
+        fn num_vregs(&self) -> usize {
+            self.func.num_vregs()
+        }
+        fn spillslot_size(&self, regclass: RegClass) -> usize {
+            self.func.spillslot_size(regclass)
+        }
+        fn reftype_vregs(&self) -> &[VReg] {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() < self.refs_from {
+                &[]
+            } else {
+                self.func.reftype_vregs()
+            }
+        }
+        fn requires_refs_on_stack(&self, insn: Inst) -> bool {
+            self.func.requires_refs_on_stack(insn)
+        }
+    }
+
+    let v0 = VReg::new(0, RegClass::Int);
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v0)],
+            vec![],
+            vec![Operand::reg_use(v0)],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(4))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 1,
+        reftype_vregs: vec![v0],
+        safepoints: vec![Inst::new(1)],
+        ..Default::default()
+    };
+    let env = int_env(2);
+    let mut func = LateRefs {
+        func,
+        calls: Cell::new(0),
+        refs_from: 0,
+    };
+    let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+    assert!(!out.safepoint_slots.is_empty());
+
+    // v0 is live over the safepoint, but only stackmap computation
+    // (the last two calls) sees it as a ref: rather than leave it
+    // out of the stackmap, allocation fails.
+    func.refs_from = func.calls.replace(0) - 1;
+    assert!(matches!(
+        run(&func, &env, &RegallocOptions::default()),
+        Err(RegAllocError::RefNotOnStackAtSafepoint(vreg, inst))
+            if vreg == v0 && inst == Inst::invalid()
+    ));
+}
+
+#[test]
+fn test_slots_at_safepoint() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // v0 is a reference live across the safepoints at inst1 and
+    // inst3, and v1 one live across inst3 only.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![],
+            vec![Operand::reg_def(v(1))],
+            vec![],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(6))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 2,
+        reftype_vregs: vec![v(0), v(1)],
+        safepoints: vec![Inst::new(1), Inst::new(3)],
+        ..Default::default()
+    };
+    let out = run(&func, &int_env(4), &RegallocOptions::default()).unwrap();
+
+    let at_1: Vec<_> = out.slots_at_safepoint(Inst::new(1)).collect();
+    let at_3: Vec<_> = out.slots_at_safepoint(Inst::new(3)).collect();
+    assert_eq!(at_1.len(), 1);
+    assert_eq!(at_3.len(), 2);
+    assert!(at_3.contains(&at_1[0]));
+    assert!(at_1.iter().chain(&at_3).all(|alloc| alloc.is_stack()));
+    for inst in [0, 2, 4, 5] {
+        assert_eq!(out.slots_at_safepoint(Inst::new(inst)).count(), 0);
+    }
+    assert_eq!(out.safepoint_slots.len(), at_1.len() + at_3.len());
+}
+
+#[test]
+fn test_stackmap_one_slot_per_safepoint() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    let r = |i| PReg::new(i, RegClass::Int);
+    // v0 is a reference live across safepoints at inst2 and
+    // inst4. Kept on the stack at safepoints, its use fixed to r0
+    // at inst3 splits it into ranges around them, two of which
+    // are in its slot at inst4: the slot is reported there once.
+    let func = TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1)), Operand::reg_def(v(2))],
+            vec![],
+            vec![Operand::reg_fixed_use(v(0), r(0))],
+            vec![],
+            vec![
+                Operand::reg_use(v(0)),
+                Operand::reg_use(v(1)),
+                Operand::reg_use(v(2)),
+            ],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(7))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        reftype_vregs: vec![v(0)],
+        safepoints: vec![Inst::new(2), Inst::new(4)],
+        ..Default::default()
+    };
+    let env = int_env(3);
+    for reftype_spill_policy in [
+        ReftypeSpillPolicy::StackOnly,
+        ReftypeSpillPolicy::RegisterAllowed,
+        ReftypeSpillPolicy::AlwaysBackedBySlot,
+    ] {
+        let options = RegallocOptions {
+            reftype_spill_policy,
+            ..RegallocOptions::default()
+        };
+        let out = run(&func, &env, &options).unwrap();
+        let mut checker =
+            checker::Checker::new(&func, &env).with_reftype_spill_policy(reftype_spill_policy);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        for inst in [2, 4] {
+            let allocs: Vec<_> = out.slots_at_safepoint(Inst::new(inst)).collect();
+            assert!(!allocs.is_empty());
+            assert!(allocs.iter().all(|alloc| !alloc.is_none()));
+            assert!(
+                allocs.iter().filter(|alloc| alloc.is_stack()).count() <= 1,
+                "{:?} at inst{}: {:?}",
+                reftype_spill_policy,
+                inst,
+                allocs
+            );
+        }
+    }
+}
+
+#[test]
+fn test_stack_affinities() {
+    let v = |i| VReg::new(i, RegClass::Int);
+    // The references v0 and v2 are each live across a safepoint, v0
+    // together with v1, and everything is spilled at the first one.
+    let func = |stack_affinities| TestFunc {
+        insts: vec![
+            vec![Operand::reg_def(v(0))],
+            vec![Operand::reg_def(v(1))],
+            vec![],
+            vec![Operand::reg_use(v(0)), Operand::reg_use(v(1))],
+            vec![Operand::reg_def(v(2))],
+            vec![],
+            vec![Operand::reg_use(v(2))],
+            vec![],
+        ],
+        blocks: vec![InstRange::new(Inst::new(0), Inst::new(8))],
+        preds: vec![vec![]],
+        succs: vec![vec![]],
+        num_vregs: 3,
+        reftype_vregs: vec![v(0), v(2)],
+        safepoints: vec![Inst::new(2), Inst::new(5)],
+        spill_all: vec![Inst::new(2)],
+        stack_affinities,
+        ..Default::default()
+    };
+    let env = int_env(4);
+    let run_with = |stack_affinities| {
+        let func = func(stack_affinities);
+        let out = run(&func, &env, &RegallocOptions::default()).unwrap();
+        let mut checker = checker::Checker::new(&func, &env);
+        checker.prepare(&out);
+        checker.run().unwrap();
+        out
+    };
+    let s = |i| Allocation::stack(SpillSlot::new(i));
+    let stackmap = |out: &Output| {
+        out.safepoint_slots
+            .iter()
+            .map(|&(_, alloc)| alloc)
+            .collect::<Vec<_>>()
+    };
+    let slots = |out: &Output| {
+        let mut slots: Vec<_> = out
+            .spilled_vregs()
+            .map(|(vreg, slot, _)| (vreg.vreg(), slot.index()))
+            .collect();
+        slots.dedup();
+        slots
+    };
+
+    // By default v2 reuses the slot v1 last had.
+    let out = run_with(vec![]);
+    assert_eq!(slots(&out), [(0, 0), (1, 1), (2, 1)]);
+    assert_eq!(stackmap(&out), [s(0), s(1)]);
+
+    // Hinted, it shares v0's slot instead, and the stackmap at its
+    // safepoint names that slot.
+    let out = run_with(vec![(v(0), v(2))]);
+    assert_eq!(slots(&out), [(0, 0), (1, 1), (2, 0)]);
+    assert_eq!(stackmap(&out), [s(0), s(0)]);
+
+    // v0 and v1 are spilled at the same time, so a hint for them is
+    // ignored.
+    let out = run_with(vec![(v(0), v(1))]);
+    assert_eq!(slots(&out), [(0, 0), (1, 1), (2, 1)]);
+    assert_eq!(stackmap(&out), [s(0), s(1)]);
+}